@@ -1,48 +1,517 @@
-use crate::storage::Storage;
+use crate::{
+    audit::AuditSink,
+    config::{
+        ApiCorsConfig, ApiRateLimitConfig, ApiTlsConfig, CnameTargetMode, DnssecConfig,
+        TxtLimitsConfig, WebhookConfig,
+    },
+    health::HealthChecker,
+    idempotency::IdempotencyStore,
+    metrics::Metrics,
+    notify::Notifier,
+    policy::AutoSerial,
+    reload::ZoneReload,
+    status::Status,
+    storage::Storage,
+    trace::QueryTracer,
+    webhook::Webhooks,
+};
 use axum::{
-    routing::{get, put},
+    extract::MatchedPath,
+    http::{header, HeaderName, HeaderValue, Method, Request},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinHandle},
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 mod a;
 mod aaaa;
+mod audit;
+mod auth;
+mod check;
 mod cname;
+mod dname;
+mod dnssec;
+mod error;
+mod export;
+mod geo;
+mod health_check;
+mod idempotency;
+mod import;
+mod index;
+mod journal;
+mod metadata;
 mod mx;
+mod openapi;
+mod page;
+mod policy;
+mod powerdns;
+mod probe;
+mod ptr;
+mod ratelimit;
+mod record;
+mod search;
+mod soa;
+mod srv;
+mod svcb;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod tlsa;
+mod token;
+mod trace;
 mod txt;
+mod validate;
+mod webhook;
+mod weights;
 mod zone;
 
+/// Prefix of the routes of the current API version. The same routes are still served without the
+/// prefix for existing clients, but those are deprecated.
+const API_PREFIX: &str = "/v1";
+
 /// State for all API handlers.
 #[derive(Clone)]
 pub struct State {
     storage: Arc<dyn Storage + Send + Sync>,
+    tokens: Arc<auth::TokenCache>,
+    mx_cname_exchange: CnameTargetMode,
+    txt_limits: TxtLimitsConfig,
+    auto_serial: AutoSerial,
+    reject_private_addresses: bool,
+    tracer: Arc<QueryTracer>,
+    notifier: Notifier,
+    health: Arc<HealthChecker>,
+    zone_reload: Arc<ZoneReload>,
+    metrics: Metrics,
+    status: Arc<Status>,
+    rate_limiter: Option<Arc<ratelimit::ApiRateLimiter>>,
+    zsk_prepublish_secs: u64,
+    key_encryption_key: Option<Arc<dnssec::KeyEncryptionKey>>,
+    webhooks: Webhooks,
+    audit: Arc<dyn AuditSink + Send + Sync>,
+    audit_mandatory: bool,
+    idempotency: Arc<dyn IdempotencyStore + Send + Sync>,
+    idempotency_ttl: Duration,
+}
+
+/// Configuration of the API, and the parts of the DNS server it shares.
+pub struct ApiConfig {
+    /// Tokens which have every scope. As soon as there is a bootstrap token or a token created
+    /// through the API, all requests must be authenticated with one of them.
+    pub bootstrap_tokens: Vec<String>,
+    pub mx_cname_exchange: CnameTargetMode,
+    pub txt_limits: TxtLimitsConfig,
+    /// How the SOA serial changes when records change, for zones which don't override it.
+    pub auto_serial: AutoSerial,
+    /// Refuse special purpose addresses in A and AAAA records, for zones which don't override it.
+    pub reject_private_addresses: bool,
+    pub tracer: Arc<QueryTracer>,
+    pub notifier: Notifier,
+    pub health: Arc<HealthChecker>,
+    /// Signals the DNS server to reload zones into its zone cache.
+    pub zone_reload: Arc<ZoneReload>,
+    /// Metrics of the server, which the API adds its request metrics to.
+    pub metrics: Metrics,
+    /// Runtime status of the server, reported by the readiness probe.
+    pub status: Arc<Status>,
+    /// Rate limits of requests, requests are not limited if this is not set.
+    pub rate_limit: Option<ApiRateLimitConfig>,
+    /// CORS headers to add to responses, none are added if this is not set.
+    pub cors: Option<ApiCorsConfig>,
+    pub dnssec: DnssecConfig,
+    /// Endpoints which receive an event for every change made through the API.
+    pub webhooks: Vec<WebhookConfig>,
+    /// Audit log of requests which change data.
+    pub audit: Arc<dyn AuditSink + Send + Sync>,
+    /// Refuse requests which change data if the audit log can't be written.
+    pub audit_mandatory: bool,
+    /// Storage of the responses of requests with an idempotency key.
+    pub idempotency: Arc<dyn IdempotencyStore + Send + Sync>,
+    /// Time the response of a request with an idempotency key is replayed, in seconds.
+    pub idempotency_ttl_secs: u64,
+    /// Serve the API over TLS instead of plain HTTP.
+    pub tls: Option<ApiTlsConfig>,
+}
+
+/// Handle to a running API server.
+pub struct ApiHandle {
+    local_addr: SocketAddr,
+    shutdown: watch::Sender<()>,
+    server: JoinHandle<()>,
 }
 
-/// Create a new API instance with the given storage, and starts listening on the provided address
-pub fn listen<S>(storage: Arc<S>, listen_address: SocketAddr)
+impl ApiHandle {
+    /// The address the API is listening on. This differs from the configured address if that
+    /// has port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting new connections, and close existing connections once their in flight
+    /// requests are answered. Dropping the handle has the same effect.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(());
+    }
+
+    /// Wait until the server has stopped, which happens after a [`shutdown`](Self::shutdown).
+    pub async fn join(self) -> Result<(), JoinError> {
+        self.server.await
+    }
+}
+
+/// Create a new API instance with the given storage, and starts listening on the provided address.
+/// The listener is bound before returning, so a port which is not available is reported to the
+/// caller.
+pub fn listen<S>(
+    storage: Arc<S>,
+    listen_address: SocketAddr,
+    config: ApiConfig,
+) -> Result<ApiHandle, Box<dyn Error + Send + Sync>>
 where
     S: Storage + Send + Sync + 'static,
 {
     log::trace!("Setting up API");
-    // TODO: shutdown
-    let shared_state = State { storage };
-    let app = Router::new()
+    let tls_config = config
+        .tls
+        .map(|tls_config| {
+            let server_config = tls::load_server_config(&tls_config)
+                .map_err(|e| format!("Could not load api TLS configuration: {}", e))?;
+            Ok::<_, Box<dyn Error + Send + Sync>>((tls_config, server_config))
+        })
+        .transpose()?;
+    let cors = config.cors.as_ref().map(cors_layer).transpose()?;
+    let webhooks = Webhooks::new(config.webhooks)?;
+    let key_encryption_key = config
+        .dnssec
+        .key_encryption_key
+        .as_deref()
+        .map(dnssec::KeyEncryptionKey::from_base64)
+        .transpose()
+        .map_err(|e| format!("Invalid DNSSEC key encryption key: {}", e))?
+        .map(Arc::new);
+    let listener = std::net::TcpListener::bind(listen_address)
+        .map_err(|e| format!("Could not bind api listener {}: {}", listen_address, e))?;
+    listener.set_nonblocking(true)?;
+    let local_addr = listener.local_addr()?;
+
+    let shared_state = State {
+        storage,
+        tokens: Arc::new(auth::TokenCache::new(&config.bootstrap_tokens)),
+        mx_cname_exchange: config.mx_cname_exchange,
+        txt_limits: config.txt_limits,
+        auto_serial: config.auto_serial,
+        reject_private_addresses: config.reject_private_addresses,
+        tracer: config.tracer,
+        notifier: config.notifier,
+        health: config.health,
+        zone_reload: config.zone_reload,
+        metrics: config.metrics,
+        status: config.status,
+        rate_limiter: config
+            .rate_limit
+            .map(|config| Arc::new(ratelimit::ApiRateLimiter::new(config))),
+        zsk_prepublish_secs: config.dnssec.zsk_prepublish_secs,
+        key_encryption_key,
+        webhooks,
+        audit: config.audit,
+        audit_mandatory: config.audit_mandatory,
+        idempotency: config.idempotency,
+        idempotency_ttl: Duration::from_secs(config.idempotency_ttl_secs),
+    };
+    tokio::spawn(auth::refresh_loop(shared_state.clone()));
+    if let Some(ref rate_limiter) = shared_state.rate_limiter {
+        tokio::spawn(rate_limiter.clone().prune_loop());
+    }
+    let routes = Router::new()
         .route("/zones", get(zone::list_zones))
+        .route("/zones/reload", post(zone::reload_zones))
         .route(
             "/zones/:zone",
-            get(zone::list_zone_domains).put(zone::add_zone),
+            get(zone::list_zone_domains)
+                .put(zone::add_zone)
+                .delete(zone::delete_zone),
+        )
+        .route(
+            "/zones/:zone/policy",
+            get(policy::get_zone_policy).patch(policy::update_zone_policy),
         )
+        .route("/zones/:zone/export", get(export::export_zone))
+        .route("/zones/:zone/import", post(import::import_zone))
+        .route("/zones/import/powerdns", post(powerdns::import_powerdns))
+        .route("/zones/:zone/soa", patch(soa::update_soa))
+        .route("/zones/:zone/check", get(check::check_zone))
+        .route(
+            "/zones/:zone/dnssec/keys",
+            get(dnssec::list_keys).post(dnssec::create_key),
+        )
+        .route(
+            "/zones/:zone/dnssec/keys/rollover",
+            post(dnssec::rollover_zsk),
+        )
+        .route("/zones/:zone/dnssec/keys/:id", delete(dnssec::delete_key))
         .route("/zones/:zone/:domain", get(zone::list_domain_records))
-        .route("/zones/:zone/:domain/a", put(a::add_record))
-        .route("/zones/:zone/:domain/aaaa", put(aaaa::add_record))
-        .route("/zones/:zone/:domain/mx", put(mx::add_record))
-        .route("/zones/:zone/:domain/cname", put(cname::add_record))
-        .route("/zones/:zone/:domain/txt", put(txt::add_record))
+        // Routes of record types which can be added take precedence over the generic one, so they
+        // need to handle the generic methods as well.
+        .route(
+            "/zones/:zone/:domain/a",
+            put(a::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/aaaa",
+            put(aaaa::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/mx",
+            put(mx::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/cname",
+            put(cname::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/dname",
+            put(dname::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/txt",
+            put(txt::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/ptr",
+            put(ptr::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/srv",
+            put(srv::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/https",
+            put(svcb::add_https_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/svcb",
+            put(svcb::add_svcb_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/tlsa",
+            put(tlsa::add_record)
+                .get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/:rtype",
+            get(record::get_records)
+                .patch(record::update_ttl)
+                .delete(record::delete_records),
+        )
+        .route(
+            "/zones/:zone/:domain/:rtype/policy",
+            get(geo::get_geo_policy)
+                .put(geo::set_geo_policy)
+                .delete(geo::delete_geo_policy),
+        )
+        .route(
+            "/zones/:zone/:domain/:rtype/weights",
+            get(weights::get_weights).put(weights::set_weights),
+        )
+        .route("/records/search", get(search::search_records))
+        .route("/health-checks", get(health_check::list_health_checks))
+        .route("/tokens", get(token::list_tokens).post(token::create_token))
+        .route("/tokens/:id", delete(token::revoke_token))
+        .route(
+            "/admin/trace-filters",
+            get(trace::list_trace_filters)
+                .post(trace::add_trace_filter)
+                .delete(trace::clear_trace_filters),
+        )
+        .route(
+            "/admin/trace-filters/:id",
+            delete(trace::remove_trace_filter),
+        )
+        .route("/webhooks/deliveries", get(webhook::list_deliveries))
+        .route("/audit", get(audit::list_audit))
+        // Events and the audit log are written from within the authentication layer, so they know
+        // the token which made the change. Replayed responses don't send events again, but are
+        // still audited.
+        .layer(middleware::from_fn(journal::report_serial))
+        .layer(middleware::from_fn(webhook::send_events))
+        .layer(middleware::from_fn(idempotency::replay))
+        .layer(middleware::from_fn(audit::record))
+        .layer(middleware::from_fn(auth::authenticate));
+    // Nesting strips the prefix before the request reaches the routes, so the handlers and the
+    // authentication see the same path for both forms.
+    let mut app = Router::new()
+        .nest(API_PREFIX, routes.clone())
+        .merge(routes.layer(middleware::from_fn(deprecated)))
+        // The index and the specification are public, so they are added after the
+        // authentication layer.
+        .route(API_PREFIX, get(index::index))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/docs", get(openapi::docs))
+        .layer(middleware::from_fn(ratelimit::rate_limit));
+    // Preflight requests are answered by the CORS layer itself, so they need neither a token nor
+    // a share of the rate limit.
+    if let Some(cors) = cors {
+        app = app.layer(cors);
+    }
+    let app = app
+        // Probes are never throttled, a throttled liveness probe would get the process killed.
+        .route("/health", get(probe::health))
+        .route("/ready", get(probe::ready))
+        .layer(middleware::from_fn(track_metrics))
         .layer(Extension(shared_state));
-    tokio::spawn(async move {
-        axum::Server::bind(&listen_address)
-            .serve(app.into_make_service())
-            .await
-    });
+    let (shutdown, mut shutdown_rx) = watch::channel(());
+    let server = match tls_config {
+        Some((tls_config, server_config)) => tokio::spawn(tls::serve(
+            app,
+            listener,
+            tls_config,
+            server_config,
+            shutdown_rx,
+        )),
+        None => {
+            let server = axum::Server::from_tcp(listener)?
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async move {
+                    // An error means the handle is dropped, which also stops the server.
+                    let _ = shutdown_rx.changed().await;
+                });
+            tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    log::error!("Api server failed: {}", e);
+                }
+            })
+        }
+    };
     log::trace!("API set up");
+
+    Ok(ApiHandle {
+        local_addr,
+        shutdown,
+        server,
+    })
+}
+
+/// Build the CORS layer from its configuration.
+fn cors_layer(config: &ApiCorsConfig) -> Result<CorsLayer, Box<dyn Error + Send + Sync>> {
+    let origins = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid api CORS origin: {}", e))?,
+        )
+    };
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid api CORS method: {}", e))?;
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            HeaderName::from_static(idempotency::IDEMPOTENCY_KEY_HEADER),
+        ])
+        .expose_headers([
+            header::RETRY_AFTER,
+            HeaderName::from_static(idempotency::REPLAYED_HEADER),
+            HeaderName::from_static(journal::ZONE_SERIAL_HEADER),
+        ]);
+    if let Some(max_age) = config.max_age_secs {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+    Ok(layer)
+}
+
+/// Middleware marking responses of the unversioned routes as deprecated, pointing to the route of
+/// the current version (RFC 8594).
+async fn deprecated<B>(req: Request<B>, next: Next<B>) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        API_PREFIX,
+        req.uri().path()
+    );
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
+
+/// Middleware recording the amount and duration of requests, labeled with the route template
+/// rather than the path so zone and domain names don't end up in the labels.
+async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let method = req.method().clone();
+    let metrics = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set")
+        .metrics
+        .clone();
+
+    let response = next.run(req).await;
+
+    let status = format!("{}xx", response.status().as_u16() / 100);
+    metrics.observe_api_request(&route, method.as_str(), &status, start.elapsed());
+    response
 }
@@ -1,54 +1,129 @@
-use std::net::Ipv6Addr;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr},
+};
 
-use super::State;
-use crate::storage::StorageRecord;
-use axum::{extract, http::StatusCode, response, Extension};
-use log::error;
+use super::{
+    error::ApiError,
+    metadata::{validate_geo_policy, validate_health_check, RecordMetadata, RecordWeight},
+    ptr,
+    record::{self, WriteQuery},
+    validate::{validate_public_addresses, AddressCheck, OneOrMany, ZoneDomain},
+    State,
+};
+use crate::{geo::GeoPolicy, health::HealthCheck};
+use axum::{extract, http::StatusCode, Extension};
 use serde::Deserialize;
-use trust_dns_proto::rr::{Name, RData, Record};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
-pub struct AddARecord {
-    data: Ipv6Addr,
+#[derive(Deserialize, ToSchema)]
+pub struct AddAaaaRecord {
+    /// An address, or an array of addresses to add a record for each of them.
+    #[schema(value_type = String, format = "ipv6", example = "2001:db8::1")]
+    data: OneOrMany<Ipv6Addr>,
     ttl: u32,
+    /// Only serve the record to clients in these locations.
+    geo: Option<GeoPolicy>,
+    /// Leave the record out of answers while the address is unhealthy.
+    health_check: Option<HealthCheck>,
+    #[serde(flatten)]
+    weight: RecordWeight,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
 }
 
+/// Add an AAAA record to a domain, or one record per address if multiple addresses are given. The
+/// records share the TTL and the other fields of the request.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/aaaa",
+    tag = "records",
+    params(ZoneDomain, WriteQuery, AddressCheck),
+    request_body = AddAaaaRecord,
+    responses(
+        (status = 201, description = "The records were added"),
+        (status = 200, description = "The records were already present, or replaced the existing records"),
+        (status = 400, description = "The records are not valid, or an address is private in a zone which refuses those"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The record conflicts with the records of the domain"),
+    )
+)]
 pub async fn add_record(
     extract::Path((zone, domain)): extract::Path<(Name, Name)>,
-    extract::Json(data): extract::Json<AddARecord>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Query(address_check): extract::Query<AddressCheck>,
+    extract::Json(data): extract::Json<AddAaaaRecord>,
     Extension(state): Extension<State>,
-) -> response::Result<StatusCode> {
+) -> Result<StatusCode, ApiError> {
     if !zone.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn zones",
-        )
-            .into());
+        return Err(ApiError::not_fqdn("Zone"));
     }
 
     if !domain.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn domains",
-        )
-            .into());
+        return Err(ApiError::not_fqdn("Domain"));
     }
 
-    let record = Record::from_rdata(domain.clone(), data.ttl, RData::AAAA(data.data));
-
-    state
-        .storage
-        .add_record(
-            &LowerName::from(zone),
-            &LowerName::from(domain),
-            StorageRecord { record },
-        )
-        .await
-        .map_err(|err| {
-            error!("Failed to insert AAAA record: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    Ok(StatusCode::CREATED)
+    data.metadata.validate()?;
+    data.weight.validate()?;
+    if let Some(ref geo) = data.geo {
+        validate_geo_policy(geo)?;
+    }
+    if let Some(ref check) = data.health_check {
+        validate_health_check(check)?;
+    }
+
+    // Addresses which are given more than once only get a single record.
+    let mut seen = HashSet::new();
+    let addresses = data
+        .data
+        .into_vec()
+        .into_iter()
+        .filter(|addr| seen.insert(*addr))
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(ApiError::bad_request("no_records", "No addresses given"));
+    }
+
+    let zone = LowerName::from(zone);
+    validate_public_addresses(
+        &state,
+        &zone,
+        addresses.iter().map(|&addr| IpAddr::V6(addr)),
+        &address_check,
+    )
+    .await?;
+    // Every record gets the same metadata, only the address differs.
+    let mut template = data.metadata.into_storage_record(Record::from_rdata(
+        domain.clone(),
+        data.ttl,
+        RData::AAAA(addresses[0]),
+    ));
+    template.geo = data.geo;
+    template.health_check = data.health_check;
+    data.weight.apply(&mut template);
+    let storage_records = addresses
+        .iter()
+        .map(|&addr| {
+            let mut storage_record = template.clone();
+            storage_record.record = Record::from_rdata(domain.clone(), data.ttl, RData::AAAA(addr));
+            storage_record
+        })
+        .collect();
+
+    let status = record::store_records(
+        &state,
+        &zone,
+        &domain,
+        RecordType::AAAA,
+        storage_records,
+        write,
+    )
+    .await?;
+    for addr in addresses {
+        ptr::auto_ptr(&state, &zone, IpAddr::V6(addr), &domain, data.ttl).await;
+    }
+
+    Ok(status)
 }
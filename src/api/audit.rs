@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+
+use super::{
+    auth::{unix_now, Actor},
+    error::ApiError,
+    page::{page_error, Listing, PageQuery},
+    State,
+};
+use crate::audit::AuditEntry;
+use axum::{
+    extract::{self, ConnectInfo, OriginalUri},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{self, IntoResponse, Response},
+    Extension,
+};
+use log::error;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+/// Time range of the audit log returned if no start is given, in seconds.
+const DEFAULT_AUDIT_RANGE: u64 = 86_400;
+
+/// Middleware recording every request which changes data in the audit log. Entries are written in
+/// the background, so a failing audit log does not slow down or fail requests, unless the audit
+/// log is mandatory. In that case an entry is written before the request is handled, and the
+/// request is refused if that fails.
+pub async fn record<B>(req: Request<B>, next: Next<B>) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let state = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set")
+        .clone();
+    // Nested routes see the path without the version prefix, the original has it.
+    let uri = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| req.uri().clone(), |OriginalUri(uri)| uri.clone());
+    let mut entry = AuditEntry {
+        timestamp: unix_now(),
+        actor: req
+            .extensions()
+            .get::<Actor>()
+            .map(|Actor(actor)| actor.clone()),
+        client: req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        method: req.method().to_string(),
+        path: uri.path().to_string(),
+        query: uri.query().map(str::to_string),
+        body_size: req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok()),
+        zone: zone_of_path(uri.path()),
+        status: None,
+    };
+
+    if state.audit_mandatory {
+        if let Err(e) = state.audit.append_audit(&entry).await {
+            error!("Failed to write audit log entry, refusing request: {}", e);
+            state.metrics.increment_audit_write_failure();
+            return ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "audit_unavailable",
+                "The audit log can't be written, changes are refused",
+            )
+            .into_response();
+        }
+    }
+
+    let response = next.run(req).await;
+
+    entry.status = Some(response.status().as_u16());
+    tokio::spawn(async move {
+        if let Err(e) = state.audit.append_audit(&entry).await {
+            error!("Failed to write audit log entry: {}", e);
+            state.metrics.increment_audit_write_failure();
+        }
+    });
+
+    response
+}
+
+/// The zone in a path of the form `[/v1]/zones/<zone>/...`. Paths of changes to multiple zones
+/// have no zone.
+fn zone_of_path(path: &str) -> Option<String> {
+    let mut segments = path.split('/').skip_while(|segment| *segment != "zones");
+    segments.next()?;
+    segments
+        .next()
+        .filter(|zone| !zone.is_empty() && !matches!(*zone, "reload" | "import"))
+        .map(normalize_zone)
+}
+
+/// Lowercase a zone and make it fully qualified, so a zone matches regardless of how it was
+/// written in the path.
+fn normalize_zone(zone: &str) -> String {
+    let zone = zone.to_lowercase();
+    if zone.ends_with('.') {
+        zone
+    } else {
+        zone + "."
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only return entries of this zone.
+    zone: Option<String>,
+    /// Only return entries from this time on, in seconds since the unix epoch. Defaults to 24
+    /// hours ago.
+    since: Option<u64>,
+}
+
+/// List the audit log of requests which changed data, oldest first. This is always paged.
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "admin",
+    params(AuditQuery, PageQuery),
+    responses(
+        (status = 200, description = "A page of the audit log", body = AuditListing),
+        (status = 400, description = "The cursor is not valid"),
+    )
+)]
+pub async fn list_audit(
+    extract::Query(query): extract::Query<AuditQuery>,
+    extract::Query(page): extract::Query<PageQuery>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<Listing<AuditEntry>>, ApiError> {
+    let since = query
+        .since
+        .unwrap_or_else(|| unix_now().saturating_sub(DEFAULT_AUDIT_RANGE));
+    let zone = query.zone.as_deref().map(normalize_zone);
+
+    let entries = state
+        .audit
+        .audit_page(
+            since,
+            zone.as_deref(),
+            page.cursor()?.as_deref(),
+            page.limit(),
+        )
+        .await
+        .map_err(|err| page_error("Failed to read audit log", err))?;
+
+    Ok(response::Json(Listing::from_page(entries, |entry| entry)))
+}
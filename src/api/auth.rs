@@ -0,0 +1,209 @@
+use super::State;
+use crate::storage::{ApiToken, Storage, TokenScope};
+use axum::{
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use log::{debug, error, trace, warn};
+use ring::digest::{digest, SHA256};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Id reported as the actor of requests made with a bootstrap token.
+const BOOTSTRAP_ACTOR: &str = "bootstrap";
+
+/// Interval between refreshes of the token cache.
+const TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Id of the token which authenticated a request, added to the request by [`authenticate`]. Not
+/// present if authentication is disabled.
+#[derive(Clone)]
+pub struct Actor(pub String);
+
+/// In memory view of all API tokens, so authenticating a request does not need to hit storage.
+pub struct TokenCache {
+    /// Hashes of the bootstrap tokens from the config file. These have every scope and can't be
+    /// revoked through the API.
+    bootstrap: HashSet<String>,
+    /// Tokens loaded from storage, keyed by the hash of their secret.
+    tokens: RwLock<HashMap<String, ApiToken>>,
+    /// Whether the tokens were loaded from storage at least once.
+    loaded: AtomicBool,
+    /// Last use of tokens since the previous refresh, keyed by token id. This is flushed to
+    /// storage on refresh, so usage tracking doesn't cost a storage write per request.
+    usage: Mutex<HashMap<String, u64>>,
+}
+
+impl TokenCache {
+    /// Create a new [`TokenCache`] with the given plaintext bootstrap tokens. If no bootstrap
+    /// tokens are given and there are no tokens in storage, authentication is disabled.
+    pub fn new(bootstrap_tokens: &[String]) -> Self {
+        TokenCache {
+            bootstrap: bootstrap_tokens
+                .iter()
+                .map(|token| hash_secret(token))
+                .collect(),
+            tokens: RwLock::new(HashMap::new()),
+            loaded: AtomicBool::new(false),
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if requests need to be authenticated. This is the case as soon as any token exists,
+    /// either a bootstrap token or a token in storage. Revoked and expired tokens count as well, so
+    /// revoking the last token does not open up the API.
+    pub fn enabled(&self) -> bool {
+        !self.bootstrap.is_empty() || !self.tokens.read().unwrap().is_empty()
+    }
+
+    /// Load the tokens from storage if that did not happen yet, so tokens in storage are enforced
+    /// from the first request on.
+    pub async fn load(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.loaded.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.refresh(storage).await?;
+        if !self.enabled() {
+            warn!("No API tokens configured, API authentication is disabled");
+        }
+        Ok(())
+    }
+
+    /// Reload all tokens from storage, and persist the usage recorded since the last refresh.
+    pub async fn refresh(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        trace!("Refreshing API token cache");
+        let usage = std::mem::take(&mut *self.usage.lock().unwrap());
+        for (id, last_used) in usage {
+            if let Err(e) = storage.set_token_last_used(&id, last_used).await {
+                warn!("Failed to persist last use of API token {}: {}", id, e);
+            }
+        }
+
+        let tokens = storage.tokens().await?;
+        debug!("Loaded {} API tokens", tokens.len());
+        *self.tokens.write().unwrap() = tokens
+            .into_iter()
+            .map(|token| (token.secret_hash.clone(), token))
+            .collect();
+        self.loaded.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Check if the plaintext token grants the required scope, returning the id of the token.
+    /// Returns [`StatusCode::UNAUTHORIZED`] if the token is unknown or no longer valid, and
+    /// [`StatusCode::FORBIDDEN`] if it is valid but does not grant the required scope.
+    fn authorize(&self, token: &str, required: TokenScope) -> Result<String, StatusCode> {
+        let secret_hash = hash_secret(token);
+        if self.bootstrap.contains(&secret_hash) {
+            return Ok(BOOTSTRAP_ACTOR.to_string());
+        }
+
+        let tokens = self.tokens.read().unwrap();
+        let api_token = tokens.get(&secret_hash).ok_or(StatusCode::UNAUTHORIZED)?;
+        let now = unix_now();
+        if api_token.disabled || matches!(api_token.expires_at, Some(expiry) if expiry <= now) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        if !api_token.scopes.iter().any(|scope| scope.grants(required)) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        self.usage.lock().unwrap().insert(api_token.id.clone(), now);
+
+        Ok(api_token.id.clone())
+    }
+}
+
+/// Periodically refresh the token cache.
+pub async fn refresh_loop(state: State) {
+    if let Err(e) = state.tokens.load(&*state.storage).await {
+        error!("Failed to load API tokens: {}", e);
+    }
+    let mut interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + TOKEN_REFRESH_INTERVAL,
+        TOKEN_REFRESH_INTERVAL,
+    );
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.tokens.refresh(&*state.storage).await {
+            error!("Failed to refresh API token cache: {}", e);
+        }
+    }
+}
+
+/// Middleware authenticating requests with a bearer token. Token management, webhook deliveries,
+/// the audit log and the admin endpoints require the admin scope, other GET requests require the
+/// read scope, and all other requests the write scope. The id of the token is added to the request
+/// as an [`Actor`]. If the tokens could not be loaded from storage yet, requests are refused.
+pub async fn authenticate<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    let state = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set")
+        .clone();
+    if let Err(e) = state.tokens.load(&*state.storage).await {
+        error!("Failed to load API tokens: {}", e);
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let actor = {
+        if state.tokens.enabled() {
+            let token = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+
+            let path = req.uri().path();
+            let required = if path.starts_with("/tokens")
+                || path.starts_with("/admin")
+                || path.starts_with("/webhooks")
+                || path.starts_with("/audit")
+            {
+                TokenScope::Admin
+            } else if req.method() == Method::GET {
+                TokenScope::Read
+            } else {
+                TokenScope::Write
+            };
+
+            Some(state.tokens.authorize(token, required)?)
+        } else {
+            None
+        }
+    };
+    if let Some(actor) = actor {
+        req.extensions_mut().insert(Actor(actor));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Hash a plaintext token secret into the form stored in an [`ApiToken`].
+pub fn hash_secret(secret: &str) -> String {
+    faster_hex::hex_string(digest(&SHA256, secret.as_bytes()).as_ref())
+}
+
+/// Current time in seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time is after the unix epoch")
+        .as_secs()
+}
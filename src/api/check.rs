@@ -0,0 +1,373 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{error::ApiError, record::type_name, zone, State};
+use axum::{extract, response, Extension};
+use log::trace;
+use serde::Serialize;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// Maximum amount of findings included in a report. Further findings are only counted, so a
+/// badly broken zone can't produce an unbounded response.
+const MAX_FINDINGS: usize = 1_000;
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The zone is broken, resolvers will get wrong or no answers.
+    Error,
+    /// The zone works, but likely not as intended.
+    Warning,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Finding {
+    severity: Severity,
+    /// Machine readable code of the problem.
+    code: &'static str,
+    #[schema(value_type = String)]
+    domain: Name,
+    /// Type of the RRset the problem is in, if it is limited to one.
+    record_type: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ZoneCheck {
+    #[schema(value_type = String)]
+    zone: Name,
+    domains_checked: usize,
+    /// Total amount of errors found, including the ones not listed.
+    errors: usize,
+    /// Total amount of warnings found, including the ones not listed.
+    warnings: usize,
+    /// The findings, in canonical order of their domain, up to a fixed maximum.
+    findings: Vec<Finding>,
+    /// Not all findings are listed, as there are more than the maximum.
+    truncated: bool,
+}
+
+impl ZoneCheck {
+    fn report(
+        &mut self,
+        severity: Severity,
+        code: &'static str,
+        domain: &Name,
+        rtype: Option<RecordType>,
+        message: String,
+    ) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        if self.findings.len() >= MAX_FINDINGS {
+            self.truncated = true;
+            return;
+        }
+        self.findings.push(Finding {
+            severity,
+            code,
+            domain: domain.clone(),
+            record_type: rtype.map(type_name),
+            message,
+        });
+    }
+}
+
+/// A zone cut below the apex, with the addresses found for it so far.
+struct Delegation {
+    name: Name,
+    /// Name servers of the delegation which are below the cut, and so need glue.
+    in_bailiwick: HashSet<Name>,
+    /// Name servers for which glue was found.
+    glued: HashSet<Name>,
+}
+
+/// Check a zone for common mistakes, like a missing SOA or apex NS, CNAMEs next to other data,
+/// records outside the zone, glue without a delegation which uses it, TTLs of 0, and duplicate
+/// records. Domains are checked one at a time, so only the records of a single domain are loaded
+/// at once.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/check",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    responses(
+        (status = 200, description = "The findings of the check", body = ZoneCheck),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn check_zone(
+    extract::Path(zone): extract::Path<Name>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<ZoneCheck>, ApiError> {
+    trace!("Checking zone {}", zone);
+    if !zone.is_fqdn() {
+        return Err(ApiError::not_fqdn("Zone"));
+    }
+    let zone_name = LowerName::from(zone.clone());
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    let mut domains = state
+        .storage
+        .list_domains(&zone_name)
+        .await
+        .map_err(|err| ApiError::storage("Failed to list domains", err))?;
+    // In canonical order a zone cut comes right before all names below it, so delegations can be
+    // checked in a single pass.
+    domains.sort();
+
+    let mut check = ZoneCheck {
+        zone: zone.clone(),
+        domains_checked: 0,
+        errors: 0,
+        warnings: 0,
+        findings: Vec::new(),
+        truncated: false,
+    };
+    // The apex sorts first, so if it is not the first domain the zone has no apex records at all.
+    if domains.first() != Some(&zone_name) {
+        check_apex(&mut check, &zone, &HashMap::new());
+    }
+    let mut delegation: Option<Delegation> = None;
+    for domain in domains {
+        let records = state
+            .storage
+            .list_records(&zone_name, &domain)
+            .await
+            .map_err(|err| ApiError::storage("Failed to list records", err))?;
+        let domain = Name::from(domain);
+        check.domains_checked += 1;
+
+        if delegation
+            .as_ref()
+            .is_some_and(|cut| !cut.name.zone_of(&domain))
+        {
+            finish_delegation(&mut check, delegation.take());
+        }
+
+        let mut rrsets: HashMap<RecordType, Vec<&Record>> = HashMap::new();
+        for sr in &records {
+            let record = sr.as_record();
+            if !zone.zone_of(record.name()) {
+                check.report(
+                    Severity::Error,
+                    "outside_zone",
+                    &domain,
+                    Some(record.record_type()),
+                    format!("Record owner {} is not in the zone", record.name()),
+                );
+                continue;
+            }
+            rrsets.entry(record.record_type()).or_default().push(record);
+        }
+
+        check_rrsets(&mut check, &domain, &rrsets);
+
+        if domain == zone {
+            check_apex(&mut check, &domain, &rrsets);
+        } else if let Some(cut) = delegation.as_mut() {
+            check_glue(&mut check, cut, &domain, &rrsets);
+        } else if let Some(ns) = rrsets.get(&RecordType::NS) {
+            let mut cut = Delegation {
+                name: domain.clone(),
+                in_bailiwick: ns
+                    .iter()
+                    .filter_map(|record| match record.data() {
+                        Some(RData::NS(target)) if domain.zone_of(target) => {
+                            Some(target.to_lowercase())
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                glued: HashSet::new(),
+            };
+            // A name server can be the delegated name itself.
+            check_glue(&mut check, &mut cut, &domain, &rrsets);
+            delegation = Some(cut);
+        }
+    }
+    finish_delegation(&mut check, delegation);
+
+    Ok(response::Json(check))
+}
+
+/// Check the records of a domain which don't depend on other domains.
+fn check_rrsets(check: &mut ZoneCheck, domain: &Name, rrsets: &HashMap<RecordType, Vec<&Record>>) {
+    let mut rtypes = rrsets.keys().copied().collect::<Vec<_>>();
+    rtypes.sort_by_key(|rtype| u16::from(*rtype));
+
+    if let Some(cnames) = rrsets.get(&RecordType::CNAME) {
+        if rtypes.len() > 1 {
+            check.report(
+                Severity::Error,
+                "cname_conflict",
+                domain,
+                Some(RecordType::CNAME),
+                format!(
+                    "CNAME exists next to other data: {}",
+                    rtypes
+                        .iter()
+                        .filter(|rtype| **rtype != RecordType::CNAME)
+                        .map(|rtype| type_name(*rtype))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            );
+        }
+        if cnames.len() > 1 {
+            check.report(
+                Severity::Error,
+                "cname_conflict",
+                domain,
+                Some(RecordType::CNAME),
+                format!("Domain has {} CNAME records", cnames.len()),
+            );
+        }
+    }
+
+    for rtype in rtypes {
+        let rrset = &rrsets[&rtype];
+        if rrset.iter().any(|record| record.ttl() == 0) {
+            check.report(
+                Severity::Warning,
+                "zero_ttl",
+                domain,
+                Some(rtype),
+                "RRset has a TTL of 0, so answers are never cached".to_string(),
+            );
+        }
+        let duplicates = rrset
+            .iter()
+            .enumerate()
+            .filter(|(i, record)| {
+                rrset[..*i]
+                    .iter()
+                    .any(|other| other.data() == record.data())
+            })
+            .count();
+        if duplicates > 0 {
+            check.report(
+                Severity::Warning,
+                "duplicate_rdata",
+                domain,
+                Some(rtype),
+                format!("RRset contains {} duplicate records", duplicates),
+            );
+        }
+    }
+}
+
+fn check_apex(check: &mut ZoneCheck, apex: &Name, rrsets: &HashMap<RecordType, Vec<&Record>>) {
+    if !rrsets.contains_key(&RecordType::SOA) {
+        check.report(
+            Severity::Error,
+            "missing_soa",
+            apex,
+            Some(RecordType::SOA),
+            "Zone has no SOA record".to_string(),
+        );
+    }
+    if !rrsets.contains_key(&RecordType::NS) {
+        check.report(
+            Severity::Error,
+            "missing_apex_ns",
+            apex,
+            Some(RecordType::NS),
+            "Zone has no NS records at the apex".to_string(),
+        );
+    }
+}
+
+/// Check the address records at or below a zone cut, which are only served as glue.
+fn check_glue(
+    check: &mut ZoneCheck,
+    cut: &mut Delegation,
+    domain: &Name,
+    rrsets: &HashMap<RecordType, Vec<&Record>>,
+) {
+    for rtype in [RecordType::A, RecordType::AAAA] {
+        if !rrsets.contains_key(&rtype) {
+            continue;
+        }
+        if cut.in_bailiwick.contains(domain) {
+            cut.glued.insert(domain.clone());
+        } else {
+            check.report(
+                Severity::Warning,
+                "dangling_glue",
+                domain,
+                Some(rtype),
+                format!(
+                    "Address records below the delegation of {} are not used by any of its name servers",
+                    cut.name
+                ),
+            );
+        }
+    }
+}
+
+/// Report the name servers of a delegation which need glue, but have none.
+fn finish_delegation(check: &mut ZoneCheck, cut: Option<Delegation>) {
+    let cut = match cut {
+        Some(cut) => cut,
+        None => return,
+    };
+    let mut missing = cut
+        .in_bailiwick
+        .difference(&cut.glued)
+        .cloned()
+        .collect::<Vec<_>>();
+    missing.sort();
+    for ns in missing {
+        check.report(
+            Severity::Error,
+            "missing_glue",
+            &cut.name,
+            Some(RecordType::NS),
+            format!(
+                "Name server {} is below the delegation, but has no glue",
+                ns
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::{
+        api::tests::TestApi,
+        handle::tests::{a, lower},
+        storage::Storage,
+    };
+
+    #[tokio::test]
+    async fn zone_without_soa_is_an_error() {
+        let api = TestApi::start().await;
+        let zone = lower("broken.test.");
+        api.storage.add_zone(&zone).await.expect("Can add zone");
+        api.storage
+            .add_record(
+                &zone,
+                &lower("www.broken.test."),
+                a("www.broken.test.", [192, 0, 2, 1]),
+            )
+            .await
+            .expect("Can add record");
+
+        let (status, body) = api.get("/v1/zones/broken.test./check").await;
+        assert_eq!(status, StatusCode::OK);
+        let finding = body["findings"]
+            .as_array()
+            .expect("Findings are listed")
+            .iter()
+            .find(|finding| finding["code"] == "missing_soa")
+            .expect("Missing SOA is reported");
+        assert_eq!(finding["severity"], "error");
+        assert_eq!(finding["domain"], "broken.test.");
+    }
+}
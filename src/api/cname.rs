@@ -1,22 +1,46 @@
-use super::State;
-use crate::storage::StorageRecord;
-use axum::{extract, http::StatusCode, response, Extension};
-use log::error;
+use super::{
+    error::ApiError,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::ZoneDomain,
+    State,
+};
+use axum::{extract, http::StatusCode, Extension};
 use serde::Deserialize;
-use trust_dns_proto::rr::{Name, RData, Record};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
-pub struct AddARecord {
+#[derive(Deserialize, ToSchema)]
+pub struct AddCnameRecord {
+    #[schema(value_type = String, example = "target.example.com.")]
     data: Name,
     ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
 }
 
+/// Set the CNAME record of a domain.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/cname",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body = AddCnameRecord,
+    responses(
+        (status = 201, description = "The record was added"),
+        (status = 200, description = "The record was already present, or replaced the existing records"),
+        (status = 400, description = "The record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The record conflicts with the records of the domain"),
+    )
+)]
 pub async fn add_record(
     extract::Path((zone, domain)): extract::Path<(Name, Name)>,
-    extract::Json(data): extract::Json<AddARecord>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<AddCnameRecord>,
     Extension(state): Extension<State>,
-) -> response::Result<StatusCode> {
+) -> Result<StatusCode, ApiError> {
     if !zone.is_fqdn() {
         return Err((
             StatusCode::BAD_REQUEST,
@@ -33,20 +57,24 @@ pub async fn add_record(
             .into());
     }
 
+    if !data.data.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "CNAME target must be an fqdn").into());
+    }
+
+    data.metadata.validate()?;
+
     let record = Record::from_rdata(domain.clone(), data.ttl, RData::CNAME(data.data));
 
-    state
-        .storage
-        .add_record(
-            &LowerName::from(zone),
-            &LowerName::from(domain),
-            StorageRecord { record },
-        )
-        .await
-        .map_err(|err| {
-            error!("Failed to insert CNAME record: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let zone = LowerName::from(zone);
+    let storage_record = data.metadata.into_storage_record(record);
 
-    Ok(StatusCode::CREATED)
+    record::store_records(
+        &state,
+        &zone,
+        &domain,
+        RecordType::CNAME,
+        vec![storage_record],
+        write,
+    )
+    .await
 }
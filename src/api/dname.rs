@@ -0,0 +1,91 @@
+use super::{
+    error::ApiError,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::ZoneDomain,
+    State,
+};
+use crate::dname;
+use axum::{extract, http::StatusCode, Extension};
+use serde::Deserialize;
+use trust_dns_proto::rr::Name;
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddDnameRecord {
+    #[schema(value_type = String, example = "example.net.")]
+    data: Name,
+    ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
+}
+
+/// Set the DNAME record of a domain.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/dname",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body = AddDnameRecord,
+    responses(
+        (status = 201, description = "The record was added"),
+        (status = 200, description = "The record was already present, or replaced the existing records"),
+        (status = 400, description = "The record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The record conflicts with the records of the domain"),
+    )
+)]
+pub async fn add_record(
+    extract::Path((zone, domain)): extract::Path<(Name, Name)>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<AddDnameRecord>,
+    Extension(state): Extension<State>,
+) -> Result<StatusCode, ApiError> {
+    if !zone.is_fqdn() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Can only add records for fqdn zones",
+        )
+            .into());
+    }
+
+    if !domain.is_fqdn() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Can only add records for fqdn domains",
+        )
+            .into());
+    }
+
+    if !data.data.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "DNAME target must be an fqdn").into());
+    }
+
+    // Names below the target would be redirected to themselves over and over.
+    if domain.zone_of(&data.data) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "DNAME target can't be the domain or a name below it",
+        )
+            .into());
+    }
+
+    data.metadata.validate()?;
+
+    let record = dname::record(domain.clone(), data.ttl, &data.data)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid DNAME target"))?;
+
+    let zone = LowerName::from(zone);
+    let storage_record = data.metadata.into_storage_record(record);
+
+    record::store_records(
+        &state,
+        &zone,
+        &domain,
+        dname::DNAME,
+        vec![storage_record],
+        write,
+    )
+    .await
+}
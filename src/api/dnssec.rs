@@ -0,0 +1,531 @@
+use super::{auth::unix_now, error::ApiError, zone, State};
+use crate::storage::{DnssecKey, KeyAlgorithm, KeyRole, KeyState};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::{error, trace};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use trust_dns_proto::rr::Name;
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// Length of a generated key id, in bytes.
+const KEY_ID_LENGTH: usize = 8;
+/// Flags of the DNSKEY record of a zone signing key: only the Zone Key bit.
+const ZSK_FLAGS: u16 = 256;
+/// Flags of the DNSKEY record of a key signing key: the Zone Key and Secure Entry Point bits.
+const KSK_FLAGS: u16 = 257;
+/// Protocol field of a DNSKEY record, which is always 3 (RFC 4034, section 2.1.2).
+const DNSKEY_PROTOCOL: u8 = 3;
+/// Digest type of the DS records generated for key signing keys: SHA-256.
+const DS_DIGEST_SHA256: u8 = 2;
+
+/// Key which encrypts the private keys of DNSSEC keys before they are stored.
+pub struct KeyEncryptionKey(LessSafeKey);
+
+impl KeyEncryptionKey {
+    /// Load a base64 encoded 32 byte key.
+    pub fn from_base64(key: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let key = base64::decode(key.trim())?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| {
+            format!(
+                "key must be {} bytes, got {}",
+                CHACHA20_POLY1305.key_len(),
+                key.len()
+            )
+        })?;
+        Ok(KeyEncryptionKey(LessSafeKey::new(key)))
+    }
+
+    /// Encrypt a private key, bound to the zone and id of the key. Returns the base64 encoded
+    /// random nonce, followed by the ciphertext and tag.
+    fn seal(
+        &self,
+        zone: &LowerName,
+        id: &str,
+        private_key: &[u8],
+        rng: &dyn SecureRandom,
+    ) -> Option<String> {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut nonce).ok()?;
+        let mut sealed = private_key.to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(associated_data(zone, id)),
+                &mut sealed,
+            )
+            .ok()?;
+        let mut encoded = nonce.to_vec();
+        encoded.append(&mut sealed);
+        Some(base64::encode(encoded))
+    }
+
+    /// Decrypt a private key sealed for the zone and id of the key.
+    #[cfg(test)]
+    fn open(&self, zone: &LowerName, id: &str, sealed: &str) -> Option<Vec<u8>> {
+        let mut sealed = base64::decode(sealed).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).ok()?;
+        let plaintext = self
+            .0
+            .open_in_place(nonce, Aad::from(associated_data(zone, id)), &mut ciphertext)
+            .ok()?;
+        Some(plaintext.to_vec())
+    }
+}
+
+/// The data authenticated along with an encrypted private key, so it can't be swapped with the
+/// private key of another key.
+fn associated_data(zone: &LowerName, id: &str) -> Vec<u8> {
+    format!("{}/{}", zone, id).into_bytes()
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateKey {
+    role: KeyRole,
+    algorithm: KeyAlgorithm,
+}
+
+/// A DNSSEC key of a zone. The private key is never returned.
+#[derive(Serialize, ToSchema)]
+pub struct KeyInfo {
+    id: String,
+    role: KeyRole,
+    algorithm: KeyAlgorithm,
+    key_tag: u16,
+    state: KeyState,
+    created_at: u64,
+    activate_at: u64,
+    retire_at: Option<u64>,
+    /// The DNSKEY record data, in presentation format.
+    dnskey: String,
+    /// The DS record data to publish in the parent zone, in presentation format. Only set for key
+    /// signing keys.
+    ds: Option<String>,
+}
+
+impl KeyInfo {
+    fn new(zone: &Name, key: &DnssecKey, now: u64) -> KeyInfo {
+        let rdata = dnskey_rdata(key);
+        let key_tag = key_tag(&rdata);
+        let ds = (key.role == KeyRole::Ksk).then(|| {
+            let mut digest_input = canonical_wire_name(zone);
+            digest_input.extend_from_slice(&rdata);
+            let digest = digest::digest(&digest::SHA256, &digest_input);
+            format!(
+                "{} {} {} {}",
+                key_tag,
+                key.algorithm.number(),
+                DS_DIGEST_SHA256,
+                faster_hex::hex_string(digest.as_ref()).to_uppercase()
+            )
+        });
+        KeyInfo {
+            id: key.id.clone(),
+            role: key.role,
+            algorithm: key.algorithm,
+            key_tag,
+            state: key.state(now),
+            created_at: key.created_at,
+            activate_at: key.activate_at,
+            retire_at: key.retire_at,
+            dnskey: format!(
+                "{} {} {} {}",
+                flags(key.role),
+                DNSKEY_PROTOCOL,
+                key.algorithm.number(),
+                base64::encode(&key.public_key)
+            ),
+            ds,
+        }
+    }
+}
+
+/// List the DNSSEC keys of a zone, in all states.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/dnssec/keys",
+    tag = "dnssec",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    responses(
+        (status = 200, description = "The keys of the zone", body = [KeyInfo]),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn list_keys(
+    extract::Path(zone): extract::Path<Name>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<Vec<KeyInfo>>, ApiError> {
+    let (zone_name, mut keys) = load_keys(&state, &zone).await?;
+    trace!("Listing {} DNSSEC keys of zone {}", keys.len(), zone_name);
+    keys.sort_by_key(|key| (key.created_at, key.id.clone()));
+    let now = unix_now();
+    Ok(response::Json(
+        keys.iter()
+            .map(|key| KeyInfo::new(&zone, key, now))
+            .collect(),
+    ))
+}
+
+/// Generate a new key for a zone, which is published and active immediately. For a key signing
+/// key, the DS record to add to the parent zone is returned as well.
+#[utoipa::path(
+    post,
+    path = "/zones/{zone}/dnssec/keys",
+    tag = "dnssec",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    request_body = CreateKey,
+    responses(
+        (status = 201, description = "The key was created", body = KeyInfo),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "No key encryption key is configured"),
+    )
+)]
+pub async fn create_key(
+    extract::Path(zone): extract::Path<Name>,
+    Extension(state): Extension<State>,
+    extract::Json(data): extract::Json<CreateKey>,
+) -> Result<(StatusCode, response::Json<KeyInfo>), ApiError> {
+    let (zone_name, _) = load_keys(&state, &zone).await?;
+
+    let now = unix_now();
+    let key = generate_key(&state, &zone_name, data.role, data.algorithm, now, now)?;
+    store_key(&state, &zone_name, &key).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        response::Json(KeyInfo::new(&zone, &key, now)),
+    ))
+}
+
+/// Remove a key of a zone. The last active key signing key can't be removed, as that would leave
+/// the DS record in the parent zone without a matching key.
+#[utoipa::path(
+    delete,
+    path = "/zones/{zone}/dnssec/keys/{id}",
+    tag = "dnssec",
+    params(
+        ("zone" = String, Path, description = "Fully qualified name of the zone"),
+        ("id" = String, Path, description = "Id of the key"),
+    ),
+    responses(
+        (status = 204, description = "The key was removed"),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone or the key does not exist"),
+        (status = 409, description = "The key is the only active key signing key of the zone"),
+    )
+)]
+pub async fn delete_key(
+    extract::Path((zone, id)): extract::Path<(Name, String)>,
+    Extension(state): Extension<State>,
+) -> Result<StatusCode, ApiError> {
+    let (zone_name, keys) = load_keys(&state, &zone).await?;
+    let now = unix_now();
+    let key = keys.iter().find(|key| key.id == id).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "key_not_found",
+            "The zone has no key with this id",
+        )
+    })?;
+
+    let is_active_ksk =
+        |key: &DnssecKey| key.role == KeyRole::Ksk && key.state(now) == KeyState::Active;
+    if is_active_ksk(key) && keys.iter().filter(|key| is_active_ksk(key)).count() == 1 {
+        return Err(ApiError::conflict(
+            "last_active_ksk",
+            "The only active key signing key of a zone can't be removed",
+        ));
+    }
+
+    state
+        .storage
+        .remove_dnssec_key(&zone_name, &id)
+        .await
+        .map_err(|err| ApiError::storage("Failed to remove DNSSEC key", err))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start a rollover of the zone signing key. A new key is published right away, and becomes
+/// active after the configured pre-publication interval, at which point the currently active zone
+/// signing keys are retired. Retired keys stay published until they are removed.
+#[utoipa::path(
+    post,
+    path = "/zones/{zone}/dnssec/keys/rollover",
+    tag = "dnssec",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    responses(
+        (status = 201, description = "The new zone signing key", body = KeyInfo),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The zone has no active zone signing key to roll over, or no key encryption key is configured"),
+    )
+)]
+pub async fn rollover_zsk(
+    extract::Path(zone): extract::Path<Name>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<KeyInfo>), ApiError> {
+    let (zone_name, keys) = load_keys(&state, &zone).await?;
+    let now = unix_now();
+    let activate_at = now + state.zsk_prepublish_secs;
+
+    let mut old_keys = keys
+        .into_iter()
+        .filter(|key| key.role == KeyRole::Zsk && key.state(now) == KeyState::Active)
+        .filter(|key| {
+            key.retire_at
+                .is_none_or(|retire_at| retire_at > activate_at)
+        })
+        .collect::<Vec<_>>();
+    // The new key uses the algorithm of the current key, changing algorithms needs a different
+    // rollover procedure (RFC 6781, section 4.1.4).
+    let algorithm = match old_keys.first() {
+        Some(key) => key.algorithm,
+        None => {
+            return Err(ApiError::conflict(
+                "no_active_zsk",
+                "The zone has no active zone signing key to roll over",
+            ))
+        }
+    };
+
+    let key = generate_key(
+        &state,
+        &zone_name,
+        KeyRole::Zsk,
+        algorithm,
+        now,
+        activate_at,
+    )?;
+    // Store the new key first, so a failure never leaves the zone without an active key.
+    store_key(&state, &zone_name, &key).await?;
+    for old_key in &mut old_keys {
+        old_key.retire_at = Some(activate_at);
+        store_key(&state, &zone_name, old_key).await?;
+    }
+    trace!(
+        "Started ZSK rollover of zone {}, new key {} activates at {}",
+        zone_name,
+        key.id,
+        activate_at
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        response::Json(KeyInfo::new(&zone, &key, now)),
+    ))
+}
+
+/// Check that the zone exists, and load its keys.
+async fn load_keys(state: &State, zone: &Name) -> Result<(LowerName, Vec<DnssecKey>), ApiError> {
+    if !zone.is_fqdn() {
+        return Err(ApiError::not_fqdn("Zone"));
+    }
+    let zone_name = LowerName::from(zone.clone());
+    zone::ensure_zone_exists(state, &zone_name).await?;
+    let keys = state
+        .storage
+        .dnssec_keys(&zone_name)
+        .await
+        .map_err(|err| ApiError::storage("Failed to load DNSSEC keys", err))?;
+    Ok((zone_name, keys))
+}
+
+async fn store_key(state: &State, zone: &LowerName, key: &DnssecKey) -> Result<(), ApiError> {
+    state
+        .storage
+        .set_dnssec_key(zone, key)
+        .await
+        .map_err(|err| ApiError::storage("Failed to store DNSSEC key", err))
+}
+
+/// Generate a key, with its private key encrypted by the configured key encryption key.
+fn generate_key(
+    state: &State,
+    zone: &LowerName,
+    role: KeyRole,
+    algorithm: KeyAlgorithm,
+    created_at: u64,
+    activate_at: u64,
+) -> Result<DnssecKey, ApiError> {
+    let key_encryption_key = state.key_encryption_key.as_ref().ok_or_else(|| {
+        ApiError::conflict(
+            "no_key_encryption_key",
+            "DNSSEC keys can't be generated without a configured key encryption key",
+        )
+    })?;
+    let rng = SystemRandom::new();
+    let mut id = [0; KEY_ID_LENGTH];
+    let generated = rng.fill(&mut id).ok().and_then(|_| match algorithm {
+        KeyAlgorithm::EcdsaP256Sha256 => {
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).ok()?;
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).ok()?;
+            // DNSKEY records hold the bare point, without the uncompressed point marker
+            // (RFC 6605, section 4).
+            let public_key = key_pair.public_key().as_ref()[1..].to_vec();
+            Some((pkcs8, public_key))
+        }
+        KeyAlgorithm::Ed25519 => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).ok()?;
+            let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).ok()?;
+            let public_key = key_pair.public_key().as_ref().to_vec();
+            Some((pkcs8, public_key))
+        }
+    });
+    let id = faster_hex::hex_string(&id);
+    let sealed = generated.and_then(|(pkcs8, public_key)| {
+        let private_key = key_encryption_key.seal(zone, &id, pkcs8.as_ref(), &rng)?;
+        Some((private_key, public_key))
+    });
+    let (private_key, public_key) = sealed.ok_or_else(|| {
+        error!("Failed to generate DNSSEC key");
+        ApiError::from(StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+
+    Ok(DnssecKey {
+        id,
+        role,
+        algorithm,
+        private_key,
+        public_key,
+        created_at,
+        activate_at,
+        retire_at: None,
+    })
+}
+
+fn flags(role: KeyRole) -> u16 {
+    match role {
+        KeyRole::Ksk => KSK_FLAGS,
+        KeyRole::Zsk => ZSK_FLAGS,
+    }
+}
+
+/// The wire format of the DNSKEY record data of a key.
+fn dnskey_rdata(key: &DnssecKey) -> Vec<u8> {
+    let mut rdata = Vec::with_capacity(4 + key.public_key.len());
+    rdata.extend_from_slice(&flags(key.role).to_be_bytes());
+    rdata.push(DNSKEY_PROTOCOL);
+    rdata.push(key.algorithm.number());
+    rdata.extend_from_slice(&key.public_key);
+    rdata
+}
+
+/// The key tag of a DNSKEY record, computed over its record data (RFC 4034, appendix B).
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut acc: u32 = 0;
+    for (i, &b) in rdata.iter().enumerate() {
+        acc += if i & 1 == 0 {
+            u32::from(b) << 8
+        } else {
+            u32::from(b)
+        };
+    }
+    acc += (acc >> 16) & 0xffff;
+    (acc & 0xffff) as u16
+}
+
+/// The canonical wire format of a name, which is the uncompressed, lowercase, form (RFC 4034,
+/// section 6.2).
+fn canonical_wire_name(name: &Name) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.to_lowercase().iter() {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label);
+    }
+    wire.push(0);
+    wire
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    use super::KeyEncryptionKey;
+    use crate::{api::tests::TestApi, handle::tests::lower, storage::Storage};
+
+    /// A valid, base64 encoded, key encryption key.
+    const KEY_ENCRYPTION_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[tokio::test]
+    async fn keys_require_key_encryption_key() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+
+        let (status, body) = api
+            .request(
+                Method::POST,
+                "/v1/zones/example.com./dnssec/keys",
+                Some(json!({"role": "ksk", "algorithm": "ECDSAP256SHA256"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "no_key_encryption_key");
+        let keys = api.storage.dnssec_keys(&lower("example.com.")).await;
+        assert!(keys.expect("Can load keys").is_empty());
+    }
+
+    #[tokio::test]
+    async fn private_keys_are_encrypted() {
+        let api = TestApi::start_with(|config| {
+            config.dnssec.key_encryption_key = Some(KEY_ENCRYPTION_KEY.to_string());
+        })
+        .await;
+        api.add_zone("example.com.").await;
+
+        let (status, body) = api
+            .request(
+                Method::POST,
+                "/v1/zones/example.com./dnssec/keys",
+                Some(json!({"role": "ksk", "algorithm": "ECDSAP256SHA256"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let zone = lower("example.com.");
+        let keys = api.storage.dnssec_keys(&zone).await.expect("Can load keys");
+        assert_eq!(keys.len(), 1);
+        let key = &keys[0];
+        assert_eq!(body["id"], key.id.as_str());
+
+        let key_encryption_key =
+            KeyEncryptionKey::from_base64(KEY_ENCRYPTION_KEY).expect("Valid key");
+        let pkcs8 = key_encryption_key
+            .open(&zone, &key.id, &key.private_key)
+            .expect("Private key is encrypted with the key encryption key");
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+            .expect("Private key is a PKCS#8 document");
+        assert_eq!(key_pair.public_key().as_ref()[1..], key.public_key[..]);
+
+        // The private key is bound to the zone and id of the key.
+        assert!(key_encryption_key
+            .open(&lower("example.net."), &key.id, &key.private_key)
+            .is_none());
+        assert!(key_encryption_key
+            .open(&zone, "0000000000000000", &key.private_key)
+            .is_none());
+    }
+
+    #[test]
+    fn key_encryption_key_must_be_32_bytes() {
+        assert!(KeyEncryptionKey::from_base64(&base64::encode([0; 16])).is_err());
+        assert!(KeyEncryptionKey::from_base64("not base64!").is_err());
+        assert!(KeyEncryptionKey::from_base64(&base64::encode([0; 32])).is_ok());
+    }
+}
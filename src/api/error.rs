@@ -0,0 +1,145 @@
+use std::{borrow::Cow, fmt::Display};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::error;
+use serde::Serialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+/// An error returned by the API. It is rendered as
+/// `{"error": {"code": "...", "message": "...", "details": {...}}}`, where `code` is a stable
+/// machine readable identifier of the kind of error, and `message` is meant for humans.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: Cow<'static, str>,
+    details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(
+        status: StatusCode,
+        code: &'static str,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// The request itself is invalid.
+    pub fn bad_request(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    /// The request conflicts with the current data.
+    pub fn conflict(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::CONFLICT, code, message)
+    }
+
+    /// The zone of the request does not exist.
+    pub fn zone_not_found() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "zone_not_found",
+            "Zone does not exist",
+        )
+    }
+
+    /// A name in the request is not fully qualified.
+    pub fn not_fqdn(what: &str) -> Self {
+        Self::bad_request("not_fqdn", format!("{} must be an fqdn", what))
+    }
+
+    /// The storage failed. The error itself is only logged, together with a correlation id which
+    /// is returned to the client so the log entry can be found.
+    pub fn storage(context: &str, err: impl Display) -> Self {
+        let correlation_id = format!("{:016x}", rand::random::<u64>());
+        error!("{} [{}]: {}", context, correlation_id, err);
+        Self::new(
+            StatusCode::BAD_GATEWAY,
+            "storage_error",
+            "The storage failed to handle the request",
+        )
+        .with_details(json!({ "correlation_id": correlation_id }))
+    }
+
+    /// Attach structured details to the error.
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+/// Body of error responses.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetails {
+    /// Machine readable kind of the error.
+    #[schema(value_type = String, example = "zone_not_found")]
+    code: &'static str,
+    /// Description of the error, meant for humans.
+    #[schema(value_type = String)]
+    message: Cow<'static, str>,
+    /// Structured details, depending on the kind of error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    details: Option<Value>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetails {
+                code: self.code,
+                message: self.message,
+                details: self.details,
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Generic code of errors which are only known by their status.
+fn status_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::CONFLICT => "conflict",
+        _ => "internal_error",
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self::new(
+            status,
+            status_code(status),
+            status.canonical_reason().unwrap_or("Request failed"),
+        )
+    }
+}
+
+impl From<(StatusCode, &'static str)> for ApiError {
+    fn from((status, message): (StatusCode, &'static str)) -> Self {
+        Self::new(status, status_code(status), message)
+    }
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self::new(status, status_code(status), message)
+    }
+}
@@ -0,0 +1,219 @@
+use std::{error::Error, fmt::Write};
+
+use super::{error::ApiError, zone, State};
+use crate::{dname, storage::StorageRecord};
+use axum::{
+    body::StreamBody,
+    extract,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures_util::{stream, StreamExt};
+use log::error;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+/// Amount of domains of which the records are loaded at once.
+const EXPORT_BATCH_SIZE: usize = 500;
+
+/// Export a zone, as an RFC 1035 master file or, if the client accepts `application/json`, as a
+/// JSON list of records. Records are sorted, so exports of the same data are identical. The
+/// export is streamed one batch of domains at a time.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/export",
+    tag = "zones",
+    params(
+        ("zone" = String, Path, description = "Fully qualified name of the zone"),
+        ("Accept" = Option<String>, Header, description = "`application/json` to export as JSON"),
+    ),
+    responses(
+        (status = 200, description = "The records of the zone", content(
+            ("text/dns" = String),
+            ("application/json" = [StorageRecord]),
+        )),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn export_zone(
+    extract::Path(zone): extract::Path<Name>,
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Response, ApiError> {
+    if !zone.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "Zone must be an fqdn").into());
+    }
+    let zone_name = LowerName::from(zone.clone());
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    let soa = state
+        .storage
+        .lookup_records(&zone_name, &zone_name, RecordType::SOA)
+        .await
+        .map_err(|err| {
+            error!("Failed to load SOA of zone {}: {}", zone_name, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .and_then(|soas| soas.into_iter().next())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut domains = state
+        .storage
+        .list_domains(&zone_name)
+        .await
+        .map_err(|err| {
+            error!("Failed to list domains of zone {}: {}", zone_name, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    // Canonical order puts the apex, and with it the SOA, first.
+    domains.sort();
+
+    let json = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+
+    let batches = domains
+        .chunks(EXPORT_BATCH_SIZE)
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+    let records = stream::iter(batches).then(move |batch| {
+        let state = state.clone();
+        let zone_name = zone_name.clone();
+        async move {
+            let mut records = Vec::new();
+            for mut domain_records in state.storage.list_records_bulk(&zone_name, &batch).await? {
+                domain_records.sort_by_cached_key(|sr| {
+                    let record = sr.as_record();
+                    (
+                        record.record_type() != RecordType::SOA,
+                        u16::from(record.record_type()),
+                        rdata_text(record),
+                    )
+                });
+                records.append(&mut domain_records);
+            }
+            Ok::<_, Box<dyn Error + Send + Sync>>(records)
+        }
+    });
+
+    if json {
+        let mut first = true;
+        let body = stream::once(async { Ok("[".to_string()) })
+            .chain(records.map(move |records| {
+                let mut chunk = String::new();
+                for sr in records? {
+                    if !first {
+                        chunk.push(',');
+                    }
+                    first = false;
+                    chunk.push_str(&serde_json::to_string(&sr)?);
+                }
+                Ok::<_, Box<dyn Error + Send + Sync>>(chunk)
+            }))
+            .chain(stream::once(async { Ok("]".to_string()) }));
+        return Ok((
+            [(header::CONTENT_TYPE, "application/json")],
+            StreamBody::new(body),
+        )
+            .into_response());
+    }
+
+    let header_lines = format!("$ORIGIN {}\n$TTL {}\n", zone, soa.as_record().ttl());
+    let body = stream::once(async { Ok(header_lines) }).chain(records.map(move |records| {
+        let mut chunk = String::new();
+        for sr in records? {
+            // Writing to a String can't fail.
+            let _ = writeln!(chunk, "{}", zone_file_line(&zone, &sr));
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>(chunk)
+    }));
+    Ok(([(header::CONTENT_TYPE, "text/dns")], StreamBody::new(body)).into_response())
+}
+
+/// Render a record as a line of a master file, with the owner relative to the origin.
+fn zone_file_line(origin: &Name, sr: &StorageRecord) -> String {
+    let record = sr.as_record();
+    let owner = record.name();
+    let relative_owner = if owner == origin {
+        "@".to_string()
+    } else if origin.zone_of(owner) {
+        let prefix = owner.num_labels() - origin.num_labels();
+        match Name::from_labels(owner.iter().take(usize::from(prefix))) {
+            Ok(relative) => relative.to_string(),
+            Err(_) => owner.to_string(),
+        }
+    } else {
+        owner.to_string()
+    };
+
+    let rtype = if record.record_type() == dname::DNAME {
+        "DNAME".to_string()
+    } else {
+        match record.record_type() {
+            RecordType::Unknown(code) => format!("TYPE{}", code),
+            rtype => rtype.to_string(),
+        }
+    };
+
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        relative_owner,
+        record.ttl(),
+        record.dns_class(),
+        rtype,
+        rdata_text(record)
+    )
+}
+
+/// Render the data of a record in master file format. Types without a presentation format of
+/// their own use the generic format of RFC 3597.
+pub fn rdata_text(record: &Record) -> String {
+    if let Some(target) = dname::target(record) {
+        return target.to_string();
+    }
+    match record.data() {
+        Some(RData::TXT(txt)) => txt
+            .txt_data()
+            .iter()
+            .map(|s| character_string(s))
+            .collect::<Vec<_>>()
+            .join(" "),
+        Some(RData::HINFO(hinfo)) => format!(
+            "{} {}",
+            character_string(hinfo.cpu()),
+            character_string(hinfo.os())
+        ),
+        Some(RData::Unknown { rdata, .. }) | Some(RData::NULL(rdata)) => {
+            let data = rdata.anything();
+            let mut hex = vec![0; data.len() * 2];
+            // The buffer is exactly twice the size of the input, so encoding can't fail.
+            let _ = faster_hex::hex_encode(data, &mut hex);
+            format!("\\# {} {}", data.len(), String::from_utf8_lossy(&hex))
+        }
+        Some(rdata) => rdata.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Quote a character-string, escaping quotes, backslashes and non printable bytes.
+fn character_string(data: &[u8]) -> String {
+    let mut quoted = String::with_capacity(data.len() + 2);
+    quoted.push('"');
+    for &b in data {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(char::from(b));
+            }
+            0x20..=0x7e => quoted.push(char::from(b)),
+            _ => {
+                let _ = write!(quoted, "\\{:03}", b);
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
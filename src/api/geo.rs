@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+
+use super::{
+    error::ApiError,
+    metadata::validate_geo_policy,
+    record::{self, type_name},
+    validate::RrsetPath,
+    State,
+};
+use crate::{geo::GeoPolicy, storage::StorageRecord};
+use axum::{extract, response, Extension};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::RData;
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// The locations a record of the RRset is served to. The record is referenced by either its
+/// position in the RRset, as listed, or its data.
+#[derive(Deserialize, ToSchema)]
+pub struct RecordLocations {
+    /// Position of the record in the RRset.
+    index: Option<usize>,
+    /// Data of the record, in the same form as records are listed in.
+    #[schema(value_type = Option<Object>)]
+    rdata: Option<RData>,
+    /// ISO 3166-1 alpha-2 codes of the countries the record is served to.
+    #[serde(default)]
+    countries: Vec<String>,
+    /// Codes of the continents the record is served to.
+    #[serde(default)]
+    continents: Vec<String>,
+    /// Put the record in the default pool, which is served to clients for which no other record
+    /// matches. This can't be combined with countries or continents.
+    #[serde(default)]
+    default: bool,
+}
+
+/// The geo policy of an RRset. Records which are not listed are put in the default pool.
+#[derive(Deserialize, ToSchema)]
+pub struct SetGeoPolicy {
+    records: Vec<RecordLocations>,
+}
+
+/// The locations a record of the RRset is served to.
+#[derive(Serialize, ToSchema)]
+pub struct RecordGeoPolicy {
+    /// Position of the record in the RRset.
+    index: usize,
+    #[schema(value_type = Option<Object>)]
+    rdata: Option<RData>,
+    countries: Vec<String>,
+    continents: Vec<String>,
+    /// The record is in the default pool, which is served to clients for which no other record
+    /// matches.
+    default: bool,
+}
+
+/// The geo policy of an RRset, with an entry for every record. An RRset in which all records are
+/// in the default pool is served to all clients alike.
+#[derive(Serialize, ToSchema)]
+pub struct RrsetGeoPolicy {
+    records: Vec<RecordGeoPolicy>,
+}
+
+impl RrsetGeoPolicy {
+    fn new(records: &[StorageRecord]) -> Self {
+        RrsetGeoPolicy {
+            records: records
+                .iter()
+                .enumerate()
+                .map(|(index, sr)| {
+                    let geo = sr.geo.clone().unwrap_or_default();
+                    RecordGeoPolicy {
+                        index,
+                        rdata: sr.as_record().data().cloned(),
+                        countries: geo.countries,
+                        continents: geo.continents,
+                        default: sr.geo.is_none(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Get the geo policy of an RRset.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/{domain}/{rtype}/policy",
+    tag = "records",
+    params(RrsetPath),
+    responses(
+        (status = 200, description = "The geo policy of the RRset", body = RrsetGeoPolicy),
+        (status = 400, description = "The zone or domain is not an fqdn, or the record type is unknown"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+    )
+)]
+pub async fn get_geo_policy(
+    RrsetPath {
+        zone,
+        domain,
+        rtype,
+    }: RrsetPath,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<RrsetGeoPolicy>, ApiError> {
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    trace!(
+        "Loading geo policy of {} {} records",
+        domain_name,
+        type_name(rtype)
+    );
+    let records = record::load_rrset(&state, &zone_name, &domain_name, rtype).await?;
+
+    Ok(response::Json(RrsetGeoPolicy::new(&records)))
+}
+
+/// Set the locations the records of an RRset are served to, returning the new policy. Clients
+/// get the records for their country if there are any, otherwise the records for their continent,
+/// and otherwise the records in the default pool. The RRset is replaced as a whole, so it is
+/// never served with a partially applied policy.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/{rtype}/policy",
+    tag = "records",
+    params(RrsetPath),
+    request_body = SetGeoPolicy,
+    responses(
+        (status = 200, description = "The new geo policy of the RRset", body = RrsetGeoPolicy),
+        (status = 400, description = "A path segment or the policy is invalid, or it references a record which is not in the RRset"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+    )
+)]
+pub async fn set_geo_policy(
+    RrsetPath {
+        zone,
+        domain,
+        rtype,
+    }: RrsetPath,
+    extract::Json(data): extract::Json<SetGeoPolicy>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<RrsetGeoPolicy>, ApiError> {
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    trace!(
+        "Setting geo policy of {} {} records",
+        domain_name,
+        type_name(rtype)
+    );
+
+    for entry in &data.records {
+        if entry.default {
+            if !(entry.countries.is_empty() && entry.continents.is_empty()) {
+                return Err(ApiError::bad_request(
+                    "invalid_geo_policy",
+                    "A record in the default pool can't have countries or continents",
+                ));
+            }
+        } else {
+            validate_geo_policy(&GeoPolicy {
+                countries: entry.countries.clone(),
+                continents: entry.continents.clone(),
+            })
+            .map_err(|(_, msg)| ApiError::bad_request("invalid_geo_policy", msg))?;
+        }
+    }
+
+    let mut records = record::load_rrset(&state, &zone_name, &domain_name, rtype).await?;
+
+    // Resolve all references before changing anything, so an invalid one leaves the RRset as is.
+    let mut policies = vec![None; records.len()];
+    let mut referenced = HashSet::new();
+    for entry in data.records {
+        let index = match (entry.index, &entry.rdata) {
+            (Some(index), None) if index < records.len() => index,
+            (Some(index), None) => {
+                return Err(ApiError::bad_request(
+                    "unknown_record",
+                    format!("The RRset has no record at index {}", index),
+                ))
+            }
+            (None, Some(rdata)) => records
+                .iter()
+                .position(|sr| sr.as_record().data() == Some(rdata))
+                .ok_or_else(|| {
+                    ApiError::bad_request(
+                        "unknown_record",
+                        "The RRset has no record with this data",
+                    )
+                })?,
+            _ => {
+                return Err(ApiError::bad_request(
+                    "invalid_record_reference",
+                    "Records must be referenced by either their index or their data",
+                ))
+            }
+        };
+        if !referenced.insert(index) {
+            return Err(ApiError::bad_request(
+                "duplicate_record_reference",
+                format!("The record at index {} is referenced more than once", index),
+            ));
+        }
+        if !entry.default {
+            policies[index] = Some(GeoPolicy {
+                countries: entry.countries,
+                continents: entry.continents,
+            });
+        }
+    }
+    for (sr, geo) in records.iter_mut().zip(policies) {
+        sr.geo = geo;
+    }
+
+    record::store_rrset(&state, &zone_name, domain_name, rtype, &records).await?;
+
+    Ok(response::Json(RrsetGeoPolicy::new(&records)))
+}
+
+/// Remove the geo policy of an RRset, so all of its records are served to all clients alike.
+/// Returns the now empty policy.
+#[utoipa::path(
+    delete,
+    path = "/zones/{zone}/{domain}/{rtype}/policy",
+    tag = "records",
+    params(RrsetPath),
+    responses(
+        (status = 200, description = "The geo policy of the RRset, with all records in the default pool", body = RrsetGeoPolicy),
+        (status = 400, description = "The zone or domain is not an fqdn, or the record type is unknown"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+    )
+)]
+pub async fn delete_geo_policy(
+    RrsetPath {
+        zone,
+        domain,
+        rtype,
+    }: RrsetPath,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<RrsetGeoPolicy>, ApiError> {
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    trace!(
+        "Removing geo policy of {} {} records",
+        domain_name,
+        type_name(rtype)
+    );
+
+    let mut records = record::load_rrset(&state, &zone_name, &domain_name, rtype).await?;
+    if records.iter().any(|sr| sr.geo.is_some()) {
+        for sr in &mut records {
+            sr.geo = None;
+        }
+        record::store_rrset(&state, &zone_name, domain_name, rtype, &records).await?;
+    }
+
+    Ok(response::Json(RrsetGeoPolicy::new(&records)))
+}
@@ -0,0 +1,18 @@
+use super::State;
+use crate::health::TargetStatus;
+use axum::{response, Extension};
+
+/// List the health of all addresses with a health check.
+#[utoipa::path(
+    get,
+    path = "/health-checks",
+    tag = "health",
+    responses(
+        (status = 200, description = "Status of all health checked addresses", body = [TargetStatus]),
+    )
+)]
+pub async fn list_health_checks(
+    Extension(state): Extension<State>,
+) -> response::Json<Vec<TargetStatus>> {
+    response::Json(state.health.statuses())
+}
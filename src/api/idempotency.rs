@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use super::{auth::Actor, error::ApiError, State};
+use crate::idempotency::{IdempotencyClaim, IdempotencyRecord, StoredResponse};
+use axum::{
+    body::{self, Body, Full},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use ring::digest;
+
+/// Header with which clients mark a request as safe to retry.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Header set on responses which are replayed instead of handled again.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+/// Maximum length of an idempotency key.
+const MAX_KEY_LENGTH: usize = 255;
+/// Time a key stays claimed by a request which is being handled. This only matters if the
+/// instance handling it dies, as keys are completed or released once the request is handled.
+const CLAIM_TTL: Duration = Duration::from_secs(60);
+/// Maximum time a request waits for another request with the same key to finish.
+const IN_PROGRESS_WAIT: Duration = Duration::from_secs(10);
+/// Time between checks whether a request with the same key has finished.
+const IN_PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Middleware making requests which change data safe to retry. If a request has an
+/// `Idempotency-Key` header, its response is stored under that key, and later requests with the
+/// same key get the stored response instead of being handled again. Requests with a key which is
+/// still being handled wait for it to finish. Keys are scoped to the token which sent them, and
+/// can't be reused for a different request. Server errors are not stored, so those requests can
+/// be retried with the same key.
+pub async fn replay(req: Request<Body>, next: Next<Body>) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => match key.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+            _ => {
+                return ApiError::bad_request(
+                    "invalid_idempotency_key",
+                    format!(
+                        "Idempotency key must be between 1 and {} visible ASCII characters",
+                        MAX_KEY_LENGTH
+                    ),
+                )
+                .into_response()
+            }
+        },
+        None => return next.run(req).await,
+    };
+
+    let state = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set")
+        .clone();
+    // Without authentication all clients share a single scope.
+    let scope = req
+        .extensions()
+        .get::<Actor>()
+        .map_or("anonymous", |Actor(actor)| actor.as_str());
+    let key = format!("{}:{}", scope, key);
+
+    // The body is needed for the fingerprint, and is then handed to the handler as is.
+    let (parts, request_body) = req.into_parts();
+    let request_body = match hyper::body::to_bytes(request_body).await {
+        Ok(request_body) => request_body,
+        Err(e) => {
+            return ApiError::bad_request("invalid_body", format!("Failed to read body: {}", e))
+                .into_response()
+        }
+    };
+    let mut fingerprint = digest::Context::new(&digest::SHA256);
+    fingerprint.update(parts.method.as_str().as_bytes());
+    fingerprint.update(b" ");
+    fingerprint.update(
+        parts
+            .uri
+            .path_and_query()
+            .map_or("", |path| path.as_str())
+            .as_bytes(),
+    );
+    fingerprint.update(b"\n");
+    fingerprint.update(&request_body);
+    let mut record = IdempotencyRecord {
+        fingerprint: faster_hex::hex_string(fingerprint.finish().as_ref()),
+        response: None,
+    };
+    let req = Request::from_parts(parts, Body::from(request_body));
+
+    let deadline = Instant::now() + IN_PROGRESS_WAIT;
+    loop {
+        let existing = match state
+            .idempotency
+            .claim_idempotency_key(&key, &record, CLAIM_TTL)
+            .await
+        {
+            Ok(IdempotencyClaim::Claimed) => break,
+            Ok(IdempotencyClaim::Existing(existing)) => existing,
+            Err(e) => {
+                return ApiError::storage("Failed to claim idempotency key", e).into_response()
+            }
+        };
+        if existing.fingerprint != record.fingerprint {
+            return ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+                "Idempotency key is already used for a different request",
+            )
+            .into_response();
+        }
+        if let Some(response) = existing.response {
+            return replayed_response(response);
+        }
+        if Instant::now() >= deadline {
+            return ApiError::conflict(
+                "idempotency_key_in_progress",
+                "A request with this idempotency key is still being handled",
+            )
+            .into_response();
+        }
+        tokio::time::sleep(IN_PROGRESS_POLL_INTERVAL).await;
+    }
+
+    let response = next.run(req).await;
+    if response.status().is_server_error() {
+        release(&state, &key).await;
+        return response;
+    }
+
+    let (parts, response_body) = response.into_parts();
+    let response_body = match hyper::body::to_bytes(response_body).await {
+        Ok(response_body) => response_body,
+        Err(e) => {
+            error!(
+                "Failed to read response to store for idempotency key: {}",
+                e
+            );
+            release(&state, &key).await;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    record.response = Some(StoredResponse {
+        status: parts.status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_string),
+        body: base64::encode(&response_body),
+    });
+    // The request is handled, so this can't fail it anymore. If the response can't be stored the
+    // claim expires, after which the key can be used again.
+    if let Err(e) = state
+        .idempotency
+        .complete_idempotency_key(&key, &record, state.idempotency_ttl)
+        .await
+    {
+        error!("Failed to store response for idempotency key: {}", e);
+    }
+
+    Response::from_parts(parts, body::boxed(Full::from(response_body)))
+}
+
+async fn release(state: &State, key: &str) {
+    if let Err(e) = state.idempotency.release_idempotency_key(key).await {
+        error!("Failed to release idempotency key: {}", e);
+    }
+}
+
+fn replayed_response(stored: StoredResponse) -> Response {
+    let body = match base64::decode(&stored.body) {
+        Ok(body) => body,
+        Err(e) => {
+            return ApiError::storage("Stored response of idempotency key is invalid", e)
+                .into_response()
+        }
+    };
+    let mut response = Response::new(body::boxed(Full::from(body)));
+    *response.status_mut() =
+        StatusCode::from_u16(stored.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+    {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+    response
+        .headers_mut()
+        .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
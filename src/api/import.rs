@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashSet};
+
+use super::{error::ApiError, journal, State};
+use crate::storage::{RecordSet, StorageOp, StorageRecord};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::{DNSClass, Name, RecordType};
+use trust_dns_server::client::{
+    rr::LowerName,
+    serialize::txt::{Lexer, Parser},
+};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    /// Replace the contents of an existing zone, instead of refusing the import.
+    #[serde(default)]
+    replace: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportSummary {
+    /// Amount of imported records per record type.
+    records: BTreeMap<String, usize>,
+    /// Records in the file which were not imported.
+    skipped: Vec<SkippedRecord>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SkippedRecord {
+    #[schema(value_type = String)]
+    name: Name,
+    #[serde(rename = "type")]
+    rtype: String,
+    reason: &'static str,
+}
+
+/// Import a zone from an RFC 1035 master file. The zone is created if it does not exist, its SOA
+/// and NS records are taken from the file. An existing zone is only overwritten if explicitly
+/// requested, in which case records which are not in the file are removed.
+#[utoipa::path(
+    post,
+    path = "/zones/{zone}/import",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone"), ImportQuery),
+    request_body(content = String, description = "RFC 1035 master file", content_type = "text/plain"),
+    responses(
+        (status = 201, description = "The zone was created", body = ImportSummary),
+        (status = 200, description = "The existing zone was replaced", body = ImportSummary),
+        (status = 400, description = "The zone file is not valid"),
+        (status = 409, description = "The zone exists and replace was not set"),
+    )
+)]
+pub async fn import_zone(
+    extract::Path(zone): extract::Path<Name>,
+    extract::Query(query): extract::Query<ImportQuery>,
+    Extension(state): Extension<State>,
+    body: String,
+) -> Result<(StatusCode, response::Json<ImportSummary>), ApiError> {
+    if !zone.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "Zone must be an fqdn").into());
+    }
+    let zone_name = LowerName::from(zone.clone());
+
+    let (_, parsed) = Parser::new()
+        .parse(Lexer::new(&body), Some(zone), Some(DNSClass::IN))
+        .map_err(|err| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid zone file: {}", err),
+            )
+        })?;
+
+    let mut summary = ImportSummary {
+        records: BTreeMap::new(),
+        skipped: Vec::new(),
+    };
+    let mut rrsets = Vec::with_capacity(parsed.len());
+    for (key, rrset) in parsed {
+        if !zone_name.zone_of(&key.name) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} is not in zone {}", key.name, zone_name),
+            )
+                .into());
+        }
+
+        let skip_reason = if rrset.dns_class() != DNSClass::IN {
+            Some("only records of class IN are supported")
+        } else if key.record_type.is_dnssec() {
+            Some("DNSSEC records are not supported")
+        } else {
+            None
+        };
+        if let Some(reason) = skip_reason {
+            summary
+                .skipped
+                .extend(rrset.records_without_rrsigs().map(|record| SkippedRecord {
+                    name: record.name().clone(),
+                    rtype: record.record_type().to_string(),
+                    reason,
+                }));
+            continue;
+        }
+
+        let records = rrset
+            .records_without_rrsigs()
+            .cloned()
+            .map(StorageRecord::new)
+            .collect::<Vec<_>>();
+        *summary
+            .records
+            .entry(key.record_type.to_string())
+            .or_default() += records.len();
+        rrsets.push(RecordSet {
+            domain: key.name,
+            rtype: key.record_type,
+            records,
+        });
+    }
+
+    for rtype in [RecordType::SOA, RecordType::NS] {
+        if !rrsets
+            .iter()
+            .any(|rrset| rrset.domain == zone_name && rrset.rtype == rtype)
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Zone file has no {} record at the zone apex", rtype),
+            )
+                .into());
+        }
+    }
+
+    let exists = state.storage.zone_exists(&zone_name).await.map_err(|err| {
+        error!("Failed to check if zone exists: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if exists && !query.replace {
+        return Err((
+            StatusCode::CONFLICT,
+            "Zone already exists, set replace=true to overwrite it",
+        )
+            .into());
+    }
+
+    let mut removed = Vec::new();
+    if exists {
+        // RRsets which are not in the file are removed.
+        let imported = rrsets
+            .iter()
+            .map(|rrset| (rrset.domain.clone(), rrset.rtype))
+            .collect::<HashSet<_>>();
+        let domains = state
+            .storage
+            .list_domains(&zone_name)
+            .await
+            .map_err(|err| {
+                error!("Failed to list domains of zone {}: {}", zone_name, err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        let mut stale = HashSet::new();
+        for domain in domains {
+            let records = state
+                .storage
+                .list_records(&zone_name, &domain)
+                .await
+                .map_err(|err| {
+                    error!("Failed to list records of {}: {}", domain, err);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            for sr in &records {
+                let key = (domain.clone(), sr.as_record().record_type());
+                if !imported.contains(&key) {
+                    stale.insert(key);
+                }
+            }
+            removed.extend(records);
+        }
+        rrsets.extend(stale.into_iter().map(|(domain, rtype)| RecordSet {
+            domain,
+            rtype,
+            records: Vec::new(),
+        }));
+    }
+
+    let mut ops = rrsets
+        .iter()
+        .cloned()
+        .map(|rrset| rrset.into_op(&zone_name))
+        .collect::<Vec<_>>();
+    // A new zone only appears once all of its records are stored.
+    if !exists {
+        ops.push(StorageOp::AddZone {
+            zone: zone_name.clone(),
+        });
+    }
+    state.storage.apply(ops).await.map_err(|err| {
+        error!("Failed to import zone {}: {}", zone_name, err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!(
+        "Imported {} records into zone {}",
+        summary.records.values().sum::<usize>(),
+        zone_name
+    );
+
+    if exists {
+        let added = rrsets.into_iter().flat_map(|rrset| rrset.records).collect();
+        journal::record_change(&state, &zone_name, added, removed).await;
+    }
+    state.zone_reload.zone(&zone_name);
+
+    let status = if exists {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, response::Json(summary)))
+}
@@ -0,0 +1,69 @@
+use super::API_PREFIX;
+use axum::response;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Groups of endpoints of the current API version, with the tags they have in the specification.
+const GROUPS: &[(&str, &str, &str)] = &[
+    ("zones", "/zones", "Zones, their settings and their records"),
+    (
+        "records",
+        "/records/search",
+        "Search of records in all zones",
+    ),
+    (
+        "health",
+        "/health-checks",
+        "Health checks of record addresses",
+    ),
+    (
+        "tokens",
+        "/tokens",
+        "API tokens, these require the admin scope",
+    ),
+    (
+        "admin",
+        "/admin/trace-filters",
+        "Query tracing, this requires the admin scope",
+    ),
+];
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiIndex {
+    /// Version of the server.
+    version: &'static str,
+    /// Path of the OpenAPI specification, which documents all endpoints.
+    specification: &'static str,
+    groups: Vec<EndpointGroup>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EndpointGroup {
+    name: &'static str,
+    path: String,
+    description: &'static str,
+}
+
+/// List the endpoint groups of this API version.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "index",
+    responses(
+        (status = 200, description = "The endpoint groups of the API", body = ApiIndex),
+    )
+)]
+pub async fn index() -> response::Json<ApiIndex> {
+    response::Json(ApiIndex {
+        version: env!("CARGO_PKG_VERSION"),
+        specification: "/openapi.json",
+        groups: GROUPS
+            .iter()
+            .map(|&(name, path, description)| EndpointGroup {
+                name,
+                path: format!("{}{}", API_PREFIX, path),
+                description,
+            })
+            .collect(),
+    })
+}
@@ -0,0 +1,168 @@
+use std::cell::Cell;
+
+use super::{auth::unix_now, State};
+use crate::{
+    policy::AutoSerial,
+    storage::{StorageRecord, ZoneChange},
+};
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use log::{error, warn};
+use trust_dns_proto::rr::{RData, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+/// Header with the serial of the zone after a change made through the API.
+pub const ZONE_SERIAL_HEADER: &str = "x-zone-serial";
+
+tokio::task_local! {
+    /// Serial of the zone changed by the request being handled.
+    static ZONE_SERIAL: Cell<Option<u32>>;
+}
+
+/// Middleware adding the serial of the zone to the response of a request which changed it. If a
+/// request changes multiple zones, like a record with an automatic PTR record, the serial of the
+/// first changed zone is returned.
+pub async fn report_serial<B>(req: Request<B>, next: Next<B>) -> Response {
+    let (mut response, serial) = ZONE_SERIAL
+        .scope(Cell::new(None), async {
+            let response = next.run(req).await;
+            (response, ZONE_SERIAL.with(Cell::get))
+        })
+        .await;
+    if let Some(serial) = serial {
+        response
+            .headers_mut()
+            .insert(ZONE_SERIAL_HEADER, HeaderValue::from(serial));
+    }
+    response
+}
+
+/// Record a change made through the API in the journal of the zone, so secondaries can pick it
+/// up with an incremental transfer, and notify the secondaries. If the zone has an automatic
+/// serial, the serial is changed first, unless the change sets the SOA itself. The change itself
+/// is already applied at this point, so failures are only logged.
+pub async fn record_change(
+    state: &State,
+    zone: &LowerName,
+    added: Vec<StorageRecord>,
+    removed: Vec<StorageRecord>,
+) {
+    let sets_soa = added
+        .iter()
+        .any(|sr| sr.as_record().record_type() == RecordType::SOA);
+    let auto_serial = if sets_soa {
+        AutoSerial::Off
+    } else {
+        match state.storage.zone_policy(zone).await {
+            Ok(policy) => policy.auto_serial.unwrap_or(state.auto_serial),
+            Err(e) => {
+                error!("Failed to load policy of zone {}: {}", zone, e);
+                state.auto_serial
+            }
+        }
+    };
+
+    let serials = match auto_serial {
+        AutoSerial::Off => current_serial(state, zone).await,
+        AutoSerial::Increment => bump_serial(state, zone, None).await,
+        AutoSerial::Date => bump_serial(state, zone, Some(date_serial_floor(unix_now()))).await,
+    };
+    let (from_serial, serial) = match serials {
+        Some(serials) => serials,
+        None => {
+            state.notifier.notify(zone);
+            return;
+        }
+    };
+    // The zone cache keeps the old SOA until its next refresh, which only affects the serial in
+    // negative answers.
+    state.metrics.set_zone_serial(zone, serial);
+    // Outside of a request there is no response to report the serial in.
+    let _ = ZONE_SERIAL.try_with(|reported| {
+        if reported.get().is_none() {
+            reported.set(Some(serial));
+        }
+    });
+
+    let change = ZoneChange {
+        from_serial,
+        serial,
+        removed,
+        added,
+    };
+    // The DNS server keeps the zone cuts in memory, so they must be reloaded right away.
+    if change.changes_cuts(zone) {
+        state.zone_reload.zone(zone);
+    }
+    if let Err(e) = state.storage.append_change(zone, &change).await {
+        error!("Failed to journal change in zone {}: {}", zone, e);
+    }
+
+    state.notifier.notify(zone);
+}
+
+/// The serial of the zone, as both the serial before and after the change.
+async fn current_serial(state: &State, zone: &LowerName) -> Option<(u32, u32)> {
+    match state
+        .storage
+        .lookup_records(zone, zone, RecordType::SOA)
+        .await
+    {
+        Ok(soas) => match soas
+            .and_then(|soas| soas.into_iter().next())
+            .and_then(|soa| soa.as_record().data().cloned())
+        {
+            Some(RData::SOA(soa)) => Some((soa.serial(), soa.serial())),
+            _ => {
+                warn!("Zone {} has no SOA, not journaling change", zone);
+                None
+            }
+        },
+        Err(e) => {
+            error!("Failed to load SOA of zone {} for journal: {}", zone, e);
+            None
+        }
+    }
+}
+
+async fn bump_serial(state: &State, zone: &LowerName, floor: Option<u32>) -> Option<(u32, u32)> {
+    match state.storage.bump_serial(zone, floor).await {
+        Ok(Some(serials)) => Some(serials),
+        Ok(None) => {
+            warn!(
+                "Zone {} has no SOA, not changing serial or journaling change",
+                zone
+            );
+            None
+        }
+        Err(e) => {
+            error!("Failed to change serial of zone {}: {}", zone, e);
+            None
+        }
+    }
+}
+
+/// The first serial of the current day in the YYYYMMDDnn scheme, for a time in seconds since the
+/// unix epoch.
+fn date_serial_floor(now: u64) -> u32 {
+    // Civil date from the days since the epoch, as described in
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (now / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year * 1_000_000 + month * 10_000 + day * 100) as u32
+}
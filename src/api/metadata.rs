@@ -0,0 +1,175 @@
+use crate::{
+    geo::{GeoPolicy, CONTINENT_CODES, COUNTRY_CODES},
+    health::{HealthCheck, HealthProtocol},
+    storage::StorageRecord,
+    weight::WeightedAnswer,
+};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use trust_dns_proto::rr::Record;
+use utoipa::ToSchema;
+
+/// Maximum length of a record comment, in bytes.
+const MAX_COMMENT_LENGTH: usize = 512;
+/// Maximum amount of labels on a single record.
+const MAX_LABELS: usize = 32;
+/// Maximum length of a label key, in bytes.
+const MAX_LABEL_KEY_LENGTH: usize = 64;
+/// Maximum length of a label value, in bytes.
+const MAX_LABEL_VALUE_LENGTH: usize = 256;
+
+/// Optional weight of a record, to split traffic between the records of an RRset.
+#[derive(Deserialize, ToSchema, Default)]
+pub struct RecordWeight {
+    /// Relative weight of the record in a weighted pick from its RRset.
+    weight: Option<u32>,
+    weighted_answer: Option<WeightedAnswer>,
+}
+
+impl RecordWeight {
+    /// Verify the answer mode is only set together with a weight.
+    pub fn validate(&self) -> Result<(), (StatusCode, &'static str)> {
+        if self.weight.is_none() && self.weighted_answer.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "weighted_answer can only be set on records with a weight",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Set the weight on a record about to be persisted.
+    pub fn apply(self, record: &mut StorageRecord) {
+        record.weight = self.weight;
+        record.weighted_answer = self.weighted_answer;
+    }
+}
+
+/// Verify a geo policy only lists known location codes, and does not match nothing at all.
+pub fn validate_geo_policy(geo: &GeoPolicy) -> Result<(), (StatusCode, &'static str)> {
+    if geo.countries.is_empty() && geo.continents.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "A geo policy must list at least one country or continent",
+        ));
+    }
+
+    if !geo
+        .countries
+        .iter()
+        .all(|c| COUNTRY_CODES.binary_search(&c.as_str()).is_ok())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Countries must be assigned uppercase ISO 3166-1 alpha-2 codes",
+        ));
+    }
+
+    if !geo
+        .continents
+        .iter()
+        .all(|c| CONTINENT_CODES.contains(&c.as_str()))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Continents must be one of AF, AN, AS, EU, NA, OC or SA",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Verify a health check can actually be probed.
+pub fn validate_health_check(check: &HealthCheck) -> Result<(), (StatusCode, &'static str)> {
+    if check.port == 0 {
+        return Err((StatusCode::BAD_REQUEST, "Health check port can't be 0"));
+    }
+
+    if check.interval_secs == 0 || check.failure_threshold == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Health check interval and failure threshold must be at least 1",
+        ));
+    }
+
+    if let Some(ref path) = check.path {
+        if check.protocol != HealthProtocol::Http {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Health check path is only supported for HTTP checks",
+            ));
+        }
+        if !path.starts_with('/') || path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Health check path must start with / and can't contain whitespace",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Optional operator metadata which can be attached to a record when it is created. This is
+/// stored alongside the record and returned in listings, but never served over DNS.
+#[derive(Deserialize, ToSchema, Default)]
+pub struct RecordMetadata {
+    /// Free form comment, at most 512 bytes.
+    comment: Option<String>,
+    /// Free form labels, at most 32.
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+impl RecordMetadata {
+    /// Verify the metadata is within the limits imposed to protect the storage.
+    pub fn validate(&self) -> Result<(), (StatusCode, &'static str)> {
+        if let Some(ref comment) = self.comment {
+            if comment.len() > MAX_COMMENT_LENGTH {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Record comment is limited to 512 bytes",
+                ));
+            }
+        }
+
+        if self.labels.len() > MAX_LABELS {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "A record can have at most 32 labels",
+            ));
+        }
+
+        for (key, value) in &self.labels {
+            if key.is_empty() || key.len() > MAX_LABEL_KEY_LENGTH {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Label keys must be between 1 and 64 bytes",
+                ));
+            }
+            if value.len() > MAX_LABEL_VALUE_LENGTH {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Label values are limited to 256 bytes",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attach the metadata to a record, creating the [`StorageRecord`] to persist.
+    pub fn into_storage_record(self, record: Record) -> StorageRecord {
+        StorageRecord {
+            record,
+            comment: self.comment,
+            labels: self.labels,
+            geo: None,
+            weight: None,
+            weighted_answer: None,
+            health_check: None,
+        }
+    }
+}
@@ -1,52 +1,418 @@
-use super::State;
-use crate::storage::StorageRecord;
+use super::{
+    error::ApiError,
+    metadata::{RecordMetadata, RecordWeight},
+    record::{self, WriteQuery},
+    validate::{OneOrMany, ZoneDomain},
+    State,
+};
+use crate::{config::CnameTargetMode, storage::StorageRecord};
 use axum::{extract, http::StatusCode, response, Extension};
-use log::error;
-use serde::Deserialize;
-use trust_dns_proto::rr::{rdata::MX, Name, RData, Record};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use trust_dns_proto::rr::{rdata::MX, RData, Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
 
-#[derive(Deserialize)]
-pub struct AddARecord {
+#[derive(Deserialize, ToSchema)]
+pub struct AddMxRecord {
+    #[schema(value_type = Object, example = json!({"preference": 10, "exchange": "mail.example.com."}))]
     data: MX,
     ttl: u32,
+    #[serde(flatten)]
+    weight: RecordWeight,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
 }
 
+#[derive(Serialize, ToSchema)]
+pub struct AddedMxRecords {
+    records: Vec<StorageRecord>,
+    /// Problems with the records which don't prevent adding them, like an exchange in the zone
+    /// which has no addresses.
+    warnings: Vec<String>,
+}
+
+/// Add one or more MX records to a domain. The created records are returned. Records with the
+/// same preference and exchange are only added once.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/mx",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body(content = [AddMxRecord], description = "A single record, or an array of records"),
+    responses(
+        (status = 201, description = "The records were added", body = AddedMxRecords),
+        (status = 200, description = "The records were already present, or replaced the existing records", body = AddedMxRecords),
+        (status = 400, description = "A record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The records conflict with the records of the domain"),
+    )
+)]
 pub async fn add_record(
-    extract::Path((zone, domain)): extract::Path<(Name, Name)>,
-    extract::Json(data): extract::Json<AddARecord>,
+    ZoneDomain { zone, domain }: ZoneDomain,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<OneOrMany<AddMxRecord>>,
     Extension(state): Extension<State>,
-) -> response::Result<StatusCode> {
-    if !zone.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn zones",
-        )
-            .into());
+) -> Result<(StatusCode, response::Json<AddedMxRecords>), ApiError> {
+    let zone_name = LowerName::from(zone);
+    let data = data.into_vec();
+    if data.is_empty() {
+        return Err(ApiError::bad_request("no_records", "No MX records given"));
+    }
+
+    // A null MX says the domain accepts no mail at all, so other exchanges contradict it.
+    if data.len() > 1 && data.iter().any(|entry| entry.data.exchange().is_root()) {
+        return Err(ApiError::bad_request(
+            "invalid_null_mx",
+            "A null MX (exchange \".\") must be the only MX record of a domain",
+        ));
+    }
+
+    // Validate everything first, so we don't end up with a partial insert on invalid input.
+    let mut warnings = Vec::new();
+    for entry in &data {
+        warnings.extend(validate_exchange(&state, &zone_name, &entry.data).await?);
+        entry.metadata.validate()?;
+        entry.weight.validate()?;
+    }
+
+    let mut created = Vec::<StorageRecord>::with_capacity(data.len());
+    for entry in data {
+        if created
+            .iter()
+            .any(|sr| sr.as_record().data() == Some(&RData::MX(entry.data.clone())))
+        {
+            continue;
+        }
+        let record = Record::from_rdata(domain.clone(), entry.ttl, RData::MX(entry.data));
+        let mut storage_record = entry.metadata.into_storage_record(record);
+        entry.weight.apply(&mut storage_record);
+        created.push(storage_record);
+    }
+
+    let status = record::store_records(
+        &state,
+        &zone_name,
+        &domain,
+        RecordType::MX,
+        created.clone(),
+        write,
+    )
+    .await?;
+
+    Ok((
+        status,
+        response::Json(AddedMxRecords {
+            records: created,
+            warnings,
+        }),
+    ))
+}
+
+/// Validate the exchange of an MX record. It must be an fqdn and not an IP address, the root name
+/// is only allowed as a null MX (RFC 7505), and it should not point to a CNAME in the same zone
+/// (RFC 2181). Returns a warning if the exchange is in the zone but has no addresses, as mail
+/// can't be delivered to it until they are added.
+async fn validate_exchange(
+    state: &State,
+    zone: &LowerName,
+    mx: &MX,
+) -> Result<Option<String>, ApiError> {
+    let exchange = mx.exchange();
+    if !exchange.is_fqdn() {
+        return Err(ApiError::not_fqdn("MX exchange"));
+    }
+
+    // Mail servers look up the addresses of the exchange, an address literal is taken as a name.
+    let name = exchange.to_string();
+    let literal = name.trim_end_matches('.');
+    if literal.parse::<IpAddr>().is_ok() {
+        return Err(ApiError::bad_request(
+            "invalid_mx_exchange",
+            format!(
+                "MX exchange must be a host name, not an IP address. Add an A or AAAA record with \
+                 address {} to a name and use that name as exchange",
+                literal
+            ),
+        ));
     }
 
-    if !domain.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn domains",
-        )
-            .into());
+    if exchange.is_root() {
+        if mx.preference() != 0 {
+            return Err(ApiError::bad_request(
+                "invalid_null_mx",
+                "A null MX (exchange \".\") must have preference 0",
+            ));
+        }
+        return Ok(None);
     }
 
-    let record = Record::from_rdata(domain.clone(), data.ttl, RData::MX(data.data));
+    let exchange_name = LowerName::from(exchange);
+    if !zone.zone_of(&exchange_name) {
+        // We can't verify names outside of the zone.
+        return Ok(None);
+    }
 
-    state
+    let cnames = state
         .storage
-        .add_record(
-            &LowerName::from(zone),
-            &LowerName::from(domain),
-            StorageRecord { record },
-        )
+        .lookup_records(&exchange_name, zone, RecordType::CNAME)
         .await
-        .map_err(|err| {
-            error!("Failed to insert MX record: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|err| ApiError::storage("Failed to check MX exchange target", err))?;
+    if matches!(cnames, Some(ref cnames) if !cnames.is_empty()) {
+        match state.mx_cname_exchange {
+            CnameTargetMode::Warn => {
+                warn!(
+                    "MX exchange {} in zone {} points to a CNAME",
+                    exchange_name, zone
+                );
+                return Ok(Some(format!(
+                    "MX exchange {} points to a CNAME",
+                    exchange_name
+                )));
+            }
+            CnameTargetMode::Reject => {
+                return Err(ApiError::bad_request(
+                    "cname_target",
+                    "MX exchange must not point to a CNAME",
+                ));
+            }
+        }
+    }
+
+    for rtype in [RecordType::A, RecordType::AAAA] {
+        let addresses = state
+            .storage
+            .lookup_records(&exchange_name, zone, rtype)
+            .await
+            .map_err(|err| ApiError::storage("Failed to check MX exchange addresses", err))?;
+        if matches!(addresses, Some(ref addresses) if !addresses.is_empty()) {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(format!(
+        "MX exchange {} has no A or AAAA records",
+        exchange_name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
 
-    Ok(StatusCode::CREATED)
+    use crate::{
+        api::tests::TestApi,
+        config::CnameTargetMode,
+        handle::tests::{a, cname, lower},
+        storage::Storage,
+    };
+
+    const PATH: &str = "/v1/zones/example.com./example.com./mx";
+
+    #[tokio::test]
+    async fn round_trip() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "mail.example.org."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["records"].as_array().map(Vec::len), Some(1));
+        assert_eq!(body["warnings"], json!([]));
+        let (status, records) = api.get(PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(records, body["records"]);
+
+        // Several records can be added at once, duplicates are only added once.
+        let (status, body) = api
+            .put(
+                PATH,
+                json!([
+                    {"data": {"preference": 20, "exchange": "backup.example.org."}, "ttl": 300},
+                    {"data": {"preference": 30, "exchange": "last.example.org."}, "ttl": 300},
+                    {"data": {"preference": 30, "exchange": "last.example.org."}, "ttl": 300},
+                ]),
+            )
+            .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(body["records"].as_array().map(Vec::len), Some(2));
+        let (_, records) = api.get(PATH).await;
+        assert_eq!(records.as_array().map(Vec::len), Some(3));
+    }
+
+    #[tokio::test]
+    async fn exchange_must_be_fqdn() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "mail.example.org"}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "not_fqdn");
+        let (_, records) = api.get(PATH).await;
+        assert_eq!(records, json!([]));
+    }
+
+    #[tokio::test]
+    async fn null_mx() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_null_mx");
+        let (status, body) = api
+            .put(
+                PATH,
+                json!([
+                    {"data": {"preference": 0, "exchange": "."}, "ttl": 300},
+                    {"data": {"preference": 10, "exchange": "mail.example.org."}, "ttl": 300},
+                ]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_null_mx");
+
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 0, "exchange": "."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["warnings"], json!([]));
+        let (_, records) = api.get(PATH).await;
+        assert_eq!(records.as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn ip_literal_exchange_is_rejected() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "192.0.2.25."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_mx_exchange");
+        assert!(body["error"]["message"]
+            .as_str()
+            .expect("Error has a message")
+            .contains("192.0.2.25"));
+        let (_, records) = api.get(PATH).await;
+        assert_eq!(records, json!([]));
+    }
+
+    #[tokio::test]
+    async fn exchange_without_addresses_is_a_warning() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "mail.example.com."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            body["warnings"],
+            json!(["MX exchange mail.example.com. has no A or AAAA records"])
+        );
+
+        // Exchanges outside of the zone can't be checked.
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 20, "exchange": "mail.example.org."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["warnings"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn cname_exchange_is_rejected() {
+        let api = TestApi::start_with(|config| {
+            config.mx_cname_exchange = CnameTargetMode::Reject;
+        })
+        .await;
+        api.add_zone("example.com.").await;
+        api.storage
+            .add_record(
+                &lower("example.com."),
+                &lower("mail.example.com."),
+                cname("mail.example.com.", "mx.example.net."),
+            )
+            .await
+            .expect("Can add record");
+
+        let (status, body) = api
+            .put(
+                PATH,
+                json!({"data": {"preference": 10, "exchange": "mail.example.com."}, "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "cname_target");
+        let (_, records) = api.get(PATH).await;
+        assert_eq!(records, json!([]));
+    }
+
+    #[tokio::test]
+    async fn cname_exchange_is_a_warning() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let zone = lower("example.com.");
+        api.storage
+            .add_record(
+                &zone,
+                &lower("mail.example.com."),
+                cname("mail.example.com.", "mx.example.net."),
+            )
+            .await
+            .expect("Can add record");
+        api.storage
+            .add_record(
+                &zone,
+                &lower("mx.example.com."),
+                a("mx.example.com.", [192, 0, 2, 25]),
+            )
+            .await
+            .expect("Can add record");
+
+        let (status, body) = api
+            .put(
+                PATH,
+                json!([
+                    {"data": {"preference": 10, "exchange": "mail.example.com."}, "ttl": 300},
+                    {"data": {"preference": 20, "exchange": "mx.example.com."}, "ttl": 300},
+                    {"data": {"preference": 30, "exchange": "none.example.com."}, "ttl": 300},
+                ]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            body["warnings"],
+            json!([
+                "MX exchange mail.example.com. points to a CNAME",
+                "MX exchange none.example.com. has no A or AAAA records",
+            ])
+        );
+    }
 }
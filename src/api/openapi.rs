@@ -0,0 +1,313 @@
+use super::{
+    a, aaaa, audit, check, cname, dname, dnssec,
+    error::{ErrorBody, ErrorDetails},
+    export, geo as rrset_geo, health_check,
+    idempotency::IDEMPOTENCY_KEY_HEADER,
+    import, index, metadata, mx, page, policy, powerdns, probe, ptr, record, search, soa, srv,
+    svcb, tlsa, token, trace, txt, webhook, weights, zone, API_PREFIX,
+};
+use crate::{
+    audit as audit_log, geo, health, policy as zone_policy, status, storage, webhook as webhooks,
+    weight,
+};
+use axum::response::{Html, IntoResponse};
+use utoipa::{
+    openapi::{
+        path::{Operation, ParameterBuilder, ParameterIn, PathItemType},
+        security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme},
+        Content, ObjectBuilder, OpenApi as OpenApiSpec, Ref, RefOr, Required, Response, SchemaType,
+    },
+    Modify, OpenApi,
+};
+
+/// Name of the bearer token security scheme in the specification.
+const SECURITY_SCHEME: &str = "token";
+/// Tag of the probes, which are neither authenticated nor rate limited, and not versioned.
+const PROBES_TAG: &str = "probes";
+/// Tag of the index of the API version, which is not authenticated.
+const INDEX_TAG: &str = "index";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Cetus management API"),
+    paths(
+        index::index,
+        zone::list_zones,
+        zone::reload_zones,
+        zone::list_zone_domains,
+        zone::add_zone,
+        zone::delete_zone,
+        policy::get_zone_policy,
+        policy::update_zone_policy,
+        export::export_zone,
+        import::import_zone,
+        powerdns::import_powerdns,
+        soa::update_soa,
+        check::check_zone,
+        dnssec::list_keys,
+        dnssec::create_key,
+        dnssec::rollover_zsk,
+        dnssec::delete_key,
+        zone::list_domain_records,
+        a::add_record,
+        aaaa::add_record,
+        mx::add_record,
+        cname::add_record,
+        dname::add_record,
+        txt::add_record,
+        ptr::add_record,
+        srv::add_record,
+        svcb::add_https_record,
+        svcb::add_svcb_record,
+        tlsa::add_record,
+        record::get_records,
+        record::update_ttl,
+        record::delete_records,
+        rrset_geo::get_geo_policy,
+        rrset_geo::set_geo_policy,
+        rrset_geo::delete_geo_policy,
+        weights::get_weights,
+        weights::set_weights,
+        search::search_records,
+        health_check::list_health_checks,
+        token::list_tokens,
+        token::create_token,
+        token::revoke_token,
+        trace::list_trace_filters,
+        trace::add_trace_filter,
+        trace::clear_trace_filters,
+        trace::remove_trace_filter,
+        webhook::list_deliveries,
+        audit::list_audit,
+        probe::health,
+        probe::ready,
+    ),
+    components(schemas(
+        ErrorBody,
+        ErrorDetails,
+        index::ApiIndex,
+        index::EndpointGroup,
+        page::ZoneListing,
+        page::AuditListing,
+        audit_log::AuditEntry,
+        zone::AddZone,
+        zone::NS,
+        zone::DeletedZone,
+        zone_policy::ZonePolicy,
+        zone_policy::AnyResponse,
+        zone_policy::AnswerOrder,
+        zone_policy::AutoSerial,
+        policy::UpdateZonePolicy,
+        soa::UpdateSoa,
+        soa::ZoneSoa,
+        check::ZoneCheck,
+        check::Finding,
+        check::Severity,
+        dnssec::CreateKey,
+        dnssec::KeyInfo,
+        storage::KeyRole,
+        storage::KeyAlgorithm,
+        storage::KeyState,
+        import::ImportSummary,
+        import::SkippedRecord,
+        powerdns::PowerDnsExport,
+        powerdns::PowerDnsZone,
+        powerdns::PowerDnsRrset,
+        powerdns::PowerDnsRecord,
+        powerdns::PowerDnsImportReport,
+        powerdns::ZoneImport,
+        powerdns::ZoneImportStatus,
+        powerdns::SkippedRrset,
+        storage::StorageRecord,
+        storage::TokenScope,
+        metadata::RecordMetadata,
+        metadata::RecordWeight,
+        geo::GeoPolicy,
+        health::HealthCheck,
+        health::HealthProtocol,
+        health::TargetStatus,
+        weight::WeightedAnswer,
+        record::WriteMode,
+        record::DuplicateMode,
+        record::RemoveRecord,
+        rrset_geo::SetGeoPolicy,
+        rrset_geo::RecordLocations,
+        rrset_geo::RrsetGeoPolicy,
+        rrset_geo::RecordGeoPolicy,
+        weights::SetWeights,
+        weights::RrsetWeights,
+        weights::EffectiveWeight,
+        record::UpdateTtl,
+        search::SearchResult,
+        search::SearchMatch,
+        search::SearchSummary,
+        a::AddARecord,
+        aaaa::AddAaaaRecord,
+        mx::AddMxRecord,
+        mx::AddedMxRecords,
+        cname::AddCnameRecord,
+        dname::AddDnameRecord,
+        txt::AddTxtRecord,
+        ptr::AddPtrRecord,
+        srv::AddSrvRecord,
+        svcb::AddSvcbRecord,
+        tlsa::AddTlsaRecord,
+        token::CreateToken,
+        token::CreatedToken,
+        token::TokenInfo,
+        trace::AddTraceFilter,
+        trace::TraceFilterInfo,
+        webhooks::Delivery,
+        webhooks::DeliveryStatus,
+        status::ProbeResult,
+        status::Check,
+    )),
+    modifiers(&CommonResponses),
+    tags(
+        (name = "index", description = "Endpoints of the API version"),
+        (name = "zones", description = "Zones and their settings"),
+        (name = "dnssec", description = "DNSSEC signing keys of zones"),
+        (name = "records", description = "Records of the domains in a zone"),
+        (name = "health", description = "Health checks of record addresses"),
+        (name = "tokens", description = "API tokens, these require the admin scope"),
+        (name = "admin", description = "Query tracing, webhook deliveries and the audit log, these require the admin scope"),
+        (name = "probes", description = "Liveness and readiness probes, these don't require a token"),
+    )
+)]
+struct ApiDoc;
+
+/// Adds what all operations have in common, so the handler annotations only list what is
+/// specific to them: the version prefix, bearer authentication, the idempotency key of operations
+/// which change data, and the error body on every error response.
+struct CommonResponses;
+
+impl Modify for CommonResponses {
+    fn modify(&self, openapi: &mut OpenApiSpec) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                SECURITY_SCHEME,
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .description(Some(
+                            "Only required if API tokens are configured on the server",
+                        ))
+                        .build(),
+                ),
+            );
+        }
+
+        let paths = std::mem::take(&mut openapi.paths.paths);
+        for (path, mut item) in paths {
+            let has_tag = |tag: &str| {
+                item.operations.values().any(|operation| {
+                    operation
+                        .tags
+                        .as_ref()
+                        .is_some_and(|tags| tags.iter().any(|t| t == tag))
+                })
+            };
+            let (is_probe, is_index) = (has_tag(PROBES_TAG), has_tag(INDEX_TAG));
+            if !(is_probe || is_index) {
+                for (method, operation) in item.operations.iter_mut() {
+                    add_common_responses(operation);
+                    if !matches!(method, PathItemType::Get) {
+                        add_idempotency_key(operation);
+                    }
+                }
+            }
+            let path = match path.as_str() {
+                _ if is_probe => path,
+                "/" => API_PREFIX.to_string(),
+                _ => format!("{}{}", API_PREFIX, path),
+            };
+            openapi.paths.paths.insert(path, item);
+        }
+    }
+}
+
+fn add_idempotency_key(operation: &mut Operation) {
+    operation.parameters.get_or_insert_with(Vec::new).push(
+        ParameterBuilder::new()
+            .name(IDEMPOTENCY_KEY_HEADER)
+            .parameter_in(ParameterIn::Header)
+            .required(Required::False)
+            .description(Some(
+                "Retries with the same key get the stored response instead of changing data again. \
+                Keys are scoped to the token, and expire after a day by default",
+            ))
+            .schema(Some(ObjectBuilder::new().schema_type(SchemaType::String)))
+            .build(),
+    );
+}
+
+fn add_common_responses(operation: &mut Operation) {
+    operation.security = Some(vec![SecurityRequirement::new(
+        SECURITY_SCHEME,
+        Vec::<String>::new(),
+    )]);
+
+    let responses = &mut operation.responses.responses;
+    for (status, description) in [
+        ("401", "No valid token was given"),
+        ("403", "The token does not have the required scope"),
+    ] {
+        responses
+            .entry(status.to_string())
+            .or_insert_with(|| Response::new(description).into());
+    }
+    responses.entry("429".to_string()).or_insert_with(|| {
+        Response::new("A rate limit was exceeded, see the Retry-After header").into()
+    });
+    responses
+        .entry("502".to_string())
+        .or_insert_with(|| Response::new("The storage failed to handle the request").into());
+
+    for (status, response) in responses.iter_mut() {
+        // Authentication failures are rejected by the middleware before a handler runs, without a
+        // body.
+        if !(status.starts_with('4') || status.starts_with('5'))
+            || status == "401"
+            || status == "403"
+        {
+            continue;
+        }
+        if let RefOr::T(response) = response {
+            if response.content.is_empty() {
+                response.content.insert(
+                    "application/json".to_string(),
+                    Content::new(Ref::from_schema_name("ErrorBody")),
+                );
+            }
+        }
+    }
+}
+
+/// The OpenAPI specification of the API.
+pub async fn openapi_json() -> impl IntoResponse {
+    axum::Json(ApiDoc::openapi())
+}
+
+/// Swagger UI for the specification served at `/openapi.json`. The UI itself is loaded from a CDN.
+pub async fn docs() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Cetus management API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
@@ -0,0 +1,85 @@
+use super::error::ApiError;
+use crate::{
+    audit::AuditEntry,
+    storage::{InvalidCursor, Page},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use utoipa::{IntoParams, ToSchema};
+
+/// Maximum amount of items in a single page of a listing. Larger limits are capped to this.
+pub const MAX_PAGE_SIZE: usize = 1_000;
+
+/// Query parameters of listings which can be paged. Without either of them the full listing is
+/// returned at once.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageQuery {
+    /// Amount of items in a page, capped to [`MAX_PAGE_SIZE`].
+    limit: Option<usize>,
+    /// The `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+impl PageQuery {
+    /// Check if a page is requested rather than the full listing.
+    pub fn is_paged(&self) -> bool {
+        self.limit.is_some() || self.cursor.is_some()
+    }
+
+    /// The requested page size.
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Decode the cursor back into the cursor of the storage backend.
+    pub fn cursor(&self) -> Result<Option<String>, ApiError> {
+        self.cursor
+            .as_ref()
+            .map(|cursor| {
+                base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+                    .ok()
+                    .and_then(|raw| String::from_utf8(raw).ok())
+                    .ok_or_else(invalid_cursor)
+            })
+            .transpose()
+    }
+}
+
+/// A listing, either complete or a single page of it.
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+#[aliases(ZoneListing = Listing<String>, AuditListing = Listing<AuditEntry>)]
+pub enum Listing<T> {
+    All(Vec<T>),
+    Page {
+        items: Vec<T>,
+        next_cursor: Option<String>,
+    },
+}
+
+impl<T> Listing<T> {
+    /// Wrap a page of the storage, making its cursor opaque to clients.
+    pub fn from_page<S>(page: Page<S>, f: impl FnMut(S) -> T) -> Self {
+        Listing::Page {
+            items: page.items.into_iter().map(f).collect(),
+            next_cursor: page
+                .next_cursor
+                .map(|cursor| base64::encode_config(cursor, base64::URL_SAFE_NO_PAD)),
+        }
+    }
+}
+
+/// Convert an error of a paged storage listing, which is the client's fault if the cursor is not
+/// valid.
+pub fn page_error(context: &str, err: Box<dyn Error + Send + Sync>) -> ApiError {
+    if err.is::<InvalidCursor>() {
+        invalid_cursor()
+    } else {
+        ApiError::storage(context, err)
+    }
+}
+
+fn invalid_cursor() -> ApiError {
+    ApiError::bad_request("invalid_cursor", "Cursor is not valid")
+}
@@ -0,0 +1,254 @@
+use super::{error::ApiError, zone, State};
+use crate::policy::{AnswerOrder, AnyResponse, AutoSerial, ZonePolicy};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::{error, trace};
+use serde::{Deserialize, Deserializer};
+use trust_dns_proto::rr::Name;
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// Maximum value of TTL overrides, 1 week.
+const MAX_POLICY_TTL: u32 = 604_800;
+
+/// Partial update of a zone policy. Fields which are absent are left as is, fields which are
+/// explicitly set to `null` are cleared so the zone inherits the global policy again.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateZonePolicy {
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<bool>)]
+    include_authority_ns: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<u32>)]
+    min_ttl: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<AnyResponse>)]
+    any_response: Option<Option<AnyResponse>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<u32>)]
+    any_hinfo_ttl: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<AnswerOrder>)]
+    answer_order: Option<Option<AnswerOrder>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<bool>)]
+    auto_ptr: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<AutoSerial>)]
+    auto_serial: Option<Option<AutoSerial>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<bool>)]
+    reject_private_addresses: Option<Option<bool>>,
+}
+
+/// Distinguish between an absent field (`None`, through `#[serde(default)]`) and an explicit
+/// `null` (`Some(None)`).
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Get the policy overrides of a zone.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/policy",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    responses(
+        (status = 200, description = "The policy overrides of the zone", body = ZonePolicy),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn get_zone_policy(
+    extract::Path(zone): extract::Path<Name>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<ZonePolicy>, ApiError> {
+    trace!("Loading policy for zone {}", zone);
+    let zone_name = existing_zone(&state, zone).await?;
+
+    Ok(response::Json(
+        state.storage.zone_policy(&zone_name).await.map_err(|err| {
+            error!("Failed to load zone policy: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    ))
+}
+
+/// Update the policy overrides of a zone, returning the new policy.
+#[utoipa::path(
+    patch,
+    path = "/zones/{zone}/policy",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    request_body = UpdateZonePolicy,
+    responses(
+        (status = 200, description = "The new policy overrides of the zone", body = ZonePolicy),
+        (status = 400, description = "The zone is not an fqdn, or an override is out of range"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn update_zone_policy(
+    extract::Path(zone): extract::Path<Name>,
+    extract::Json(data): extract::Json<UpdateZonePolicy>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<ZonePolicy>, ApiError> {
+    trace!("Updating policy for zone {}", zone);
+    let zone_name = existing_zone(&state, zone).await?;
+
+    if let Some(Some(min_ttl)) = data.min_ttl {
+        if min_ttl > MAX_POLICY_TTL {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Minimum TTL can be at most 604800 seconds",
+            )
+                .into());
+        }
+    }
+
+    if let Some(Some(any_hinfo_ttl)) = data.any_hinfo_ttl {
+        if any_hinfo_ttl > MAX_POLICY_TTL {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "ANY HINFO TTL can be at most 604800 seconds",
+            )
+                .into());
+        }
+    }
+
+    let mut policy = state.storage.zone_policy(&zone_name).await.map_err(|err| {
+        error!("Failed to load zone policy: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if let Some(include_authority_ns) = data.include_authority_ns {
+        policy.include_authority_ns = include_authority_ns;
+    }
+    if let Some(min_ttl) = data.min_ttl {
+        policy.min_ttl = min_ttl;
+    }
+    if let Some(any_response) = data.any_response {
+        policy.any_response = any_response;
+    }
+    if let Some(any_hinfo_ttl) = data.any_hinfo_ttl {
+        policy.any_hinfo_ttl = any_hinfo_ttl;
+    }
+    if let Some(answer_order) = data.answer_order {
+        policy.answer_order = answer_order;
+    }
+    if let Some(auto_ptr) = data.auto_ptr {
+        policy.auto_ptr = auto_ptr;
+    }
+    if let Some(auto_serial) = data.auto_serial {
+        policy.auto_serial = auto_serial;
+    }
+    if let Some(reject_private_addresses) = data.reject_private_addresses {
+        policy.reject_private_addresses = reject_private_addresses;
+    }
+
+    state
+        .storage
+        .set_zone_policy(&zone_name, &policy)
+        .await
+        .map_err(|err| {
+            error!("Failed to save zone policy: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(response::Json(policy))
+}
+
+/// Validate the zone name, and make sure the zone exists.
+async fn existing_zone(state: &State, zone: Name) -> Result<LowerName, ApiError> {
+    if !zone.is_fqdn() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Can only manage policies of fqdn zones",
+        )
+            .into());
+    }
+
+    let zone_name = LowerName::from(zone);
+    zone::ensure_zone_exists(state, &zone_name).await?;
+
+    Ok(zone_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::{api::tests::TestApi, handle::tests::lower, policy::ZonePolicy, storage::Storage};
+
+    #[tokio::test]
+    async fn absent_policy_is_empty() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, body) = api.get("/v1/zones/example.com./policy").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({}));
+    }
+
+    #[tokio::test]
+    async fn update_sets_and_clears_overrides() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, body) = api
+            .patch(
+                "/v1/zones/example.com./policy",
+                json!({"min_ttl": 600, "include_authority_ns": true, "any_response": "full"}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"min_ttl": 600, "include_authority_ns": true, "any_response": "full"})
+        );
+
+        // Absent fields are kept, null fields are cleared.
+        let (status, body) = api
+            .patch(
+                "/v1/zones/example.com./policy",
+                json!({"min_ttl": null, "answer_order": "round_robin"}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"include_authority_ns": true, "any_response": "full", "answer_order": "round_robin"})
+        );
+        let stored = api
+            .storage
+            .zone_policy(&lower("example.com."))
+            .await
+            .expect("Can load zone policy");
+        assert_eq!(
+            serde_json::from_value::<ZonePolicy>(body).expect("Valid policy"),
+            stored
+        );
+    }
+
+    #[tokio::test]
+    async fn update_is_validated() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        for body in [
+            json!({"min_ttl": 604_801}),
+            json!({"any_hinfo_ttl": 604_801}),
+            json!({"answer_order": "sorted"}),
+        ] {
+            let (status, _) = api.patch("/v1/zones/example.com./policy", body).await;
+            assert!(status.is_client_error(), "{}", status);
+        }
+        let (_, body) = api.get("/v1/zones/example.com./policy").await;
+        assert_eq!(body, json!({}));
+
+        let (status, _) = api
+            .patch("/v1/zones/example.org./policy", json!({"min_ttl": 60}))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
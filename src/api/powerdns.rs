@@ -0,0 +1,293 @@
+use std::{collections::BTreeMap, error::Error, str::FromStr};
+
+use super::{journal, State};
+use crate::storage::{RecordSet, StorageOp, StorageRecord};
+use axum::{extract, response, Extension};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::{DNSClass, Name, RecordType};
+use trust_dns_server::client::{
+    rr::LowerName,
+    serialize::txt::{Lexer, Parser},
+};
+use utoipa::ToSchema;
+
+/// A PowerDNS API export, of either a single zone or a list of zones.
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum PowerDnsExport {
+    Zone(PowerDnsZone),
+    Zones(Vec<PowerDnsZone>),
+}
+
+/// A zone as exported by the PowerDNS API. Fields other than the name and RRsets are ignored.
+#[derive(Deserialize, ToSchema)]
+pub struct PowerDnsZone {
+    /// Fully qualified name of the zone.
+    name: String,
+    #[serde(default)]
+    rrsets: Vec<PowerDnsRrset>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PowerDnsRrset {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: String,
+    ttl: u32,
+    records: Vec<PowerDnsRecord>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PowerDnsRecord {
+    /// Data of the record in master file format.
+    content: String,
+    #[serde(default)]
+    disabled: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PowerDnsImportReport {
+    zones: Vec<ZoneImport>,
+}
+
+/// Outcome of the import of a single zone.
+#[derive(Serialize, ToSchema)]
+pub struct ZoneImport {
+    zone: String,
+    status: ZoneImportStatus,
+    /// Why the zone was not imported, only set if it failed.
+    error: Option<String>,
+    /// Amount of imported records per record type.
+    records: BTreeMap<String, usize>,
+    /// RRsets, or records of them, which were not imported.
+    skipped: Vec<SkippedRrset>,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneImportStatus {
+    /// The zone did not exist, and was created.
+    Created,
+    /// The zone existed, the imported RRsets replaced its RRsets with the same name and type.
+    Updated,
+    /// Nothing of the zone was imported.
+    Failed,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SkippedRrset {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: String,
+    /// Amount of records of the RRset which were skipped.
+    records: usize,
+    reason: String,
+}
+
+/// Import zones from a PowerDNS API export, as returned by `GET /api/v1/servers/{server}/zones/
+/// {zone}`. The body is either a single zone or a list of zones. Zones which don't exist are
+/// created, and must then have SOA and NS records at the apex. In zones which exist, every
+/// imported RRset replaces the RRset with the same name and type, other RRsets are left as is.
+/// Disabled records are skipped, as are RRsets with content which can't be translated. Every zone
+/// is imported on its own, so a zone which fails does not affect the others.
+#[utoipa::path(
+    post,
+    path = "/zones/import/powerdns",
+    tag = "zones",
+    request_body = PowerDnsExport,
+    responses(
+        (status = 200, description = "The outcome of the import per zone", body = PowerDnsImportReport),
+        (status = 400, description = "The body is not a PowerDNS zone export"),
+    )
+)]
+pub async fn import_powerdns(
+    Extension(state): Extension<State>,
+    extract::Json(export): extract::Json<PowerDnsExport>,
+) -> response::Json<PowerDnsImportReport> {
+    let zones = match export {
+        PowerDnsExport::Zone(zone) => vec![zone],
+        PowerDnsExport::Zones(zones) => zones,
+    };
+
+    let mut report = PowerDnsImportReport {
+        zones: Vec::with_capacity(zones.len()),
+    };
+    for zone in zones {
+        report.zones.push(import_zone(&state, zone).await);
+    }
+    for zone in &report.zones {
+        if zone.status == ZoneImportStatus::Failed {
+            continue;
+        }
+        if let Ok(zone) = LowerName::from_str(&zone.zone) {
+            state.zone_reload.zone(&zone);
+        }
+    }
+
+    response::Json(report)
+}
+
+async fn import_zone(state: &State, zone: PowerDnsZone) -> ZoneImport {
+    let mut outcome = ZoneImport {
+        zone: zone.name.clone(),
+        status: ZoneImportStatus::Failed,
+        error: None,
+        records: BTreeMap::new(),
+        skipped: Vec::new(),
+    };
+
+    let origin = match zone.name.parse::<Name>() {
+        Ok(origin) if origin.is_fqdn() => origin,
+        _ => {
+            outcome.error = Some("Zone name is not an fqdn".to_string());
+            return outcome;
+        }
+    };
+    let zone_name = LowerName::from(origin.clone());
+
+    let mut rrsets = Vec::with_capacity(zone.rrsets.len());
+    for rrset in zone.rrsets {
+        let mut skip = |records: usize, reason: String| {
+            outcome.skipped.push(SkippedRrset {
+                name: rrset.name.clone(),
+                rtype: rrset.rtype.clone(),
+                records,
+                reason,
+            })
+        };
+
+        let disabled = rrset.records.iter().filter(|r| r.disabled).count();
+        if disabled > 0 {
+            skip(disabled, "record is disabled".to_string());
+        }
+        if disabled == rrset.records.len() {
+            continue;
+        }
+
+        match translate_rrset(&origin, &rrset) {
+            Ok(translated) if !zone_name.zone_of(&translated.domain) => skip(
+                rrset.records.len() - disabled,
+                format!("{} is not in zone {}", translated.domain, zone_name),
+            ),
+            Ok(translated) if translated.rtype.is_dnssec() => skip(
+                rrset.records.len() - disabled,
+                "DNSSEC records are not supported".to_string(),
+            ),
+            Ok(translated) => rrsets.push(translated),
+            Err(e) => skip(rrset.records.len() - disabled, e.to_string()),
+        }
+    }
+
+    let exists = match state.storage.zone_exists(&zone_name).await {
+        Ok(exists) => exists,
+        Err(e) => {
+            error!("Failed to check if zone {} exists: {}", zone_name, e);
+            outcome.error = Some("Failed to check if the zone exists".to_string());
+            return outcome;
+        }
+    };
+    if !exists {
+        for rtype in [RecordType::SOA, RecordType::NS] {
+            if !rrsets
+                .iter()
+                .any(|rrset| rrset.domain == zone_name && rrset.rtype == rtype)
+            {
+                outcome.error = Some(format!("Zone has no {} record at the apex", rtype));
+                return outcome;
+            }
+        }
+    }
+
+    // Records which are replaced in an existing zone, to journal the change.
+    let mut removed = Vec::new();
+    if exists {
+        for rrset in &rrsets {
+            match state
+                .storage
+                .lookup_records(&rrset.domain, &zone_name, rrset.rtype)
+                .await
+            {
+                Ok(records) => removed.extend(records.unwrap_or_default()),
+                Err(e) => {
+                    error!(
+                        "Failed to load {} records of {}: {}",
+                        rrset.rtype, rrset.domain, e
+                    );
+                    outcome.error = Some("Failed to load the existing records".to_string());
+                    return outcome;
+                }
+            }
+        }
+    }
+
+    let mut ops = rrsets
+        .iter()
+        .cloned()
+        .map(|rrset| rrset.into_op(&zone_name))
+        .collect::<Vec<_>>();
+    // A new zone only appears once all of its records are stored.
+    if !exists {
+        ops.push(StorageOp::AddZone {
+            zone: zone_name.clone(),
+        });
+    }
+    if let Err(e) = state.storage.apply(ops).await {
+        error!("Failed to import zone {}: {}", zone_name, e);
+        outcome.error = Some("Failed to store the records".to_string());
+        return outcome;
+    }
+    for rrset in &rrsets {
+        *outcome.records.entry(rrset.rtype.to_string()).or_default() += rrset.records.len();
+    }
+    info!(
+        "Imported {} records from PowerDNS into zone {}",
+        outcome.records.values().sum::<usize>(),
+        zone_name
+    );
+
+    if exists {
+        let added = rrsets.into_iter().flat_map(|rrset| rrset.records).collect();
+        journal::record_change(state, &zone_name, added, removed).await;
+        outcome.status = ZoneImportStatus::Updated;
+    } else {
+        outcome.status = ZoneImportStatus::Created;
+    }
+
+    outcome
+}
+
+/// Translate the enabled records of a PowerDNS RRset. The content of PowerDNS records is in
+/// master file format, so the RRset is written as a master file and parsed as such, which takes
+/// care of the type specific formats like the priority of MX records and quoting of TXT records.
+fn translate_rrset(
+    origin: &Name,
+    rrset: &PowerDnsRrset,
+) -> Result<RecordSet, Box<dyn Error + Send + Sync>> {
+    let mut lines = String::new();
+    for record in rrset.records.iter().filter(|r| !r.disabled) {
+        if record.content.contains(['\n', '\r']) {
+            return Err("record content spans multiple lines".into());
+        }
+        lines.push_str(&format!(
+            "{} {} IN {} {}\n",
+            rrset.name, rrset.ttl, rrset.rtype, record.content
+        ));
+    }
+
+    let (_, mut parsed) = Parser::new()
+        .parse(Lexer::new(&lines), Some(origin.clone()), Some(DNSClass::IN))
+        .map_err(|e| format!("invalid record content: {}", e))?;
+    // All lines share the name and type, so they form a single RRset.
+    let (key, parsed) = parsed.pop_first().ok_or("RRset has no records")?;
+
+    Ok(RecordSet {
+        domain: key.name,
+        rtype: key.record_type,
+        records: parsed
+            .records_without_rrsigs()
+            .cloned()
+            .map(StorageRecord::new)
+            .collect(),
+    })
+}
@@ -0,0 +1,32 @@
+use super::State;
+use crate::status::{self, ProbeResult};
+use axum::Extension;
+
+/// Liveness of the process. This fails if the runtime does not pick up new tasks in time.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "probes",
+    responses(
+        (status = 200, description = "The process is alive", body = ProbeResult),
+        (status = 503, description = "The process is stuck", body = ProbeResult),
+    )
+)]
+pub async fn health() -> ProbeResult {
+    status::liveness().await
+}
+
+/// Readiness of the server to answer queries: the storage answers, the zones are loaded and the
+/// GeoIP database is opened.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "probes",
+    responses(
+        (status = 200, description = "The server is ready", body = ProbeResult),
+        (status = 503, description = "The server is not ready, see the failed checks", body = ProbeResult),
+    )
+)]
+pub async fn ready(Extension(state): Extension<State>) -> ProbeResult {
+    state.status.readiness(&*state.storage).await
+}
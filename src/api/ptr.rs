@@ -0,0 +1,359 @@
+use std::net::IpAddr;
+
+use super::{
+    error::ApiError,
+    journal,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::ZoneDomain,
+    State,
+};
+use crate::storage::{StorageOp, StorageRecord};
+use axum::{extract, http::StatusCode, Extension};
+use log::{debug, error};
+use serde::Deserialize;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddPtrRecord {
+    #[schema(value_type = String, example = "host.example.com.")]
+    data: Name,
+    ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
+}
+
+/// Add a PTR record to a domain.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/ptr",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body = AddPtrRecord,
+    responses(
+        (status = 201, description = "The record was added"),
+        (status = 200, description = "The record was already present, or replaced the existing records"),
+        (status = 400, description = "The record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The record conflicts with the records of the domain"),
+    )
+)]
+pub async fn add_record(
+    extract::Path((zone, domain)): extract::Path<(Name, Name)>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<AddPtrRecord>,
+    Extension(state): Extension<State>,
+) -> Result<StatusCode, ApiError> {
+    if !zone.is_fqdn() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Can only add records for fqdn zones",
+        )
+            .into());
+    }
+
+    if !domain.is_fqdn() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Can only add records for fqdn domains",
+        )
+            .into());
+    }
+
+    if !data.data.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "PTR target must be an fqdn").into());
+    }
+
+    data.metadata.validate()?;
+
+    let record = Record::from_rdata(domain.clone(), data.ttl, RData::PTR(data.data));
+
+    let zone = LowerName::from(zone);
+    let storage_record = data.metadata.into_storage_record(record);
+
+    record::store_records(
+        &state,
+        &zone,
+        &domain,
+        RecordType::PTR,
+        vec![storage_record],
+        write,
+    )
+    .await
+}
+
+/// Point the reverse name of an address added to a forward zone back to the name it was added
+/// to, if the forward zone has auto PTR enabled and a zone holding the reverse name exists. An
+/// existing PTR record for the address is replaced.
+///
+/// The forward record is already added at this point, so failures are only logged.
+pub async fn auto_ptr(state: &State, zone: &LowerName, addr: IpAddr, domain: &Name, ttl: u32) {
+    let (reverse_zone, reverse_name) = match reverse_location(state, zone, addr).await {
+        Some(location) => location,
+        None => return,
+    };
+
+    let removed = match state
+        .storage
+        .lookup_records(&reverse_name, &reverse_zone, RecordType::PTR)
+        .await
+    {
+        Ok(records) => records.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load PTR records of {}: {}", reverse_name, e);
+            return;
+        }
+    };
+    let ptr = StorageRecord::new(Record::from_rdata(
+        reverse_name.clone().into(),
+        ttl,
+        RData::PTR(domain.clone()),
+    ));
+    let op = StorageOp::SetRecords {
+        zone: reverse_zone.clone(),
+        domain: reverse_name.clone(),
+        rtype: RecordType::PTR,
+        records: vec![ptr.clone()],
+    };
+    if let Err(e) = state.storage.apply(vec![op]).await {
+        error!("Failed to set PTR record of {}: {}", reverse_name, e);
+        return;
+    }
+    debug!("Pointed {} to {}", reverse_name, domain);
+
+    journal::record_change(state, &reverse_zone, vec![ptr], removed).await;
+}
+
+/// Remove the PTR record pointing back to a name an address was removed from, if the forward
+/// zone has auto PTR enabled. PTR records pointing elsewhere are left alone, they were not added
+/// for this address.
+///
+/// The forward record is already removed at this point, so failures are only logged.
+pub async fn remove_auto_ptr(state: &State, zone: &LowerName, addr: IpAddr, domain: &Name) {
+    let (reverse_zone, reverse_name) = match reverse_location(state, zone, addr).await {
+        Some(location) => location,
+        None => return,
+    };
+
+    let ptr = Record::from_rdata(reverse_name.clone().into(), 0, RData::PTR(domain.clone()));
+    match state
+        .storage
+        .remove_record(&reverse_zone, &reverse_name, &ptr)
+        .await
+    {
+        Ok(Some(removed)) => {
+            debug!("Removed PTR record of {} to {}", reverse_name, domain);
+            journal::record_change(state, &reverse_zone, Vec::new(), vec![removed]).await;
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to remove PTR record of {}: {}", reverse_name, e),
+    }
+}
+
+/// Find the zone holding the reverse name of an address, and the reverse name itself, if the
+/// forward zone has auto PTR enabled. Failures are logged and give no location.
+async fn reverse_location(
+    state: &State,
+    zone: &LowerName,
+    addr: IpAddr,
+) -> Option<(LowerName, LowerName)> {
+    match state.storage.zone_policy(zone).await {
+        Ok(policy) if policy.auto_ptr == Some(true) => {}
+        Ok(_) => return None,
+        Err(e) => {
+            error!("Failed to load policy of zone {} for auto PTR: {}", zone, e);
+            return None;
+        }
+    }
+
+    let reverse_name = LowerName::from(Name::from(addr));
+    let zones = match state.storage.zones().await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("Failed to load zones for auto PTR: {}", e);
+            return None;
+        }
+    };
+    // The most specific zone holds the name, in case reverse zones are delegated from each other.
+    match zones
+        .into_iter()
+        .filter(|zone| zone.zone_of(&reverse_name))
+        .max_by_key(|zone| zone.num_labels())
+    {
+        Some(reverse_zone) => Some((reverse_zone, reverse_name)),
+        None => {
+            debug!("No reverse zone for {}, not managing PTR record", addr);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use crate::{api::tests::TestApi, handle::tests::lower, policy::ZonePolicy, storage::Storage};
+
+    const V4_REVERSE: &str = "2.0.192.in-addr.arpa.";
+    const V6_REVERSE: &str = "8.b.d.0.1.0.0.2.ip6.arpa.";
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).expect("Valid name")
+    }
+
+    #[test]
+    fn reverse_names() {
+        for (addr, reverse) in [
+            ("192.0.2.1", "1.2.0.192.in-addr.arpa."),
+            ("10.0.0.255", "255.0.0.10.in-addr.arpa."),
+            (
+                "2001:db8::1",
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.",
+            ),
+            (
+                "2001:db8:abcd:12::f00",
+                "0.0.f.0.0.0.0.0.0.0.0.0.0.0.0.0.2.1.0.0.d.c.b.a.8.b.d.0.1.0.0.2.ip6.arpa.",
+            ),
+            (
+                "::ffff:192.0.2.1",
+                "1.0.2.0.0.0.0.c.f.f.f.f.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+            ),
+        ] {
+            let addr = addr.parse::<IpAddr>().expect("Valid address");
+            assert_eq!(Name::from(addr).to_string(), reverse, "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn ptr_round_trip() {
+        let api = TestApi::start().await;
+        api.add_zone(V4_REVERSE).await;
+        let path = "/v1/zones/2.0.192.in-addr.arpa./1.2.0.192.in-addr.arpa./ptr";
+
+        let (status, _) = api
+            .put(path, json!({"data": "host.example.com.", "ttl": 300}))
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            api.stored(V4_REVERSE, "1.2.0.192.in-addr.arpa.", RecordType::PTR)
+                .await,
+            vec![RData::PTR(name("host.example.com."))]
+        );
+
+        let (status, _) = api
+            .put(path, json!({"data": "host.example.com", "ttl": 300}))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    /// Start an API with a forward zone, and the reverse zones of its addresses.
+    async fn auto_ptr_api(auto_ptr: bool) -> TestApi {
+        let api = TestApi::start().await;
+        for zone in ["example.com.", V4_REVERSE, V6_REVERSE] {
+            api.add_zone(zone).await;
+        }
+        api.storage
+            .set_zone_policy(
+                &lower("example.com."),
+                &ZonePolicy {
+                    auto_ptr: Some(auto_ptr),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("Can set zone policy");
+        api
+    }
+
+    #[tokio::test]
+    async fn auto_ptr_follows_addresses() {
+        let api = auto_ptr_api(true).await;
+        let v4_name = "10.2.0.192.in-addr.arpa.";
+        let v6_name = "0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.";
+
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./www.example.com./a",
+                json!({"data": "192.0.2.10", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./www.example.com./aaaa",
+                json!({"data": "2001:db8::10", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        for (zone, reverse) in [(V4_REVERSE, v4_name), (V6_REVERSE, v6_name)] {
+            assert_eq!(
+                api.stored(zone, reverse, RecordType::PTR).await,
+                vec![RData::PTR(name("www.example.com."))],
+                "{}",
+                reverse
+            );
+        }
+
+        // The address moving to another name moves the PTR record along.
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./mail.example.com./a",
+                json!({"data": "192.0.2.10", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            api.stored(V4_REVERSE, v4_name, RecordType::PTR).await,
+            vec![RData::PTR(name("mail.example.com."))]
+        );
+
+        // Removing the address from the old name leaves the PTR record of the new name alone,
+        // removing it from the new name removes the PTR record.
+        let (status, _) = api
+            .delete("/v1/zones/example.com./www.example.com./a")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            api.stored(V4_REVERSE, v4_name, RecordType::PTR).await,
+            vec![RData::PTR(name("mail.example.com."))]
+        );
+        let (status, _) = api
+            .delete("/v1/zones/example.com./mail.example.com./a")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(api
+            .stored(V4_REVERSE, v4_name, RecordType::PTR)
+            .await
+            .is_empty());
+        let (status, _) = api
+            .delete("/v1/zones/example.com./www.example.com./aaaa")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(api
+            .stored(V6_REVERSE, v6_name, RecordType::PTR)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn auto_ptr_is_opt_in() {
+        let api = auto_ptr_api(false).await;
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./www.example.com./a",
+                json!({"data": "192.0.2.10", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(api
+            .stored(V4_REVERSE, "10.2.0.192.in-addr.arpa.", RecordType::PTR)
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{error::ApiError, State};
+use crate::config::{ApiRateLimitConfig, ApiRateLimits, TokenBucketConfig};
+use axum::{
+    extract::ConnectInfo,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::debug;
+
+/// Interval at which buckets of clients which are no longer active are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket rate limits of API requests. Reads and writes are limited separately, as listing
+/// is cheap but creating zones is not.
+pub struct ApiRateLimiter {
+    read: Limits,
+    write: Limits,
+}
+
+/// Limits of a single kind of request.
+struct Limits {
+    global: Option<(TokenBucketConfig, Mutex<Bucket>)>,
+    per_client: Option<(TokenBucketConfig, Mutex<HashMap<IpAddr, Bucket>>)>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(config: &TokenBucketConfig) -> Bucket {
+        Bucket {
+            tokens: capacity(config),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refill the bucket for the time passed since the last update.
+    fn refill(&mut self, config: &TokenBucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate(config)).min(capacity(config));
+        self.updated = now;
+    }
+
+    /// Take a token from the bucket, or return how long it takes until one is available.
+    fn take(&mut self, config: &TokenBucketConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(config, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate(config)))
+    }
+}
+
+fn rate(config: &TokenBucketConfig) -> f64 {
+    config.requests_per_second.max(1) as f64
+}
+
+fn capacity(config: &TokenBucketConfig) -> f64 {
+    config.burst.unwrap_or(config.requests_per_second).max(1) as f64
+}
+
+impl Limits {
+    fn new(config: ApiRateLimits) -> Limits {
+        Limits {
+            global: config
+                .global
+                .map(|global| (global, Mutex::new(Bucket::full(&global)))),
+            per_client: config
+                .per_client
+                .map(|per_client| (per_client, Mutex::new(HashMap::new()))),
+        }
+    }
+
+    /// Take a token for a request from the given client. If the request is throttled, the limit
+    /// which throttled it is returned, together with the time until the request can be retried.
+    fn check(&self, client: Option<IpAddr>) -> Result<(), (&'static str, Duration)> {
+        let now = Instant::now();
+        // The client limit is checked first, so a single client going over its own limit does
+        // not use up the global limit.
+        if let (Some((config, buckets)), Some(client)) = (&self.per_client, client) {
+            buckets
+                .lock()
+                .unwrap()
+                .entry(client)
+                .or_insert_with(|| Bucket::full(config))
+                .take(config, now)
+                .map_err(|retry_after| ("client", retry_after))?;
+        }
+        if let Some((config, bucket)) = &self.global {
+            bucket
+                .lock()
+                .unwrap()
+                .take(config, now)
+                .map_err(|retry_after| ("global", retry_after))?;
+        }
+        Ok(())
+    }
+
+    /// Remove buckets which are full again, as they are the same as a new bucket.
+    fn prune(&self) -> usize {
+        let (config, buckets) = match &self.per_client {
+            Some(per_client) => per_client,
+            None => return 0,
+        };
+        let now = Instant::now();
+        let mut buckets = buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            bucket.refill(config, now);
+            bucket.tokens < capacity(config)
+        });
+        buckets.len()
+    }
+}
+
+impl ApiRateLimiter {
+    pub fn new(config: ApiRateLimitConfig) -> ApiRateLimiter {
+        ApiRateLimiter {
+            read: Limits::new(config.read),
+            write: Limits::new(config.write),
+        }
+    }
+
+    /// Periodically remove the buckets of clients which have not sent requests for a while.
+    pub async fn prune_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let left = self.read.prune() + self.write.prune();
+            debug!("Pruned api rate limit buckets, {} left", left);
+        }
+    }
+}
+
+/// Middleware throttling requests which go over the configured rate limits with a 429 response.
+pub async fn rate_limit<B>(req: Request<B>, next: Next<B>) -> Response {
+    let state = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set");
+    if let Some(limiter) = &state.rate_limiter {
+        let (kind, limits) = if req.method() == Method::GET || req.method() == Method::HEAD {
+            ("read", &limiter.read)
+        } else {
+            ("write", &limiter.write)
+        };
+        let client = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if let Err((limit, retry_after)) = limits.check(client) {
+            state.metrics.increment_api_throttled(kind, limit);
+            // Retry-After only has second precision, round up so the retry is not throttled again.
+            let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            let mut response = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "Too many requests, retry later",
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+            return response;
+        }
+    }
+
+    next.run(req).await
+}
@@ -0,0 +1,766 @@
+use std::{collections::BTreeSet, net::IpAddr, str::FromStr};
+
+use super::{
+    error::ApiError,
+    journal, ptr,
+    validate::{validate_domain_in_zone, ZoneDomain},
+    zone, State,
+};
+use crate::{
+    dname::DNAME,
+    storage::{RecordSet, StorageRecord},
+};
+use axum::{
+    body::Bytes,
+    extract,
+    http::{StatusCode, Uri},
+    response, Extension,
+};
+use log::error;
+use serde::Deserialize;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::{IntoParams, ToSchema};
+
+/// How the add endpoints of record types store the records they are given.
+#[derive(Deserialize, ToSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    /// Add the records to the existing RRset.
+    #[default]
+    Append,
+    /// Replace the existing RRset with the records.
+    Replace,
+}
+
+/// What the add endpoints of record types do with records which have the same data as a record
+/// already in the RRset.
+#[derive(Deserialize, ToSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateMode {
+    /// Keep the existing record, only taking over the TTL of the new one.
+    #[default]
+    Ignore,
+    /// Refuse the request.
+    Reject,
+}
+
+/// Query parameters of the add endpoints of record types.
+#[derive(Deserialize, IntoParams, Clone, Copy)]
+#[into_params(parameter_in = Query)]
+pub struct WriteQuery {
+    /// Add the records to the RRset, or replace the RRset with them.
+    #[serde(default)]
+    #[param(inline)]
+    pub mode: WriteMode,
+    /// What to do with records which are already in the RRset.
+    #[serde(default)]
+    #[param(inline)]
+    pub on_duplicate: DuplicateMode,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RemoveRecord {
+    /// Data of the record to remove, in the same form as records are listed in.
+    #[schema(value_type = Object)]
+    rdata: RData,
+}
+
+/// Get the records of the type in the last path segment of a domain. A domain without records of
+/// the type gives an empty list, a domain which does not exist at all is not found.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/{domain}/{rtype}",
+    tag = "records",
+    params(
+        ZoneDomain,
+        ("rtype" = String, Path, description = "Type of the records, e.g. `a` or `txt`"),
+    ),
+    responses(
+        (status = 200, description = "The records of the type", body = [StorageRecord]),
+        (status = 400, description = "Unknown record type"),
+        (status = 404, description = "The zone or the domain does not exist"),
+    )
+)]
+pub async fn get_records(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    uri: Uri,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<Vec<StorageRecord>>, ApiError> {
+    let rtype = path_record_type(&uri)?;
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    let records = state
+        .storage
+        .lookup_records(&domain_name, &zone_name, rtype)
+        .await
+        .map_err(|err| {
+            ApiError::storage(
+                &format!("Failed to load {} records of {}", rtype, domain_name),
+                err,
+            )
+        })?
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "domain_not_found",
+                "Domain does not exist",
+            )
+        })?;
+
+    Ok(response::Json(records))
+}
+
+/// Load the records of an RRset, which must exist.
+pub async fn load_rrset(
+    state: &State,
+    zone_name: &LowerName,
+    domain_name: &LowerName,
+    rtype: RecordType,
+) -> Result<Vec<StorageRecord>, ApiError> {
+    zone::ensure_zone_exists(state, zone_name).await?;
+
+    let records = state
+        .storage
+        .lookup_records(domain_name, zone_name, rtype)
+        .await
+        .map_err(|err| {
+            ApiError::storage(
+                &format!("Failed to load {} records of {}", rtype, domain_name),
+                err,
+            )
+        })?
+        .unwrap_or_default();
+    if records.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "rrset_not_found",
+            format!("{} has no {} records", domain_name, type_name(rtype)),
+        ));
+    }
+
+    Ok(records)
+}
+
+/// Replace the records of an RRset as a whole, without journaling the change. This is meant for
+/// changes of record metadata, which is not transferred to secondaries.
+pub async fn store_rrset(
+    state: &State,
+    zone_name: &LowerName,
+    domain: LowerName,
+    rtype: RecordType,
+    records: &[StorageRecord],
+) -> Result<(), ApiError> {
+    let op = RecordSet {
+        domain,
+        rtype,
+        records: records.to_vec(),
+    }
+    .into_op(zone_name);
+    state
+        .storage
+        .apply(vec![op])
+        .await
+        .map_err(|err| ApiError::storage(&format!("Failed to store {} records", rtype), err))
+}
+
+/// Largest TTL allowed by RFC 2181, larger values are treated as 0 by resolvers.
+const MAX_TTL: u32 = i32::MAX as u32;
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateTtl {
+    /// New TTL of all records in the RRset.
+    ttl: u32,
+}
+
+/// Change the TTL of all records of the type in the last path segment of a domain, returning the
+/// updated records. The RRset is replaced as a whole, so it is never served partially updated.
+#[utoipa::path(
+    patch,
+    path = "/zones/{zone}/{domain}/{rtype}",
+    tag = "records",
+    params(
+        ZoneDomain,
+        ("rtype" = String, Path, description = "Type of the records, e.g. `a` or `txt`"),
+    ),
+    request_body = UpdateTtl,
+    responses(
+        (status = 200, description = "The updated records", body = [StorageRecord]),
+        (status = 400, description = "Unknown record type, or the TTL is out of range"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+        (status = 409, description = "The SOA is changed through the SOA endpoint"),
+    )
+)]
+pub async fn update_ttl(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    uri: Uri,
+    Extension(state): Extension<State>,
+    extract::Json(data): extract::Json<UpdateTtl>,
+) -> Result<response::Json<Vec<StorageRecord>>, ApiError> {
+    let rtype = path_record_type(&uri)?;
+    if data.ttl > MAX_TTL {
+        return Err(ApiError::bad_request(
+            "ttl_out_of_range",
+            format!("TTL can be at most {}", MAX_TTL),
+        ));
+    }
+    if rtype == RecordType::SOA {
+        return Err(ApiError::conflict(
+            "use_soa_endpoint",
+            "The TTL of the SOA is changed through the SOA endpoint",
+        ));
+    }
+
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    let old = state
+        .storage
+        .lookup_records(&domain_name, &zone_name, rtype)
+        .await
+        .map_err(|err| {
+            ApiError::storage(
+                &format!("Failed to load {} records of {}", rtype, domain_name),
+                err,
+            )
+        })?
+        .unwrap_or_default();
+    if old.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "rrset_not_found",
+            format!("{} has no {} records", domain_name, type_name(rtype)),
+        ));
+    }
+
+    let updated = old
+        .iter()
+        .cloned()
+        .map(|mut sr| {
+            sr.as_mut_record().set_ttl(data.ttl);
+            sr
+        })
+        .collect::<Vec<_>>();
+    state
+        .storage
+        .set_records(&zone_name, &domain_name, rtype, updated.clone())
+        .await
+        .map_err(|err| ApiError::storage(&format!("Failed to update {} records", rtype), err))?;
+
+    journal::record_change(&state, &zone_name, updated.clone(), old).await;
+
+    Ok(response::Json(updated))
+}
+
+/// Remove records of the type in the last path segment from a domain. Without a request body
+/// the whole RRset is removed, otherwise only the record with the data given in the body. The
+/// removed records are returned.
+#[utoipa::path(
+    delete,
+    path = "/zones/{zone}/{domain}/{rtype}",
+    tag = "records",
+    params(
+        ZoneDomain,
+        ("rtype" = String, Path, description = "Type of the records, e.g. `a` or `txt`"),
+    ),
+    request_body(content = Option<RemoveRecord>, description = "The record to remove, without a body the whole RRset is removed"),
+    responses(
+        (status = 200, description = "The removed records", body = [StorageRecord]),
+        (status = 400, description = "Unknown record type or invalid record data"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+        (status = 409, description = "The SOA record, or the last NS record of the zone, can't be removed"),
+    )
+)]
+pub async fn delete_records(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    uri: Uri,
+    body: Bytes,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<Vec<StorageRecord>>, ApiError> {
+    let rtype = path_record_type(&uri)?;
+    // Parse the body before touching anything, so a malformed body never removes a whole RRset.
+    let rdata = if body.is_empty() {
+        None
+    } else {
+        let data = serde_json::from_slice::<RemoveRecord>(&body).map_err(|err| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid record data: {}", err),
+            )
+        })?;
+        Some(data.rdata)
+    };
+
+    if rtype == RecordType::SOA {
+        return Err((
+            StatusCode::CONFLICT,
+            "The SOA record of a zone can't be removed",
+        )
+            .into());
+    }
+
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain.clone());
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    // A zone without NS records is lame, so the last one must be replaced rather than removed.
+    if rtype == RecordType::NS && domain_name == zone_name {
+        let records = state
+            .storage
+            .lookup_records(&domain_name, &zone_name, rtype)
+            .await
+            .map_err(|err| {
+                error!("Failed to load NS records of zone {}: {}", zone_name, err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .unwrap_or_default();
+        let removes_all = match rdata {
+            Some(ref rdata) => records
+                .iter()
+                .all(|sr| sr.as_record().data() == Some(rdata)),
+            None => true,
+        };
+        if !records.is_empty() && removes_all {
+            return Err((
+                StatusCode::CONFLICT,
+                "The last NS record of a zone can't be removed",
+            )
+                .into());
+        }
+    }
+
+    let removed = match rdata {
+        Some(rdata) => {
+            let record = Record::from_rdata(domain.clone(), 0, rdata);
+            state
+                .storage
+                .remove_record(&zone_name, &domain_name, &record)
+                .await
+                .map(|removed| removed.into_iter().collect::<Vec<_>>())
+        }
+        None => {
+            state
+                .storage
+                .remove_rrset(&zone_name, &domain_name, rtype)
+                .await
+        }
+    }
+    .map_err(|err| {
+        error!(
+            "Failed to remove {} records of {}: {}",
+            rtype, domain_name, err
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if removed.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "No such record").into());
+    }
+
+    journal::record_change(&state, &zone_name, Vec::new(), removed.clone()).await;
+    for sr in &removed {
+        if let Some(addr) = address(sr.as_record()) {
+            ptr::remove_auto_ptr(&state, &zone_name, addr, &domain).await;
+        }
+    }
+
+    Ok(response::Json(removed))
+}
+
+/// Store new records of a single type in a domain, and journal the change. The records are
+/// validated by the caller, this only verifies the zone exists and contains the domain. Returns
+/// 201 if records were added, or 200 if an existing RRset was replaced or all records were
+/// already present.
+pub async fn store_records(
+    state: &State,
+    zone: &LowerName,
+    domain: &Name,
+    rtype: RecordType,
+    records: Vec<StorageRecord>,
+    write: WriteQuery,
+) -> Result<StatusCode, ApiError> {
+    let domain_name = LowerName::from(domain.clone());
+    validate_domain_in_zone(zone, &domain_name)?;
+    zone::ensure_zone_exists(state, zone).await?;
+    check_cname_conflict(state, zone, &domain_name, rtype, &records, write.mode).await?;
+
+    let existing = state
+        .storage
+        .lookup_records(&domain_name, zone, rtype)
+        .await
+        .map_err(|err| {
+            ApiError::storage(
+                &format!("Failed to load {} records of {}", rtype, domain_name),
+                err,
+            )
+        })?
+        .unwrap_or_default();
+
+    if write.mode == WriteMode::Append {
+        return append_records(
+            state,
+            zone,
+            &domain_name,
+            rtype,
+            existing,
+            records,
+            write.on_duplicate,
+        )
+        .await;
+    }
+
+    let replaced = state
+        .storage
+        .set_records(zone, &domain_name, rtype, records.clone())
+        .await
+        .map_err(|err| ApiError::storage(&format!("Failed to replace {} records", rtype), err))?;
+
+    // Addresses which are gone should not keep pointing back here.
+    for sr in &existing {
+        if let Some(addr) = address(sr.as_record()) {
+            if !records
+                .iter()
+                .any(|new| address(new.as_record()) == Some(addr))
+            {
+                ptr::remove_auto_ptr(state, zone, addr, domain).await;
+            }
+        }
+    }
+    journal::record_change(state, zone, records, existing).await;
+
+    Ok(if replaced {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    })
+}
+
+/// Add records to an existing RRset. A record with the same data as one in the set is not added
+/// again, but its TTL is taken over by the existing record.
+async fn append_records(
+    state: &State,
+    zone: &LowerName,
+    domain: &LowerName,
+    rtype: RecordType,
+    existing: Vec<StorageRecord>,
+    records: Vec<StorageRecord>,
+    on_duplicate: DuplicateMode,
+) -> Result<StatusCode, ApiError> {
+    let existing_len = existing.len();
+    let mut rrset = existing.clone();
+    // Indices of existing records which got a new TTL.
+    let mut updated = BTreeSet::new();
+    for new in records {
+        let duplicate = rrset
+            .iter()
+            .position(|sr| sr.as_record().data() == new.as_record().data());
+        match duplicate {
+            Some(idx) => {
+                if on_duplicate == DuplicateMode::Reject {
+                    return Err(ApiError::conflict(
+                        "duplicate_record",
+                        format!(
+                            "The domain already has a {} record with this data",
+                            type_name(rtype)
+                        ),
+                    ));
+                }
+                let ttl = new.as_record().ttl();
+                if rrset[idx].as_record().ttl() != ttl {
+                    rrset[idx].record.set_ttl(ttl);
+                    if idx < existing_len {
+                        updated.insert(idx);
+                    }
+                }
+            }
+            None => rrset.push(new),
+        }
+    }
+
+    let added = rrset[existing_len..].to_vec();
+    if added.is_empty() && updated.is_empty() {
+        return Ok(StatusCode::OK);
+    }
+
+    state
+        .storage
+        .set_records(zone, domain, rtype, rrset.clone())
+        .await
+        .map_err(|err| ApiError::storage(&format!("Failed to insert {} records", rtype), err))?;
+
+    let removed = updated.iter().map(|&idx| existing[idx].clone()).collect();
+    let status = if added.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    let added = added
+        .into_iter()
+        .chain(updated.into_iter().map(|idx| rrset[idx].clone()))
+        .collect();
+    journal::record_change(state, zone, added, removed).await;
+
+    Ok(status)
+}
+
+/// Verify records of a type can be stored at a domain without breaking the CNAME rules: a CNAME
+/// is the only record of its name (RFC 1034 section 3.6.2), so it can't be at the zone apex next
+/// to the SOA and NS records either.
+async fn check_cname_conflict(
+    state: &State,
+    zone: &LowerName,
+    domain: &LowerName,
+    rtype: RecordType,
+    records: &[StorageRecord],
+    mode: WriteMode,
+) -> Result<(), ApiError> {
+    if rtype == RecordType::CNAME && domain == zone {
+        return Err(ApiError::conflict(
+            "cname_conflict",
+            "A CNAME can't be added at the zone apex",
+        ));
+    }
+
+    let existing = state
+        .storage
+        .list_records(zone, domain)
+        .await
+        .map_err(|err| ApiError::storage(&format!("Failed to load records of {}", domain), err))?;
+    for sr in &existing {
+        let existing_type = sr.as_record().record_type();
+        let conflict = if rtype == RecordType::CNAME {
+            // Replacing the CNAME itself is fine, adding a second one is not. Adding the same one
+            // again is left to the duplicate handling.
+            existing_type != RecordType::CNAME
+                || (mode == WriteMode::Append
+                    && records
+                        .iter()
+                        .any(|new| new.as_record().data() != sr.as_record().data()))
+        } else {
+            existing_type == RecordType::CNAME
+        };
+        if conflict {
+            return Err(ApiError::conflict(
+                "cname_conflict",
+                format!(
+                    "Can't add {} records, the domain already has {} records",
+                    type_name(rtype),
+                    type_name(existing_type)
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of a record type, including the ones unknown to the protocol library.
+pub fn type_name(rtype: RecordType) -> String {
+    if rtype == DNAME {
+        "DNAME".to_string()
+    } else {
+        rtype.to_string()
+    }
+}
+
+/// The record type in the last segment of the request path. Routes of specific record types have
+/// the type as a literal rather than a parameter, so it can't be taken from the path parameters.
+fn path_record_type(uri: &Uri) -> Result<RecordType, ApiError> {
+    uri.path()
+        .rsplit('/')
+        .next()
+        .and_then(parse_record_type)
+        .ok_or_else(|| ApiError::bad_request("unknown_record_type", "Unknown record type"))
+}
+
+/// Parse a record type from its name in a path, case insensitive.
+pub fn parse_record_type(name: &str) -> Option<RecordType> {
+    let name = name.to_ascii_uppercase();
+    // DNAME is not known to the protocol library.
+    if name == "DNAME" {
+        return Some(DNAME);
+    }
+    match RecordType::from_str(&name) {
+        Ok(RecordType::Unknown(_)) | Err(_) => None,
+        Ok(rtype) => Some(rtype),
+    }
+}
+
+/// The address in an A or AAAA record.
+fn address(record: &Record) -> Option<IpAddr> {
+    match record.data() {
+        Some(RData::A(addr)) => Some(IpAddr::V4(*addr)),
+        Some(RData::AAAA(addr)) => Some(IpAddr::V6(*addr)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use trust_dns_proto::rr::{RData, RecordType};
+
+    use crate::{api::tests::TestApi, handle::tests::lower, storage::Storage};
+
+    /// Get the TTLs of the records of a type of `www.example.com.`.
+    async fn ttls(api: &TestApi, rtype: RecordType) -> Vec<u32> {
+        api.storage
+            .lookup_records(&lower("www.example.com."), &lower("example.com."), rtype)
+            .await
+            .expect("Can look up records")
+            .unwrap_or_default()
+            .iter()
+            .map(|record| record.as_record().ttl())
+            .collect()
+    }
+
+    /// Add a record, then check that adding the same data again doesn't duplicate it, but does
+    /// take over a new TTL or is refused if asked for. `same` has the same data as `record` in a
+    /// different form, `different` has different data.
+    async fn assert_duplicates(rtype: RecordType, record: Value, same: Value, different: Value) {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let path = format!(
+            "/v1/zones/example.com./www.example.com./{}",
+            rtype.to_string().to_lowercase()
+        );
+        let with_ttl = |data: &Value, ttl: u32| {
+            let mut data = data.clone();
+            data["ttl"] = json!(ttl);
+            data
+        };
+
+        let (status, _) = api.put(&path, with_ttl(&record, 300)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", rtype);
+        let (status, _) = api.put(&path, with_ttl(&record, 300)).await;
+        assert_eq!(status, StatusCode::OK, "{}", rtype);
+        let (status, _) = api.put(&path, with_ttl(&same, 300)).await;
+        assert_eq!(status, StatusCode::OK, "{}", rtype);
+        assert_eq!(ttls(&api, rtype).await, vec![300], "{}", rtype);
+
+        // The same data with a different TTL updates the TTL of the existing record.
+        let (status, _) = api.put(&path, with_ttl(&record, 600)).await;
+        assert_eq!(status, StatusCode::OK, "{}", rtype);
+        assert_eq!(ttls(&api, rtype).await, vec![600], "{}", rtype);
+
+        let (status, body) = api
+            .put(
+                &format!("{}?on_duplicate=reject", path),
+                with_ttl(&record, 600),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", rtype);
+        assert_eq!(body["error"]["code"], "duplicate_record", "{}", rtype);
+
+        let (status, _) = api.put(&path, with_ttl(&different, 300)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", rtype);
+        assert_eq!(ttls(&api, rtype).await, vec![600, 300], "{}", rtype);
+    }
+
+    #[tokio::test]
+    async fn duplicate_a_records() {
+        assert_duplicates(
+            RecordType::A,
+            json!({"data": "192.0.2.1"}),
+            json!({"data": ["192.0.2.1", "192.0.2.1"]}),
+            json!({"data": "192.0.2.2"}),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_txt_records() {
+        // The split into character-strings is part of the data.
+        assert_duplicates(
+            RecordType::TXT,
+            json!({"strings": ["v=spf1", "-all"]}),
+            json!({"data": ["763d73706631", "2d616c6c"]}),
+            json!({"strings": ["v=spf1-all"]}),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_mx_records() {
+        // Names compare case insensitively, but the preference is part of the data.
+        assert_duplicates(
+            RecordType::MX,
+            json!({"data": {"preference": 10, "exchange": "mail.example.org."}}),
+            json!({"data": {"preference": 10, "exchange": "MAIL.example.org."}}),
+            json!({"data": {"preference": 20, "exchange": "mail.example.org."}}),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn cname_next_to_other_records_is_refused() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let a = json!({"data": "192.0.2.1", "ttl": 300});
+        let cname = json!({"data": "target.example.org.", "ttl": 300});
+
+        // A CNAME can't be added to a name with other records.
+        let (status, _) = api
+            .put("/v1/zones/example.com./www.example.com./a", a.clone())
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        for path in [
+            "/v1/zones/example.com./www.example.com./cname",
+            "/v1/zones/example.com./www.example.com./cname?mode=replace",
+        ] {
+            let (status, body) = api.put(path, cname.clone()).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}", path);
+            assert_eq!(body["error"]["code"], "cname_conflict", "{}", path);
+        }
+        assert!(api
+            .stored("example.com.", "www.example.com.", RecordType::CNAME)
+            .await
+            .is_empty());
+
+        // Nor can other records be added to a name with a CNAME.
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./alias.example.com./cname",
+                cname.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        for path in [
+            "/v1/zones/example.com./alias.example.com./a",
+            "/v1/zones/example.com./alias.example.com./a?mode=replace",
+        ] {
+            let (status, body) = api.put(path, a.clone()).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}", path);
+            assert_eq!(body["error"]["code"], "cname_conflict", "{}", path);
+        }
+        assert!(api
+            .stored("example.com.", "alias.example.com.", RecordType::A)
+            .await
+            .is_empty());
+
+        // A second CNAME is refused, replacing the CNAME is not.
+        let other = json!({"data": "other.example.org.", "ttl": 300});
+        let path = "/v1/zones/example.com./alias.example.com./cname";
+        let (status, body) = api.put(path, other.clone()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "cname_conflict");
+        let (status, _) = api.put(&format!("{}?mode=replace", path), other).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            api.stored("example.com.", "alias.example.com.", RecordType::CNAME)
+                .await,
+            vec![RData::CNAME("other.example.org.".parse().unwrap())]
+        );
+
+        // The apex holds the SOA record, so it can't have a CNAME.
+        let (status, body) = api
+            .put("/v1/zones/example.com./example.com./cname", cname)
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "cname_conflict");
+    }
+}
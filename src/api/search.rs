@@ -0,0 +1,355 @@
+use std::{error::Error, sync::Arc, vec};
+
+use super::{error::ApiError, export::rdata_text, record::parse_record_type, State};
+use crate::storage::{Storage, StorageRecord};
+use axum::{
+    body::StreamBody,
+    extract,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures_util::{stream, StreamExt};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::{IntoParams, ToSchema};
+
+/// Amount of matches returned if no limit is given.
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+/// Maximum amount of matches returned by a single search.
+const MAX_SEARCH_LIMIT: usize = 1_000;
+/// Amount of domains loaded at once while scanning a zone.
+const DOMAIN_BATCH_SIZE: usize = 500;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    /// Only match records of this type.
+    #[serde(rename = "type")]
+    #[param(rename = "type")]
+    rtype: Option<String>,
+    /// Only match records with exactly this data, in presentation format. Names are compared
+    /// case insensitive.
+    data: Option<String>,
+    /// Only match records of which the owner contains this, case insensitive.
+    name: Option<String>,
+    /// Only match TXT records of which the text contains this.
+    txt: Option<String>,
+    /// Maximum amount of matches to return, defaults to 100 and can be at most 1000.
+    limit: Option<usize>,
+}
+
+/// A record found by a search.
+#[derive(Serialize, ToSchema)]
+pub struct SearchMatch {
+    #[schema(value_type = String)]
+    zone: Name,
+    record: StorageRecord,
+}
+
+/// How much was scanned by a search.
+#[derive(Serialize, ToSchema)]
+pub struct SearchSummary {
+    scanned_zones: usize,
+    scanned_domains: usize,
+    scanned_records: usize,
+    matched: usize,
+    /// The search stopped at the limit, so there may be more matches.
+    limit_reached: bool,
+}
+
+/// The body of a search as JSON. It is streamed, so it is only used to describe the body.
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct SearchResult {
+    matches: Vec<SearchMatch>,
+    summary: SearchSummary,
+}
+
+struct Filter {
+    rtype: Option<RecordType>,
+    data: Option<String>,
+    name: Option<String>,
+    txt: Option<String>,
+}
+
+impl Filter {
+    /// Check if records of a domain can match, so domains which can't are never loaded.
+    fn matches_domain(&self, domain: &LowerName) -> bool {
+        // The string form of a LowerName is lowercase already.
+        self.name
+            .as_ref()
+            .is_none_or(|name| domain.to_string().contains(name.as_str()))
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        if self
+            .rtype
+            .is_some_and(|rtype| rtype != record.record_type())
+        {
+            return false;
+        }
+        if let Some(data) = &self.data {
+            if !rdata_text(record).eq_ignore_ascii_case(data) {
+                return false;
+            }
+        }
+        if let Some(txt) = &self.txt {
+            match record.data() {
+                Some(RData::TXT(text)) => {
+                    if !String::from_utf8_lossy(&text.txt_data().concat()).contains(txt.as_str()) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// A search in progress. Zones are scanned one batch of domains at a time, so memory use does not
+/// depend on the size of the zones.
+struct Search {
+    storage: Arc<dyn Storage + Send + Sync>,
+    filter: Filter,
+    ndjson: bool,
+    zones: vec::IntoIter<LowerName>,
+    zone: Option<LowerName>,
+    /// Records of the current batch of domains, not loaded for domains which don't match the
+    /// filter.
+    domains: vec::IntoIter<Option<Vec<StorageRecord>>>,
+    /// Cursor of the next batch of domains of the current zone, if there is one.
+    cursor: Option<String>,
+    limit: usize,
+    first: bool,
+    summary: SearchSummary,
+}
+
+impl Search {
+    /// Scan until a domain with matching records is found. `None` is returned once everything is
+    /// scanned, or the limit is reached.
+    async fn next_matches(
+        &mut self,
+    ) -> Result<Option<Vec<SearchMatch>>, Box<dyn Error + Send + Sync>> {
+        loop {
+            if self.summary.matched >= self.limit {
+                self.summary.limit_reached = true;
+                return Ok(None);
+            }
+
+            let (zone, records) = match (&self.zone, self.domains.next()) {
+                (Some(zone), Some(records)) => (zone.clone(), records),
+                _ => {
+                    let cursor = self.cursor.take();
+                    if cursor.is_none() {
+                        self.zone = self.zones.next();
+                        if self.zone.is_some() {
+                            self.summary.scanned_zones += 1;
+                        }
+                    }
+                    let zone = match &self.zone {
+                        Some(zone) => zone,
+                        None => return Ok(None),
+                    };
+                    let page = self
+                        .storage
+                        .list_domains_page(zone, cursor.as_deref(), DOMAIN_BATCH_SIZE)
+                        .await?;
+                    // Load the records of the whole batch at once.
+                    let matching = page
+                        .items
+                        .iter()
+                        .filter(|domain| self.filter.matches_domain(domain))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let mut records = self
+                        .storage
+                        .list_records_bulk(zone, &matching)
+                        .await?
+                        .into_iter();
+                    self.domains = page
+                        .items
+                        .into_iter()
+                        .map(|domain| {
+                            if self.filter.matches_domain(&domain) {
+                                records.next()
+                            } else {
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .into_iter();
+                    self.cursor = page.next_cursor;
+                    continue;
+                }
+            };
+
+            self.summary.scanned_domains += 1;
+            let records = match records {
+                Some(records) => records,
+                None => continue,
+            };
+            self.summary.scanned_records += records.len();
+            let matches = records
+                .into_iter()
+                .filter(|sr| self.filter.matches(sr.as_record()))
+                .take(self.limit - self.summary.matched)
+                .map(|record| SearchMatch {
+                    zone: Name::from(zone.clone()),
+                    record,
+                })
+                .collect::<Vec<_>>();
+            if matches.is_empty() {
+                continue;
+            }
+            self.summary.matched += matches.len();
+            return Ok(Some(matches));
+        }
+    }
+
+    fn render_matches(
+        &mut self,
+        matches: Vec<SearchMatch>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut chunk = String::new();
+        for m in matches {
+            if self.ndjson {
+                chunk.push_str(&serde_json::to_string(&m)?);
+                chunk.push('\n');
+            } else {
+                if !self.first {
+                    chunk.push(',');
+                }
+                chunk.push_str(&serde_json::to_string(&m)?);
+            }
+            self.first = false;
+        }
+        Ok(chunk)
+    }
+
+    fn render_summary(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let summary = serde_json::to_string(&self.summary)?;
+        Ok(if self.ndjson {
+            format!("{{\"summary\":{}}}\n", summary)
+        } else {
+            format!("],\"summary\":{}}}", summary)
+        })
+    }
+}
+
+/// Search records in all zones. Matches are streamed as they are found, as a JSON object or, if
+/// the client accepts `application/x-ndjson`, as 1 JSON object per line with the summary last.
+/// At least 1 filter is required.
+#[utoipa::path(
+    get,
+    path = "/records/search",
+    tag = "records",
+    params(
+        SearchQuery,
+        ("Accept" = Option<String>, Header, description = "`application/x-ndjson` to stream newline delimited JSON"),
+    ),
+    responses(
+        (status = 200, description = "The matching records, and how much was scanned", content(
+            ("application/json" = SearchResult),
+            ("application/x-ndjson" = String),
+        )),
+        (status = 400, description = "No filter is given, the record type is unknown, or the limit is too high"),
+    )
+)]
+pub async fn search_records(
+    extract::Query(query): extract::Query<SearchQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Response, ApiError> {
+    let rtype = query
+        .rtype
+        .as_deref()
+        .map(|rtype| {
+            parse_record_type(rtype)
+                .ok_or_else(|| ApiError::bad_request("unknown_record_type", "Unknown record type"))
+        })
+        .transpose()?;
+    let filter = Filter {
+        rtype,
+        data: query.data,
+        name: query.name.map(|name| name.to_lowercase()),
+        txt: query.txt,
+    };
+    if filter.rtype.is_none()
+        && filter.data.is_none()
+        && filter.name.is_none()
+        && filter.txt.is_none()
+    {
+        return Err(ApiError::bad_request(
+            "no_filter",
+            "At least 1 of type, data, name or txt is required",
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if limit == 0 || limit > MAX_SEARCH_LIMIT {
+        return Err(ApiError::bad_request(
+            "invalid_limit",
+            format!("Limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        ));
+    }
+
+    let mut zones = state
+        .storage
+        .zones()
+        .await
+        .map_err(|err| ApiError::storage("Failed to list zones", err))?;
+    zones.sort();
+    trace!("Searching records in {} zones", zones.len());
+
+    let ndjson = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/x-ndjson"));
+    let search = Search {
+        storage: state.storage.clone(),
+        filter,
+        ndjson,
+        zones: zones.into_iter(),
+        zone: None,
+        domains: Vec::new().into_iter(),
+        cursor: None,
+        limit,
+        first: true,
+        summary: SearchSummary {
+            scanned_zones: 0,
+            scanned_domains: 0,
+            scanned_records: 0,
+            matched: 0,
+            limit_reached: false,
+        },
+    };
+
+    let results = stream::unfold(Some(search), |search| async move {
+        let mut search = search?;
+        match search.next_matches().await {
+            Ok(Some(matches)) => {
+                let chunk = search.render_matches(matches);
+                Some((chunk, Some(search)))
+            }
+            Ok(None) => Some((search.render_summary(), None)),
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+
+    if ndjson {
+        return Ok((
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            StreamBody::new(results),
+        )
+            .into_response());
+    }
+    let body = stream::once(async { Ok("{\"matches\":[".to_string()) }).chain(results);
+    Ok((
+        [(header::CONTENT_TYPE, "application/json")],
+        StreamBody::new(body),
+    )
+        .into_response())
+}
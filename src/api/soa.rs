@@ -0,0 +1,132 @@
+use super::{error::ApiError, journal, zone, State};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::{rdata::SOA, Name, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// Partial update of a zone SOA. Fields which are absent are left as is.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateSoa {
+    #[schema(value_type = Option<String>)]
+    mname: Option<Name>,
+    #[schema(value_type = Option<String>)]
+    rname: Option<Name>,
+    /// Explicit new serial. If absent, the serial is incremented.
+    serial: Option<u32>,
+    refresh: Option<i32>,
+    retry: Option<i32>,
+    expire: Option<i32>,
+    minimum: Option<u32>,
+    ttl: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ZoneSoa {
+    #[schema(value_type = String)]
+    mname: Name,
+    #[schema(value_type = String)]
+    rname: Name,
+    serial: u32,
+    refresh: i32,
+    retry: i32,
+    expire: i32,
+    minimum: u32,
+    ttl: u32,
+}
+
+/// Change fields of the SOA of a zone, returning the new SOA. The serial is incremented, unless
+/// an explicit serial is given.
+#[utoipa::path(
+    patch,
+    path = "/zones/{zone}/soa",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    request_body = UpdateSoa,
+    responses(
+        (status = 200, description = "The new SOA of the zone", body = ZoneSoa),
+        (status = 400, description = "The zone or one of the SOA names is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn update_soa(
+    extract::Path(zone): extract::Path<Name>,
+    extract::Json(data): extract::Json<UpdateSoa>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<ZoneSoa>, ApiError> {
+    trace!("Updating SOA of zone {}", zone);
+    if !zone.is_fqdn() {
+        return Err((StatusCode::BAD_REQUEST, "Zone must be an fqdn").into());
+    }
+    for name in [&data.mname, &data.rname].into_iter().flatten() {
+        if !name.is_fqdn() {
+            return Err((StatusCode::BAD_REQUEST, "SOA names must be fqdns").into());
+        }
+    }
+
+    let zone_name = LowerName::from(zone.clone());
+    zone::ensure_zone_exists(&state, &zone_name).await?;
+
+    let old_record = state
+        .storage
+        .lookup_records(&zone_name, &zone_name, RecordType::SOA)
+        .await
+        .map_err(|err| {
+            error!("Failed to load SOA of zone {}: {}", zone_name, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .and_then(|soas| soas.into_iter().next())
+        .ok_or((StatusCode::NOT_FOUND, "Zone has no SOA record"))?;
+    let (old_soa, old_ttl) = match old_record.as_record().data() {
+        Some(RData::SOA(soa)) => (soa.clone(), old_record.as_record().ttl()),
+        _ => {
+            error!("SOA record of zone {} has no SOA data", zone_name);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+
+    let soa = SOA::new(
+        data.mname.unwrap_or_else(|| old_soa.mname().clone()),
+        data.rname.unwrap_or_else(|| old_soa.rname().clone()),
+        // Serial arithmetic wraps around (RFC 1982).
+        data.serial
+            .unwrap_or_else(|| old_soa.serial().wrapping_add(1)),
+        data.refresh.unwrap_or_else(|| old_soa.refresh()),
+        data.retry.unwrap_or_else(|| old_soa.retry()),
+        data.expire.unwrap_or_else(|| old_soa.expire()),
+        data.minimum.unwrap_or_else(|| old_soa.minimum()),
+    );
+    let ttl = data.ttl.unwrap_or(old_ttl);
+
+    let mut new_record = old_record.clone();
+    *new_record.as_mut_record() = Record::from_rdata(zone, ttl, RData::SOA(soa.clone()));
+    state
+        .storage
+        .set_records(
+            &zone_name,
+            &zone_name,
+            RecordType::SOA,
+            vec![new_record.clone()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to save SOA of zone {}: {}", zone_name, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    journal::record_change(&state, &zone_name, vec![new_record], vec![old_record]).await;
+    // The DNS server keeps the SOA in memory for negative answers.
+    state.zone_reload.zone(&zone_name);
+
+    Ok(response::Json(ZoneSoa {
+        mname: soa.mname().clone(),
+        rname: soa.rname().clone(),
+        serial: soa.serial(),
+        refresh: soa.refresh(),
+        retry: soa.retry(),
+        expire: soa.expire(),
+        minimum: soa.minimum(),
+        ttl,
+    }))
+}
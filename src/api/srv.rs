@@ -0,0 +1,84 @@
+use super::{
+    error::ApiError,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::{validate_service_owner, OneOrMany, OwnerCheck, ZoneDomain},
+    State,
+};
+use crate::storage::StorageRecord;
+use axum::{extract, http::StatusCode, response, Extension};
+use serde::Deserialize;
+use trust_dns_proto::rr::{rdata::SRV, RData, Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddSrvRecord {
+    #[schema(value_type = Object, example = json!({"priority": 10, "weight": 5, "port": 443, "target": "svc.example.com."}))]
+    data: SRV,
+    ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
+}
+
+/// Add one or more SRV records to a domain, which must be a `_service._proto` name unless
+/// explicitly allowed otherwise. The created records are returned.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/srv",
+    tag = "records",
+    params(ZoneDomain, OwnerCheck, WriteQuery),
+    request_body(content = [AddSrvRecord], description = "A single record, or an array of records"),
+    responses(
+        (status = 201, description = "The records were added", body = [StorageRecord]),
+        (status = 200, description = "The records were already present, or replaced the existing records", body = [StorageRecord]),
+        (status = 400, description = "A record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The records conflict with the records of the domain"),
+    )
+)]
+pub async fn add_record(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    extract::Query(owner_check): extract::Query<OwnerCheck>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<OneOrMany<AddSrvRecord>>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<Vec<StorageRecord>>), ApiError> {
+    let data = data.into_vec();
+    if data.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No SRV records given").into());
+    }
+
+    if !owner_check.allow_nonstandard_owner {
+        validate_service_owner(&domain, RecordType::SRV)?;
+    }
+    // Validate everything first, so we don't end up with a partial insert on invalid input.
+    for entry in &data {
+        // A target of "." means the service is not available at this domain (RFC 2782).
+        if !entry.data.target().is_fqdn() {
+            return Err((StatusCode::BAD_REQUEST, "SRV target must be an fqdn").into());
+        }
+        entry.metadata.validate()?;
+    }
+
+    let zone_name = LowerName::from(zone);
+    let created = data
+        .into_iter()
+        .map(|entry| {
+            let record = Record::from_rdata(domain.clone(), entry.ttl, RData::SRV(entry.data));
+            entry.metadata.into_storage_record(record)
+        })
+        .collect::<Vec<_>>();
+
+    let status = record::store_records(
+        &state,
+        &zone_name,
+        &domain,
+        RecordType::SRV,
+        created.clone(),
+        write,
+    )
+    .await?;
+
+    Ok((status, response::Json(created)))
+}
@@ -0,0 +1,269 @@
+use super::{
+    error::ApiError,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::{OneOrMany, ZoneDomain},
+    State,
+};
+use crate::storage::StorageRecord;
+use axum::{extract, http::StatusCode, response, Extension};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+use trust_dns_proto::rr::{
+    rdata::svcb::{Alpn, EchConfig, IpHint, Mandatory, SvcParamKey, SvcParamValue, SVCB},
+    Name, RData, Record, RecordType,
+};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddSvcbRecord {
+    priority: u16,
+    #[schema(value_type = String, example = "svc.example.com.")]
+    target: Name,
+    /// SvcParams by their presentation name. Values are kept as raw JSON so unknown or malformed
+    /// params can be rejected with a descriptive message.
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    params: HashMap<String, Value>,
+    ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
+}
+
+/// Add one or more HTTPS records to a domain. The created records are returned.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/https",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body(content = [AddSvcbRecord], description = "A single record, or an array of records"),
+    responses(
+        (status = 201, description = "The records were added", body = [StorageRecord]),
+        (status = 200, description = "The records were already present, or replaced the existing records", body = [StorageRecord]),
+        (status = 400, description = "A record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The records conflict with the records of the domain"),
+    )
+)]
+pub async fn add_https_record(
+    zone_domain: ZoneDomain,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<OneOrMany<AddSvcbRecord>>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<Vec<StorageRecord>>), ApiError> {
+    add_records(
+        zone_domain,
+        data.into_vec(),
+        state,
+        RecordType::HTTPS,
+        write,
+    )
+    .await
+}
+
+/// Add one or more SVCB records to a domain. The created records are returned.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/svcb",
+    tag = "records",
+    params(ZoneDomain, WriteQuery),
+    request_body(content = [AddSvcbRecord], description = "A single record, or an array of records"),
+    responses(
+        (status = 201, description = "The records were added", body = [StorageRecord]),
+        (status = 200, description = "The records were already present, or replaced the existing records", body = [StorageRecord]),
+        (status = 400, description = "A record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The records conflict with the records of the domain"),
+    )
+)]
+pub async fn add_svcb_record(
+    zone_domain: ZoneDomain,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<OneOrMany<AddSvcbRecord>>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<Vec<StorageRecord>>), ApiError> {
+    add_records(zone_domain, data.into_vec(), state, RecordType::SVCB, write).await
+}
+
+async fn add_records(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    data: Vec<AddSvcbRecord>,
+    state: State,
+    rtype: RecordType,
+    write: WriteQuery,
+) -> Result<(StatusCode, response::Json<Vec<StorageRecord>>), ApiError> {
+    if data.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("No {} records given", rtype),
+        )
+            .into());
+    }
+
+    // Validate everything first, so we don't end up with a partial insert on invalid input.
+    let mut records = Vec::with_capacity(data.len());
+    for entry in data {
+        entry.metadata.validate()?;
+        let svcb = build_svcb(entry.priority, entry.target, &entry.params)
+            .map_err(|msg| (StatusCode::BAD_REQUEST, msg))?;
+        let rdata = if rtype == RecordType::HTTPS {
+            RData::HTTPS(svcb)
+        } else {
+            RData::SVCB(svcb)
+        };
+        let record = Record::from_rdata(domain.clone(), entry.ttl, rdata);
+        records.push(entry.metadata.into_storage_record(record));
+    }
+
+    let status = record::store_records(
+        &state,
+        &LowerName::from(zone),
+        &domain,
+        rtype,
+        records.clone(),
+        write,
+    )
+    .await?;
+
+    Ok((status, response::Json(records)))
+}
+
+/// Build the data of an SVCB or HTTPS record. Params are emitted in ascending key order, as
+/// required for the wire format.
+fn build_svcb(
+    priority: u16,
+    target: Name,
+    params: &HashMap<String, Value>,
+) -> Result<SVCB, String> {
+    if !target.is_fqdn() {
+        return Err("Target must be an fqdn".to_string());
+    }
+    // AliasMode records only point to another name.
+    if priority == 0 && !params.is_empty() {
+        return Err("Records with priority 0 (alias mode) can't have params".to_string());
+    }
+
+    let mut svc_params = Vec::with_capacity(params.len());
+    for key in params.keys() {
+        if parse_key(key).is_none() {
+            return Err(format!(
+                "Unknown param \"{}\", supported params are mandatory, alpn, no-default-alpn, \
+                 port, ipv4hint, ech and ipv6hint",
+                key
+            ));
+        }
+    }
+    if params.contains_key("no-default-alpn") && !params.contains_key("alpn") {
+        return Err("Param no-default-alpn requires the alpn param".to_string());
+    }
+
+    if let Some(value) = params.get("mandatory") {
+        let mut keys = string_list("mandatory", value)?
+            .into_iter()
+            .map(|key| match parse_key(&key) {
+                Some(SvcParamKey::Mandatory) => {
+                    Err("Param mandatory can't list itself".to_string())
+                }
+                Some(parsed) if params.contains_key(&key) => Ok(parsed),
+                _ => Err(format!(
+                    "Param mandatory lists \"{}\", which is not a param of the record",
+                    key
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.sort_by_key(|key| u16::from(*key));
+        keys.dedup();
+        svc_params.push((
+            SvcParamKey::Mandatory,
+            SvcParamValue::Mandatory(Mandatory(keys)),
+        ));
+    }
+    if let Some(value) = params.get("alpn") {
+        let ids = string_list("alpn", value)?;
+        if ids.is_empty() || ids.iter().any(|id| id.is_empty() || id.len() > 255) {
+            return Err(
+                "Param alpn must be a non empty list of protocol ids of 1 to 255 bytes".to_string(),
+            );
+        }
+        svc_params.push((SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(ids))));
+    }
+    if let Some(value) = params.get("no-default-alpn") {
+        if !matches!(value, Value::Bool(true) | Value::Null) {
+            return Err("Param no-default-alpn has no value, set it to true".to_string());
+        }
+        svc_params.push((SvcParamKey::NoDefaultAlpn, SvcParamValue::NoDefaultAlpn));
+    }
+    if let Some(value) = params.get("port") {
+        let port = value
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or_else(|| "Param port must be a number between 0 and 65535".to_string())?;
+        svc_params.push((SvcParamKey::Port, SvcParamValue::Port(port)));
+    }
+    if let Some(value) = params.get("ipv4hint") {
+        let addrs = address_list::<Ipv4Addr>("ipv4hint", value)?;
+        svc_params.push((
+            SvcParamKey::Ipv4Hint,
+            SvcParamValue::Ipv4Hint(IpHint(addrs)),
+        ));
+    }
+    if let Some(value) = params.get("ech") {
+        let config = value
+            .as_str()
+            .and_then(|config| base64::decode(config).ok())
+            .filter(|config| !config.is_empty())
+            .ok_or_else(|| "Param ech must be a base64 encoded ECHConfigList".to_string())?;
+        svc_params.push((
+            SvcParamKey::EchConfig,
+            SvcParamValue::EchConfig(EchConfig(config)),
+        ));
+    }
+    if let Some(value) = params.get("ipv6hint") {
+        let addrs = address_list::<Ipv6Addr>("ipv6hint", value)?;
+        svc_params.push((
+            SvcParamKey::Ipv6Hint,
+            SvcParamValue::Ipv6Hint(IpHint(addrs)),
+        ));
+    }
+
+    Ok(SVCB::new(priority, target, svc_params))
+}
+
+/// Get the key of a param by its presentation name.
+fn parse_key(key: &str) -> Option<SvcParamKey> {
+    Some(match key {
+        "mandatory" => SvcParamKey::Mandatory,
+        "alpn" => SvcParamKey::Alpn,
+        "no-default-alpn" => SvcParamKey::NoDefaultAlpn,
+        "port" => SvcParamKey::Port,
+        "ipv4hint" => SvcParamKey::Ipv4Hint,
+        "ech" => SvcParamKey::EchConfig,
+        "ipv6hint" => SvcParamKey::Ipv6Hint,
+        _ => return None,
+    })
+}
+
+fn string_list(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    serde_json::from_value(value.clone())
+        .map_err(|_| format!("Param {} must be a list of strings", key))
+}
+
+fn address_list<T>(key: &str, value: &Value) -> Result<Vec<T>, String>
+where
+    T: std::str::FromStr,
+{
+    let addrs = string_list(key, value)?
+        .iter()
+        .map(|addr| addr.parse())
+        .collect::<Result<Vec<T>, _>>()
+        .map_err(|_| format!("Param {} must be a list of addresses", key))?;
+    if addrs.is_empty() {
+        return Err(format!("Param {} must list at least one address", key));
+    }
+    Ok(addrs)
+}
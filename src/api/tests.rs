@@ -0,0 +1,313 @@
+//! Loopback tests of the API: the API over a [MemoryStorage] is served on an ephemeral port, and
+//! requests are made with a plain HTTP client like any client would.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use hyper::{client::HttpConnector, Body, Client};
+use serde_json::Value;
+use trust_dns_proto::rr::{RData, RecordType};
+
+use super::{cors_layer, listen, ApiConfig, ApiHandle};
+use crate::{
+    config::ApiCorsConfig,
+    handle::tests::{lower, soa},
+    health::HealthChecker,
+    memory::MemoryStorage,
+    metrics::Metrics,
+    notify::Notifier,
+    reload::ZoneReload,
+    status::Status,
+    storage::Storage,
+    trace::QueryTracer,
+};
+
+mod bench;
+
+/// How long to wait for the API to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The API listening on an ephemeral loopback port.
+pub(crate) struct TestApi {
+    pub storage: Arc<MemoryStorage>,
+    addr: SocketAddr,
+    client: Client<HttpConnector>,
+    _handle: ApiHandle,
+}
+
+impl TestApi {
+    /// Serve the API without authentication, with the default configuration.
+    pub async fn start() -> TestApi {
+        TestApi::start_with(|_| {}).await
+    }
+
+    /// Serve the API without authentication, after adjusting the default configuration.
+    pub async fn start_with(configure: impl FnOnce(&mut ApiConfig)) -> TestApi {
+        TestApi::start_on(Arc::new(MemoryStorage::new()), configure).await
+    }
+
+    /// Serve the API over existing storage, after adjusting the default configuration.
+    pub async fn start_on(
+        storage: Arc<MemoryStorage>,
+        configure: impl FnOnce(&mut ApiConfig),
+    ) -> TestApi {
+        let mut config = TestApi::config(&storage);
+        configure(&mut config);
+        let handle =
+            listen(storage.clone(), "127.0.0.1:0".parse().unwrap(), config).expect("Can start API");
+        TestApi {
+            storage,
+            addr: handle.local_addr(),
+            client: Client::new(),
+            _handle: handle,
+        }
+    }
+
+    /// The default configuration of the API, without authentication. The audit log and the
+    /// idempotency keys are kept in the storage.
+    pub fn config(storage: &Arc<MemoryStorage>) -> ApiConfig {
+        let metrics = Metrics::new("test".to_string());
+        ApiConfig {
+            bootstrap_tokens: Vec::new(),
+            mx_cname_exchange: Default::default(),
+            txt_limits: Default::default(),
+            auto_serial: Default::default(),
+            reject_private_addresses: false,
+            tracer: Arc::new(QueryTracer::new()),
+            notifier: Notifier::new(Vec::new(), Default::default(), metrics.clone()),
+            health: Arc::new(HealthChecker::new()),
+            zone_reload: Arc::new(ZoneReload::new()),
+            metrics,
+            status: Arc::new(Status::default()),
+            rate_limit: None,
+            cors: None,
+            dnssec: Default::default(),
+            webhooks: Vec::new(),
+            audit: storage.clone(),
+            audit_mandatory: false,
+            idempotency: storage.clone(),
+            idempotency_ttl_secs: 3600,
+            tls: None,
+        }
+    }
+
+    /// Add a zone with an SOA record directly to the storage.
+    pub async fn add_zone(&self, zone: &str) {
+        let zone_name = lower(zone);
+        self.storage
+            .add_zone(&zone_name)
+            .await
+            .expect("Can add zone");
+        self.storage
+            .add_record(&zone_name, &zone_name, soa(zone, 1))
+            .await
+            .expect("Can add SOA record");
+    }
+
+    /// Send a request, and wait for the response.
+    pub async fn send(&self, mut request: Request<Body>) -> (StatusCode, HeaderMap, Value) {
+        *request.uri_mut() = format!("http://{}{}", self.addr, request.uri())
+            .parse()
+            .expect("Valid request URI");
+        let response = tokio::time::timeout(TIMEOUT, self.client.request(request))
+            .await
+            .expect("Response arrives in time")
+            .expect("Can send request");
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Can read response body");
+        let body = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()))
+        };
+        (status, headers, body)
+    }
+
+    /// Send a request with an optional JSON body, and get the status and body of the response.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder().method(method).uri(path);
+        let request = match body {
+            Some(body) => request
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .expect("Valid request");
+        let (status, _, body) = self.send(request).await;
+        (status, body)
+    }
+
+    pub async fn get(&self, path: &str) -> (StatusCode, Value) {
+        self.request(Method::GET, path, None).await
+    }
+
+    pub async fn put(&self, path: &str, body: Value) -> (StatusCode, Value) {
+        self.request(Method::PUT, path, Some(body)).await
+    }
+
+    pub async fn patch(&self, path: &str, body: Value) -> (StatusCode, Value) {
+        self.request(Method::PATCH, path, Some(body)).await
+    }
+
+    pub async fn delete(&self, path: &str) -> (StatusCode, Value) {
+        self.request(Method::DELETE, path, None).await
+    }
+
+    /// Get the data of the records of a type in the storage, in the order they are stored.
+    pub async fn stored(&self, zone: &str, domain: &str, rtype: RecordType) -> Vec<RData> {
+        self.storage
+            .lookup_records(&lower(domain), &lower(zone), rtype)
+            .await
+            .expect("Can look up records")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|record| record.as_record().data().cloned())
+            .collect()
+    }
+}
+
+/// Send a preflight request for a `PUT` from an origin, and get the status and headers of the
+/// response.
+async fn preflight(api: &TestApi, origin: &str) -> (StatusCode, HeaderMap) {
+    let request = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/v1/zones/example.com.")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+        .body(Body::empty())
+        .expect("Valid request");
+    let (status, headers, _) = api.send(request).await;
+    (status, headers)
+}
+
+/// List the zones from an origin, with an optional token.
+async fn list_zones(api: &TestApi, origin: &str, token: Option<&str>) -> (StatusCode, HeaderMap) {
+    let mut request = Request::builder()
+        .uri("/v1/zones")
+        .header(header::ORIGIN, origin);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let (status, headers, _) = api
+        .send(request.body(Body::empty()).expect("Valid request"))
+        .await;
+    (status, headers)
+}
+
+#[tokio::test]
+async fn cors_allowed_origin() {
+    let api = TestApi::start_with(|config| {
+        config.bootstrap_tokens = vec!["secret".to_string()];
+        config.cors = Some(ApiCorsConfig {
+            allowed_origins: vec!["https://ui.example.com".to_string()],
+            allowed_methods: vec!["get".to_string(), "put".to_string()],
+            max_age_secs: Some(600),
+        });
+    })
+    .await;
+
+    // Preflight requests don't need a token.
+    let (status, headers) = preflight(&api, "https://ui.example.com").await;
+    assert!(status.is_success(), "{}", status);
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://ui.example.com"
+    );
+    let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+        .to_str()
+        .expect("Methods are ASCII");
+    assert!(
+        methods.contains("GET") && methods.contains("PUT"),
+        "{}",
+        methods
+    );
+    assert!(!methods.contains("DELETE"), "{}", methods);
+    assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+        .to_str()
+        .expect("Headers are ASCII")
+        .contains("authorization"));
+    assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+    // The actual requests still do.
+    let (status, headers) = list_zones(&api, "https://ui.example.com", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://ui.example.com"
+    );
+    let (status, headers) = list_zones(&api, "https://ui.example.com", Some("secret")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://ui.example.com"
+    );
+    assert!(headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+        .to_str()
+        .expect("Headers are ASCII")
+        .contains("retry-after"));
+}
+
+#[tokio::test]
+async fn cors_disallowed_origin() {
+    let api = TestApi::start_with(|config| {
+        config.cors = Some(ApiCorsConfig {
+            allowed_origins: vec!["https://ui.example.com".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            max_age_secs: None,
+        });
+    })
+    .await;
+    let (_, headers) = preflight(&api, "https://evil.example.org").await;
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    let (status, headers) = list_zones(&api, "https://evil.example.org", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+}
+
+#[tokio::test]
+async fn cors_any_origin() {
+    let api = TestApi::start_with(|config| {
+        config.cors = Some(ApiCorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            max_age_secs: None,
+        });
+    })
+    .await;
+    let (_, headers) = list_zones(&api, "https://anywhere.example.net", None).await;
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+}
+
+#[tokio::test]
+async fn cors_is_disabled_by_default() {
+    let api = TestApi::start().await;
+    let (_, headers) = list_zones(&api, "https://ui.example.com", None).await;
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    let (_, headers) = preflight(&api, "https://ui.example.com").await;
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+}
+
+#[test]
+fn invalid_cors_config() {
+    for (origins, methods) in [
+        (vec!["https://ui\n.example.com"], vec!["GET"]),
+        (vec!["*"], vec!["G ET"]),
+    ] {
+        let config = ApiCorsConfig {
+            allowed_origins: origins.into_iter().map(String::from).collect(),
+            allowed_methods: methods.into_iter().map(String::from).collect(),
+            max_age_secs: None,
+        };
+        assert!(cors_layer(&config).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Benchmarks of the API. These are ignored by default, run them in release mode with
+//! `cargo test --release bench_ -- --ignored --nocapture`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{body::HttpBody, Body, Client, Request};
+use trust_dns_proto::rr::{rdata::TXT, RData};
+
+use super::TestApi;
+use crate::{
+    api::listen,
+    handle::tests::{a, add_zone, lower, record, CountingStorage},
+    storage::Storage,
+};
+
+/// Amount of domains in the exported zone.
+const DOMAINS: usize = 5_000;
+/// Time of a roundtrip to the storage.
+const ROUNDTRIP: Duration = Duration::from_millis(1);
+
+/// Export a zone through the API, and get the size of the export.
+async fn export(storage: &CountingStorage, zone: &str) -> usize {
+    let handle = listen(
+        Arc::new(storage.clone()),
+        "127.0.0.1:0".parse().unwrap(),
+        TestApi::config(&storage.memory()),
+    )
+    .expect("Can start API");
+    let request = Request::get(format!(
+        "http://{}/v1/zones/{}/export",
+        handle.local_addr(),
+        zone
+    ))
+    .body(Body::empty())
+    .expect("Valid request");
+    let response = Client::new()
+        .request(request)
+        .await
+        .expect("Can send request");
+    assert!(response.status().is_success(), "{}", response.status());
+    let mut body = response.into_body();
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        size += chunk.expect("Can read response body").len();
+    }
+    size
+}
+
+#[tokio::test]
+#[ignore = "benchmark"]
+async fn bench_export_zone() {
+    let storage = CountingStorage::new();
+    let records = (0..DOMAINS).flat_map(|idx| {
+        let domain = format!("host{}.example.com.", idx);
+        [
+            a(&domain, [192, 0, 2, (idx % 256) as u8]),
+            record(
+                &domain,
+                300,
+                RData::TXT(TXT::new(vec![format!("host number {}", idx)])),
+            ),
+        ]
+    });
+    add_zone(&storage, "example.com.", records.collect()).await;
+    let zone = lower("example.com.");
+    let domains = storage.list_domains(&zone).await.expect("Can list domains");
+
+    // Without storage latency, this is the time spent rendering the export.
+    let start = Instant::now();
+    let size = export(&storage, "example.com.").await;
+    println!(
+        "export of {} domains ({} bytes) without latency: {:?}",
+        DOMAINS,
+        size,
+        start.elapsed()
+    );
+
+    // Every lookup takes a roundtrip, like it does with Redis. Before the batched lookups, the
+    // export listed the records of one domain at a time.
+    storage.set_lookup_delay(ROUNDTRIP);
+    storage.take_lookups();
+    let start = Instant::now();
+    for domain in &domains {
+        storage
+            .list_records(&zone, domain)
+            .await
+            .expect("Can list records");
+    }
+    println!(
+        "records of {} domains one at a time with {:?} roundtrips: {:?} in {} lookups",
+        DOMAINS,
+        ROUNDTRIP,
+        start.elapsed(),
+        storage.take_lookups()
+    );
+
+    let start = Instant::now();
+    export(&storage, "example.com.").await;
+    let elapsed = start.elapsed();
+    let lookups = storage.take_lookups();
+    println!(
+        "export of {} domains with {:?} roundtrips: {:?} in {} lookups",
+        DOMAINS, ROUNDTRIP, elapsed, lookups
+    );
+    assert!(lookups < DOMAINS / 100, "{} lookups", lookups);
+}
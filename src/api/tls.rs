@@ -0,0 +1,147 @@
+use std::{error::Error, pin::Pin, sync::Arc};
+
+use crate::config::ApiTlsConfig;
+use arc_swap::ArcSwap;
+use axum::{extract::ConnectInfo, Router};
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+};
+use log::{debug, error, info};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+};
+use tokio_rustls::{
+    rustls::{server::AllowAnyAuthenticatedClient, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+use trust_dns_proto::rustls::tls_server::{read_cert, read_key_from_pem};
+
+/// Serve the API over TLS. The certificate and key are reloaded on SIGHUP, connections which are
+/// already established keep the configuration they started with. Once shutdown is signaled no new
+/// connections are accepted, and this returns after the existing connections are closed.
+pub async fn serve(
+    app: Router,
+    listener: std::net::TcpListener,
+    config: ApiTlsConfig,
+    server_config: ServerConfig,
+    mut shutdown: watch::Receiver<()>,
+) {
+    let current = Arc::new(ArcSwap::from_pointee(server_config));
+    let reload = tokio::spawn(reload_loop(config, current.clone()));
+
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not register api listener: {}", e);
+            return;
+        }
+    };
+    // Every connection task holds a sender, so the channel closes once all of them are done.
+    let (connections, mut connections_done) = mpsc::channel::<()>(1);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to accept api connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        let acceptor = TlsAcceptor::from(current.load_full());
+        let app = app.clone();
+        let mut shutdown = shutdown.clone();
+        let connection_guard = connections.clone();
+        // The handshake happens on the connection task, so slow clients don't hold up others.
+        tokio::spawn(async move {
+            let _connection_guard = connection_guard;
+            let stream = tokio::select! {
+                res = acceptor.accept(stream) => match res {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("TLS handshake with api client {} failed: {}", peer, e);
+                        return;
+                    }
+                },
+                _ = shutdown.changed() => return,
+            };
+            // Expose the client address to handlers, like the plain HTTP server does.
+            let service = service_fn(move |mut req| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                app.clone().call(req)
+            });
+            let mut connection = Http::new().serve_connection(stream, service);
+            let finished = tokio::select! {
+                res = &mut connection => Some(res),
+                _ = shutdown.changed() => None,
+            };
+            let res = match finished {
+                Some(res) => res,
+                None => {
+                    Pin::new(&mut connection).graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = res {
+                debug!("Api connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    reload.abort();
+    drop(connections);
+    let _ = connections_done.recv().await;
+}
+
+/// Reload the TLS configuration every time the process receives SIGHUP. A configuration which
+/// fails to load is logged, and the previous one stays in use.
+async fn reload_loop(config: ApiTlsConfig, current: Arc<ArcSwap<ServerConfig>>) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!(
+                "Could not install SIGHUP handler, api certificate won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        match load_server_config(&config) {
+            Ok(server_config) => {
+                current.store(Arc::new(server_config));
+                info!("Reloaded api TLS certificate");
+            }
+            Err(e) => error!("Could not reload api TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Load the certificate and key, and the client CA if client certificates are required.
+pub fn load_server_config(
+    config: &ApiTlsConfig,
+) -> Result<ServerConfig, Box<dyn Error + Send + Sync>> {
+    let cert = read_cert(&config.tls_cert)?;
+    let key = read_key_from_pem(&config.tls_key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let mut server_config = match config.client_ca {
+        Some(ref client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_cert(client_ca)? {
+                roots.add(&ca)?;
+            }
+            builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                .with_single_cert(cert, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(cert, key)?,
+    };
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
@@ -0,0 +1,205 @@
+use super::{
+    error::ApiError,
+    metadata::RecordMetadata,
+    record::{self, WriteQuery},
+    validate::{validate_service_owner, OneOrMany, OwnerCheck, ZoneDomain},
+    State,
+};
+use crate::storage::StorageRecord;
+use axum::{extract, http::StatusCode, response, Extension};
+use ring::digest;
+use serde::Deserialize;
+use trust_dns_proto::rr::{
+    rdata::tlsa::{CertUsage, Matching, Selector, TLSA},
+    RData, Record, RecordType,
+};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// DER tag of a SEQUENCE.
+const DER_SEQUENCE: u8 = 0x30;
+/// DER tag of the explicit version field of a certificate.
+const DER_VERSION: u8 = 0xa0;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddTlsaRecord {
+    cert_usage: u8,
+    selector: u8,
+    matching: u8,
+    /// Hex encoded certificate association data.
+    data: Option<String>,
+    /// PEM encoded certificate to compute the certificate association data from.
+    certificate: Option<String>,
+    ttl: u32,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
+}
+
+/// Add one or more TLSA records to a domain, which must be a `_port._proto` name unless explicitly
+/// allowed otherwise. The created records are returned.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/tlsa",
+    tag = "records",
+    params(ZoneDomain, OwnerCheck, WriteQuery),
+    request_body(content = [AddTlsaRecord], description = "A single record, or an array of records"),
+    responses(
+        (status = 201, description = "The records were added", body = [StorageRecord]),
+        (status = 200, description = "The records were already present, or replaced the existing records", body = [StorageRecord]),
+        (status = 400, description = "A record is not valid"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The records conflict with the records of the domain"),
+    )
+)]
+pub async fn add_record(
+    ZoneDomain { zone, domain }: ZoneDomain,
+    extract::Query(owner_check): extract::Query<OwnerCheck>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Json(data): extract::Json<OneOrMany<AddTlsaRecord>>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<Vec<StorageRecord>>), ApiError> {
+    let data = data.into_vec();
+    if data.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No TLSA records given").into());
+    }
+
+    if !owner_check.allow_nonstandard_owner {
+        validate_service_owner(&domain, RecordType::TLSA)?;
+    }
+    // Validate everything first, so we don't end up with a partial insert on invalid input.
+    let mut created = Vec::with_capacity(data.len());
+    for entry in data {
+        entry.metadata.validate()?;
+        let tlsa = build_tlsa(&entry).map_err(|msg| (StatusCode::BAD_REQUEST, msg))?;
+        let record = Record::from_rdata(domain.clone(), entry.ttl, RData::TLSA(tlsa));
+        created.push(entry.metadata.into_storage_record(record));
+    }
+
+    let status = record::store_records(
+        &state,
+        &LowerName::from(zone),
+        &domain,
+        RecordType::TLSA,
+        created.clone(),
+        write,
+    )
+    .await?;
+
+    Ok((status, response::Json(created)))
+}
+
+fn build_tlsa(entry: &AddTlsaRecord) -> Result<TLSA, &'static str> {
+    let cert_usage =
+        match CertUsage::from(entry.cert_usage) {
+            CertUsage::Unassigned(_) | CertUsage::Private => return Err(
+                "Certificate usage must be 0 (PKIX-TA), 1 (PKIX-EE), 2 (DANE-TA) or 3 (DANE-EE)",
+            ),
+            cert_usage => cert_usage,
+        };
+    let selector = match Selector::from(entry.selector) {
+        Selector::Unassigned(_) | Selector::Private => {
+            return Err("Selector must be 0 (full certificate) or 1 (SubjectPublicKeyInfo)")
+        }
+        selector => selector,
+    };
+    let matching = match Matching::from(entry.matching) {
+        Matching::Unassigned(_) | Matching::Private => {
+            return Err("Matching type must be 0 (exact), 1 (SHA-256) or 2 (SHA-512)")
+        }
+        matching => matching,
+    };
+
+    let cert_data = match (&entry.data, &entry.certificate) {
+        (Some(hex_data), None) => {
+            let mut cert_data = vec![0; hex_data.len() / 2];
+            faster_hex::hex_decode(hex_data.as_bytes(), &mut cert_data)
+                .map_err(|_| "Certificate association data must be valid hex")?;
+            cert_data
+        }
+        (None, Some(pem)) => {
+            let der = decode_pem_certificate(pem)?;
+            let selected = match selector {
+                Selector::Spki => subject_public_key_info(&der)
+                    .ok_or("Failed to find the SubjectPublicKeyInfo of the certificate")?,
+                _ => &der[..],
+            };
+            match matching {
+                Matching::Sha256 => digest::digest(&digest::SHA256, selected).as_ref().to_vec(),
+                Matching::Sha512 => digest::digest(&digest::SHA512, selected).as_ref().to_vec(),
+                _ => selected.to_vec(),
+            }
+        }
+        _ => return Err("Exactly one of data (hex) or certificate (PEM) must be given"),
+    };
+
+    let expected_length = match matching {
+        Matching::Sha256 => Some(digest::SHA256_OUTPUT_LEN),
+        Matching::Sha512 => Some(digest::SHA512_OUTPUT_LEN),
+        _ => None,
+    };
+    if cert_data.is_empty() || expected_length.is_some_and(|len| cert_data.len() != len) {
+        return Err("Certificate association data does not match the length of the matching type");
+    }
+
+    Ok(TLSA::new(cert_usage, selector, matching, cert_data))
+}
+
+/// Decode the first certificate in a PEM document to DER.
+fn decode_pem_certificate(pem: &str) -> Result<Vec<u8>, &'static str> {
+    let body = pem
+        .split("-----BEGIN CERTIFICATE-----")
+        .nth(1)
+        .and_then(|rest| rest.split("-----END CERTIFICATE-----").next())
+        .ok_or("Certificate must be a PEM encoded certificate")?;
+    let encoded = body
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+    base64::decode(encoded).map_err(|_| "Certificate contains invalid base64")
+}
+
+/// Find the DER encoded SubjectPublicKeyInfo in a DER encoded X.509 certificate. It is the 7th
+/// element of the TBSCertificate, or the 6th if the optional version is absent (RFC 5280).
+fn subject_public_key_info(der: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(der)?;
+    let (tag, tbs_certificate, _) = der_element(certificate)?;
+    if tag != DER_SEQUENCE {
+        return None;
+    }
+    let mut rest = tbs_certificate;
+    let (tag, _, _) = der_element(rest)?;
+    // Skip the version, serial number, signature algorithm, issuer, validity and subject.
+    let skip = if tag == DER_VERSION { 6 } else { 5 };
+    for _ in 0..skip {
+        rest = der_element(rest)?.2;
+    }
+    let (tag, _, after) = der_element(rest)?;
+    if tag != DER_SEQUENCE {
+        return None;
+    }
+    Some(&rest[..rest.len() - after.len()])
+}
+
+/// Split the first DER element off the input, returning its tag, its contents, and the remaining
+/// input.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first & 0x80 == 0 {
+        usize::from(first)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > std::mem::size_of::<usize>() || input.len() < octets {
+            return None;
+        }
+        let len = input[..octets]
+            .iter()
+            .fold(0, |len, &octet| (len << 8) | usize::from(octet));
+        input = &input[octets..];
+        len
+    };
+    if input.len() < len {
+        return None;
+    }
+    Some((tag, &input[..len], &input[len..]))
+}
@@ -0,0 +1,330 @@
+use super::{
+    auth::{hash_secret, unix_now},
+    error::ApiError,
+    State,
+};
+use crate::storage::{ApiToken, TokenScope};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::{error, trace};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Length of a generated token secret, in bytes.
+const TOKEN_SECRET_LENGTH: usize = 32;
+/// Length of a generated token id, in bytes.
+const TOKEN_ID_LENGTH: usize = 8;
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateToken {
+    scopes: Vec<TokenScope>,
+    // Optional lifetime of the token, in seconds.
+    expires_in: Option<u64>,
+}
+
+/// A newly created token. This is the only time the plaintext token is ever returned.
+#[derive(Serialize, ToSchema)]
+pub struct CreatedToken {
+    id: String,
+    token: String,
+    scopes: Vec<TokenScope>,
+    expires_at: Option<u64>,
+}
+
+/// Public info of a token, which does not include the secret hash.
+#[derive(Serialize, ToSchema)]
+pub struct TokenInfo {
+    id: String,
+    scopes: Vec<TokenScope>,
+    created_at: u64,
+    expires_at: Option<u64>,
+    disabled: bool,
+    last_used: Option<u64>,
+}
+
+impl From<ApiToken> for TokenInfo {
+    fn from(token: ApiToken) -> Self {
+        TokenInfo {
+            id: token.id,
+            scopes: token.scopes,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            disabled: token.disabled,
+            last_used: token.last_used,
+        }
+    }
+}
+
+/// List all tokens known in storage.
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    tag = "tokens",
+    responses(
+        (status = 200, description = "All tokens", body = [TokenInfo]),
+    )
+)]
+pub async fn list_tokens(
+    Extension(state): Extension<State>,
+) -> Result<response::Json<Vec<TokenInfo>>, ApiError> {
+    trace!("Listing API tokens");
+    Ok(response::Json(
+        state
+            .storage
+            .tokens()
+            .await
+            .map_err(|err| {
+                error!("Failed to load API tokens: {}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .into_iter()
+            .map(TokenInfo::from)
+            .collect(),
+    ))
+}
+
+/// Create a new token. The plaintext token is returned once, only its hash is persisted.
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body = CreateToken,
+    responses(
+        (status = 201, description = "The token was created", body = CreatedToken),
+        (status = 400, description = "The scopes or lifetime are not valid"),
+        (status = 403, description = "API authentication is disabled"),
+    )
+)]
+pub async fn create_token(
+    extract::Json(data): extract::Json<CreateToken>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<CreatedToken>), ApiError> {
+    // Without authentication anybody could create tokens, and the first one would lock out
+    // everybody else.
+    if !state.tokens.enabled() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "authentication_disabled",
+            "Tokens can only be created when API authentication is enabled",
+        ));
+    }
+    if data.scopes.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A token needs at least 1 scope").into());
+    }
+    let created_at = unix_now();
+    let expires_at = match data.expires_in {
+        Some(expires_in) => Some(created_at.checked_add(expires_in).ok_or_else(|| {
+            ApiError::bad_request("invalid_expiry", "Token lifetime is too long")
+        })?),
+        None => None,
+    };
+
+    let rng = SystemRandom::new();
+    let mut id = [0; TOKEN_ID_LENGTH];
+    let mut secret = [0; TOKEN_SECRET_LENGTH];
+    if rng.fill(&mut id).is_err() || rng.fill(&mut secret).is_err() {
+        error!("Failed to generate random API token");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+    let id = faster_hex::hex_string(&id);
+    let secret = faster_hex::hex_string(&secret);
+
+    let token = ApiToken {
+        id: id.clone(),
+        secret_hash: hash_secret(&secret),
+        scopes: data.scopes.clone(),
+        created_at,
+        expires_at,
+        disabled: false,
+        last_used: None,
+    };
+
+    state.storage.set_token(&token).await.map_err(|err| {
+        error!("Failed to store API token: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    refresh_tokens(&state).await;
+
+    Ok((
+        StatusCode::CREATED,
+        response::Json(CreatedToken {
+            id,
+            token: secret,
+            scopes: data.scopes,
+            expires_at: token.expires_at,
+        }),
+    ))
+}
+
+/// Revoke an existing token. The token is disabled rather than removed.
+#[utoipa::path(
+    delete,
+    path = "/tokens/{id}",
+    tag = "tokens",
+    params(("id" = String, Path, description = "Id of the token")),
+    responses(
+        (status = 204, description = "The token was revoked"),
+        (status = 404, description = "The token does not exist"),
+    )
+)]
+pub async fn revoke_token(
+    extract::Path(id): extract::Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<StatusCode, ApiError> {
+    let mut token = state
+        .storage
+        .tokens()
+        .await
+        .map_err(|err| {
+            error!("Failed to load API tokens: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .find(|token| token.id == id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    token.disabled = true;
+    state.storage.set_token(&token).await.map_err(|err| {
+        error!("Failed to revoke API token: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    refresh_tokens(&state).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Refresh the token cache so token changes take effect immediately on this instance. Other
+/// instances pick them up on their next refresh.
+async fn refresh_tokens(state: &State) {
+    if let Err(e) = state.tokens.refresh(&*state.storage).await {
+        error!("Failed to refresh API token cache: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{header, Method, Request, StatusCode};
+    use hyper::Body;
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::{auth::hash_secret, tests::TestApi},
+        memory::MemoryStorage,
+        storage::{ApiToken, Storage, TokenScope},
+    };
+
+    /// Send a request with an optional bearer token and JSON body.
+    async fn request(
+        api: &TestApi,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .expect("Valid request");
+        let (status, _, body) = api.send(request).await;
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn lifetime_overflow_is_rejected() {
+        let api = TestApi::start_with(|config| {
+            config.bootstrap_tokens = vec!["secret".to_string()];
+        })
+        .await;
+        let (status, body) = request(
+            &api,
+            Method::POST,
+            "/v1/tokens",
+            Some("secret"),
+            Some(json!({"scopes": ["read"], "expires_in": u64::MAX})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_expiry");
+        assert!(api
+            .storage
+            .tokens()
+            .await
+            .expect("Can load tokens")
+            .is_empty());
+
+        let (status, body) = request(
+            &api,
+            Method::POST,
+            "/v1/tokens",
+            Some("secret"),
+            Some(json!({"scopes": ["read"], "expires_in": 3600})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(body["expires_at"].as_u64().is_some(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn no_tokens_without_authentication() {
+        let api = TestApi::start().await;
+        let (status, body) = request(
+            &api,
+            Method::POST,
+            "/v1/tokens",
+            None,
+            Some(json!({"scopes": ["admin"]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "authentication_disabled");
+        assert!(api
+            .storage
+            .tokens()
+            .await
+            .expect("Can load tokens")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn stored_tokens_enable_authentication() {
+        // A token created while a bootstrap token was configured, which was removed since.
+        let storage = Arc::new(MemoryStorage::new());
+        storage
+            .set_token(&ApiToken {
+                id: "reader".to_string(),
+                secret_hash: hash_secret("reader-secret"),
+                scopes: vec![TokenScope::Read],
+                created_at: 0,
+                expires_at: None,
+                disabled: false,
+                last_used: None,
+            })
+            .await
+            .expect("Can store token");
+        let api = TestApi::start_on(storage, |_| {}).await;
+
+        let (status, _) = request(&api, Method::GET, "/v1/zones", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) =
+            request(&api, Method::GET, "/v1/zones", Some("reader-secret"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request(
+            &api,
+            Method::PUT,
+            "/v1/zones/example.com.",
+            Some("reader-secret"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,170 @@
+use super::{error::ApiError, State};
+use crate::{prefix::IpPrefix, trace::TraceFilter};
+use axum::{extract, http::StatusCode, response, Extension};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use trust_dns_proto::rr::Name;
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// Maximum lifetime of a trace filter, 1 hour.
+const MAX_TRACE_FILTER_SECS: u64 = 3_600;
+/// Lifetime of a trace filter if none is given, 10 minutes.
+const DEFAULT_TRACE_FILTER_SECS: u64 = 600;
+/// Maximum amount of concurrently active trace filters.
+const MAX_TRACE_FILTERS: usize = 16;
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddTraceFilter {
+    #[schema(value_type = Option<String>)]
+    qname_suffix: Option<Name>,
+    // IP network in CIDR notation, a plain address matches only that address.
+    client_prefix: Option<String>,
+    // lifetime of the filter in seconds.
+    duration_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TraceFilterInfo {
+    id: u64,
+    qname_suffix: Option<String>,
+    client_prefix: Option<String>,
+    expires_at: u64,
+}
+
+impl From<TraceFilter> for TraceFilterInfo {
+    fn from(filter: TraceFilter) -> Self {
+        TraceFilterInfo {
+            id: filter.id,
+            qname_suffix: filter.qname_suffix.map(|name| name.to_string()),
+            client_prefix: filter.client_prefix.map(|prefix| prefix.to_string()),
+            expires_at: filter.expires_at,
+        }
+    }
+}
+
+/// List all active trace filters.
+#[utoipa::path(
+    get,
+    path = "/admin/trace-filters",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The active trace filters", body = [TraceFilterInfo]),
+    )
+)]
+pub async fn list_trace_filters(
+    Extension(state): Extension<State>,
+) -> response::Json<Vec<TraceFilterInfo>> {
+    response::Json(
+        state
+            .tracer
+            .filters()
+            .into_iter()
+            .map(TraceFilterInfo::from)
+            .collect(),
+    )
+}
+
+/// Add a new trace filter. Queries matching the filter are traced until it expires.
+#[utoipa::path(
+    post,
+    path = "/admin/trace-filters",
+    tag = "admin",
+    request_body = AddTraceFilter,
+    responses(
+        (status = 201, description = "The filter was added", body = TraceFilterInfo),
+        (status = 400, description = "The filter is not valid"),
+        (status = 409, description = "Too many filters are active"),
+    )
+)]
+pub async fn add_trace_filter(
+    extract::Json(data): extract::Json<AddTraceFilter>,
+    Extension(state): Extension<State>,
+) -> Result<(StatusCode, response::Json<TraceFilterInfo>), ApiError> {
+    if data.qname_suffix.is_none() && data.client_prefix.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "A trace filter needs a qname suffix, a client prefix, or both",
+        )
+            .into());
+    }
+
+    if let Some(ref qname_suffix) = data.qname_suffix {
+        if !qname_suffix.is_fqdn() {
+            return Err((StatusCode::BAD_REQUEST, "Qname suffix must be an fqdn").into());
+        }
+    }
+
+    let client_prefix = match data.client_prefix {
+        Some(ref prefix) => Some(
+            prefix
+                .parse::<IpPrefix>()
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        ),
+        None => None,
+    };
+
+    let duration_secs = data.duration_secs.unwrap_or(DEFAULT_TRACE_FILTER_SECS);
+    if duration_secs == 0 || duration_secs > MAX_TRACE_FILTER_SECS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Trace filter duration must be between 1 and 3600 seconds",
+        )
+            .into());
+    }
+
+    if state.tracer.filters().len() >= MAX_TRACE_FILTERS {
+        return Err((StatusCode::CONFLICT, "Too many active trace filters").into());
+    }
+
+    let filter = state.tracer.add_filter(
+        data.qname_suffix.map(LowerName::from),
+        client_prefix,
+        Duration::from_secs(duration_secs),
+    );
+    info!(
+        "Added trace filter {} (qname suffix {:?}, client prefix {:?}) for {} seconds",
+        filter.id, filter.qname_suffix, filter.client_prefix, duration_secs
+    );
+
+    Ok((StatusCode::CREATED, response::Json(filter.into())))
+}
+
+/// Remove a single trace filter.
+#[utoipa::path(
+    delete,
+    path = "/admin/trace-filters/{id}",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Id of the filter")),
+    responses(
+        (status = 204, description = "The filter was removed"),
+        (status = 404, description = "The filter does not exist"),
+    )
+)]
+pub async fn remove_trace_filter(
+    extract::Path(id): extract::Path<u64>,
+    Extension(state): Extension<State>,
+) -> StatusCode {
+    if state.tracer.remove_filter(id) {
+        info!("Removed trace filter {}", id);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Remove all trace filters.
+#[utoipa::path(
+    delete,
+    path = "/admin/trace-filters",
+    tag = "admin",
+    responses(
+        (status = 204, description = "All filters were removed"),
+    )
+)]
+pub async fn clear_trace_filters(Extension(state): Extension<State>) -> StatusCode {
+    state.tracer.clear();
+    info!("Cleared all trace filters");
+    StatusCode::NO_CONTENT
+}
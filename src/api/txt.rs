@@ -1,71 +1,225 @@
-use super::State;
-use crate::storage::StorageRecord;
-use axum::{extract, http::StatusCode, response, Extension};
-use log::error;
+use super::{
+    error::ApiError,
+    metadata::{RecordMetadata, RecordWeight},
+    record::{self, WriteMode, WriteQuery},
+    validate::ZoneDomain,
+    State,
+};
+use axum::{extract, http::StatusCode, Extension};
 use serde::Deserialize;
-use trust_dns_proto::rr::{rdata::TXT, Name, RData, Record};
+use trust_dns_proto::rr::{rdata::TXT, Name, RData, Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
+use utoipa::{IntoParams, ToSchema};
 
 const MAX_TXT_SECTION_LENGTH: usize = 255;
+/// Size of a record in an answer besides its data: a compressed name, the type, class, TTL and
+/// data length.
+const RECORD_OVERHEAD: usize = 12;
 
-#[derive(Deserialize)]
-pub struct AddARecord {
-    data: Vec<String>,
+#[derive(Deserialize, ToSchema)]
+pub struct AddTxtRecord {
+    /// Hex encoded character-strings, for binary data.
+    data: Option<Vec<String>>,
+    /// Plain text, split into character-strings as needed.
+    strings: Option<Vec<String>>,
     ttl: u32,
+    #[serde(flatten)]
+    weight: RecordWeight,
+    #[serde(flatten)]
+    metadata: RecordMetadata,
 }
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TxtQuery {
+    /// Add an SPF record to a domain which already has one. Resolvers treat a domain with more
+    /// than one SPF record as a permanent error (RFC 7208 section 4.5), so this is refused by
+    /// default.
+    #[serde(default)]
+    allow_multiple_spf: bool,
+}
+
+/// Add a TXT record to a domain. The encoded size of the record and of the resulting RRset is
+/// limited, so answers with the RRset fit in a response. Use `mode=replace` to swap out a record,
+/// like an SPF policy, instead of adding another one next to it.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/txt",
+    tag = "records",
+    params(ZoneDomain, WriteQuery, TxtQuery),
+    request_body = AddTxtRecord,
+    responses(
+        (status = 201, description = "The record was added"),
+        (status = 200, description = "The record was already present, or replaced the existing records"),
+        (status = 400, description = "The record is not valid, or it or the RRset is too large"),
+        (status = 404, description = "The zone does not exist"),
+        (status = 409, description = "The record conflicts with the records of the domain, or the domain already has an SPF record"),
+    )
+)]
 pub async fn add_record(
     extract::Path((zone, domain)): extract::Path<(Name, Name)>,
-    extract::Json(data): extract::Json<AddARecord>,
+    extract::Query(write): extract::Query<WriteQuery>,
+    extract::Query(query): extract::Query<TxtQuery>,
+    extract::Json(data): extract::Json<AddTxtRecord>,
     Extension(state): Extension<State>,
-) -> response::Result<StatusCode> {
+) -> Result<StatusCode, ApiError> {
     if !zone.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn zones",
-        )
-            .into());
+        return Err(ApiError::not_fqdn("Zone"));
     }
 
     if !domain.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only add records for fqdn domains",
-        )
-            .into());
+        return Err(ApiError::not_fqdn("Domain"));
+    }
+
+    data.metadata.validate()?;
+    data.weight.validate()?;
+
+    let sections = match (data.data, data.strings) {
+        (Some(hex_sections), None) => decode_hex_sections(hex_sections)?,
+        (None, Some(strings)) => strings.iter().flat_map(|s| split_text(s)).collect(),
+        _ => {
+            return Err(ApiError::bad_request(
+                "invalid_txt_data",
+                "Exactly one of data (hex) or strings (text) must be given",
+            ))
+        }
+    };
+    let txt = TXT::from_bytes(sections.iter().map(|s| s.as_slice()).collect());
+
+    let limits = state.txt_limits;
+    let size = encoded_size(&txt);
+    if size > limits.max_record_size {
+        return Err(ApiError::bad_request(
+            "txt_record_too_large",
+            format!(
+                "TXT record is {} bytes encoded, over the limit of {} bytes",
+                size, limits.max_record_size
+            ),
+        ));
     }
 
-    let mut decoded_sections = Vec::with_capacity(data.data.len());
-    for section in data.data {
-        // Input must be hex encoded
+    let zone = LowerName::from(zone);
+    // In replace mode the record is the whole RRset, which the record limit already covers.
+    if write.mode == WriteMode::Append {
+        let domain_name = LowerName::from(domain.clone());
+        let existing = state
+            .storage
+            .lookup_records(&domain_name, &zone, RecordType::TXT)
+            .await
+            .map_err(|err| {
+                ApiError::storage(
+                    &format!("Failed to load TXT records of {}", domain_name),
+                    err,
+                )
+            })?
+            .unwrap_or_default();
+        let existing = existing
+            .iter()
+            .filter_map(|sr| match sr.as_record().data() {
+                Some(RData::TXT(existing)) => Some(existing),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !query.allow_multiple_spf
+            && is_spf(&txt)
+            && existing
+                .iter()
+                .any(|existing| is_spf(existing) && **existing != txt)
+        {
+            return Err(ApiError::conflict(
+                "multiple_spf_records",
+                "The domain already has an SPF record, use mode=replace to replace it",
+            ));
+        }
+
+        // A record with the same data is not added again, so it does not grow the RRset.
+        let rrset_size = existing
+            .iter()
+            .map(|existing| encoded_size(existing) + RECORD_OVERHEAD)
+            .sum::<usize>()
+            + if existing.contains(&&txt) {
+                0
+            } else {
+                size + RECORD_OVERHEAD
+            };
+        if rrset_size > limits.max_rrset_size {
+            return Err(ApiError::bad_request(
+                "txt_rrset_too_large",
+                format!(
+                    "TXT RRset would be {} bytes encoded, over the limit of {} bytes",
+                    rrset_size, limits.max_rrset_size
+                ),
+            ));
+        }
+    }
+
+    let record = Record::from_rdata(domain.clone(), data.ttl, RData::TXT(txt));
+
+    let mut storage_record = data.metadata.into_storage_record(record);
+    data.weight.apply(&mut storage_record);
+
+    record::store_records(
+        &state,
+        &zone,
+        &domain,
+        RecordType::TXT,
+        vec![storage_record],
+        write,
+    )
+    .await
+}
+
+/// Size of the data of a TXT record on the wire: every character-string is prefixed with its
+/// length.
+fn encoded_size(txt: &TXT) -> usize {
+    txt.txt_data().iter().map(|s| 1 + s.len()).sum()
+}
+
+/// Check if a TXT record is an SPF policy, which starts with the version tag `v=spf1` (RFC 7208
+/// section 4.5). The character-strings of a record are concatenated before checking.
+fn is_spf(txt: &TXT) -> bool {
+    let data = txt.txt_data().concat();
+    data.len() >= 6
+        && data[..6].eq_ignore_ascii_case(b"v=spf1")
+        && data.get(6).is_none_or(|&b| b == b' ')
+}
+
+/// Decode hex encoded character-strings. Every section becomes a single character-string, so
+/// they are limited to 255 bytes.
+fn decode_hex_sections(sections: Vec<String>) -> Result<Vec<Vec<u8>>, (StatusCode, &'static str)> {
+    let mut decoded_sections = Vec::with_capacity(sections.len());
+    for section in sections {
         if section.len() > MAX_TXT_SECTION_LENGTH * 2 {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "TXT section length is limited to 255 characters (510 hex characters)",
-            )
-                .into());
+            ));
         }
         let mut dst = vec![0; section.len() / 2];
         faster_hex::hex_decode(section.as_bytes(), &mut dst)
             .map_err(|_| (StatusCode::BAD_REQUEST, "TXT section must be valid hex"))?;
         decoded_sections.push(dst);
     }
-    let txt = TXT::from_bytes(decoded_sections.iter().map(|s| s.as_slice()).collect());
+    Ok(decoded_sections)
+}
 
-    let record = Record::from_rdata(domain.clone(), data.ttl, RData::TXT(txt));
+/// Split text into character-strings of at most 255 bytes. Splits only happen between code
+/// points, so every character-string is valid UTF-8 on its own.
+fn split_text(text: &str) -> Vec<Vec<u8>> {
+    if text.is_empty() {
+        return vec![Vec::new()];
+    }
 
-    state
-        .storage
-        .add_record(
-            &LowerName::from(zone),
-            &LowerName::from(domain),
-            StorageRecord { record },
-        )
-        .await
-        .map_err(|err| {
-            error!("Failed to insert CNAME record: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    Ok(StatusCode::CREATED)
+    let mut sections = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_TXT_SECTION_LENGTH);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        sections.push(rest.as_bytes()[..end].to_vec());
+        rest = &rest[end..];
+    }
+    sections
 }
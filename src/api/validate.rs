@@ -0,0 +1,472 @@
+use std::net::IpAddr;
+
+use super::{error::ApiError, record::parse_record_type, State};
+use crate::prefix::special_purpose_range;
+use axum::{
+    async_trait,
+    extract::{FromRequest, Path, RequestParts},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use serde::Deserialize;
+use trust_dns_proto::rr::{Name, RecordType};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::IntoParams;
+
+/// Extractor for the `/zones/:zone/:domain` path segments of record endpoints, which rejects the
+/// request if either of them is not an fqdn.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct ZoneDomain {
+    /// Fully qualified name of the zone.
+    #[param(value_type = String)]
+    pub zone: Name,
+    /// Fully qualified name of the domain.
+    #[param(value_type = String)]
+    pub domain: Name,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ZoneDomain
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Path((zone, domain)) = Path::<(Name, Name)>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if !zone.is_fqdn() {
+            return Err(ApiError::not_fqdn("Zone").into_response());
+        }
+
+        if !domain.is_fqdn() {
+            return Err(ApiError::not_fqdn("Domain").into_response());
+        }
+
+        Ok(ZoneDomain { zone, domain })
+    }
+}
+
+/// Extractor for the `/zones/:zone/:domain/:rtype` path segments of endpoints of an RRset, which
+/// rejects the request if the zone or domain is not an fqdn, or the record type is unknown.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct RrsetPath {
+    /// Fully qualified name of the zone.
+    #[param(value_type = String)]
+    pub zone: Name,
+    /// Fully qualified name of the domain.
+    #[param(value_type = String)]
+    pub domain: Name,
+    /// Type of the records, e.g. `a` or `txt`.
+    #[param(value_type = String)]
+    pub rtype: RecordType,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for RrsetPath
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Path((zone, domain, rtype)) = Path::<(Name, Name, String)>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if !zone.is_fqdn() {
+            return Err(ApiError::not_fqdn("Zone").into_response());
+        }
+
+        if !domain.is_fqdn() {
+            return Err(ApiError::not_fqdn("Domain").into_response());
+        }
+
+        let rtype = parse_record_type(&rtype).ok_or_else(|| {
+            ApiError::bad_request("unknown_record_type", "Unknown record type").into_response()
+        })?;
+
+        Ok(RrsetPath {
+            zone,
+            domain,
+            rtype,
+        })
+    }
+}
+
+/// Verify a domain is part of a zone. Records of a domain outside of the zone are stored, but
+/// never served, as lookups go through the authoritative zone of the query name.
+pub fn validate_domain_in_zone(zone: &LowerName, domain: &LowerName) -> Result<(), ApiError> {
+    if !zone.zone_of(domain) {
+        return Err(ApiError::bad_request(
+            "domain_not_in_zone",
+            format!("Domain {} is not in zone {}", domain, zone),
+        ));
+    }
+    Ok(())
+}
+
+/// A request body which is either a single item, or an array of items.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(item) => vec![item],
+            OneOrMany::Many(items) => items,
+        }
+    }
+}
+
+/// Query parameters of endpoints which add address records. Private addresses can be stored in
+/// zones which refuse them by setting `allow_private`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AddressCheck {
+    /// Store the records even if an address is in a private or other special purpose range.
+    #[serde(default)]
+    pub allow_private: bool,
+}
+
+/// Verify no address is in a private or other special purpose range, if the zone policy or the
+/// global setting asks for it. This catches internal addresses which are published in a public
+/// zone by accident.
+pub async fn validate_public_addresses(
+    state: &State,
+    zone: &LowerName,
+    addresses: impl IntoIterator<Item = IpAddr>,
+    check: &AddressCheck,
+) -> Result<(), ApiError> {
+    if check.allow_private {
+        return Ok(());
+    }
+    let reject = match state.storage.zone_policy(zone).await {
+        Ok(policy) => policy
+            .reject_private_addresses
+            .unwrap_or(state.reject_private_addresses),
+        Err(e) => {
+            error!("Failed to load policy of zone {}: {}", zone, e);
+            state.reject_private_addresses
+        }
+    };
+    if !reject {
+        return Ok(());
+    }
+
+    for addr in addresses {
+        if let Some((range, kind)) = special_purpose_range(addr) {
+            return Err(ApiError::bad_request(
+                "private_address",
+                format!(
+                    "{} is in {}, a {} range. Set allow_private=true if this is intended",
+                    addr, range, kind
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Query parameters of endpoints which check the owner name of service records. Intentionally
+/// nonstandard owner names can be stored by setting `allow_nonstandard_owner`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OwnerCheck {
+    /// Store the records even if the owner name does not follow the convention of the type.
+    #[serde(default)]
+    pub allow_nonstandard_owner: bool,
+}
+
+/// Verify the owner name of a service record follows the naming convention of its type: SRV
+/// records live at `_service._proto.name` (RFC 2782), TLSA records at `_port._proto.name`
+/// (RFC 6698). Other record types are not checked.
+pub fn validate_service_owner(
+    owner: &Name,
+    rtype: RecordType,
+) -> Result<(), (StatusCode, &'static str)> {
+    let mut labels = owner.iter();
+    let (first, proto) = match (labels.next(), labels.next(), labels.next()) {
+        (Some(first), Some(proto), Some(_)) => (first, proto),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Service record owner must have a service and protocol label below a domain",
+            ))
+        }
+    };
+
+    match rtype {
+        RecordType::SRV => {
+            if !is_service_label(first) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "SRV owner must start with a _service label of 1 to 15 letters, digits or \
+                     hyphens",
+                ));
+            }
+            if !is_service_label(proto) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "SRV owner must have a _proto label after the _service label, e.g. _tcp",
+                ));
+            }
+        }
+        RecordType::TLSA => {
+            let port = first
+                .strip_prefix(b"_")
+                .and_then(|port| std::str::from_utf8(port).ok());
+            // The port is in decimal without leading zeros.
+            let valid_port = matches!(port, Some(port)
+                if port.bytes().all(|b| b.is_ascii_digit())
+                    && (port == "0" || !port.starts_with('0'))
+                    && port.parse::<u16>().is_ok());
+            if !valid_port {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "TLSA owner must start with a _port label, e.g. _443",
+                ));
+            }
+            if !matches!(
+                proto.to_ascii_lowercase().as_slice(),
+                b"_tcp" | b"_udp" | b"_sctp"
+            ) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "TLSA owner must have a _tcp, _udp or _sctp label after the _port label",
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Check if a label is an underscore followed by a service name as defined in RFC 6335 section
+/// 5.1: 1 to 15 letters, digits and hyphens, not starting or ending with a hyphen.
+fn is_service_label(label: &[u8]) -> bool {
+    let name = match label.strip_prefix(b"_") {
+        Some(name) => name,
+        None => return false,
+    };
+    !name.is_empty()
+        && name.len() <= 15
+        && name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+        && !name.starts_with(b"-")
+        && !name.ends_with(b"-")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::validate_service_owner;
+    use crate::api::tests::TestApi;
+
+    fn valid(owner: &str, rtype: RecordType) -> bool {
+        let owner = Name::from_ascii(owner).expect("Valid name");
+        validate_service_owner(&owner, rtype).is_ok()
+    }
+
+    #[test]
+    fn srv_owners() {
+        for owner in [
+            "_sip._tcp.example.com.",
+            "_xmpp-server._tcp.example.com.",
+            "_ldap._udp.dc.example.com.",
+            "_a._b.example.com.",
+            "_123456789012345._tcp.example.com.",
+            "_SIP._TCP.example.com.",
+        ] {
+            assert!(valid(owner, RecordType::SRV), "{}", owner);
+        }
+        for owner in [
+            "example.com.",
+            "sip.tcp.example.com.",
+            "_sip.example.com.",
+            "_sip._tcp.",
+            "_._tcp.example.com.",
+            "_-sip._tcp.example.com.",
+            "_sip-._tcp.example.com.",
+            "_1234567890123456._tcp.example.com.",
+            "_sip.tcp.example.com.",
+            "_s_p._tcp.example.com.",
+        ] {
+            assert!(!valid(owner, RecordType::SRV), "{}", owner);
+        }
+    }
+
+    #[test]
+    fn tlsa_owners() {
+        for owner in [
+            "_443._tcp.www.example.com.",
+            "_25._tcp.mail.example.com.",
+            "_853._udp.example.com.",
+            "_0._sctp.example.com.",
+            "_65535._TCP.example.com.",
+        ] {
+            assert!(valid(owner, RecordType::TLSA), "{}", owner);
+        }
+        for owner in [
+            "www.example.com.",
+            "_443.www.example.com.",
+            "443._tcp.example.com.",
+            "_https._tcp.example.com.",
+            "_0443._tcp.example.com.",
+            "_65536._tcp.example.com.",
+            "_443._quic.example.com.",
+            "_._tcp.example.com.",
+            "_443._tcp.",
+        ] {
+            assert!(!valid(owner, RecordType::TLSA), "{}", owner);
+        }
+    }
+
+    #[test]
+    fn other_types_are_not_checked() {
+        assert!(valid("www.example.com.", RecordType::A));
+        assert!(valid("_443._tcp.example.com.", RecordType::TXT));
+    }
+
+    #[tokio::test]
+    async fn nonstandard_owner_can_be_allowed() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let record = json!({"data": {"priority": 10, "weight": 5, "port": 5060, "target": "sip.example.com."}, "ttl": 300});
+
+        let (status, body) = api
+            .put(
+                "/v1/zones/example.com./sip.example.com./srv",
+                record.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]["message"]
+            .as_str()
+            .expect("Error has a message")
+            .contains("_service"));
+
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./sip.example.com./srv?allow_nonstandard_owner=true",
+                record.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = api
+            .put("/v1/zones/example.com./_sip._tcp.example.com./srv", record)
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn private_addresses_are_allowed_by_default() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./www.example.com./a",
+                json!({"data": "10.0.0.1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn private_addresses_are_rejected() {
+        let api = TestApi::start_with(|config| config.reject_private_addresses = true).await;
+        api.add_zone("example.com.").await;
+
+        for (path, data) in [
+            ("a", json!(["198.51.99.1", "100.64.0.1"])),
+            ("aaaa", json!("fd00::1")),
+        ] {
+            let path = format!("/v1/zones/example.com./www.example.com./{}", path);
+            let (status, body) = api
+                .put(&path, json!({"data": data.clone(), "ttl": 300}))
+                .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(body["error"]["code"], "private_address");
+            assert!(body["error"]["message"]
+                .as_str()
+                .expect("Error has a message")
+                .contains("allow_private=true"));
+
+            let (status, _) = api
+                .put(
+                    &format!("{}?allow_private=true", path),
+                    json!({"data": data, "ttl": 300}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED, "{}", path);
+        }
+
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./public.example.com./a",
+                json!({"data": "198.51.99.1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn zone_policy_overrides_private_address_setting() {
+        let api = TestApi::start_with(|config| config.reject_private_addresses = true).await;
+        api.add_zone("internal.example.").await;
+        api.add_zone("example.com.").await;
+        let (status, _) = api
+            .patch(
+                "/v1/zones/internal.example./policy",
+                json!({"reject_private_addresses": false}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = api
+            .put(
+                "/v1/zones/internal.example./db.internal.example./a",
+                json!({"data": "10.0.0.1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./db.example.com./a",
+                json!({"data": "10.0.0.1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // And the other way around.
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        let (status, _) = api
+            .patch(
+                "/v1/zones/example.com./policy",
+                json!({"reject_private_addresses": true}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = api
+            .put(
+                "/v1/zones/example.com./db.example.com./aaaa",
+                json!({"data": "fe80::1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "private_address");
+    }
+}
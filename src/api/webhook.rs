@@ -0,0 +1,92 @@
+use super::{
+    auth::{unix_now, Actor},
+    State, API_PREFIX,
+};
+use crate::webhook::{Delivery, WebhookEvent};
+use axum::{
+    extract::MatchedPath,
+    http::{Method, Request},
+    middleware::Next,
+    response::{self, Response},
+    Extension,
+};
+
+/// Middleware sending a webhook event for every successful change of a zone or its records. This
+/// runs after the handler, so only changes which are applied are sent.
+pub async fn send_events<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let actor = req
+        .extensions()
+        .get::<Actor>()
+        .map(|Actor(actor)| actor.clone());
+    let state = req
+        .extensions()
+        .get::<State>()
+        .expect("API state is always set")
+        .clone();
+
+    let response = next.run(req).await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let route = match &route {
+        // Nested routes match with the version prefix, the path of the request has it stripped.
+        Some(route) => route.strip_prefix(API_PREFIX).unwrap_or(route),
+        None => return response,
+    };
+    let query = uri.query().unwrap_or_default();
+    let has_param = |param: &str| query.split('&').any(|pair| pair == param);
+    let mut segments = uri.path().trim_start_matches('/').split('/').skip(1);
+    let (zone, name, rtype) = (segments.next(), segments.next(), segments.next());
+
+    let action = match (&method, route) {
+        (&Method::PUT, "/zones/:zone") => "zone.add",
+        (&Method::DELETE, "/zones/:zone") if !has_param("dry_run=true") => "zone.delete",
+        (&Method::PUT | &Method::DELETE, "/zones/:zone/:domain/:rtype/policy") => "record.update",
+        (&Method::PUT, "/zones/:zone/:domain/:rtype/weights") => "record.update",
+        (&Method::PUT, route) if route.starts_with("/zones/:zone/:domain/") => {
+            if has_param("mode=replace") {
+                "record.replace"
+            } else {
+                "record.add"
+            }
+        }
+        (&Method::PATCH, route) if route.starts_with("/zones/:zone/:domain/") => "record.update",
+        (&Method::DELETE, route) if route.starts_with("/zones/:zone/:domain/") => "record.delete",
+        _ => return response,
+    };
+    let is_record = action.starts_with("record.");
+    if let Some(zone) = zone {
+        state.webhooks.send(WebhookEvent {
+            action,
+            zone: zone.to_string(),
+            name: name.filter(|_| is_record).map(str::to_string),
+            record_type: rtype
+                .filter(|_| is_record)
+                .map(|rtype| rtype.to_ascii_uppercase()),
+            actor,
+            timestamp: unix_now(),
+        });
+    }
+
+    response
+}
+
+/// List the most recent webhook delivery attempts, newest first.
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The most recent delivery attempts", body = [Delivery]),
+    )
+)]
+pub async fn list_deliveries(Extension(state): Extension<State>) -> response::Json<Vec<Delivery>> {
+    response::Json(state.webhooks.deliveries())
+}
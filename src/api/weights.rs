@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use super::{
+    error::ApiError,
+    export::rdata_text,
+    record::{self, type_name},
+    validate::RrsetPath,
+    State,
+};
+use crate::{storage::StorageRecord, weight::WeightedAnswer};
+use axum::{extract, response, Extension};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use trust_dns_server::client::rr::LowerName;
+use utoipa::ToSchema;
+
+/// The weights of the records of an RRset.
+#[derive(Deserialize, ToSchema)]
+pub struct SetWeights {
+    /// Weight per record, keyed by the data of the record in master file format, as in a zone
+    /// export. Data is matched ignoring case. Records which are not listed lose their weight.
+    weights: HashMap<String, u32>,
+    /// Allow a weight of 0. Such a record is never picked, unless no record with a weight above 0
+    /// is left, e.g. because they all failed their health check.
+    #[serde(default)]
+    allow_zero: bool,
+}
+
+/// The effective weight of a record of the RRset.
+#[derive(Serialize, ToSchema)]
+pub struct EffectiveWeight {
+    /// Data of the record in master file format.
+    rdata: String,
+    /// Weight of the record, not set if the record has no weight. Records without a weight are
+    /// not part of the pick, and are always answered.
+    weight: Option<u32>,
+    weighted_answer: Option<WeightedAnswer>,
+    /// Chance the record is picked, not set if it is not part of the pick. If no record has a
+    /// weight above 0, nothing is picked and the whole RRset is answered.
+    chance: Option<f64>,
+}
+
+/// The effective weights of an RRset, with an entry for every record.
+#[derive(Serialize, ToSchema)]
+pub struct RrsetWeights {
+    records: Vec<EffectiveWeight>,
+}
+
+impl RrsetWeights {
+    fn new(records: &[StorageRecord]) -> Self {
+        let total = records
+            .iter()
+            .filter_map(|sr| sr.weight)
+            .map(f64::from)
+            .sum::<f64>();
+        RrsetWeights {
+            records: records
+                .iter()
+                .map(|sr| EffectiveWeight {
+                    rdata: rdata_text(sr.as_record()),
+                    weight: sr.weight,
+                    weighted_answer: sr.weight.map(|_| sr.weighted_answer.unwrap_or_default()),
+                    chance: sr
+                        .weight
+                        .filter(|_| total > 0.0)
+                        .map(|weight| f64::from(weight) / total),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Get the effective weights of the records of an RRset.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/{domain}/{rtype}/weights",
+    tag = "records",
+    params(RrsetPath),
+    responses(
+        (status = 200, description = "The weights of the RRset", body = RrsetWeights),
+        (status = 400, description = "The zone or domain is not an fqdn, or the record type is unknown"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+    )
+)]
+pub async fn get_weights(
+    RrsetPath {
+        zone,
+        domain,
+        rtype,
+    }: RrsetPath,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<RrsetWeights>, ApiError> {
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    trace!(
+        "Loading weights of {} {} records",
+        domain_name,
+        type_name(rtype)
+    );
+    let records = record::load_rrset(&state, &zone_name, &domain_name, rtype).await?;
+
+    Ok(response::Json(RrsetWeights::new(&records)))
+}
+
+/// Set the weights of the records of an RRset, returning the new weights. The RRset is replaced
+/// as a whole, so it is never served with partially applied weights.
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}/{domain}/{rtype}/weights",
+    tag = "records",
+    params(RrsetPath),
+    request_body = SetWeights,
+    responses(
+        (status = 200, description = "The new weights of the RRset", body = RrsetWeights),
+        (status = 400, description = "A path segment or weight is invalid, or a weight is set for a record which is not in the RRset"),
+        (status = 404, description = "The zone does not exist, or there are no such records"),
+    )
+)]
+pub async fn set_weights(
+    RrsetPath {
+        zone,
+        domain,
+        rtype,
+    }: RrsetPath,
+    extract::Json(data): extract::Json<SetWeights>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<RrsetWeights>, ApiError> {
+    let zone_name = LowerName::from(zone);
+    let domain_name = LowerName::from(domain);
+    trace!(
+        "Setting weights of {} {} records",
+        domain_name,
+        type_name(rtype)
+    );
+
+    if !data.allow_zero {
+        if let Some(rdata) = data
+            .weights
+            .iter()
+            .find_map(|(rdata, weight)| (*weight == 0).then_some(rdata))
+        {
+            return Err(ApiError::bad_request(
+                "invalid_weight",
+                format!(
+                    "Weight of {} is 0, which requires allow_zero to be set",
+                    rdata
+                ),
+            ));
+        }
+    }
+
+    let mut records = record::load_rrset(&state, &zone_name, &domain_name, rtype).await?;
+    let rdatas = records
+        .iter()
+        .map(|sr| rdata_text(sr.as_record()))
+        .collect::<Vec<_>>();
+    if let Some(unknown) = data.weights.keys().find(|key| {
+        !rdatas
+            .iter()
+            .any(|rdata| rdata.eq_ignore_ascii_case(key.as_str()))
+    }) {
+        return Err(ApiError::bad_request(
+            "unknown_record",
+            format!("The RRset has no record with data {}", unknown),
+        ));
+    }
+
+    for (sr, rdata) in records.iter_mut().zip(&rdatas) {
+        sr.weight = data
+            .weights
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(rdata))
+            .map(|(_, weight)| *weight);
+        if sr.weight.is_none() {
+            sr.weighted_answer = None;
+        }
+    }
+
+    record::store_rrset(&state, &zone_name, domain_name, rtype, &records).await?;
+
+    Ok(response::Json(RrsetWeights::new(&records)))
+}
@@ -1,16 +1,24 @@
-use super::State;
-use crate::storage::StorageRecord;
+use super::{
+    error::ApiError,
+    page::{page_error, Listing, PageQuery},
+    validate::ZoneDomain,
+    State,
+};
+use crate::storage::{StorageOp, StorageRecord};
 use axum::{extract, http::StatusCode, response, Extension};
-use log::{error, trace};
+use log::trace;
 use serde::{Deserialize, Serialize};
-use trust_dns_proto::rr::{rdata::SOA, Name, RData, Record};
+use trust_dns_proto::rr::{rdata::SOA, Name, RData, Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct AddZone {
     // primary dns name
+    #[schema(value_type = String)]
     mname: Name,
     // mailbox domain
+    #[schema(value_type = String)]
     rname: Name,
     // serial, not really used by cetus.
     serial: u32,
@@ -22,53 +30,84 @@ pub struct AddZone {
     nameservers: Vec<NS>,
 }
 
-#[derive(Deserialize)]
-struct NS {
+#[derive(Deserialize, ToSchema)]
+pub struct NS {
+    #[schema(value_type = String)]
     name: Name,
     ttl: u32,
 }
 
-/// Load all existing zones from the server.
+/// Load all existing zones from the server, or a page of them if a limit or cursor is given.
+#[utoipa::path(
+    get,
+    path = "/zones",
+    tag = "zones",
+    params(PageQuery),
+    responses(
+        (status = 200, description = "The zones, or a page of them", body = ZoneListing),
+        (status = 400, description = "The cursor is not valid"),
+    )
+)]
 pub async fn list_zones(
+    extract::Query(page): extract::Query<PageQuery>,
     Extension(state): Extension<State>,
-) -> response::Result<response::Json<Vec<String>>> {
+) -> Result<response::Json<Listing<String>>, ApiError> {
     trace!("Loading zones through API");
-    Ok(response::Json(
+    if page.is_paged() {
+        let zones = state
+            .storage
+            .zones_page(page.cursor()?.as_deref(), page.limit())
+            .await
+            .map_err(|err| page_error("Failed to load zones in API", err))?;
+        return Ok(response::Json(Listing::from_page(zones, |ln| {
+            ln.to_string()
+        })));
+    }
+
+    Ok(response::Json(Listing::All(
         state
             .storage
             .zones()
             .await
-            .map_err(|err| {
-                error!("Failed to load zones in API: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
+            .map_err(|err| ApiError::storage("Failed to load zones in API", err))?
             .into_iter()
             .map(|ln| ln.to_string())
             .collect(),
-    ))
+    )))
 }
 
 /// Add a new zone to the server
+#[utoipa::path(
+    put,
+    path = "/zones/{zone}",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone")),
+    request_body = AddZone,
+    responses(
+        (status = 201, description = "The zone was added"),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 409, description = "The zone already exists"),
+    )
+)]
 pub async fn add_zone(
     extract::Path(zone): extract::Path<Name>,
-    extract::Json(data): extract::Json<AddZone>,
     Extension(state): Extension<State>,
-) -> response::Result<StatusCode> {
-    let existing_zones = state.storage.zones().await.map_err(|err| {
-        error!("Failed to load zones in API: {}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
+    extract::Json(data): extract::Json<AddZone>,
+) -> Result<StatusCode, ApiError> {
     let zone_name = LowerName::from(zone.clone());
 
     if !zone_name.is_fqdn() {
         log::debug!("Refusing to add zone which is not an fqdn ({})", zone_name);
-        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        return Err(ApiError::not_fqdn("Zone"));
     }
 
-    if existing_zones.contains(&zone_name) {
-        // Zone already exists
-        return Err(StatusCode::CONFLICT.into());
+    let exists = state
+        .storage
+        .zone_exists(&zone_name)
+        .await
+        .map_err(|err| ApiError::storage("Failed to check if zone exists", err))?;
+    if exists {
+        return Err(ApiError::conflict("zone_exists", "Zone already exists"));
     }
 
     let soa = SOA::new(
@@ -86,7 +125,7 @@ pub async fn add_zone(
         .into_iter()
         .map(|ns| {
             let rdata = RData::NS(ns.name.clone());
-            Record::from_rdata(zone.clone(), ns.ttl, rdata)
+            StorageRecord::new(Record::from_rdata(zone.clone(), ns.ttl, rdata))
         })
         .collect::<Vec<_>>();
 
@@ -94,35 +133,132 @@ pub async fn add_zone(
 
     log::trace!("NS records {:?}", ns_records);
 
-    // Insert the zone first, otherwise the records will get rejected
-    state.storage.add_zone(&zone_name).await.map_err(|err| {
-        error!("Failed to add zone: {}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Now insert the SOA record
+    // The zone is added with its SOA and NS records at once, so it never exists without them.
+    // Records left behind by an interrupted earlier attempt are replaced.
     state
         .storage
-        .add_record(&zone_name, &zone_name, StorageRecord { record: soa_record })
+        .apply(vec![
+            StorageOp::SetRecords {
+                zone: zone_name.clone(),
+                domain: zone_name.clone(),
+                rtype: RecordType::SOA,
+                records: vec![StorageRecord::new(soa_record)],
+            },
+            StorageOp::SetRecords {
+                zone: zone_name.clone(),
+                domain: zone_name.clone(),
+                rtype: RecordType::NS,
+                records: ns_records,
+            },
+            StorageOp::AddZone {
+                zone: zone_name.clone(),
+            },
+        ])
         .await
-        .map_err(|err| {
-            error!("Failed to insert zone SOA: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|err| ApiError::storage("Failed to add zone", err))?;
+
+    // Make the zone available for queries right away.
+    state.zone_reload.zone(&zone_name);
 
-    // Finally insert the NS records
-    for ns_record in ns_records {
+    Ok(StatusCode::CREATED)
+}
+
+/// Verify a zone exists, so writes don't create records in a zone which is never served.
+pub async fn ensure_zone_exists(state: &State, zone: &LowerName) -> Result<(), ApiError> {
+    let exists = state
+        .storage
+        .zone_exists(zone)
+        .await
+        .map_err(|err| ApiError::storage("Failed to check if zone exists", err))?;
+    if !exists {
+        return Err(ApiError::zone_not_found());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteZoneQuery {
+    /// Only count what would be removed.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeletedZone {
+    /// Amount of storage entries removed, or which would be removed in a dry run.
+    removed_entries: usize,
+    dry_run: bool,
+}
+
+/// Remove a zone and all of its records. The DNS server stops answering for the zone right away.
+#[utoipa::path(
+    delete,
+    path = "/zones/{zone}",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone"), DeleteZoneQuery),
+    responses(
+        (status = 200, description = "The zone was removed, or would be in a dry run", body = DeletedZone),
+        (status = 400, description = "The zone is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
+pub async fn delete_zone(
+    extract::Path(zone): extract::Path<Name>,
+    extract::Query(query): extract::Query<DeleteZoneQuery>,
+    Extension(state): Extension<State>,
+) -> Result<response::Json<DeletedZone>, ApiError> {
+    if !zone.is_fqdn() {
+        return Err(ApiError::not_fqdn("Zone"));
+    }
+
+    let zone_name = LowerName::from(zone);
+    ensure_zone_exists(&state, &zone_name).await?;
+
+    // The entries are counted first, the zone itself is removed in a batch like any other change.
+    let delete_error =
+        |err| ApiError::storage(&format!("Failed to delete zone {}", zone_name), err);
+    let removed_entries = state
+        .storage
+        .delete_zone(&zone_name, true)
+        .await
+        .map_err(delete_error)?;
+
+    if !query.dry_run {
         state
             .storage
-            .add_record(&zone_name, &zone_name, StorageRecord { record: ns_record })
+            .apply(vec![StorageOp::DeleteZone {
+                zone: zone_name.clone(),
+            }])
             .await
-            .map_err(|err| {
-                error!("Failed to insert NS record: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
+            .map_err(delete_error)?;
+        log::info!(
+            "Deleted zone {} ({} storage entries)",
+            zone_name,
+            removed_entries
+        );
+        state.zone_reload.zone(&zone_name);
     }
 
-    Ok(StatusCode::CREATED)
+    Ok(response::Json(DeletedZone {
+        removed_entries,
+        dry_run: query.dry_run,
+    }))
+}
+
+/// Refresh the zone cache of the DNS server now, rather than waiting for the periodic refresh.
+/// The refresh happens in the background.
+#[utoipa::path(
+    post,
+    path = "/zones/reload",
+    tag = "zones",
+    responses(
+        (status = 202, description = "The zone cache will be refreshed"),
+    )
+)]
+pub async fn reload_zones(Extension(state): Extension<State>) -> StatusCode {
+    state.zone_reload.all();
+    StatusCode::ACCEPTED
 }
 
 #[derive(Serialize)]
@@ -131,63 +267,291 @@ pub struct RecordList {
 }
 
 /// List all records of a given domain.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}/{domain}",
+    tag = "records",
+    params(ZoneDomain),
+    responses(
+        (status = 200, description = "The records of the domain", body = [StorageRecord]),
+        (status = 400, description = "The zone or domain is not an fqdn"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
 pub async fn list_domain_records(
-    extract::Path((zone, domain)): extract::Path<(Name, Name)>,
+    ZoneDomain { zone, domain }: ZoneDomain,
     Extension(state): Extension<State>,
-) -> response::Result<response::Json<Vec<StorageRecord>>> {
+) -> Result<response::Json<Vec<StorageRecord>>, ApiError> {
     trace!("Listing domain records for {} in zone {}", domain, zone);
-    if !zone.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only query storage records for fqdn zones",
-        )
-            .into());
-    }
-
-    if !domain.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only query storage records for fqdn domains",
-        )
-            .into());
-    }
+    let zone_name = LowerName::from(zone);
+    ensure_zone_exists(&state, &zone_name).await?;
 
     Ok(response::Json(
         state
             .storage
-            .list_records(&zone.into(), &domain.into())
+            .list_records(&zone_name, &domain.into())
             .await
-            .map_err(|err| {
-                error!("Failed to extract domain records: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?,
+            .map_err(|err| ApiError::storage("Failed to extract domain records", err))?,
     ))
 }
 
+/// List the domains of a zone, or a page of them if a limit or cursor is given.
+#[utoipa::path(
+    get,
+    path = "/zones/{zone}",
+    tag = "zones",
+    params(("zone" = String, Path, description = "Fully qualified name of the zone"), PageQuery),
+    responses(
+        (status = 200, description = "The domains of the zone, or a page of them", body = ZoneListing),
+        (status = 400, description = "The zone is not an fqdn, or the cursor is not valid"),
+        (status = 404, description = "The zone does not exist"),
+    )
+)]
 pub async fn list_zone_domains(
     extract::Path(zone): extract::Path<Name>,
+    extract::Query(page): extract::Query<PageQuery>,
     Extension(state): Extension<State>,
-) -> response::Result<response::Json<Vec<Name>>> {
+) -> Result<response::Json<Listing<Name>>, ApiError> {
     trace!("Listing zone domains in API for {}", zone);
     if !zone.is_fqdn() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Can only query domains for fqdn zones",
-        )
-            .into());
+        return Err(ApiError::not_fqdn("Zone"));
     }
 
-    Ok(response::Json(
+    let zone_name = LowerName::from(zone);
+    ensure_zone_exists(&state, &zone_name).await?;
+
+    if page.is_paged() {
+        let domains = state
+            .storage
+            .list_domains_page(&zone_name, page.cursor()?.as_deref(), page.limit())
+            .await
+            .map_err(|err| page_error("Failed to extract domain records", err))?;
+        return Ok(response::Json(Listing::from_page(domains, Name::from)));
+    }
+
+    Ok(response::Json(Listing::All(
         state
             .storage
-            .list_domains(&zone.into())
+            .list_domains(&zone_name)
             .await
-            .map_err(|err| {
-                error!("Failed to extract domain records: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
+            .map_err(|err| ApiError::storage("Failed to extract domain records", err))?
             .into_iter()
             .map(Name::from)
             .collect(),
-    ))
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use axum::http::StatusCode;
+    use futures_util::future::BoxFuture;
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::tests::TestApi,
+        handle::tests::{a, lower},
+        storage::Storage,
+    };
+
+    /// Walk the pages of a listing, calling `between` after every page. Returns the items in the
+    /// order they were listed.
+    async fn walk_pages<'a>(
+        api: &'a TestApi,
+        path: &str,
+        limit: usize,
+        mut between: impl FnMut(&'a TestApi, usize) -> BoxFuture<'a, ()>,
+    ) -> Vec<String> {
+        let mut items = Vec::new();
+        let mut cursor: Option<String> = None;
+        for page in 0.. {
+            let url = match cursor {
+                Some(ref cursor) => format!("{}?limit={}&cursor={}", path, limit, cursor),
+                None => format!("{}?limit={}", path, limit),
+            };
+            let (status, body) = api.get(&url).await;
+            assert_eq!(status, StatusCode::OK);
+            let page_items = body["items"].as_array().expect("Page has items");
+            assert!(page_items.len() <= limit);
+            items.extend(
+                page_items
+                    .iter()
+                    .map(|item| item.as_str().expect("Items are names").to_string()),
+            );
+            match body["next_cursor"] {
+                Value::String(ref next) => cursor = Some(next.clone()),
+                Value::Null => break,
+                ref other => panic!("Unexpected cursor {}", other),
+            }
+            between(api, page).await;
+        }
+        items
+    }
+
+    /// Check that every name is listed once, and nothing is listed twice.
+    fn assert_listed_once(items: &[String], expected: &[String]) {
+        let unique = items.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), items.len(), "{:?}", items);
+        for name in expected {
+            assert!(
+                unique.contains(name),
+                "{} is missing from {:?}",
+                name,
+                items
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn zone_routes() {
+        let api = TestApi::start().await;
+        let zone = json!({
+            "mname": "ns1.example.com.",
+            "rname": "hostmaster.example.com.",
+            "serial": 1,
+            "refresh": 7200,
+            "retry": 900,
+            "expire": 1209600,
+            "minimum": 300,
+            "ttl": 3600,
+            "nameservers": [{"name": "ns1.example.com.", "ttl": 3600}],
+        });
+        let (status, _) = api.put("/v1/zones/example.com.", zone.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = api.put("/v1/zones/example.com.", zone.clone()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "zone_exists");
+        let (status, _) = api.put("/v1/zones/example.com", zone).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = api.get("/v1/zones/example.com.").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!(["example.com."]));
+        let (status, body) = api.get("/v1/zones/example.com./example.com.").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().map(Vec::len), Some(2));
+
+        let (status, _) = api
+            .put(
+                "/v1/zones/example.com./www.example.com./a",
+                json!({"data": "192.0.2.1", "ttl": 300}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        // The unversioned routes are still served.
+        for path in [
+            "/v1/zones/example.com./www.example.com.",
+            "/zones/example.com./www.example.com.",
+        ] {
+            let (status, body) = api.get(path).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(body.as_array().map(Vec::len), Some(1), "{}", path);
+        }
+        let (status, _) = api.get("/v1/zones/example.com./www.example.com").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = api.get("/v1/zones/example.org./www.example.org.").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = api.delete("/v1/zones/example.com.?dry_run=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["dry_run"], true);
+        let (status, _) = api.get("/v1/zones/example.com.").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = api.delete("/v1/zones/example.com.").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["dry_run"], false);
+        let (status, _) = api.get("/v1/zones/example.com.").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = api.delete("/v1/zones/example.com.").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn zone_pages() {
+        let api = TestApi::start().await;
+        let zones = (0..25)
+            .map(|i| format!("z{:02}.example.", i))
+            .collect::<Vec<_>>();
+        for zone in &zones {
+            api.add_zone(zone).await;
+        }
+
+        let items = walk_pages(&api, "/v1/zones", 7, |_, _| Box::pin(async {})).await;
+        assert_eq!(items.len(), 25);
+        assert_listed_once(&items, &zones);
+
+        // Zones added while paging don't make existing zones listed twice or not at all.
+        let items = walk_pages(&api, "/v1/zones", 7, |api, page| {
+            Box::pin(async move {
+                api.add_zone(&format!("a{}.example.", page)).await;
+                api.add_zone(&format!("zz{}.example.", page)).await;
+            })
+        })
+        .await;
+        assert_listed_once(&items, &zones);
+
+        // Without a limit or cursor, everything is listed at once.
+        let (status, body) = api.get("/v1/zones").await;
+        assert_eq!(status, StatusCode::OK);
+        let zones = api.storage.zones().await.expect("Can list zones");
+        assert_eq!(body.as_array().map(Vec::len), Some(zones.len()));
+    }
+
+    #[tokio::test]
+    async fn domain_pages() {
+        let api = TestApi::start().await;
+        let zone = lower("example.com.");
+        api.add_zone("example.com.").await;
+        let mut domains = vec!["example.com.".to_string()];
+        for i in 0..30 {
+            let domain = format!("d{:02}.example.com.", i);
+            api.storage
+                .add_record(&zone, &lower(&domain), a(&domain, [192, 0, 2, i]))
+                .await
+                .expect("Can add record");
+            domains.push(domain);
+        }
+
+        let items = walk_pages(&api, "/v1/zones/example.com.", 4, |api, page| {
+            Box::pin(async move {
+                let domain = format!("new{}.example.com.", page);
+                api.storage
+                    .add_record(
+                        &lower("example.com."),
+                        &lower(&domain),
+                        a(&domain, [192, 0, 2, 100]),
+                    )
+                    .await
+                    .expect("Can add record");
+            })
+        })
+        .await;
+        assert_listed_once(&items, &domains);
+    }
+
+    #[tokio::test]
+    async fn invalid_pages() {
+        let api = TestApi::start().await;
+        api.add_zone("example.com.").await;
+        for path in [
+            "/v1/zones?cursor=not-base64!",
+            "/v1/zones?cursor=Li4",
+            "/v1/zones/example.com.?cursor=not-base64!",
+        ] {
+            let (status, body) = api.get(path).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(body["error"]["code"], "invalid_cursor", "{}", path);
+        }
+
+        // Limits are capped rather than refused.
+        let (status, body) = api.get("/v1/zones?limit=0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"items": ["example.com."], "next_cursor": null})
+        );
+        let (status, _) = api.get("/v1/zones?limit=1000000").await;
+        assert_eq!(status, StatusCode::OK);
+    }
 }
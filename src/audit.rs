@@ -0,0 +1,66 @@
+use std::{error::Error, ops::Deref, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::storage::Page;
+
+/// A request to the API which changes data, as recorded in the audit log.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct AuditEntry {
+    /// Time of the request, in seconds since the unix epoch.
+    pub timestamp: u64,
+    /// Id of the token which made the request, or `bootstrap` for a token from the config file.
+    /// Not set if authentication is disabled.
+    pub actor: Option<String>,
+    /// Address of the client which sent the request.
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// Size of the request body, in bytes, if the client announced it.
+    pub body_size: Option<u64>,
+    /// The zone the request is about, if any.
+    pub zone: Option<String>,
+    /// Status code of the response. Not set for the entry written before the request is handled,
+    /// if the audit log is mandatory.
+    pub status: Option<u16>,
+}
+
+/// Append only storage of [`AuditEntry`]s.
+#[async_trait::async_trait]
+pub trait AuditSink {
+    /// Append an entry to the audit log.
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Get a page of the entries from the given time on, in the order they were appended,
+    /// optionally limited to the entries of a zone. Pass the `next_cursor` of a page to get the
+    /// next page.
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>>;
+}
+
+#[async_trait::async_trait]
+impl<S> AuditSink for Arc<S>
+where
+    S: AuditSink + Send + Sync,
+{
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().append_audit(entry).await
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>> {
+        self.deref().audit_page(since, zone, cursor, limit).await
+    }
+}
@@ -0,0 +1,538 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    error::Error,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::broadcast;
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::{
+    audit::{AuditEntry, AuditSink},
+    config::CacheConfig,
+    idempotency::{IdempotencyClaim, IdempotencyRecord, IdempotencyStore},
+    metrics::Metrics,
+    policy::ZonePolicy,
+    reload::ZoneReload,
+    storage::{
+        ApiToken, DnssecKey, Page, Storage, StorageChange, StorageOp, StorageRecord, ZoneChange,
+        ZoneCuts,
+    },
+};
+
+/// Amount of shards of the cache. All RRsets of a domain are in the same shard, so the domain can
+/// be invalidated by locking a single shard.
+const SHARDS: usize = 16;
+/// Part of the entries which is evicted at once when a shard is full, so the scan for the least
+/// recently used entries is not done on every insert.
+const EVICTION_FRACTION: usize = 10;
+
+type CacheKey = (LowerName, LowerName, RecordType);
+
+struct CacheEntry {
+    records: Option<Vec<StorageRecord>>,
+    expires: Instant,
+    /// Tick of the shard when the entry was last used, for least recently used eviction.
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<CacheKey, CacheEntry>,
+    tick: u64,
+    /// Incremented on every invalidation in the shard. A lookup which started before an
+    /// invalidation could have read the old records from storage, so it does not cache them.
+    generation: u64,
+}
+
+/// A [`Storage`] which caches the results of [`lookup_records`](Storage::lookup_records) in
+/// memory. Changes made through the cache invalidate the affected domains right away, changes
+/// made in any other way (e.g. by another instance) are only seen once the entries expire.
+pub struct CachedStorage<S> {
+    storage: S,
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+    metrics: Metrics,
+}
+
+impl<S> CachedStorage<S> {
+    pub fn new(storage: S, cfg: &CacheConfig, metrics: Metrics) -> Self {
+        CachedStorage {
+            storage,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            shard_capacity: (cfg.capacity / SHARDS).max(1),
+            ttl: Duration::from_secs(cfg.ttl_secs),
+            negative_ttl: Duration::from_secs(cfg.negative_ttl_secs),
+            metrics,
+        }
+    }
+
+    fn shard(&self, zone: &LowerName, domain: &LowerName) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        zone.hash(&mut hasher);
+        domain.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Get the cached records of an RRset, or the generation of the shard if they are not cached.
+    fn get(&self, key: &CacheKey) -> Result<Option<Vec<StorageRecord>>, u64> {
+        let mut shard = self.shard(&key.0, &key.1).lock().unwrap();
+        shard.tick += 1;
+        let tick = shard.tick;
+        match shard.entries.get_mut(key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                entry.last_used = tick;
+                Ok(entry.records.clone())
+            }
+            Some(_) => {
+                shard.entries.remove(key);
+                self.metrics.add_storage_cache_evictions("expired", 1);
+                Err(shard.generation)
+            }
+            None => Err(shard.generation),
+        }
+    }
+
+    /// Cache the records of an RRset, unless the shard was invalidated since the generation the
+    /// records were looked up at.
+    fn insert(&self, key: CacheKey, records: Option<Vec<StorageRecord>>, generation: u64) {
+        let mut shard = self.shard(&key.0, &key.1).lock().unwrap();
+        if shard.generation != generation {
+            return;
+        }
+        if shard.entries.len() >= self.shard_capacity && !shard.entries.contains_key(&key) {
+            self.evict(&mut shard);
+        }
+
+        // A domain which does not exist, or has no records of the type, is cached for a shorter
+        // time, as it is likely to be created soon if it is being looked up.
+        let ttl = match &records {
+            Some(records) if !records.is_empty() => self.ttl,
+            _ => self.negative_ttl,
+        };
+        shard.tick += 1;
+        let last_used = shard.tick;
+        shard.entries.insert(
+            key,
+            CacheEntry {
+                records,
+                expires: Instant::now() + ttl,
+                last_used,
+            },
+        );
+    }
+
+    /// Make room in a full shard, by removing the expired entries, or if there are none, the least
+    /// recently used ones.
+    fn evict(&self, shard: &mut Shard) {
+        let now = Instant::now();
+        let before = shard.entries.len();
+        shard.entries.retain(|_, entry| entry.expires > now);
+        if shard.entries.len() < before {
+            self.metrics
+                .add_storage_cache_evictions("expired", before - shard.entries.len());
+            return;
+        }
+
+        let mut last_used = shard
+            .entries
+            .values()
+            .map(|entry| entry.last_used)
+            .collect::<Vec<_>>();
+        let evicted = (last_used.len() / EVICTION_FRACTION).max(1);
+        let (_, threshold, _) = last_used.select_nth_unstable(evicted - 1);
+        let threshold = *threshold;
+        shard.entries.retain(|_, entry| entry.last_used > threshold);
+        self.metrics
+            .add_storage_cache_evictions("capacity", before - shard.entries.len());
+    }
+
+    /// Remove all cached RRsets of a domain.
+    fn invalidate_domain(&self, zone: &LowerName, domain: &LowerName) {
+        let mut shard = self.shard(zone, domain).lock().unwrap();
+        shard.generation += 1;
+        shard.entries.retain(|(entry_zone, entry_domain, _), _| {
+            entry_zone != zone || entry_domain != domain
+        });
+    }
+
+    /// Remove all cached RRsets of a zone.
+    fn invalidate_zone(&self, zone: &LowerName) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.generation += 1;
+            shard
+                .entries
+                .retain(|(entry_zone, _, _), _| entry_zone != zone);
+        }
+    }
+
+    /// Remove all cached RRsets.
+    fn invalidate_all(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.generation += 1;
+            shard.entries.clear();
+        }
+    }
+
+    /// Invalidate cached RRsets as they are changed through other instances, until the storage
+    /// stops announcing changes. Changes which were missed could have touched anything.
+    pub async fn follow_changes(self: Arc<Self>, mut changes: broadcast::Receiver<StorageChange>) {
+        loop {
+            match changes.recv().await {
+                Ok(StorageChange::Rrset { zone, domain, .. }) => {
+                    self.invalidate_domain(&zone, &domain)
+                }
+                Ok(StorageChange::Zone { zone }) => self.invalidate_zone(&zone),
+                Ok(StorageChange::Missed) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    self.invalidate_all()
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Storage for CachedStorage<S>
+where
+    S: Storage + Send + Sync,
+{
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.ping().await
+    }
+
+    async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.storage.zones().await
+    }
+
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.storage.zone_exists(zone).await
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.storage.zones_page(cursor, limit).await
+    }
+
+    async fn lookup_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let key = (zone.clone(), domain.clone(), rtype);
+        let generation = match self.get(&key) {
+            Ok(records) => {
+                self.metrics.increment_storage_cache_lookup("hit");
+                return Ok(records);
+            }
+            Err(generation) => generation,
+        };
+
+        self.metrics.increment_storage_cache_lookup("miss");
+        let records = self.storage.lookup_records(domain, zone, rtype).await?;
+        self.insert(key, records.clone(), generation);
+        Ok(records)
+    }
+
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.storage.lookup_all_records(domain, zone).await
+    }
+
+    #[cfg(test)]
+    async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let res = self.storage.add_zone(zone).await;
+        self.invalidate_zone(zone);
+        res
+    }
+
+    #[cfg(test)]
+    async fn add_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let res = self.storage.add_record(zone, domain, record).await;
+        self.invalidate_domain(zone, domain);
+        res
+    }
+
+    async fn list_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.storage.list_records(zone, domain).await
+    }
+
+    async fn list_records_bulk(
+        &self,
+        zone: &LowerName,
+        domains: &[LowerName],
+    ) -> Result<Vec<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.storage.list_records_bulk(zone, domains).await
+    }
+
+    async fn list_domains(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.storage.list_domains(zone).await
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.storage.list_domains_page(zone, cursor, limit).await
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        self.storage.zone_policy(zone).await
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.set_zone_policy(zone, policy).await
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.append_change(zone, change).await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        self.storage.changes_since(zone, serial).await
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The batch is consumed by the storage, so remember what to invalidate up front.
+        let mut zones = Vec::new();
+        let mut domains = Vec::new();
+        for op in &ops {
+            match op {
+                StorageOp::AddZone { zone } | StorageOp::DeleteZone { zone } => {
+                    zones.push(zone.clone())
+                }
+                StorageOp::SetRecords { zone, domain, .. }
+                | StorageOp::RemoveRrset { zone, domain, .. } => {
+                    domains.push((zone.clone(), domain.clone()))
+                }
+                StorageOp::SwapSoa { zone, .. } => domains.push((zone.clone(), zone.clone())),
+            }
+        }
+
+        let res = self.storage.apply(ops).await;
+        for zone in &zones {
+            self.invalidate_zone(zone);
+        }
+        for (zone, domain) in &domains {
+            self.invalidate_domain(zone, domain);
+        }
+        res
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.storage.has_subdomains(zone, domain).await
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        self.storage.closest_encloser(zone, name).await
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        self.storage.zone_cuts(zone).await
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let res = self.storage.remove_rrset(zone, domain, rtype).await;
+        self.invalidate_domain(zone, domain);
+        res
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let res = self.storage.remove_record(zone, domain, record).await;
+        self.invalidate_domain(zone, domain);
+        res
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let res = self.storage.delete_zone(zone, dry_run).await;
+        if !dry_run {
+            self.invalidate_zone(zone);
+        }
+        res
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let res = self.storage.set_records(zone, domain, rtype, records).await;
+        self.invalidate_domain(zone, domain);
+        res
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        let res = self.storage.bump_serial(zone, floor).await;
+        self.invalidate_domain(zone, zone);
+        res
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        self.storage.tokens().await
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.set_token(token).await
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.set_token_last_used(id, last_used).await
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        self.storage.dnssec_keys(zone).await
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.set_dnssec_key(zone, key).await
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.storage.remove_dnssec_key(zone, id).await
+    }
+
+    fn watch_zones(&self, reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        self.storage.watch_zones(reload)
+    }
+
+    fn subscribe_changes(&self) -> Option<broadcast::Receiver<StorageChange>> {
+        self.storage.subscribe_changes()
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> AuditSink for CachedStorage<S>
+where
+    S: AuditSink + Send + Sync,
+{
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.append_audit(entry).await
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>> {
+        self.storage.audit_page(since, zone, cursor, limit).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> IdempotencyStore for CachedStorage<S>
+where
+    S: IdempotencyStore + Send + Sync,
+{
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>> {
+        self.storage.claim_idempotency_key(key, record, ttl).await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage
+            .complete_idempotency_key(key, record, ttl)
+            .await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.storage.release_idempotency_key(key).await
+    }
+}
@@ -1,24 +1,418 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 use serde::Deserialize;
+use trust_dns_proto::rr::Name;
+
+use crate::{
+    policy::{AnyOverUdp, AutoSerial, ResponsePolicy},
+    prefix::IpPrefix,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub instance_name: String,
 
+    // Amount of worker threads of the runtime. If this is 0, everything runs on a single thread.
+    #[serde(default)]
+    pub worker_threads: usize,
+
     // TCP address for the api HTTP server
     pub api_listener: Option<SocketAddr>,
+    // Serve the api over HTTPS instead of plain HTTP. The certificate is reloaded on SIGHUP.
+    pub api_tls: Option<ApiTlsConfig>,
+    // Bootstrap tokens for the api. If none are set and there are no tokens in storage, api
+    // authentication is disabled.
+    #[serde(default = "Vec::new")]
+    pub api_tokens: Vec<String>,
+    // How the api handles an MX exchange which points to a CNAME in the same zone.
+    #[serde(default)]
+    pub mx_cname_exchange: CnameTargetMode,
+    // Rate limits of api requests, disabled if not set.
+    pub api_rate_limit: Option<ApiRateLimitConfig>,
+    // CORS headers for browsers calling the api from another origin, disabled if not set.
+    pub api_cors: Option<ApiCorsConfig>,
+    // Endpoints which receive an event for every change made through the api.
+    #[serde(default = "Vec::new")]
+    pub webhooks: Vec<WebhookConfig>,
+    // Refuse api requests which change data if they can't be written to the audit log. By default
+    // the audit log is best effort.
+    #[serde(default)]
+    pub api_audit_mandatory: bool,
+    // Time the response to an api request with an Idempotency-Key header is replayed for retries
+    // of that request, in seconds.
+    #[serde(default = "default_api_idempotency_ttl_secs")]
+    pub api_idempotency_ttl_secs: u64,
+    // Management of DNSSEC keys through the api.
+    #[serde(default)]
+    pub dnssec: DnssecConfig,
+    // Size limits of TXT records added through the api.
+    #[serde(default)]
+    pub txt_limits: TxtLimitsConfig,
 
     pub metric_listener: Option<SocketAddr>,
 
     pub geoip_db_location: PathBuf,
 
+    // Backend the zones and records are stored in.
+    #[serde(default)]
+    pub storage: StorageBackend,
+    // Connection to the Redis cluster, only used with the redis storage backend.
+    #[serde(default)]
     pub redis_config: RedisConnectionConfig,
+    // Database file, only used with the sqlite storage backend.
+    #[serde(default)]
+    pub sqlite_config: SqliteConfig,
+    // Connection to the etcd cluster, only used with the etcd storage backend.
+    #[serde(default)]
+    pub etcd_config: EtcdConfig,
+
+    // Maximum time to wait for the storage to become reachable on startup, in milliseconds.
+    #[serde(default = "default_storage_startup_timeout_millis")]
+    pub storage_startup_timeout_millis: u64,
+    // Maximum time spent on storage lookups to answer a single query, in milliseconds. Queries
+    // which take longer are answered with SERVFAIL.
+    #[serde(default = "default_storage_query_timeout_millis")]
+    pub storage_query_timeout_millis: u64,
+    // Maximum time spent answering a single query in a zone, in milliseconds, including the time
+    // spent on storage. Queries which take longer are answered with SERVFAIL.
+    #[serde(default = "default_query_deadline_millis")]
+    pub query_deadline_millis: u64,
+    // Queries in a zone which take at least this long to answer are logged, in milliseconds.
+    #[serde(default = "default_slow_query_threshold_millis")]
+    pub slow_query_threshold_millis: u64,
+    // Behavior if the storage is not reachable on startup.
+    #[serde(default)]
+    pub storage_startup_mode: StorageStartupMode,
 
     #[serde(default = "Vec::new")]
     pub udp_sockets: Vec<SocketAddr>,
     #[serde(default = "Vec::new")]
     pub tcp_listeners: Vec<TcpListenerConfig>,
+    // DNS over HTTPS (RFC 8484) listeners. Queries are served on the `/dns-query` path.
+    #[serde(default = "Vec::new")]
+    pub https_listeners: Vec<HttpsListenerConfig>,
+
+    // Source networks allowed to request zone transfers (AXFR). Transfers are refused to everyone
+    // if this is empty.
+    #[serde(default = "Vec::new")]
+    pub allow_transfer: Vec<IpPrefix>,
+
+    // Source networks allowed to send dynamic updates (RFC 2136). Updates are refused from
+    // everyone if this is empty.
+    #[serde(default = "Vec::new")]
+    pub allow_update: Vec<IpPrefix>,
+
+    // Locate clients by their source address only, ignoring the EDNS Client Subnet option sent
+    // by recursive resolvers.
+    #[serde(default)]
+    pub ignore_client_subnet: bool,
+
+    // Secondaries to send a NOTIFY to when a zone changes through the api or a dynamic update.
+    #[serde(default)]
+    pub notify: NotifyConfig,
+
+    // Global response policy, which can be overridden per zone.
+    #[serde(default)]
+    pub response_policy: ResponsePolicy,
+    // How the SOA serial changes when records change through the api, unless the zone policy
+    // overrides it.
+    #[serde(default)]
+    pub auto_serial: AutoSerial,
+    // Refuse addresses in private, loopback, link-local and other special purpose ranges in A and
+    // AAAA records added through the api, unless the zone policy overrides it.
+    #[serde(default)]
+    pub reject_private_addresses: bool,
+
+    // Handling of ANY queries over UDP, for all zones.
+    #[serde(default)]
+    pub any_over_udp: AnyOverUdp,
+
+    // Answers to CHAOS class identification queries.
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+
+    // Response Rate Limiting of UDP queries, disabled if not set.
+    pub rate_limit: Option<RateLimitConfig>,
+
+    // In process cache of record lookups, disabled if not set.
+    pub cache: Option<CacheConfig>,
+
+    // Settings to allow seamless rolling restarts, disabled if not set.
+    pub rolling_restart: Option<RollingRestartConfig>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct NotifyConfig {
+    // Secondaries which are notified of changes in any zone.
+    #[serde(default = "Vec::new")]
+    pub also_notify: Vec<SocketAddr>,
+    // Additional secondaries which are notified of changes in a specific zone.
+    #[serde(default)]
+    pub zones: HashMap<Name, Vec<SocketAddr>>,
+}
+
+#[derive(Deserialize)]
+pub struct ChaosConfig {
+    // Answer version.bind, version.server, id.server and hostname.bind queries.
+    #[serde(default = "default_chaos_enabled")]
+    pub enabled: bool,
+    // Version string to answer instead of the cetus version.
+    pub version: Option<String>,
+    // Identity to answer instead of the instance name.
+    pub id: Option<String>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            enabled: default_chaos_enabled(),
+            version: None,
+            id: None,
+        }
+    }
+}
+
+fn default_chaos_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct RateLimitConfig {
+    // Responses per second allowed to a single client network, for the same name and kind of
+    // response.
+    pub responses_per_second: u32,
+    // Window over which the response rate is measured, in seconds.
+    #[serde(default = "default_rate_limit_window_secs")]
+    pub window_secs: u32,
+    // Every nth limited response is sent truncated instead of being dropped, so legitimate
+    // clients can retry over TCP. 0 drops all limited responses.
+    #[serde(default = "default_rate_limit_slip")]
+    pub slip: u32,
+    // Prefix length of the client networks which share a limit.
+    #[serde(default = "default_rate_limit_ipv4_prefix_len")]
+    pub ipv4_prefix_len: u8,
+    #[serde(default = "default_rate_limit_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+}
+
+#[derive(Deserialize)]
+pub struct CacheConfig {
+    // Maximum amount of RRsets kept in the cache.
+    #[serde(default = "default_cache_capacity")]
+    pub capacity: usize,
+    // Time an RRset is cached for. Changes made through the api of this instance are visible right
+    // away, changes made through other instances only once the cached RRset expires.
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    // Time a lookup of a domain which does not exist, or has no records of the type, is cached for.
+    #[serde(default = "default_cache_negative_ttl_secs")]
+    pub negative_ttl_secs: u64,
+}
+
+fn default_cache_capacity() -> usize {
+    100_000
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_cache_negative_ttl_secs() -> u64 {
+    5
+}
+
+fn default_rate_limit_window_secs() -> u32 {
+    15
+}
+
+fn default_rate_limit_slip() -> u32 {
+    2
+}
+
+fn default_rate_limit_ipv4_prefix_len() -> u8 {
+    24
+}
+
+fn default_rate_limit_ipv6_prefix_len() -> u8 {
+    56
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiRateLimitConfig {
+    // Limits of requests which only read data.
+    #[serde(default)]
+    pub read: ApiRateLimits,
+    // Limits of requests which change data, like creating zones or records.
+    #[serde(default)]
+    pub write: ApiRateLimits,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ApiRateLimits {
+    // Limit shared by all clients.
+    pub global: Option<TokenBucketConfig>,
+    // Limit of every client address separately.
+    pub per_client: Option<TokenBucketConfig>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct TokenBucketConfig {
+    // Sustained amount of requests allowed per second.
+    pub requests_per_second: u32,
+    // Amount of requests allowed in a burst. Defaults to the requests per second.
+    pub burst: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DnssecConfig {
+    // Time a new zone signing key is published before it replaces the current key in a rollover,
+    // in seconds. This should be at least the TTL of the DNSKEY RRset plus the propagation delay
+    // to secondaries, so resolvers know the new key before signatures made with it show up.
+    #[serde(default = "default_zsk_prepublish_secs")]
+    pub zsk_prepublish_secs: u64,
+    // Base64 encoded 32 byte key, which encrypts the private keys of DNSSEC keys with
+    // ChaCha20-Poly1305 before they are stored. Keys can only be generated if this is set. The
+    // private keys can't be recovered without it, but keep it apart from the storage and its
+    // backups.
+    #[serde(default)]
+    pub key_encryption_key: Option<String>,
+}
+
+impl Default for DnssecConfig {
+    fn default() -> Self {
+        DnssecConfig {
+            zsk_prepublish_secs: default_zsk_prepublish_secs(),
+            key_encryption_key: None,
+        }
+    }
+}
+
+fn default_zsk_prepublish_secs() -> u64 {
+    86_400
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct TxtLimitsConfig {
+    // Maximum encoded size of the data of a single TXT record, in bytes.
+    #[serde(default = "default_max_txt_record_size")]
+    pub max_record_size: usize,
+    // Maximum encoded size of a TXT RRset, in bytes. An answer with the RRset must fit in a TCP
+    // response, which is limited to 65535 bytes.
+    #[serde(default = "default_max_txt_rrset_size")]
+    pub max_rrset_size: usize,
+}
+
+impl Default for TxtLimitsConfig {
+    fn default() -> Self {
+        TxtLimitsConfig {
+            max_record_size: default_max_txt_record_size(),
+            max_rrset_size: default_max_txt_rrset_size(),
+        }
+    }
+}
+
+fn default_max_txt_record_size() -> usize {
+    4_096
+}
+
+fn default_max_txt_rrset_size() -> usize {
+    16_384
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WebhookConfig {
+    // http or https url events are posted to.
+    pub url: String,
+    // If set, events are signed with an HMAC-SHA256 of the body with this secret, sent in the
+    // `X-Cetus-Signature` header.
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiCorsConfig {
+    // Origins allowed to call the api, like `https://ui.example.com`. A single `*` allows any
+    // origin.
+    pub allowed_origins: Vec<String>,
+    // Methods allowed in cross origin requests.
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    // Time browsers may cache the result of a preflight request, in seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    ["GET", "POST", "PUT", "PATCH", "DELETE"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct RollingRestartConfig {
+    // Time to keep answering queries on shutdown after readiness starts failing, in milliseconds.
+    pub drain_millis: u64,
+}
+
+/// Backend the zones and records are stored in.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A Redis cluster, shared by all instances.
+    #[default]
+    Redis,
+    /// Memory of the process, for local development. Nothing is persisted, and every instance has
+    /// its own data.
+    Memory,
+    /// A SQLite database file, for deployments with a single instance.
+    Sqlite,
+    /// An etcd cluster, shared by all instances. Changes to zones are watched, so every instance
+    /// picks them up right away.
+    Etcd,
+}
+
+/// Behavior of the server when the storage is not reachable during startup.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageStartupMode {
+    /// Retry with exponential backoff for up to the configured startup timeout, and exit if the
+    /// storage is still unreachable by then.
+    #[default]
+    Wait,
+    /// Start serving immediately, and keep retrying in the background until the storage is
+    /// reachable. Until then, no zones are loaded and all queries are refused.
+    Degraded,
+}
+
+/// Behavior of the api when a record targets a name which only has a CNAME record, which is
+/// forbidden for some record types by RFC 2181.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CnameTargetMode {
+    /// Log a warning, but still create the record.
+    #[default]
+    Warn,
+    /// Refuse to create the record.
+    Reject,
+}
+
+fn default_api_idempotency_ttl_secs() -> u64 {
+    86_400
+}
+
+fn default_storage_startup_timeout_millis() -> u64 {
+    60_000
+}
+
+fn default_storage_query_timeout_millis() -> u64 {
+    500
+}
+
+fn default_query_deadline_millis() -> u64 {
+    1_000
+}
+
+fn default_slow_query_threshold_millis() -> u64 {
+    200
 }
 
 #[derive(Deserialize)]
@@ -27,10 +421,179 @@ pub struct TcpListenerConfig {
     pub timeout_millis: u64,
 }
 
+#[derive(Deserialize)]
+pub struct HttpsListenerConfig {
+    pub address: SocketAddr,
+    pub timeout_millis: u64,
+    // Hostname clients use to reach the listener, requests for other hosts are rejected.
+    pub dns_hostname: String,
+    // PEM encoded certificate chain.
+    pub cert_path: PathBuf,
+    // PEM encoded PKCS8 or RSA private key.
+    pub key_path: PathBuf,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiTlsConfig {
+    // PEM encoded certificate chain.
+    pub tls_cert: PathBuf,
+    // PEM encoded PKCS8 or RSA private key.
+    pub tls_key: PathBuf,
+    // PEM encoded CA certificates. If set, clients must present a certificate issued by one of
+    // them.
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct SqliteConfig {
+    // Path of the database file, which is created if it does not exist.
+    #[serde(default = "default_sqlite_path")]
+    pub path: PathBuf,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            path: default_sqlite_path(),
+        }
+    }
+}
+
+fn default_sqlite_path() -> PathBuf {
+    PathBuf::from("cetus.sqlite")
+}
+
+#[derive(Deserialize)]
+pub struct EtcdConfig {
+    #[serde(default = "default_etcd_endpoints")]
+    pub endpoints: Vec<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // PEM encoded CA certificate to verify the etcd servers with. Setting it enables TLS.
+    pub ca_cert: Option<PathBuf>,
+    // PEM encoded client certificate and its key, for etcd clusters which authenticate clients
+    // with certificates.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for EtcdConfig {
+    fn default() -> Self {
+        EtcdConfig {
+            endpoints: default_etcd_endpoints(),
+            username: None,
+            password: None,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+fn default_etcd_endpoints() -> Vec<String> {
+    vec!["http://127.0.0.1:2379".to_string()]
+}
+
 #[derive(Deserialize)]
 pub struct RedisConnectionConfig {
+    #[serde(default)]
+    pub mode: RedisMode,
     pub username: Option<String>,
     pub password: Option<String>,
+    // Nodes to discover the cluster from, or the address of the server in standalone mode.
     #[serde(default = "Vec::new")]
     pub node_addresses: Vec<SocketAddr>,
+    // Publish every change on a channel, and follow the changes published by other instances, so
+    // their caches are invalidated right away instead of when the cached data expires. Deployments
+    // with a single instance can turn this off.
+    #[serde(default = "default_redis_change_events")]
+    pub change_events: bool,
+}
+
+impl Default for RedisConnectionConfig {
+    fn default() -> Self {
+        RedisConnectionConfig {
+            mode: RedisMode::default(),
+            username: None,
+            password: None,
+            node_addresses: Vec::new(),
+            change_events: default_redis_change_events(),
+        }
+    }
+}
+
+fn default_redis_change_events() -> bool {
+    true
+}
+
+/// Kind of Redis deployment the storage connects to.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisMode {
+    /// A Redis cluster, with keys spread over multiple nodes.
+    #[default]
+    Cluster,
+    /// A single Redis server, e.g. for staging environments.
+    Standalone,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, RedisMode, StorageBackend};
+
+    /// The smallest configuration the server starts with.
+    const MINIMAL: &str = r#"
+instance_name = "test"
+geoip_db_location = "GeoLite2-City.mmdb"
+"#;
+
+    fn parse(extra: &str) -> Config {
+        toml::from_str(&format!("{}{}", MINIMAL, extra)).expect("Valid config")
+    }
+
+    #[test]
+    fn storage_backend() {
+        assert!(matches!(parse("").storage, StorageBackend::Redis));
+        assert!(matches!(
+            parse("storage = \"memory\"\n").storage,
+            StorageBackend::Memory
+        ));
+        assert!(matches!(
+            parse("storage = \"sqlite\"\n").storage,
+            StorageBackend::Sqlite
+        ));
+        assert!(matches!(
+            parse("storage = \"etcd\"\n").storage,
+            StorageBackend::Etcd
+        ));
+        assert!(toml::from_str::<Config>(&format!("{}storage = \"mysql\"\n", MINIMAL)).is_err());
+    }
+
+    #[test]
+    fn redis_mode() {
+        let config = parse("");
+        assert!(config.redis_config.mode == RedisMode::Cluster);
+        assert!(config.redis_config.change_events);
+
+        let config = parse(
+            r#"
+[redis_config]
+mode = "standalone"
+node_addresses = ["127.0.0.1:6379"]
+change_events = false
+"#,
+        );
+        assert!(config.redis_config.mode == RedisMode::Standalone);
+        assert_eq!(
+            config.redis_config.node_addresses,
+            vec!["127.0.0.1:6379".parse().unwrap()]
+        );
+        assert!(!config.redis_config.change_events);
+
+        assert!(toml::from_str::<Config>(&format!(
+            "{}[redis_config]\nmode = \"sentinel\"\n",
+            MINIMAL
+        ))
+        .is_err());
+    }
 }
@@ -0,0 +1,41 @@
+use trust_dns_proto::{
+    error::ProtoResult,
+    rr::{rdata::NULL, Name, RData, Record, RecordType},
+    serialize::binary::{BinDecodable, BinDecoder, BinEncoder},
+};
+
+/// Record type of DNAME records (RFC 6672). These are not known to the DNS library, so they are
+/// records of an unknown type, with the target name in uncompressed wire format as data.
+pub const DNAME: RecordType = RecordType::Unknown(39);
+
+/// Create a DNAME record redirecting everything below `owner` to the same names below `target`.
+pub fn record(owner: Name, ttl: u32, target: &Name) -> ProtoResult<Record> {
+    let mut data = Vec::new();
+    let mut encoder = BinEncoder::new(&mut data);
+    target.emit_as_canonical(&mut encoder, false)?;
+    Ok(Record::from_rdata(
+        owner,
+        ttl,
+        RData::Unknown {
+            code: DNAME.into(),
+            rdata: NULL::with(data),
+        },
+    ))
+}
+
+/// Get the target of a DNAME record, or [None] if the record is not a valid DNAME record.
+pub fn target(record: &Record) -> Option<Name> {
+    match record.data()? {
+        RData::Unknown { code, rdata } if RecordType::from(*code) == DNAME => {
+            Name::read(&mut BinDecoder::new(rdata.anything())).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Substitute the owner of a DNAME at the end of a name below it with the DNAME target. This
+/// fails if the resulting name is too long.
+pub fn substitute(name: &Name, owner: &Name, target: &Name) -> ProtoResult<Name> {
+    let prefix = name.num_labels() - owner.num_labels();
+    Name::from_labels(name.iter().take(prefix as usize))?.append_domain(target)
+}
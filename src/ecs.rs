@@ -0,0 +1,85 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use trust_dns_proto::{
+    op::Edns,
+    rr::rdata::opt::{EdnsCode, EdnsOption},
+};
+
+/// Address family of an IPv4 client subnet, as assigned by IANA.
+const FAMILY_IPV4: u16 = 1;
+/// Address family of an IPv6 client subnet, as assigned by IANA.
+const FAMILY_IPV6: u16 = 2;
+
+/// The EDNS Client Subnet option (RFC 7871) sent by a recursive resolver on behalf of its client.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    source_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Parse the client subnet option from the EDNS section of a request. Returns [None] if the
+    /// option is absent, malformed, or of an unsupported address family.
+    pub fn from_edns(edns: &Edns) -> Option<Self> {
+        let data = match edns.option(EdnsCode::Subnet)? {
+            EdnsOption::Unknown(_, data) => data,
+            _ => return None,
+        };
+        if data.len() < 4 {
+            return None;
+        }
+
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let source_prefix = data[2];
+        let address = &data[4..];
+        // Only as many bytes as needed for the prefix are sent.
+        if address.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+
+        let addr = match family {
+            FAMILY_IPV4 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(ClientSubnet {
+            addr,
+            source_prefix,
+        })
+    }
+
+    /// The network address of the client subnet.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The amount of leading bits of the address which were sent by the resolver.
+    pub fn source_prefix(&self) -> u8 {
+        self.source_prefix
+    }
+
+    /// Encode the option for a response, with the given scope prefix length. The scope indicates
+    /// how much of the client subnet was used to build the answer, with 0 meaning the answer is
+    /// valid for any client.
+    pub fn to_option(self, scope_prefix: u8) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (FAMILY_IPV4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (FAMILY_IPV6, addr.octets().to_vec()),
+        };
+        let mut data = Vec::with_capacity(4 + octets.len());
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.source_prefix);
+        data.push(scope_prefix);
+        data.extend_from_slice(&octets[..(self.source_prefix as usize).div_ceil(8)]);
+        EdnsOption::Unknown(EdnsCode::Subnet.into(), data)
+    }
+}
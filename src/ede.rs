@@ -0,0 +1,74 @@
+use trust_dns_proto::rr::rdata::opt::EdnsOption;
+
+/// Option code of Extended DNS Errors, as assigned by IANA.
+const EDE_OPTION_CODE: u16 = 15;
+
+/// An Extended DNS Error (RFC 8914), telling the client why a query failed. This is attached to
+/// error responses to clients which sent an OPT record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtendedError {
+    /// The storage failed to answer a lookup.
+    StorageUnavailable,
+    /// The storage did not answer before the deadline of the query.
+    StorageTimeout,
+    /// The query was not answered before its deadline.
+    QueryTimeout,
+    /// The location of the client could not be determined.
+    GeoLookupFailed,
+    /// The zone has no SOA record, so nothing in it can be answered.
+    MissingSoa,
+    /// The server is not authoritative for the name.
+    NotAuthoritative,
+    /// The client is not allowed to make the request.
+    Prohibited,
+    /// The request is of a kind which is not supported.
+    NotSupported,
+    /// The server started, but did not load its zones yet.
+    NotReady,
+}
+
+impl ExtendedError {
+    /// The INFO-CODE of the error, as assigned by IANA.
+    fn info_code(self) -> u16 {
+        match self {
+            // No Reachable Authority
+            ExtendedError::StorageUnavailable | ExtendedError::StorageTimeout => 22,
+            // Other
+            ExtendedError::GeoLookupFailed
+            | ExtendedError::MissingSoa
+            | ExtendedError::QueryTimeout => 0,
+            // Not Authoritative
+            ExtendedError::NotAuthoritative => 20,
+            // Prohibited
+            ExtendedError::Prohibited => 18,
+            // Not Supported
+            ExtendedError::NotSupported => 21,
+            // Not Ready
+            ExtendedError::NotReady => 14,
+        }
+    }
+
+    /// Human readable description of the error, sent as EXTRA-TEXT.
+    fn extra_text(self) -> &'static str {
+        match self {
+            ExtendedError::StorageUnavailable => "storage unavailable",
+            ExtendedError::StorageTimeout => "storage timed out",
+            ExtendedError::QueryTimeout => "query timed out",
+            ExtendedError::GeoLookupFailed => "geoip lookup failed",
+            ExtendedError::MissingSoa => "zone has no SOA record",
+            ExtendedError::NotAuthoritative => "not authoritative for this name",
+            ExtendedError::Prohibited => "request not allowed",
+            ExtendedError::NotSupported => "request not supported",
+            ExtendedError::NotReady => "zones not loaded yet",
+        }
+    }
+
+    /// Encode the error as an EDNS option.
+    pub fn to_option(self) -> EdnsOption {
+        let text = self.extra_text();
+        let mut data = Vec::with_capacity(2 + text.len());
+        data.extend_from_slice(&self.info_code().to_be_bytes());
+        data.extend_from_slice(text.as_bytes());
+        EdnsOption::Unknown(EDE_OPTION_CODE, data)
+    }
+}
@@ -0,0 +1,1328 @@
+use std::{
+    error::Error,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use etcd_client::{
+    Certificate, Client, Compare, CompareOp, ConnectOptions, DeleteOptions, GetOptions, Identity,
+    PutOptions, SortOrder, SortTarget, TlsOptions, Txn, TxnOp, TxnOpResponse, WatchOptions,
+};
+use futures_util::future::BoxFuture;
+use log::{error, trace};
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::{
+    audit::{AuditEntry, AuditSink},
+    config::EtcdConfig,
+    dname::DNAME,
+    idempotency::{IdempotencyClaim, IdempotencyRecord, IdempotencyStore},
+    policy::ZonePolicy,
+    reload::ZoneReload,
+    storage::{
+        closest_encloser_from_keys, name_index_end, name_index_key, ApiToken, CutKind, DnssecKey,
+        InvalidCursor, Page, SerialChanged, Storage, StorageOp, StorageRecord, ZoneChange,
+        ZoneCuts,
+    },
+};
+
+/// Prefix of the zone markers, which hold the policy of the zone.
+const ZONES_PREFIX: &str = "/cetus/zones/";
+/// Prefix of the RRsets, which are stored as `{zone}/{domain}/{type}`.
+const RECORDS_PREFIX: &str = "/cetus/records/";
+/// Prefix of the index of names, with an empty `{zone}/{name key}/{type}` for every RRset. The
+/// index keys of the names below a domain follow the index keys of the domain.
+const NAMES_PREFIX: &str = "/cetus/names/";
+/// Prefix of the index of zone cuts, with an empty `{zone}/{domain}/{kind}` for every delegation
+/// and DNAME record, see [`CutKind`].
+const CUTS_PREFIX: &str = "/cetus/cuts/";
+const JOURNAL_PREFIX: &str = "/cetus/journal/";
+const DNSSEC_KEYS_PREFIX: &str = "/cetus/dnssec/";
+const TOKENS_PREFIX: &str = "/cetus/tokens/";
+const TOKEN_USAGE_PREFIX: &str = "/cetus/token_usage/";
+const AUDIT_PREFIX: &str = "/cetus/audit/";
+const IDEMPOTENCY_PREFIX: &str = "/cetus/idempotency/";
+
+/// Maximum amount of changes kept in the journal of a zone.
+const MAX_JOURNAL_LENGTH: i64 = 1_000;
+/// Maximum amount of entries kept in the audit log. Older entries are removed.
+const MAX_AUDIT_LENGTH: i64 = 100_000;
+/// The audit log is trimmed once every this many appends, rather than on every append.
+const AUDIT_TRIM_INTERVAL: usize = 100;
+/// Maximum amount of times a read-modify-write of a key is retried if the key is changed
+/// concurrently.
+const MAX_UPDATE_ATTEMPTS: usize = 16;
+/// Amount of keys read at once when listing.
+const LIST_BATCH_SIZE: i64 = 500;
+/// Time to wait before watching again after a watch failed.
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An implementation of storage in etcd. Every RRset is a key holding its records as a JSON
+/// array, and every zone has a marker key holding its policy.
+pub struct EtcdStorage {
+    client: Client,
+    /// Amount of audit entries appended, to know when to trim the audit log.
+    audit_appends: AtomicUsize,
+}
+
+impl EtcdStorage {
+    /// Connect to the etcd cluster, with the credentials and certificates from the config.
+    pub async fn connect(cfg: &EtcdConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut options = ConnectOptions::new();
+        if let (Some(username), Some(password)) = (&cfg.username, &cfg.password) {
+            options = options.with_user(username, password);
+        }
+        if cfg.ca_cert.is_some() || cfg.client_cert.is_some() {
+            let mut tls = TlsOptions::new();
+            if let Some(ca_cert) = &cfg.ca_cert {
+                tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca_cert)?));
+            }
+            match (&cfg.client_cert, &cfg.client_key) {
+                (Some(cert), Some(key)) => {
+                    tls = tls.identity(Identity::from_pem(
+                        std::fs::read(cert)?,
+                        std::fs::read(key)?,
+                    ))
+                }
+                (None, None) => {}
+                _ => return Err("A client certificate for etcd also needs its key".into()),
+            }
+            options = options.with_tls(tls);
+        }
+
+        Ok(EtcdStorage {
+            client: Client::connect(&cfg.endpoints, Some(options)).await?,
+            audit_appends: AtomicUsize::new(0),
+        })
+    }
+
+    /// Get a client for a request. Clients share their connection, so this is cheap.
+    fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Get all keys and values with a prefix, in the order of their keys.
+    async fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(prefix, Some(GetOptions::new().with_prefix()))
+            .await?;
+        resp.kvs()
+            .iter()
+            .map(|kv| {
+                Ok((
+                    kv.key_str()?[prefix.len()..].to_string(),
+                    kv.value().to_vec(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Check if any key with a prefix exists.
+    async fn prefix_exists(&self, prefix: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(
+                prefix,
+                Some(GetOptions::new().with_prefix().with_count_only()),
+            )
+            .await?;
+        Ok(resp.count() > 0)
+    }
+
+    /// Change the records of an RRset, without losing concurrent changes. The change is retried on
+    /// the latest records if they were changed after being read. An empty set of records removes
+    /// the key.
+    async fn update_rrset<T>(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        mut change: impl FnMut(&mut Vec<StorageRecord>) -> Option<T>,
+    ) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+        let key = rrset_key(zone, domain, rtype);
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let resp = self.client().get(key.as_str(), None).await?;
+            // A key which does not exist has a mod revision of 0, also when comparing.
+            let (mut records, revision) = match resp.kvs().first() {
+                Some(kv) => (serde_json::from_slice(kv.value())?, kv.mod_revision()),
+                None => (Vec::new(), 0),
+            };
+
+            let outcome = match change(&mut records) {
+                Some(outcome) => outcome,
+                None => return Ok(None),
+            };
+            let txn = Txn::new()
+                .when([Compare::mod_revision(
+                    key.as_str(),
+                    CompareOp::Equal,
+                    revision,
+                )])
+                .and_then(rrset_ops(zone, domain, rtype, &records)?);
+            if self.client().txn(txn).await?.succeeded() {
+                return Ok(Some(outcome));
+            }
+            trace!("{} changed concurrently, retrying", key);
+        }
+
+        Err(format!("{} keeps changing concurrently", key).into())
+    }
+
+    /// Remove the oldest keys with a prefix, so at most `max` are left. Keys with the prefix must
+    /// sort in the order they are added.
+    async fn trim(&self, prefix: &str, max: i64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(
+                prefix,
+                Some(GetOptions::new().with_prefix().with_count_only()),
+            )
+            .await?;
+        let excess = resp.count() - max;
+        if excess <= 0 {
+            return Ok(());
+        }
+
+        let resp = self
+            .client()
+            .get(
+                prefix,
+                Some(
+                    GetOptions::new()
+                        .with_prefix()
+                        .with_keys_only()
+                        .with_limit(excess),
+                ),
+            )
+            .await?;
+        if let Some(last) = resp.kvs().last() {
+            let mut end = last.key().to_vec();
+            end.push(0);
+            self.client()
+                .delete(prefix, Some(DeleteOptions::new().with_range(end)))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn zone_key(zone: &LowerName) -> String {
+    format!("{}{}", ZONES_PREFIX, zone)
+}
+
+fn zone_records_prefix(zone: &LowerName) -> String {
+    format!("{}{}/", RECORDS_PREFIX, zone)
+}
+
+fn domain_prefix(zone: &LowerName, domain: &LowerName) -> String {
+    format!("{}{}/{}/", RECORDS_PREFIX, zone, domain)
+}
+
+fn rrset_key(zone: &LowerName, domain: &LowerName, rtype: RecordType) -> String {
+    format!("{}{}/{}/{}", RECORDS_PREFIX, zone, domain, rtype)
+}
+
+fn zone_names_prefix(zone: &LowerName) -> String {
+    format!("{}{}/", NAMES_PREFIX, zone)
+}
+
+fn name_key(zone: &LowerName, domain: &LowerName, rtype: RecordType) -> String {
+    format!(
+        "{}{}/{}",
+        zone_names_prefix(zone),
+        name_index_key(domain),
+        rtype
+    )
+}
+
+fn zone_cuts_prefix(zone: &LowerName) -> String {
+    format!("{}{}/", CUTS_PREFIX, zone)
+}
+
+fn cut_key(zone: &LowerName, domain: &LowerName, kind: CutKind) -> String {
+    format!("{}{}/{}", zone_cuts_prefix(zone), domain, kind.as_str())
+}
+
+fn journal_prefix(zone: &LowerName) -> String {
+    format!("{}{}/", JOURNAL_PREFIX, zone)
+}
+
+fn dnssec_keys_prefix(zone: &LowerName) -> String {
+    format!("{}{}/", DNSSEC_KEYS_PREFIX, zone)
+}
+
+/// The end of the range of keys with a prefix. Prefixes end with a separator, so incrementing it
+/// is enough.
+fn prefix_end(prefix: &str) -> Vec<u8> {
+    let mut end = prefix.as_bytes().to_vec();
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+    }
+    end
+}
+
+/// Get the domain of the key of an RRset, which is everything between the prefix of the zone and
+/// the type.
+fn domain_of(key: &[u8], zone_prefix: &str) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+    let key = std::str::from_utf8(key)?;
+    let (domain, _) = key[zone_prefix.len()..]
+        .rsplit_once('/')
+        .ok_or_else(|| format!("{} is not the key of an RRset", key))?;
+    Ok(LowerName::from_str(domain)?)
+}
+
+/// A key which sorts in the order keys are created, also across instances.
+fn sequential_key(prefix: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}{:020}-{:08x}", prefix, nanos, rand::random::<u32>())
+}
+
+/// Create the batch operations which write an RRset and its entries in the indexes, removing all
+/// of them if there are no records.
+fn rrset_ops(
+    zone: &LowerName,
+    domain: &LowerName,
+    rtype: RecordType,
+    records: &[StorageRecord],
+) -> Result<Vec<TxnOp>, serde_json::Error> {
+    let key = rrset_key(zone, domain, rtype);
+    let mut ops = vec![if records.is_empty() {
+        TxnOp::delete(key, None)
+    } else {
+        TxnOp::put(key, serde_json::to_vec(records)?, None)
+    }];
+    ops.extend(index_ops(zone, domain, rtype, !records.is_empty()));
+    Ok(ops)
+}
+
+/// Create the batch operations which add or remove the entries of an RRset in the index of names,
+/// and in the index of zone cuts if the RRset defines one.
+fn index_ops(zone: &LowerName, domain: &LowerName, rtype: RecordType, exists: bool) -> Vec<TxnOp> {
+    let mut keys = vec![name_key(zone, domain, rtype)];
+    keys.extend(CutKind::of(zone, domain, rtype).map(|kind| cut_key(zone, domain, kind)));
+    keys.into_iter()
+        .map(|key| {
+            if exists {
+                TxnOp::put(key, "", None)
+            } else {
+                TxnOp::delete(key, None)
+            }
+        })
+        .collect()
+}
+
+/// Check if a changed key affects the zone cache, i.e. it is a zone marker, or an RRset which
+/// is either an SOA or defines a zone cut.
+fn affects_zone_cache(key: &[u8]) -> bool {
+    let key = match std::str::from_utf8(key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    if key.starts_with(ZONES_PREFIX) {
+        return true;
+    }
+    match key.rsplit_once('/') {
+        Some((_, rtype)) => [RecordType::SOA, RecordType::NS, DNAME]
+            .iter()
+            .any(|watched| watched.to_string() == rtype),
+        None => false,
+    }
+}
+
+/// Get the zone of the key of a zone marker or an RRset.
+fn zone_of_key(key: &[u8]) -> Option<LowerName> {
+    let key = std::str::from_utf8(key).ok()?;
+    let zone = match key.strip_prefix(ZONES_PREFIX) {
+        Some(zone) => zone,
+        None => key.strip_prefix(RECORDS_PREFIX)?.split_once('/')?.0,
+    };
+    LowerName::from_str(zone).ok()
+}
+
+/// Request a reload of the zone of every change affecting the zone cache, watching again if the
+/// watch fails.
+async fn watch_zones(mut client: Client, reload: Arc<ZoneReload>) {
+    loop {
+        if let Err(e) = watch_zone_changes(&mut client, &reload).await {
+            error!("Failed to watch etcd for zone changes: {}", e);
+        }
+        // Changes could have been missed while the watch was down.
+        reload.all();
+        tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+    }
+}
+
+async fn watch_zone_changes(
+    client: &mut Client,
+    reload: &ZoneReload,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (_zones_watcher, mut zones) = client
+        .watch(ZONES_PREFIX, Some(WatchOptions::new().with_prefix()))
+        .await?;
+    let (_records_watcher, mut records) = client
+        .watch(RECORDS_PREFIX, Some(WatchOptions::new().with_prefix()))
+        .await?;
+
+    loop {
+        let resp = tokio::select! {
+            resp = zones.message() => resp?,
+            resp = records.message() => resp?,
+        };
+        let resp = match resp {
+            Some(resp) => resp,
+            None => return Err("watch was closed".into()),
+        };
+        for kv in resp.events().iter().filter_map(|event| event.kv()) {
+            if affects_zone_cache(kv.key()) {
+                match zone_of_key(kv.key()) {
+                    Some(zone) => reload.zone(&zone),
+                    None => reload.all(),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for EtcdStorage {
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client().status().await?;
+        Ok(())
+    }
+
+    async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(
+                ZONES_PREFIX,
+                Some(GetOptions::new().with_prefix().with_keys_only()),
+            )
+            .await?;
+        resp.kvs()
+            .iter()
+            .map(|kv| Ok(LowerName::from_str(&kv.key_str()?[ZONES_PREFIX.len()..])?))
+            .collect()
+    }
+
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(zone_key(zone), Some(GetOptions::new().with_count_only()))
+            .await?;
+        Ok(resp.count() > 0)
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        // The cursor is the last zone of the previous page, the page starts right after it.
+        let start = match cursor {
+            Some(cursor) => {
+                LowerName::from_str(cursor).map_err(|_| InvalidCursor)?;
+                format!("{}{}\0", ZONES_PREFIX, cursor)
+            }
+            None => ZONES_PREFIX.to_string(),
+        };
+        let resp = self
+            .client()
+            .get(
+                start,
+                Some(
+                    GetOptions::new()
+                        .with_range(prefix_end(ZONES_PREFIX))
+                        .with_keys_only()
+                        .with_limit(limit as i64 + 1),
+                ),
+            )
+            .await?;
+
+        let mut items = resp
+            .kvs()
+            .iter()
+            .map(|kv| Ok(LowerName::from_str(&kv.key_str()?[ZONES_PREFIX.len()..])?))
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|zone| zone.to_string())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn lookup_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .get(rrset_key(zone, domain, rtype), None)
+            .await?;
+        if let Some(kv) = resp.kvs().first() {
+            return Ok(Some(serde_json::from_slice(kv.value())?));
+        }
+
+        // A domain which exists but has no records of the type has an empty RRset.
+        Ok(self
+            .prefix_exists(&domain_prefix(zone, domain))
+            .await?
+            .then(Vec::new))
+    }
+
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let rrsets = self.get_prefix(&domain_prefix(zone, domain)).await?;
+        if rrsets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut records = Vec::new();
+        for (_, encoded_records) in rrsets {
+            records.extend(serde_json::from_slice::<Vec<StorageRecord>>(
+                &encoded_records,
+            )?);
+        }
+        Ok(Some(records))
+    }
+
+    #[cfg(test)]
+    async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Like the other backends, this resets the marker, and so the policy, of an existing zone.
+        self.client().put(zone_key(zone), "", None).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn add_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rtype = record.record.record_type();
+        self.update_rrset(zone, domain, rtype, |records| {
+            records.push(record.clone());
+            Some(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .lookup_all_records(domain, zone)
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn list_domains(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        let prefix = zone_records_prefix(zone);
+        let resp = self
+            .client()
+            .get(
+                prefix.as_str(),
+                Some(GetOptions::new().with_prefix().with_keys_only()),
+            )
+            .await?;
+
+        // The RRsets of a domain are next to each other, as their keys only differ in the type.
+        let mut domains = Vec::new();
+        for kv in resp.kvs() {
+            let domain = domain_of(kv.key(), &prefix)?;
+            if domains.last() != Some(&domain) {
+                domains.push(domain);
+            }
+        }
+        Ok(domains)
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        let prefix = zone_records_prefix(zone);
+        // The cursor is the last domain of the previous page. All keys of that domain continue
+        // with a '/', so the page starts at the first key which continues with the next character.
+        let mut start = match cursor {
+            Some(cursor) => {
+                LowerName::from_str(cursor).map_err(|_| InvalidCursor)?;
+                format!("{}{}0", prefix, cursor).into_bytes()
+            }
+            None => prefix.clone().into_bytes(),
+        };
+
+        let mut items: Vec<LowerName> = Vec::new();
+        loop {
+            let resp = self
+                .client()
+                .get(
+                    start.clone(),
+                    Some(
+                        GetOptions::new()
+                            .with_range(prefix_end(&prefix))
+                            .with_keys_only()
+                            .with_limit(LIST_BATCH_SIZE),
+                    ),
+                )
+                .await?;
+            for kv in resp.kvs() {
+                let domain = domain_of(kv.key(), &prefix)?;
+                if items.last() == Some(&domain) {
+                    continue;
+                }
+                if items.len() == limit {
+                    let next_cursor = items.last().map(|domain| domain.to_string());
+                    return Ok(Page { items, next_cursor });
+                }
+                items.push(domain);
+            }
+
+            match resp.kvs().last() {
+                Some(last) if resp.more() => {
+                    start = last.key().to_vec();
+                    start.push(0);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(Page {
+            items,
+            next_cursor: None,
+        })
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        // Zones without a policy have an empty marker, which means the default policy.
+        let resp = self.client().get(zone_key(zone), None).await?;
+        match resp.kvs().first() {
+            Some(kv) if !kv.value().is_empty() => Ok(serde_json::from_slice(kv.value())?),
+            _ => Ok(ZonePolicy::default()),
+        }
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = zone_key(zone);
+        // This must never create a zone.
+        let txn = Txn::new()
+            .when([Compare::version(key.as_str(), CompareOp::Greater, 0)])
+            .and_then([TxnOp::put(key.as_str(), serde_json::to_vec(policy)?, None)]);
+        self.client().txn(txn).await?;
+        Ok(())
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let prefix = journal_prefix(zone);
+        self.client()
+            .put(sequential_key(&prefix), serde_json::to_vec(change)?, None)
+            .await?;
+        self.trim(&prefix, MAX_JOURNAL_LENGTH).await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        let journal = self
+            .get_prefix(&journal_prefix(zone))
+            .await?
+            .into_iter()
+            .map(|(_, encoded_change)| serde_json::from_slice::<ZoneChange>(&encoded_change))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ZoneChange::since(journal, serial))
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The whole batch is a single transaction. etcd refuses transactions which change a key
+        // more than once, or which have more operations than its --max-txn-ops.
+        let mut compares = Vec::new();
+        let mut txn_ops = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                StorageOp::AddZone { zone } => {
+                    let key = zone_key(&zone);
+                    txn_ops.push(TxnOp::txn(
+                        Txn::new()
+                            .when([Compare::version(key.as_str(), CompareOp::Equal, 0)])
+                            .and_then([TxnOp::put(key.as_str(), "", None)]),
+                    ));
+                }
+                StorageOp::SetRecords {
+                    zone,
+                    domain,
+                    rtype,
+                    records,
+                } => txn_ops.extend(rrset_ops(&zone, &domain, rtype, &records)?),
+                StorageOp::RemoveRrset {
+                    zone,
+                    domain,
+                    rtype,
+                } => txn_ops.extend(rrset_ops(&zone, &domain, rtype, &[])?),
+                StorageOp::DeleteZone { zone } => {
+                    for prefix in [
+                        zone_records_prefix(&zone),
+                        zone_names_prefix(&zone),
+                        zone_cuts_prefix(&zone),
+                        journal_prefix(&zone),
+                        dnssec_keys_prefix(&zone),
+                    ] {
+                        txn_ops.push(TxnOp::delete(
+                            prefix,
+                            Some(DeleteOptions::new().with_prefix()),
+                        ));
+                    }
+                    txn_ops.push(TxnOp::delete(zone_key(&zone), None));
+                }
+                StorageOp::SwapSoa { zone, serial, soa } => {
+                    // The SOA must still be the one which was checked when the batch is written.
+                    let key = rrset_key(&zone, &zone, RecordType::SOA);
+                    let resp = self.client().get(key.as_str(), None).await?;
+                    let (soas, revision) = match resp.kvs().first() {
+                        Some(kv) => (
+                            serde_json::from_slice::<Vec<StorageRecord>>(kv.value())?,
+                            kv.mod_revision(),
+                        ),
+                        None => (Vec::new(), 0),
+                    };
+                    if soas.first().and_then(StorageRecord::serial) != Some(serial) {
+                        return Err(SerialChanged.into());
+                    }
+                    compares.push(Compare::mod_revision(
+                        key.as_str(),
+                        CompareOp::Equal,
+                        revision,
+                    ));
+                    txn_ops.extend(rrset_ops(&zone, &zone, RecordType::SOA, &[*soa])?);
+                }
+            }
+        }
+
+        let txn = Txn::new().when(compares).and_then(txn_ops);
+        if !self.client().txn(txn).await?.succeeded() {
+            return Err(SerialChanged.into());
+        }
+        Ok(())
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // Index keys of the domain itself continue with a '/', those of the names below it with
+        // the first character of a label, which can sort on either side of the '/'.
+        let key = format!("{}{}", zone_names_prefix(zone), name_index_key(domain));
+        let below = |start: String, end: String| {
+            TxnOp::get(
+                start,
+                Some(
+                    GetOptions::new()
+                        .with_range(end)
+                        .with_count_only()
+                        .with_limit(1),
+                ),
+            )
+        };
+        let txn = Txn::new().and_then([
+            below(key.clone(), format!("{}/", key)),
+            below(format!("{}0", key), name_index_end(&key)),
+        ]);
+        Ok(self
+            .client()
+            .txn(txn)
+            .await?
+            .op_responses()
+            .iter()
+            .any(|resp| matches!(resp, TxnOpResponse::Get(get) if get.count() > 0)))
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        // The last index key before the key of the name, and the first one after it.
+        let prefix = zone_names_prefix(zone);
+        let key = format!("{}{}", prefix, name_index_key(name));
+        let txn = Txn::new().and_then([
+            TxnOp::get(
+                prefix.as_str(),
+                Some(
+                    GetOptions::new()
+                        .with_range(key.as_str())
+                        .with_sort(SortTarget::Key, SortOrder::Descend)
+                        .with_keys_only()
+                        .with_limit(1),
+                ),
+            ),
+            TxnOp::get(
+                key.as_str(),
+                Some(
+                    GetOptions::new()
+                        .with_range(prefix_end(&prefix))
+                        .with_keys_only()
+                        .with_limit(1),
+                ),
+            ),
+        ]);
+
+        let mut neighbours = Vec::with_capacity(2);
+        for resp in self.client().txn(txn).await?.op_responses() {
+            if let TxnOpResponse::Get(get) = resp {
+                for kv in get.kvs() {
+                    neighbours.push(kv.key_str()?[prefix.len()..].to_string());
+                }
+            }
+        }
+        Ok(closest_encloser_from_keys(zone, name, &neighbours))
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        let prefix = zone_cuts_prefix(zone);
+        let resp = self
+            .client()
+            .get(
+                prefix.as_str(),
+                Some(GetOptions::new().with_prefix().with_keys_only()),
+            )
+            .await?;
+        let mut cuts = ZoneCuts::default();
+        for kv in resp.kvs() {
+            let key = kv.key_str()?;
+            let (domain, kind) = key[prefix.len()..]
+                .rsplit_once('/')
+                .ok_or_else(|| format!("{} is not the key of a zone cut", key))?;
+            let kind = CutKind::from_name(kind)
+                .ok_or_else(|| format!("{} is not the key of a zone cut", key))?;
+            cuts.insert(kind, LowerName::from_str(domain)?);
+        }
+        Ok(cuts)
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let mut ops = vec![TxnOp::delete(
+            rrset_key(zone, domain, rtype),
+            Some(DeleteOptions::new().with_prev_key()),
+        )];
+        ops.extend(index_ops(zone, domain, rtype, false));
+        let txn = Txn::new().and_then(ops);
+        for resp in self.client().txn(txn).await?.op_responses() {
+            if let TxnOpResponse::Delete(delete) = resp {
+                if let Some(kv) = delete.prev_kvs().first() {
+                    return Ok(serde_json::from_slice(kv.value())?);
+                }
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.update_rrset(zone, domain, record.record_type(), |records| {
+            records
+                .iter()
+                .position(|sr| sr.as_record().data() == record.data())
+                .map(|idx| records.remove(idx))
+        })
+        .await
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        // Count entries the way the Redis backend counts keys: one per domain, the journal, the
+        // DNSSEC keys and the marker.
+        let entries = self.list_domains(zone).await?.len()
+            + usize::from(self.prefix_exists(&journal_prefix(zone)).await?)
+            + usize::from(self.prefix_exists(&dnssec_keys_prefix(zone)).await?)
+            + usize::from(self.zone_exists(zone).await?);
+        if !dry_run {
+            self.apply(vec![StorageOp::DeleteZone { zone: zone.clone() }])
+                .await?;
+        }
+        Ok(entries)
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let key = rrset_key(zone, domain, rtype);
+        let op = if records.is_empty() {
+            TxnOp::delete(key, None)
+        } else {
+            TxnOp::put(
+                key,
+                serde_json::to_vec(&records)?,
+                Some(PutOptions::new().with_prev_key()),
+            )
+        };
+        let mut ops = vec![op];
+        ops.extend(index_ops(zone, domain, rtype, !records.is_empty()));
+        let txn = Txn::new().and_then(ops);
+        // Only the first operation is the RRset itself.
+        Ok(match self.client().txn(txn).await?.op_responses().first() {
+            Some(TxnOpResponse::Put(put)) => put.prev_key().is_some(),
+            Some(TxnOpResponse::Delete(delete)) => delete.deleted() > 0,
+            _ => false,
+        })
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        self.update_rrset(zone, zone, RecordType::SOA, |records| {
+            records.first_mut().and_then(|soa| soa.bump_serial(floor))
+        })
+        .await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        let usage = self.get_prefix(TOKEN_USAGE_PREFIX).await?;
+
+        let mut tokens = Vec::new();
+        for (id, encoded_token) in self.get_prefix(TOKENS_PREFIX).await? {
+            let mut token: ApiToken = serde_json::from_slice(&encoded_token)?;
+            token.last_used = usage
+                .iter()
+                .find(|(usage_id, _)| *usage_id == id)
+                .and_then(|(_, last_used)| std::str::from_utf8(last_used).ok()?.parse().ok());
+            tokens.push(token);
+        }
+
+        Ok(tokens)
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client()
+            .put(
+                format!("{}{}", TOKENS_PREFIX, token.id),
+                serde_json::to_vec(token)?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client()
+            .put(
+                format!("{}{}", TOKEN_USAGE_PREFIX, id),
+                last_used.to_string(),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        self.get_prefix(&dnssec_keys_prefix(zone))
+            .await?
+            .into_iter()
+            .map(|(_, encoded_key)| Ok(serde_json::from_slice(&encoded_key)?))
+            .collect()
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client()
+            .put(
+                format!("{}{}", dnssec_keys_prefix(zone), key.id),
+                serde_json::to_vec(key)?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let resp = self
+            .client()
+            .delete(format!("{}{}", dnssec_keys_prefix(zone), id), None)
+            .await?;
+        Ok(resp.deleted() > 0)
+    }
+
+    fn watch_zones(&self, reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        Some(Box::pin(watch_zones(self.client(), reload)))
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for EtcdStorage {
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client()
+            .put(
+                sequential_key(AUDIT_PREFIX),
+                serde_json::to_vec(entry)?,
+                None,
+            )
+            .await?;
+        if self
+            .audit_appends
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(AUDIT_TRIM_INTERVAL)
+        {
+            self.trim(AUDIT_PREFIX, MAX_AUDIT_LENGTH).await?;
+        }
+        Ok(())
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>> {
+        // Keys start with the time they were written, so the page starts at the first entry
+        // written at or after `since`, or after the last entry of the previous page.
+        let mut start = match cursor {
+            Some(cursor) => {
+                let (nanos, _) = cursor.split_once('-').ok_or(InvalidCursor)?;
+                nanos.parse::<u128>().map_err(|_| InvalidCursor)?;
+                format!("{}{}\0", AUDIT_PREFIX, cursor).into_bytes()
+            }
+            None => format!(
+                "{}{:020}",
+                AUDIT_PREFIX,
+                u128::from(since) * Duration::from_secs(1).as_nanos()
+            )
+            .into_bytes(),
+        };
+
+        let mut items = Vec::new();
+        loop {
+            let resp = self
+                .client()
+                .get(
+                    start.clone(),
+                    Some(
+                        GetOptions::new()
+                            .with_range(prefix_end(AUDIT_PREFIX))
+                            .with_limit(LIST_BATCH_SIZE),
+                    ),
+                )
+                .await?;
+            for kv in resp.kvs() {
+                let entry: AuditEntry = serde_json::from_slice(kv.value())?;
+                if entry.timestamp < since
+                    || zone.is_some_and(|zone| entry.zone.as_deref() != Some(zone))
+                {
+                    continue;
+                }
+                items.push(entry);
+                if items.len() == limit {
+                    return Ok(Page {
+                        items,
+                        next_cursor: Some(kv.key_str()?[AUDIT_PREFIX.len()..].to_string()),
+                    });
+                }
+            }
+
+            match resp.kvs().last() {
+                Some(last) if resp.more() => {
+                    start = last.key().to_vec();
+                    start.push(0);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(Page {
+            items,
+            next_cursor: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for EtcdStorage {
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>> {
+        let key = format!("{}{}", IDEMPOTENCY_PREFIX, key);
+        // Keys expire with their lease.
+        let lease = self
+            .client()
+            .lease_grant(ttl.as_secs().max(1) as i64, None)
+            .await?;
+
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let txn = Txn::new()
+                .when([Compare::version(key.as_str(), CompareOp::Equal, 0)])
+                .and_then([TxnOp::put(
+                    key.as_str(),
+                    serde_json::to_vec(record)?,
+                    Some(PutOptions::new().with_lease(lease.id())),
+                )])
+                .or_else([TxnOp::get(key.as_str(), None)]);
+            let resp = self.client().txn(txn).await?;
+            if resp.succeeded() {
+                return Ok(IdempotencyClaim::Claimed);
+            }
+            for op in resp.op_responses() {
+                if let TxnOpResponse::Get(get) = op {
+                    if let Some(kv) = get.kvs().first() {
+                        return Ok(IdempotencyClaim::Existing(serde_json::from_slice(
+                            kv.value(),
+                        )?));
+                    }
+                }
+            }
+            // The key expired between the compare and the get, so try to claim it again.
+        }
+
+        Err(format!("Idempotency key {} keeps changing concurrently", key).into())
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let lease = self
+            .client()
+            .lease_grant(ttl.as_secs().max(1) as i64, None)
+            .await?;
+        self.client()
+            .put(
+                format!("{}{}", IDEMPOTENCY_PREFIX, key),
+                serde_json::to_vec(record)?,
+                Some(PutOptions::new().with_lease(lease.id())),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client()
+            .delete(format!("{}{}", IDEMPOTENCY_PREFIX, key), None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use trust_dns_proto::rr::RecordType;
+
+    use super::{
+        affects_zone_cache, cut_key, domain_of, domain_prefix, name_key, prefix_end, rrset_key,
+        sequential_key, zone_cuts_prefix, zone_key, zone_names_prefix, zone_records_prefix,
+        EtcdStorage, AUDIT_PREFIX,
+    };
+    use crate::{
+        config::EtcdConfig,
+        dname::DNAME,
+        handle::tests::lower,
+        storage::{tests::conformance, CutKind},
+    };
+
+    /// Check if a key is in the range of keys of a prefix, as used for range requests.
+    fn in_range(key: &str, prefix: &str) -> bool {
+        key.as_bytes() >= prefix.as_bytes() && key.as_bytes() < prefix_end(prefix).as_slice()
+    }
+
+    #[test]
+    fn key_layout() {
+        let zone = lower("example.com.");
+        let www = lower("www.example.com.");
+        assert_eq!(zone_key(&zone), "/cetus/zones/example.com.");
+        assert_eq!(
+            rrset_key(&zone, &www, RecordType::AAAA),
+            "/cetus/records/example.com./www.example.com./AAAA"
+        );
+        assert_eq!(
+            name_key(&zone, &www, RecordType::A),
+            "/cetus/names/example.com./com.example.www./A"
+        );
+        assert_eq!(
+            cut_key(&zone, &lower("sub.example.com."), CutKind::Delegation),
+            "/cetus/cuts/example.com./sub.example.com./NS"
+        );
+        assert_eq!(
+            cut_key(&zone, &www, CutKind::Dname),
+            "/cetus/cuts/example.com./www.example.com./DNAME"
+        );
+        assert!(cut_key(&zone, &www, CutKind::Dname).starts_with(&zone_cuts_prefix(&zone)));
+    }
+
+    #[test]
+    fn key_ranges() {
+        let zone = lower("example.com.");
+        let www = lower("www.example.com.");
+        let key = rrset_key(&zone, &www, RecordType::A);
+        assert!(in_range(&key, &zone_records_prefix(&zone)));
+        assert!(in_range(&key, &domain_prefix(&zone, &www)));
+        assert!(in_range(
+            &name_key(&zone, &www, RecordType::A),
+            &zone_names_prefix(&zone)
+        ));
+
+        // Zones and domains which share a suffix or a prefix don't share ranges.
+        for other in ["com.", "ample.com.", "example.co.", "example.com.au."] {
+            let other = lower(other);
+            assert!(!in_range(&key, &zone_records_prefix(&other)), "{}", other);
+            assert!(!in_range(&key, &zone_names_prefix(&other)), "{}", other);
+            assert!(!in_range(
+                &cut_key(&zone, &www, CutKind::Dname),
+                &zone_cuts_prefix(&other)
+            ));
+        }
+        for other in ["ww.example.com.", "wwww.example.com.", "example.com."] {
+            assert!(
+                !in_range(&key, &domain_prefix(&zone, &lower(other))),
+                "{}",
+                other
+            );
+        }
+    }
+
+    #[test]
+    fn domains_of_keys() {
+        let zone = lower("example.com.");
+        for domain in ["example.com.", "www.example.com.", "_443._tcp.example.com."] {
+            let key = rrset_key(&zone, &lower(domain), RecordType::TXT);
+            assert_eq!(
+                domain_of(key.as_bytes(), &zone_records_prefix(&zone)).expect("Key has a domain"),
+                lower(domain)
+            );
+        }
+        assert!(domain_of(
+            zone_records_prefix(&zone).as_bytes(),
+            &zone_records_prefix(&zone)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn zone_cache_keys() {
+        let zone = lower("example.com.");
+        let sub = lower("sub.example.com.");
+        assert!(affects_zone_cache(zone_key(&zone).as_bytes()));
+        for rtype in [RecordType::SOA, RecordType::NS, DNAME] {
+            assert!(affects_zone_cache(rrset_key(&zone, &sub, rtype).as_bytes()));
+        }
+        for rtype in [RecordType::A, RecordType::TXT, RecordType::MX] {
+            assert!(!affects_zone_cache(
+                rrset_key(&zone, &sub, rtype).as_bytes()
+            ));
+        }
+        assert!(!affects_zone_cache(b"\xff\xfe"));
+    }
+
+    #[test]
+    fn sequential_keys_sort_in_order() {
+        // Keys created within the same nanosecond are in random order.
+        let keys = (0..100)
+            .map(|_| {
+                std::thread::sleep(Duration::from_micros(1));
+                sequential_key(AUDIT_PREFIX)
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert!(keys.iter().all(|key| in_range(key, AUDIT_PREFIX)));
+    }
+
+    /// Runs against the etcd endpoint in `CETUS_TEST_ETCD`, e.g. `http://127.0.0.1:2379`, and is
+    /// skipped if it is not set.
+    #[tokio::test]
+    async fn storage_conformance() {
+        let endpoint = match std::env::var("CETUS_TEST_ETCD") {
+            Ok(endpoint) => endpoint,
+            Err(_) => {
+                eprintln!("CETUS_TEST_ETCD is not set, skipping");
+                return;
+            }
+        };
+        let storage = EtcdStorage::connect(&EtcdConfig {
+            endpoints: vec![endpoint],
+            ..Default::default()
+        })
+        .await
+        .expect("etcd is reachable");
+        conformance(&storage).await;
+    }
+}
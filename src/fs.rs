@@ -1,24 +1,365 @@
-use log::{debug, error, trace};
-use std::{path::PathBuf, str::FromStr};
-use tokio::fs;
+use log::{debug, error, trace, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{fs, sync::Mutex};
+use trust_dns_proto::rr::RecordType;
 use trust_dns_server::client::rr::LowerName;
 
-use crate::storage::{Storage, StorageRecord};
+use crate::{
+    policy::ZonePolicy,
+    storage::{
+        ApiToken, DnssecKey, InvalidCursor, Page, SerialChanged, Storage, StorageOp, StorageRecord,
+        ZoneChange,
+    },
+};
 
-/// An implementation of record storage on the filesystem.
+/// Maximum amount of changes kept in the journal of a zone.
+const MAX_JOURNAL_LENGTH: usize = 1_000;
+
+/// Directory in the base directory in which zones added by a batch are built. Zone directories
+/// are named after the zone, so always end in a dot, unlike this one.
+const STAGING_DIR: &str = "staging";
+/// File in the base directory with the API tokens, by id.
+const TOKENS_FILE: &str = "tokens.json";
+/// File in the base directory with the last used time of the API tokens, by id.
+const TOKEN_USAGE_FILE: &str = "token_usage.json";
+/// File in the directory of a zone with its policy.
+const POLICY_FILE: &str = "policy.json";
+/// File in the directory of a zone with its journal.
+const JOURNAL_FILE: &str = "journal.json";
+/// File in the directory of a zone with its DNSSEC keys, by id.
+const DNSSEC_KEYS_FILE: &str = "dnssec_keys.json";
+
+/// An implementation of record storage on the filesystem. Every zone is a directory in the base
+/// directory, with a directory for every domain in it. The records of a type are stored in a file
+/// named after the type in the directory of their domain, as a JSON array.
+///
+/// Other data is kept in JSON files next to these directories: the API tokens in the base
+/// directory, and the policy, journal and DNSSEC keys of a zone in the directory of the zone.
+/// Names always end in a dot, so these files never clash with a zone or domain.
 pub struct FSStorage {
     base: PathBuf,
+    /// Serializes writes, as changing a record file means reading and rewriting it.
+    write_lock: Mutex<()>,
 }
 
 impl FSStorage {
     #[allow(dead_code)]
     pub fn new(base: PathBuf) -> Self {
-        Self { base }
+        Self {
+            base,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Path of the directory of a zone.
+    fn zone_path(
+        &self,
+        zone: &LowerName,
+    ) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        let mut path = self.base.clone();
+        path.push(path_component(&zone.to_string())?);
+        Ok(path)
+    }
+
+    /// Path of the directory of a domain in a zone.
+    fn domain_path(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        let mut path = self.zone_path(zone)?;
+        path.push(path_component(&domain.to_string())?);
+        Ok(path)
+    }
+
+    /// Path of the file with an RRset of a domain in a zone.
+    fn rrset_path(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.domain_path(zone, domain)?.join(rtype.to_string()))
+    }
+
+    /// Apply the changes of a batch, see [Storage::apply]. Zones added by the batch are built in
+    /// the staging directory, and moved in place once all changes are applied, so they appear
+    /// with all of their records at once. Changes to zones which already exist are recorded in
+    /// the undo log. The serials of swapped SOAs are checked before anything is changed. Must be
+    /// called with the write lock held.
+    async fn apply_ops(
+        &self,
+        ops: Vec<StorageOp>,
+        staging: &Path,
+        undo: &mut Vec<Undo>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for op in &ops {
+            if let StorageOp::SwapSoa { zone, serial, .. } = op {
+                let soas = read_records(&self.rrset_path(zone, zone, RecordType::SOA)?).await?;
+                if soas.first().and_then(StorageRecord::serial) != Some(*serial) {
+                    return Err(SerialChanged.into());
+                }
+            }
+        }
+
+        // The directory of a zone is its marker, so every change to a new zone is staged, also
+        // the changes before the zone is added.
+        let mut staged: HashMap<LowerName, Staged> = HashMap::new();
+        for op in &ops {
+            if let StorageOp::AddZone { zone } = op {
+                if staged.contains_key(zone)
+                    || fs::metadata(self.zone_path(zone)?)
+                        .await
+                        .is_ok_and(|meta| meta.is_dir())
+                {
+                    continue;
+                }
+                let path = staging.join(path_component(&zone.to_string())?);
+                fs::create_dir_all(&path).await?;
+                staged.insert(zone.clone(), Staged { path, added: false });
+            }
+        }
+
+        for op in ops {
+            match op {
+                StorageOp::AddZone { zone } => {
+                    if let Some(staged) = staged.get_mut(&zone) {
+                        staged.added = true;
+                    }
+                }
+                StorageOp::SetRecords {
+                    zone,
+                    domain,
+                    rtype,
+                    records,
+                } => {
+                    self.apply_rrset(&staged, undo, &zone, &domain, rtype, &records)
+                        .await?;
+                }
+                StorageOp::RemoveRrset {
+                    zone,
+                    domain,
+                    rtype,
+                } => {
+                    self.apply_rrset(&staged, undo, &zone, &domain, rtype, &[])
+                        .await?;
+                }
+                StorageOp::DeleteZone { zone } => {
+                    if let Some(staged) = staged.get_mut(&zone) {
+                        fs::remove_dir_all(&staged.path).await?;
+                        fs::create_dir_all(&staged.path).await?;
+                        staged.added = false;
+                        continue;
+                    }
+                    // Removing a zone is never undone, so neither are earlier changes to it.
+                    let path = self.zone_path(&zone)?;
+                    undo.retain(|entry| !entry.is_in(&path));
+                    match fs::remove_dir_all(&path).await {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+                StorageOp::SwapSoa { zone, soa, .. } => {
+                    self.apply_rrset(&staged, undo, &zone, &zone, RecordType::SOA, &[*soa])
+                        .await?;
+                }
+            }
+        }
+
+        // Staged zones which end up not being added are removed with the staging directory.
+        for (zone, staged) in staged {
+            if staged.added {
+                let path = self.zone_path(&zone)?;
+                fs::rename(&staged.path, &path).await?;
+                undo.push(Undo::Zone(path));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace an RRset as part of a batch, in the staging directory if its zone is added by the
+    /// batch.
+    async fn apply_rrset(
+        &self,
+        staged: &HashMap<LowerName, Staged>,
+        undo: &mut Vec<Undo>,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: &[StorageRecord],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(staged) = staged.get(zone) {
+            write_rrset(&staged.path, domain, rtype, records).await?;
+            return Ok(());
+        }
+
+        let zone_path = self.zone_path(zone)?;
+        let previous = read_records(&self.rrset_path(zone, domain, rtype)?).await?;
+        // Log the change first, a failed write might have partially changed the RRset.
+        undo.push(Undo::Rrset {
+            zone_path: zone_path.clone(),
+            domain: domain.clone(),
+            rtype,
+            records: previous,
+        });
+        write_rrset(&zone_path, domain, rtype, records).await?;
+        Ok(())
+    }
+}
+
+/// A zone which is added by a batch, see [FSStorage::apply_ops].
+struct Staged {
+    /// Directory in which the zone is built.
+    path: PathBuf,
+    /// Whether the zone is added by the batch, after the last time it was removed.
+    added: bool,
+}
+
+/// A change made by a batch which was not applied in full, which is reverted.
+enum Undo {
+    /// Restore the previous records of an RRset.
+    Rrset {
+        zone_path: PathBuf,
+        domain: LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    },
+    /// Remove a zone which was moved in place.
+    Zone(PathBuf),
+}
+
+impl Undo {
+    /// Check if the change is in the zone at the given path.
+    fn is_in(&self, path: &Path) -> bool {
+        match self {
+            Undo::Rrset { zone_path, .. } => zone_path == path,
+            Undo::Zone(zone_path) => zone_path == path,
+        }
+    }
+
+    async fn revert(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Undo::Rrset {
+                zone_path,
+                domain,
+                rtype,
+                records,
+            } => {
+                write_rrset(&zone_path, &domain, rtype, &records).await?;
+            }
+            Undo::Zone(path) => fs::remove_dir_all(&path).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Verify a name can be used as a single path component, so a crafted name can't point outside
+/// of the base directory or into another zone.
+fn path_component(name: &str) -> Result<&str, Box<dyn std::error::Error + Send + Sync>> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None)
+            if component == name && !name.contains(['/', '\\', '\0']) =>
+        {
+            Ok(name)
+        }
+        _ => Err(format!("{} can't be stored on the filesystem", name).into()),
+    }
+}
+
+/// Read a file with records, a missing file has no records.
+async fn read_records(
+    path: &Path,
+) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    read_json(path).await
+}
+
+/// Read a JSON file, a missing file holds the default value.
+async fn read_json<T: DeserializeOwned + Default>(
+    path: &Path,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    match fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the directory of a domain if it has no record files left, so the domain no longer
+/// exists. Must be called with the write lock held, so no records are added meanwhile.
+async fn remove_empty_domain(path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut dir_reader = fs::read_dir(path).await?;
+    if dir_reader.next_entry().await?.is_none() {
+        trace!("Removing empty domain directory {:?}", path);
+        fs::remove_dir(path).await?;
+    }
+    Ok(())
+}
+
+/// Replace an RRset of a domain in the zone at the given path, removing it if there are no
+/// records, and the domain if it has no RRsets left. Must be called with the write lock held.
+/// Returns whether the RRset existed.
+async fn write_rrset(
+    zone_path: &Path,
+    domain: &LowerName,
+    rtype: RecordType,
+    records: &[StorageRecord],
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let domain_path = zone_path.join(path_component(&domain.to_string())?);
+    let path = domain_path.join(rtype.to_string());
+
+    let existed = fs::metadata(&path).await.is_ok();
+    if records.is_empty() {
+        if existed {
+            fs::remove_file(&path).await?;
+            remove_empty_domain(&domain_path).await?;
+        }
+        return Ok(existed);
     }
+
+    fs::create_dir_all(&domain_path).await?;
+    write_records(&path, records).await?;
+
+    Ok(existed)
+}
+
+/// Write a file with records, see [write_json].
+async fn write_records(
+    path: &Path,
+    records: &[StorageRecord],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    write_json(path, records).await
+}
+
+/// Write a JSON file. The value is written to a temporary file first, which then replaces the
+/// file, so readers never see a partially written file.
+async fn write_json<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
 }
 
 #[async_trait::async_trait]
 impl Storage for FSStorage {
+    async fn ping(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let meta = fs::metadata(&self.base).await?;
+        if !meta.is_dir() {
+            return Err(format!("{:?} is not a directory", self.base).into());
+        }
+        Ok(())
+    }
+
     async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
         trace!("Reading zones from {:?}", self.base);
         let mut zones = Vec::new();
@@ -36,6 +377,9 @@ impl Storage for FSStorage {
                     continue;
                 }
             };
+            if name == STAGING_DIR {
+                continue;
+            }
 
             let name = LowerName::from_str(&name)?;
 
@@ -47,6 +391,42 @@ impl Storage for FSStorage {
         Ok(zones)
     }
 
+    async fn zone_exists(
+        &self,
+        zone: &LowerName,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?;
+
+        Ok(fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()))
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
+        // The cursor is the last zone of the previous page, in the order of the sorted zones.
+        let after = cursor
+            .map(LowerName::from_str)
+            .transpose()
+            .map_err(|_| InvalidCursor)?;
+        let mut zones = self.zones().await?;
+        zones.sort();
+        let mut items = zones
+            .into_iter()
+            .filter(|zone| after.as_ref().is_none_or(|after| zone > after))
+            .take(limit + 1)
+            .collect::<Vec<_>>();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|zone| zone.to_string())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
     async fn lookup_records(
         &self,
         domain: &LowerName,
@@ -54,9 +434,7 @@ impl Storage for FSStorage {
         rtype: trust_dns_proto::rr::RecordType,
     ) -> Result<Option<Vec<crate::storage::StorageRecord>>, Box<dyn std::error::Error + Send + Sync>>
     {
-        let mut path = self.base.clone();
-        path.push(zone.to_string());
-        path.push(domain.to_string());
+        let mut path = self.domain_path(zone, domain)?;
 
         // First check if the dir exists, per the contract of this function we should return
         // Ok(None) if it does not.
@@ -76,34 +454,736 @@ impl Storage for FSStorage {
         Ok(Some(serde_json::from_slice(&data)?))
     }
 
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.domain_path(zone, domain)?;
+        if fs::metadata(&path).await.is_err() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.list_records(zone, domain).await?))
+    }
+
+    #[cfg(test)]
     async fn add_zone(
         &self,
-        _zone: &LowerName,
+        zone: &LowerName,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        todo!();
+        let path = self.zone_path(zone)?;
+        trace!("Creating zone directory {:?}", path);
+        let _guard = self.write_lock.lock().await;
+        fs::create_dir_all(&path).await?;
+        // Like the other backends, this resets the policy of an existing zone.
+        match fs::remove_file(path.join(POLICY_FILE)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
     }
 
+    #[cfg(test)]
     async fn add_record(
         &self,
-        _zone: &LowerName,
-        _domain: &LowerName,
-        _record: StorageRecord,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        todo!();
+        let mut path = self.domain_path(zone, domain)?;
+        let _guard = self.write_lock.lock().await;
+        fs::create_dir_all(&path).await?;
+        path.push(record.record.record_type().to_string());
+
+        let mut record_set = read_records(&path).await?;
+        record_set.push(record);
+        write_records(&path, &record_set).await
     }
 
     async fn list_records(
         &self,
-        _zone: &LowerName,
-        _domain: &LowerName,
+        zone: &LowerName,
+        domain: &LowerName,
     ) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
-        todo!();
+        let path = self.domain_path(zone, domain)?;
+        let mut dir_reader = match fs::read_dir(&path).await {
+            Ok(dir_reader) => dir_reader,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = Vec::new();
+        while let Some(entry) = dir_reader.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            // Leftovers of interrupted writes are not part of the domain.
+            if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                continue;
+            }
+            records.extend(read_records(&entry.path()).await?);
+        }
+
+        Ok(records)
     }
 
     async fn list_domains(
         &self,
-        _zone: &LowerName,
+        zone: &LowerName,
     ) -> Result<Vec<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
-        todo!();
+        let path = self.zone_path(zone)?;
+        let mut domains = Vec::new();
+        let mut dir_reader = match fs::read_dir(&path).await {
+            Ok(dir_reader) => dir_reader,
+            // A zone which does not exist has no domains.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(domains),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = dir_reader.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => {
+                    error!("could not convert dir name to String");
+                    continue;
+                }
+            };
+
+            domains.push(LowerName::from_str(&name)?);
+        }
+
+        Ok(domains)
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
+        // Same as zones, the cursor is the last domain of the previous page.
+        let after = cursor
+            .map(LowerName::from_str)
+            .transpose()
+            .map_err(|_| InvalidCursor)?;
+        let mut domains = self.list_domains(zone).await?;
+        domains.sort();
+        let mut items = domains
+            .into_iter()
+            .filter(|domain| after.as_ref().is_none_or(|after| domain > after))
+            .take(limit + 1)
+            .collect::<Vec<_>>();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|domain| domain.to_string())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &trust_dns_server::client::rr::LowerName,
+    ) -> Result<ZonePolicy, Box<dyn std::error::Error + Send + Sync>> {
+        read_json(&self.zone_path(zone)?.join(POLICY_FILE)).await
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &trust_dns_server::client::rr::LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?;
+        let _guard = self.write_lock.lock().await;
+        // This must never create a zone.
+        if !fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()) {
+            return Ok(());
+        }
+        write_json(&path.join(POLICY_FILE), policy).await
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?;
+        let _guard = self.write_lock.lock().await;
+        // Like the policy, a journal does not make a zone exist.
+        if !fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()) {
+            return Ok(());
+        }
+        let path = path.join(JOURNAL_FILE);
+        let mut journal: VecDeque<ZoneChange> = read_json(&path).await?;
+        journal.push_back(change.clone());
+        if journal.len() > MAX_JOURNAL_LENGTH {
+            journal.pop_front();
+        }
+        write_json(&path, &journal).await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn std::error::Error + Send + Sync>> {
+        let journal = read_json(&self.zone_path(zone)?.join(JOURNAL_FILE)).await?;
+        Ok(ZoneChange::since(journal, serial))
+    }
+
+    async fn apply(
+        &self,
+        ops: Vec<StorageOp>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let staging = self.base.join(STAGING_DIR);
+        let _guard = self.write_lock.lock().await;
+        // Zones of a batch which was interrupted were never moved in place.
+        match fs::remove_dir_all(&staging).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut undo = Vec::new();
+        let result = self.apply_ops(ops, &staging, &mut undo).await;
+        if result.is_err() {
+            for entry in undo.into_iter().rev() {
+                if let Err(e) = entry.revert().await {
+                    warn!("Could not revert change of failed batch: {}", e);
+                }
+            }
+        }
+        if let Err(e) = fs::remove_dir_all(&staging).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not clean up staging directory {:?}: {}", staging, e);
+            }
+        }
+        result
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let domains = match self.list_domains(zone).await {
+            Ok(domains) => domains,
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(domains
+            .iter()
+            .any(|name| name != domain && domain.zone_of(name)))
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: trust_dns_proto::rr::RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+        let domain_path = self.domain_path(zone, domain)?;
+        let path = domain_path.join(rtype.to_string());
+        let _guard = self.write_lock.lock().await;
+
+        let record_set = read_records(&path).await?;
+        if record_set.is_empty() {
+            return Ok(record_set);
+        }
+        fs::remove_file(&path).await?;
+        remove_empty_domain(&domain_path).await?;
+
+        Ok(record_set)
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &trust_dns_proto::rr::Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+        let domain_path = self.domain_path(zone, domain)?;
+        let path = domain_path.join(record.record_type().to_string());
+        let _guard = self.write_lock.lock().await;
+
+        let mut record_set = read_records(&path).await?;
+        let removed = match record_set
+            .iter()
+            .position(|sr| sr.as_record().data() == record.data())
+        {
+            Some(idx) => record_set.remove(idx),
+            None => return Ok(None),
+        };
+
+        if record_set.is_empty() {
+            fs::remove_file(&path).await?;
+            remove_empty_domain(&domain_path).await?;
+        } else {
+            write_records(&path, &record_set).await?;
+        }
+
+        Ok(Some(removed))
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?;
+        let _guard = self.write_lock.lock().await;
+        if !fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()) {
+            return Ok(0);
+        }
+
+        let domains = self.list_domains(zone).await?;
+        if dry_run {
+            return Ok(domains.len() + 1);
+        }
+
+        // The zone directory goes last, so the zone stays around to retry if removing any of its
+        // domains fails.
+        for (removed, domain) in domains.iter().enumerate() {
+            fs::remove_dir_all(self.domain_path(zone, domain)?).await?;
+            if (removed + 1) % 100 == 0 {
+                debug!(
+                    "Removed {} of {} domains of zone {}",
+                    removed + 1,
+                    domains.len(),
+                    zone
+                );
+            }
+        }
+        fs::remove_dir_all(&path).await?;
+
+        Ok(domains.len() + 1)
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: trust_dns_proto::rr::RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let zone_path = self.zone_path(zone)?;
+        let _guard = self.write_lock.lock().await;
+        write_rrset(&zone_path, domain, rtype, &records).await
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.rrset_path(zone, zone, RecordType::SOA)?;
+        let _guard = self.write_lock.lock().await;
+        let mut records = read_records(&path).await?;
+        let serials = match records.first_mut().and_then(|soa| soa.bump_serial(floor)) {
+            Some(serials) => serials,
+            None => return Ok(None),
+        };
+        write_records(&path, &records).await?;
+        Ok(Some(serials))
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn std::error::Error + Send + Sync>> {
+        let tokens: HashMap<String, ApiToken> = read_json(&self.base.join(TOKENS_FILE)).await?;
+        let usage: HashMap<String, u64> = read_json(&self.base.join(TOKEN_USAGE_FILE)).await?;
+        Ok(tokens
+            .into_values()
+            .map(|token| ApiToken {
+                last_used: usage.get(&token.id).copied(),
+                ..token
+            })
+            .collect())
+    }
+
+    async fn set_token(
+        &self,
+        token: &ApiToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.base.join(TOKENS_FILE);
+        let _guard = self.write_lock.lock().await;
+        let mut tokens: HashMap<String, ApiToken> = read_json(&path).await?;
+        tokens.insert(
+            token.id.clone(),
+            ApiToken {
+                last_used: None,
+                ..token.clone()
+            },
+        );
+        write_json(&path, &tokens).await
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.base.join(TOKEN_USAGE_FILE);
+        let _guard = self.write_lock.lock().await;
+        let mut usage: HashMap<String, u64> = read_json(&path).await?;
+        usage.insert(id.to_string(), last_used);
+        write_json(&path, &usage).await
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn std::error::Error + Send + Sync>> {
+        let keys: BTreeMap<String, DnssecKey> =
+            read_json(&self.zone_path(zone)?.join(DNSSEC_KEYS_FILE)).await?;
+        Ok(keys.into_values().collect())
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?;
+        let _guard = self.write_lock.lock().await;
+        // Keys are only kept for zones which exist.
+        if !fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()) {
+            return Err(format!("zone {} does not exist", zone).into());
+        }
+        let path = path.join(DNSSEC_KEYS_FILE);
+        let mut keys: BTreeMap<String, DnssecKey> = read_json(&path).await?;
+        keys.insert(key.id.clone(), key.clone());
+        write_json(&path, &keys).await
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.zone_path(zone)?.join(DNSSEC_KEYS_FILE);
+        let _guard = self.write_lock.lock().await;
+        let mut keys: BTreeMap<String, DnssecKey> = read_json(&path).await?;
+        if keys.remove(id).is_none() {
+            return Ok(false);
+        }
+        if keys.is_empty() {
+            fs::remove_file(&path).await?;
+        } else {
+            write_json(&path, &keys).await?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+    };
+
+    use trust_dns_proto::rr::{
+        rdata::{MX, TXT},
+        Name, RData, Record, RecordType,
+    };
+    use trust_dns_server::client::{op::ResponseCode, rr::LowerName};
+
+    use super::FSStorage;
+    use crate::{
+        handle::tests::{
+            a, add_zone, assert_delegations, assert_empty_non_terminals, assert_wildcards, lower,
+            name_of, record, soa, TestServer,
+        },
+        storage::{tests::conformance, Storage, StorageOp, StorageRecord, ZoneChange},
+    };
+
+    #[tokio::test]
+    async fn empty_non_terminals() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        assert_empty_non_terminals(Arc::new(FSStorage::new(dir.path().to_path_buf()))).await;
+    }
+
+    #[tokio::test]
+    async fn wildcards() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        assert_wildcards(Arc::new(FSStorage::new(dir.path().to_path_buf()))).await;
+    }
+
+    #[tokio::test]
+    async fn storage_conformance() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        conformance(&FSStorage::new(dir.path().to_path_buf())).await;
+    }
+
+    #[tokio::test]
+    async fn delegations() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        assert_delegations(Arc::new(FSStorage::new(dir.path().to_path_buf()))).await;
+    }
+
+    #[tokio::test]
+    async fn serves_records_of_several_types() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = Arc::new(FSStorage::new(dir.path().to_path_buf()));
+        add_zone(
+            &*storage,
+            "example.com.",
+            vec![
+                a("www.example.com.", [192, 0, 2, 1]),
+                a("www.example.com.", [192, 0, 2, 2]),
+                record(
+                    "www.example.com.",
+                    300,
+                    RData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                ),
+                record(
+                    "example.com.",
+                    300,
+                    RData::MX(MX::new(10, name_of("mail.example.com."))),
+                ),
+                record(
+                    "example.com.",
+                    300,
+                    RData::TXT(TXT::new(vec!["v=spf1 -all".to_string()])),
+                ),
+            ],
+        )
+        .await;
+        let server = TestServer::start(storage).await;
+
+        let response = server.query_udp("www.example.com.", RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let mut addresses = response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(ip)) => Some(*ip),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+        );
+
+        for (name, rtype) in [
+            ("www.example.com.", RecordType::AAAA),
+            ("example.com.", RecordType::MX),
+            ("example.com.", RecordType::TXT),
+            ("example.com.", RecordType::SOA),
+        ] {
+            let response = server.query_tcp(name, rtype).await;
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert_eq!(response.answers().len(), 1, "{} {}", name, rtype);
+            assert_eq!(response.answers()[0].record_type(), rtype);
+        }
+
+        let response = server
+            .query_udp("missing.example.com.", RecordType::A)
+            .await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn keeps_zone_metadata() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = FSStorage::new(dir.path().to_path_buf());
+        let zone = lower("example.com.");
+        add_zone(
+            &storage,
+            "example.com.",
+            vec![a("a.b.example.com.", [192, 0, 2, 1])],
+        )
+        .await;
+
+        // Files next to the domains are not domains themselves.
+        let change = ZoneChange {
+            from_serial: 1,
+            serial: 2,
+            removed: vec![soa("example.com.", 1)],
+            added: vec![soa("example.com.", 2)],
+        };
+        storage.append_change(&zone, &change).await.unwrap();
+        assert_eq!(
+            storage
+                .changes_since(&zone, 1)
+                .await
+                .unwrap()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(storage.changes_since(&zone, 0).await.unwrap().is_none());
+        let mut domains = storage.list_domains(&zone).await.unwrap();
+        domains.sort();
+        assert_eq!(
+            domains,
+            vec![lower("example.com."), lower("a.b.example.com.")]
+        );
+
+        assert_eq!(
+            storage.bump_serial(&zone, None).await.unwrap(),
+            Some((1, 2))
+        );
+        assert_eq!(
+            storage.bump_serial(&zone, Some(100)).await.unwrap(),
+            Some((2, 100))
+        );
+        assert_eq!(
+            storage
+                .bump_serial(&lower("example.org."), None)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(storage
+            .has_subdomains(&zone, &lower("b.example.com."))
+            .await
+            .unwrap());
+        assert!(!storage
+            .has_subdomains(&zone, &lower("a.b.example.com."))
+            .await
+            .unwrap());
+        assert!(!storage
+            .has_subdomains(&lower("example.org."), &lower("example.org."))
+            .await
+            .unwrap());
+
+        // Policies never create a zone.
+        let policy = storage.zone_policy(&zone).await.unwrap();
+        storage
+            .set_zone_policy(&lower("example.org."), &policy)
+            .await
+            .unwrap();
+        assert!(!storage.zone_exists(&lower("example.org.")).await.unwrap());
+        assert_eq!(storage.zones().await.unwrap(), vec![zone]);
+    }
+
+    #[tokio::test]
+    async fn applies_batches_in_full_or_not_at_all() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = FSStorage::new(dir.path().to_path_buf());
+        let zone = lower("example.com.");
+        let www = lower("www.example.com.");
+        let ns = record("example.com.", 300, RData::NS(name_of("ns1.example.com.")));
+
+        // Zones are created the way the API does, with the marker last.
+        storage
+            .apply(vec![
+                StorageOp::SetRecords {
+                    zone: zone.clone(),
+                    domain: zone.clone(),
+                    rtype: RecordType::SOA,
+                    records: vec![soa("example.com.", 1)],
+                },
+                StorageOp::SetRecords {
+                    zone: zone.clone(),
+                    domain: zone.clone(),
+                    rtype: RecordType::NS,
+                    records: vec![ns],
+                },
+                StorageOp::AddZone { zone: zone.clone() },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(storage.zones().await.unwrap(), vec![zone.clone()]);
+        assert_eq!(storage.list_records(&zone, &zone).await.unwrap().len(), 2);
+
+        storage
+            .set_records(
+                &zone,
+                &www,
+                RecordType::A,
+                vec![a("www.example.com.", [192, 0, 2, 1])],
+            )
+            .await
+            .unwrap();
+
+        // The second zone can't be stored, so neither the new address nor the zone are kept.
+        let invalid = LowerName::from(Name::from_labels(vec![&b"a/b"[..]]).unwrap());
+        let result = storage
+            .apply(vec![
+                StorageOp::SetRecords {
+                    zone: zone.clone(),
+                    domain: www.clone(),
+                    rtype: RecordType::A,
+                    records: vec![a("www.example.com.", [192, 0, 2, 2])],
+                },
+                StorageOp::SetRecords {
+                    zone: lower("example.org."),
+                    domain: lower("example.org."),
+                    rtype: RecordType::SOA,
+                    records: vec![soa("example.org.", 1)],
+                },
+                StorageOp::AddZone {
+                    zone: lower("example.org."),
+                },
+                StorageOp::RemoveRrset {
+                    zone: zone.clone(),
+                    domain: invalid,
+                    rtype: RecordType::A,
+                },
+            ])
+            .await;
+        assert!(result.is_err());
+        assert_eq!(storage.zones().await.unwrap(), vec![zone.clone()]);
+        let records = storage
+            .lookup_records(&www, &zone, RecordType::A)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            records[0].as_record().data(),
+            Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+        );
+
+        // A zone removed and added again in the same batch only keeps the later records.
+        storage
+            .apply(vec![
+                StorageOp::DeleteZone { zone: zone.clone() },
+                StorageOp::SetRecords {
+                    zone: zone.clone(),
+                    domain: zone.clone(),
+                    rtype: RecordType::SOA,
+                    records: vec![soa("example.com.", 5)],
+                },
+                StorageOp::AddZone { zone: zone.clone() },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.list_domains(&zone).await.unwrap(),
+            vec![zone.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn names_stay_in_the_base_directory() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = FSStorage::new(dir.path().join("zones"));
+        let zone = lower("example.com.");
+        let name = Name::from_labels(vec![&b"a/b"[..], b"example", b"com"]).unwrap();
+        let record = StorageRecord::new(Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+        let name = LowerName::from(name);
+        assert!(storage.add_zone(&name).await.is_err());
+        assert!(storage.add_record(&zone, &name, record).await.is_err());
+        assert!(storage
+            .lookup_records(&name, &zone, RecordType::A)
+            .await
+            .is_err());
     }
 }
@@ -5,26 +5,75 @@ use std::path::Path;
 use log::trace;
 
 use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::storage::StorageRecord;
+
+/// Continent codes used by the GeoIP database.
+pub const CONTINENT_CODES: [&str; 7] = ["AF", "AN", "AS", "EU", "NA", "OC", "SA"];
+
+/// Officially assigned ISO 3166-1 alpha-2 country codes, sorted.
+pub const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
 
 pub struct GeoLocator {
-    reader: Reader<Vec<u8>>,
+    source: GeoSource,
+}
+
+/// Where a [`GeoLocator`] gets the location of an IP from.
+enum GeoSource {
+    Database(Reader<Vec<u8>>),
+    /// Every IP is in the same country and continent.
+    #[cfg(test)]
+    Fixed(Option<String>, Option<String>),
 }
 
 impl GeoLocator {
     /// Create a new [`GeoLocator`] object using the database at the given path.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
         Ok(GeoLocator {
-            reader: Reader::open_readfile(path)?,
+            source: GeoSource::Database(Reader::open_readfile(path)?),
         })
     }
 
+    /// Create a [`GeoLocator`] which places every IP in the given country and continent.
+    #[cfg(test)]
+    pub fn fixed(country: Option<&str>, continent: Option<&str>) -> Self {
+        GeoLocator {
+            source: GeoSource::Fixed(country.map(String::from), continent.map(String::from)),
+        }
+    }
+
     /// Look up an IP in the database and return the country ISO code if found.
     pub fn lookup_ip(
         &self,
         ip_addr: IpAddr,
     ) -> Result<(Option<String>, Option<String>), Box<dyn Error + Send + Sync>> {
         trace!("lookup IP {}", ip_addr);
-        let country = self.reader.lookup::<geoip2::Country>(ip_addr)?;
+        let country = match self.source {
+            GeoSource::Database(ref reader) => reader.lookup::<geoip2::Country>(ip_addr)?,
+            #[cfg(test)]
+            GeoSource::Fixed(ref country, ref continent) => {
+                return Ok((country.clone(), continent.clone()))
+            }
+        };
         Ok((
             country
                 .country
@@ -35,3 +84,54 @@ impl GeoLocator {
         ))
     }
 }
+
+/// Restricts a record to clients in specific locations. Records with a policy are only served to
+/// clients in one of the listed countries or continents.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoPolicy {
+    /// ISO 3166-1 alpha-2 country codes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub countries: Vec<String>,
+    /// Continent codes, see [`CONTINENT_CODES`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub continents: Vec<String>,
+}
+
+/// Select the records of an RRset to serve to a client in the given location. Records for the
+/// country of the client are preferred, then records for its continent, and finally records
+/// without a geo policy. If nothing matches at all the full set is kept, so a name never goes
+/// missing because of a gap in the policies.
+///
+/// Returns true if the selection depends on the client location, i.e. any record in the set has
+/// a geo policy. Sets without policies are left untouched.
+pub fn steer(
+    records: &mut Vec<StorageRecord>,
+    country: Option<&str>,
+    continent: Option<&str>,
+) -> bool {
+    if records.iter().all(|sr| sr.geo.is_none()) {
+        return false;
+    }
+
+    // Lower ranks are better matches, records which don't match have no rank.
+    let rank = |sr: &StorageRecord| match sr.geo {
+        None => Some(2),
+        Some(ref geo) => {
+            let listed = |codes: &[String], code: Option<&str>| {
+                code.is_some_and(|code| codes.iter().any(|c| c.eq_ignore_ascii_case(code)))
+            };
+            if listed(&geo.countries, country) {
+                Some(0)
+            } else if listed(&geo.continents, continent) {
+                Some(1)
+            } else {
+                None
+            }
+        }
+    };
+
+    if let Some(best) = records.iter().filter_map(rank).min() {
+        records.retain(|sr| rank(sr) == Some(best));
+    }
+    true
+}
@@ -1,32 +1,195 @@
 use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
     future::Future,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicPtr, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
     time::Duration,
 };
 
+use arc_swap::ArcSwap;
+use futures_util::{future, stream, StreamExt};
 use log::{debug, error, info, trace, warn};
-use trust_dns_proto::rr::DNSClass;
+use rand::seq::SliceRandom;
+use tokio::time::Instant;
+use trust_dns_proto::{
+    rr::{rdata::HINFO, DNSClass, Name, RData, Record, RecordType},
+    serialize::binary::{BinEncodable, BinEncoder},
+};
 use trust_dns_server::{
     authority::MessageResponseBuilder,
     client::{
-        op::{LowerQuery, MessageType, OpCode, ResponseCode},
+        op::{Edns, Header, LowerQuery, MessageType, OpCode, ResponseCode},
         rr::LowerName,
     },
-    server::{RequestHandler, ResponseInfo},
+    server::{Protocol, RequestHandler, ResponseInfo},
+};
+
+use crate::{
+    ecs::ClientSubnet,
+    ede::ExtendedError,
+    geo::{self, GeoLocator},
+    health::HealthChecker,
+    metrics::Metrics,
+    notify::Notifier,
+    policy::{AnswerOrder, AnyOverUdp, AnyResponse, ResponsePolicy, ZonePolicy},
+    prefix::IpPrefix,
+    reload::{Reload, ZoneReload},
+    rrl::{RateLimitedResponseHandle, RateLimiter},
+    status::Status,
+    storage::{Storage, StorageRecord, ZoneCuts},
+    trace::{QueryTrace, QueryTracer},
+    weight,
 };
 
-use crate::{geo::GeoLocator, metrics::Metrics, storage::Storage};
+mod chaos;
+mod dname;
+mod svcb;
+#[cfg(test)]
+pub(crate) mod tests;
+mod transfer;
+mod update;
+
+pub use chaos::ChaosIdentity;
+
+/// CPU field of the HINFO record synthesized for minimal ANY responses (RFC 8482).
+const ANY_HINFO_CPU: &str = "RFC8482";
+
+/// Maximum amount of CNAME records followed to answer a single query.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Size of a DNS message over UDP if the client did not advertise a larger size with EDNS.
+const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// Maximum amount of zones loaded from storage at once when refreshing the zone cache.
+const ZONE_LOAD_CONCURRENCY: usize = 64;
+
+/// We don't expect frequent updates of the Zone list, so it is kept behind an [ArcSwap]. Readers
+/// get the current list without locking, and the loader swaps in a new list as a whole when it
+/// changes. Zones are keyed by name, so the authority of a query is found by walking up from the
+/// query name. Every zone is stored together with its [ZonePolicy] overrides and SOA records.
+type ZoneCache = ArcSwap<HashMap<LowerName, CachedZone>>;
+
+/// The data of a zone kept in the [ZoneCache].
+#[derive(Clone)]
+struct CachedZone {
+    policy: ZonePolicy,
+    /// The SOA RRset of the zone, or [None] if it is missing or could not be loaded, in which
+    /// case it is looked up in storage for every query.
+    soa: Option<Vec<StorageRecord>>,
+    /// The zone cuts, or [None] if they could not be loaded, in which case every ancestor of a
+    /// query name is checked in storage.
+    cuts: Option<Arc<ZoneCuts>>,
+}
+
+impl CachedZone {
+    fn same_as(&self, other: &CachedZone) -> bool {
+        self.policy == other.policy
+            && self.cuts == other.cuts
+            && match (&self.soa, &other.soa) {
+                (Some(soa), Some(other_soa)) => {
+                    soa.len() == other_soa.len()
+                        && soa.iter().zip(other_soa).all(|(a, b)| {
+                            // Record equality does not consider the TTL.
+                            a.as_record() == b.as_record()
+                                && a.as_record().ttl() == b.as_record().ttl()
+                        })
+                }
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// Timing of a single query in a zone.
+struct QueryClock {
+    start: Instant,
+    /// Storage lookups for the query must finish by this time, by then the client has likely
+    /// given up on us already.
+    deadline: Instant,
+    /// Total time spent waiting on storage, in microseconds.
+    storage_micros: AtomicU64,
+    /// Country the client is located in, if known.
+    country: OnceLock<String>,
+}
+
+impl QueryClock {
+    fn new(storage_timeout: Duration) -> Self {
+        let start = Instant::now();
+        QueryClock {
+            start,
+            deadline: start + storage_timeout,
+            storage_micros: AtomicU64::new(0),
+            country: OnceLock::new(),
+        }
+    }
+
+    fn add_storage_time(&self, time: Duration) {
+        self.storage_micros
+            .fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn storage_time(&self) -> Duration {
+        Duration::from_micros(self.storage_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Error of a storage operation which did not finish before the deadline of a query.
+#[derive(Debug)]
+struct StorageTimeout;
+
+impl fmt::Display for StorageTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("storage did not answer before the query deadline")
+    }
+}
+
+impl Error for StorageTimeout {}
+
+/// Get the extended error to report for a failed storage operation.
+fn storage_error(e: &(dyn Error + Send + Sync + 'static)) -> ExtendedError {
+    if e.is::<StorageTimeout>() {
+        ExtendedError::StorageTimeout
+    } else {
+        ExtendedError::StorageUnavailable
+    }
+}
 
-/// We don't expect frequent updates of the Zone list, so use an [AtomicPtr] here. The idea is that
-/// we will create a new [Arc] if there is a new list, and an atomic operation is used to swap the
-/// old list with the new list. Note that the [Arc] is not part of the type signature, for more
-/// info see [Arc::into_raw] and [Arc::from_raw].
-// TODO: vetting
-type ZoneCache = AtomicPtr<Vec<LowerName>>;
+/// The records answering a query, after following CNAME records in the zone.
+struct CnameChain {
+    /// CNAME records followed to get to the final name, in order.
+    aliases: Vec<StorageRecord>,
+    /// Records of the requested type at the final name, or [None] if that name does not exist.
+    records: Option<Vec<StorageRecord>>,
+    /// The chain could not be followed to its end in the zone, because it points outside of the
+    /// zone, loops, or is too long. Resolution stops at the last alias.
+    dangling: bool,
+}
+
+/// What is found for a name which has no records in the zone.
+enum Missing {
+    /// Names below it have records, so it exists, but without data.
+    EmptyNonTerminal,
+    /// The wildcard covering the name exists, with these records of the requested type.
+    Wildcard(LowerName, Vec<StorageRecord>),
+    /// Neither the name nor a wildcard covering it exist.
+    Absent,
+}
+
+impl CnameChain {
+    /// A direct answer, without any aliases.
+    fn direct(records: Option<Vec<StorageRecord>>) -> Self {
+        CnameChain {
+            aliases: Vec::new(),
+            records,
+            dangling: false,
+        }
+    }
+}
 
 pub struct DnsHandler<S> {
     // list of all known zones, this allows us to verify if we are an authority without hitting the
@@ -36,6 +199,44 @@ pub struct DnsHandler<S> {
     storage: S,
     geoip_db: GeoLocator,
     metrics: Metrics,
+    status: Arc<Status>,
+    tracer: Arc<QueryTracer>,
+    config: HandlerConfig,
+    // amount of answers served in round robin order, used to rotate the next answer.
+    answer_rotation: AtomicUsize,
+}
+
+/// Configuration of the behavior of a [`DnsHandler`].
+pub struct HandlerConfig {
+    /// Global response policy, zones can override parts of this.
+    pub policy: ResponsePolicy,
+    /// Handling of ANY queries over UDP.
+    pub any_over_udp: AnyOverUdp,
+    /// Networks allowed to request zone transfers.
+    pub allow_transfer: Vec<IpPrefix>,
+    /// Networks allowed to send dynamic updates.
+    pub allow_update: Vec<IpPrefix>,
+    /// Informs secondaries of zones changed by dynamic updates.
+    pub notifier: Notifier,
+    /// Locate clients by the source address of the request, even if the EDNS Client Subnet
+    /// option is present.
+    pub ignore_client_subnet: bool,
+    /// Maximum time spent on storage lookups for a single query.
+    pub storage_timeout: Duration,
+    /// Maximum time spent answering a single query in a zone, after which it is answered with
+    /// SERVFAIL.
+    pub query_deadline: Duration,
+    /// Queries which take at least this long to answer are logged.
+    pub slow_query_threshold: Duration,
+    /// Rate limiter applied to responses over UDP, if enabled.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Identification served for CHAOS class queries, these are refused if not set.
+    pub chaos: Option<ChaosIdentity>,
+    /// Health of records with a health check.
+    pub health: Arc<HealthChecker>,
+    /// Requests to reload zones into the zone cache right away, rather than on the next periodic
+    /// refresh.
+    pub zone_reload: Arc<ZoneReload>,
 }
 
 impl<S> DnsHandler<S>
@@ -48,17 +249,20 @@ where
     ///
     /// This function will panic if called outside the context of a `[tokio]` runtime.
     pub fn new(
-        instance_name: String,
+        metrics: Metrics,
         metric_socket: Option<SocketAddr>,
         geoip_db: GeoLocator,
         storage: S,
+        status: Arc<Status>,
+        tracer: Arc<QueryTracer>,
+        config: HandlerConfig,
     ) -> Self {
-        let zones = Arc::new(Vec::<LowerName>::new());
-        let zone_cache = Arc::new(AtomicPtr::new(Arc::into_raw(zones) as *mut _));
-        let metrics = Metrics::new(instance_name);
+        let zone_cache = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+        metrics.register_trace_filter_gauge(tracer.active_gauge());
+        metrics.register_health_gauge(config.health.gauge());
         // Start the metric server forever
         if let Some(metric_addr) = metric_socket {
-            tokio::spawn(metrics.server_future(metric_addr));
+            tokio::spawn(metrics.server_future(metric_addr, status.clone(), storage.clone()));
         }
 
         let handler = DnsHandler {
@@ -66,6 +270,10 @@ where
             storage,
             metrics,
             geoip_db,
+            status,
+            tracer,
+            config,
+            answer_rotation: AtomicUsize::new(0),
         };
 
         // Start permanently loading zones
@@ -90,16 +298,56 @@ where
             MessageType::Query => {}
             MessageType::Response => {
                 return self
-                    .reply_error(request, response_handle, ResponseCode::NotImp)
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::NotImp,
+                        None,
+                        Some(ExtendedError::NotSupported),
+                    )
                     .await;
             }
         };
 
+        // Only a single question is answered. For updates this is the zone section, which must
+        // hold exactly one zone as well (RFC 2136).
+        if request.header().query_count() != 1 {
+            return self
+                .reply_error(request, response_handle, ResponseCode::FormErr, None, None)
+                .await;
+        }
+
+        // A server which just started waits for its first zone load, rather than refusing
+        // queries for zones it doesn't know yet. This way it can share its sockets with the
+        // server it replaces during a rolling restart.
+        let zones_loaded = self.status.wait_for_zones();
+        if tokio::time::timeout(self.config.query_deadline, zones_loaded)
+            .await
+            .is_err()
+        {
+            return self
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::ServFail,
+                    None,
+                    Some(ExtendedError::NotReady),
+                )
+                .await;
+        }
+
         match request.op_code() {
             OpCode::Query => self.query(request, response_handle).await,
-            OpCode::Status | OpCode::Notify | OpCode::Update => {
+            OpCode::Update => self.update(request, response_handle).await,
+            OpCode::Status | OpCode::Notify => {
                 return self
-                    .reply_error(request, response_handle, ResponseCode::NotImp)
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::NotImp,
+                        None,
+                        Some(ExtendedError::NotSupported),
+                    )
                     .await;
             }
         }
@@ -112,11 +360,12 @@ where
 {
     /// Handle a request query. This function does the following:
     ///
-    /// 1. Check if the class is `IN`. We only serve these (for now), outright reject other
-    ///    classes.
-    /// 2. Check the zone cache to see if the request is a (child of) a known zone, if it is not
+    /// 1. Check if the query can be asked at all, malformed queries are answered with FORMERR.
+    /// 2. Check if the class is `IN`. We only serve these (for now), outright reject other
+    ///    classes, except for the identification queries in the `CH` class.
+    /// 3. Check the zone cache to see if the request is a (child of) a known zone, if it is not
     ///    outright reject the query.
-    /// 3. Handle the query for the domain in the known zone.
+    /// 4. Handle the query for the domain in the known zone.
     async fn query<R: trust_dns_server::server::ResponseHandler>(
         &self,
         request: &trust_dns_server::server::Request,
@@ -124,29 +373,131 @@ where
     ) -> ResponseInfo {
         let query = request.query();
 
+        // OPT is a pseudo type which only lives in the additional section (RFC 6891), and names
+        // are limited to 255 octets in wire format.
+        if query.query_type() == RecordType::OPT || query.original().name().len() >= 255 {
+            return self
+                .reply_error(request, response_handle, ResponseCode::FormErr, None, None)
+                .await;
+        }
+
         // First verify this is the IN class
+        if query.query_class() == DNSClass::CH {
+            return self.query_chaos(request, response_handle).await;
+        }
         if query.query_class() != DNSClass::IN {
             // Refuse to answer anything for these
             return self
-                .reply_error(request, response_handle, ResponseCode::Refused)
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::Refused,
+                    None,
+                    Some(ExtendedError::NotSupported),
+                )
                 .await;
         }
 
+        // Zone transfers are answered with (changes to) the full zone, rather than a regular
+        // lookup.
+        if matches!(query.query_type(), RecordType::AXFR | RecordType::IXFR) {
+            return self.zone_transfer(request, response_handle).await;
+        }
+
         // Next check if we are authorized for the zone.
         let zone = self.find_authority(query);
-        if let Some(zone_name) = zone {
-            self.query_zone(request, &zone_name, response_handle).await
+
+        // Responses over UDP can be sent to spoofed sources, so those are rate limited.
+        let rate_limiter = match request.protocol() {
+            Protocol::Udp => self.config.rate_limiter.clone(),
+            _ => None,
+        };
+        let response_handle = RateLimitedResponseHandle::new(
+            response_handle,
+            rate_limiter,
+            self.metrics.clone(),
+            request.src().ip(),
+            query.clone(),
+            zone.as_ref().map(|(zone_name, _)| zone_name.clone()),
+        );
+
+        if let Some((zone_name, zone)) = zone {
+            let policy = zone.policy.resolve(&self.config.policy);
+            self.query_zone(request, &zone_name, &policy, zone, response_handle)
+                .await
         } else {
             self.query_unknown_zone(request, response_handle).await
         }
     }
 
     /// Handle a query in a zone. At this point, validation of the zone is assumed to already have
-    /// happened, i.e. we are certain that we are an authority for this zone.
+    /// happened, i.e. we are certain that we are an authority for this zone. The given policy is
+    /// the effective policy for the zone, and the cached zone is the entry from the zone cache.
+    ///
+    /// Queries which are not answered before the query deadline are answered with SERVFAIL.
+    /// Queries which take longer than the slow query threshold are logged, with the time spent
+    /// waiting on storage.
     async fn query_zone<R: trust_dns_server::server::ResponseHandler>(
         &self,
         request: &trust_dns_server::server::Request,
         zone_name: &LowerName,
+        policy: &ResponsePolicy,
+        cached_zone: CachedZone,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let clock = QueryClock::new(self.config.storage_timeout);
+        let answer = self.answer_zone_query(
+            request,
+            zone_name,
+            policy,
+            cached_zone,
+            &clock,
+            response_handle.clone(),
+        );
+        let info = match tokio::time::timeout(self.config.query_deadline, answer).await {
+            Ok(info) => info,
+            Err(_) => {
+                self.metrics.increment_zone_query_timeout(zone_name);
+                self.reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::ServFail,
+                    Some(zone_name),
+                    Some(ExtendedError::QueryTimeout),
+                )
+                .await
+            }
+        };
+
+        let elapsed = clock.start.elapsed();
+        if elapsed >= self.config.slow_query_threshold {
+            self.metrics.increment_zone_slow_query(zone_name);
+            let storage = clock.storage_time();
+            let query = request.query();
+            warn!(
+                "Slow query zone={} name={} type={} country={} protocol={} total_ms={} storage_ms={} response_ms={}",
+                zone_name,
+                query.name(),
+                query.query_type(),
+                clock.country.get().map_or("unknown", String::as_str),
+                request.protocol(),
+                elapsed.as_millis(),
+                storage.as_millis(),
+                elapsed.saturating_sub(storage).as_millis()
+            );
+        }
+
+        info
+    }
+
+    /// Answer a query in a zone, see [DnsHandler::query_zone].
+    async fn answer_zone_query<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        zone_name: &LowerName,
+        policy: &ResponsePolicy,
+        cached_zone: CachedZone,
+        clock: &QueryClock,
         mut response_handle: R,
     ) -> ResponseInfo {
         self.metrics
@@ -157,24 +508,53 @@ where
         self.metrics
             .increment_zone_query_class(zone_name, query.query_class());
 
-        let (country, continent) = match self.geoip_db.lookup_ip(request.src().ip()) {
+        let trace = self.tracer.start(query.name(), request.src().ip());
+        trace.stage("zone", || {
+            format!(
+                "zone={} type={} protocol={} policy={:?}",
+                zone_name,
+                query.query_type(),
+                request.protocol(),
+                policy
+            )
+        });
+
+        let (client_ip, source) = self.client_ip(request);
+        self.metrics.increment_zone_geo_lookup(zone_name, source);
+        let (country, continent) = match self.geoip_db.lookup_ip(client_ip) {
             Ok(info) => info,
             Err(e) => {
-                error!("Failed to fetch IP location {}: {}", &request.src().ip(), e);
-                self.metrics
-                    .increment_zone_response_code(zone_name, ResponseCode::ServFail);
+                error!("Failed to fetch IP location {}: {}", client_ip, e);
+                trace.stage("geo", || {
+                    format!("ip={} source={} error={}", client_ip, source, e)
+                });
                 return self
-                    .reply_error(request, response_handle, ResponseCode::ServFail)
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone_name),
+                        Some(ExtendedError::GeoLookupFailed),
+                    )
                     .await;
             }
         };
+        trace.stage("geo", || {
+            format!(
+                "ip={} source={} country={:?} continent={:?}",
+                client_ip, source, country, continent
+            )
+        });
         if let Some(ref country) = country {
             self.metrics
                 .increment_zone_country_query(zone_name, country);
+            let _ = clock.country.set(country.clone());
         }
         trace!(
-            "Request source {} from country {:?} in {:?}",
+            "Request source {} ({} {}) from country {:?} in {:?}",
             &request.src(),
+            source,
+            client_ip,
             country,
             continent
         );
@@ -184,91 +564,521 @@ where
         header.set_authoritative(true);
         header.set_message_type(MessageType::Response);
 
-        trace!("Getting zone SOA for {}", zone_name);
-        let soas = match self
-            .storage
-            .lookup_records(zone_name, zone_name, trust_dns_proto::rr::RecordType::SOA)
-            .await
-        {
-            Err(e) => {
-                error!("Failed to fetch SOA record for {}: {}", zone_name, e);
-                self.metrics
-                    .increment_zone_response_code(zone_name, ResponseCode::ServFail);
+        if query.query_type() == RecordType::ANY && request.protocol() == Protocol::Udp {
+            match self.config.any_over_udp {
+                AnyOverUdp::Answer => {}
+                AnyOverUdp::Truncate => {
+                    self.metrics
+                        .increment_zone_any_response(zone_name, "udp_truncated");
+                    trace.stage("any", || "response=udp_truncated");
+                    header.set_truncated(true);
+                    return self
+                        .reply_any_truncated(request, response_handle, header, zone_name)
+                        .await;
+                }
+                AnyOverUdp::Refuse => {
+                    self.metrics
+                        .increment_zone_any_response(zone_name, "udp_refused");
+                    trace.stage("any", || "response=udp_refused");
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::NotImp,
+                            Some(zone_name),
+                            Some(ExtendedError::NotSupported),
+                        )
+                        .await;
+                }
+            }
+        }
+
+        if query.query_type() == RecordType::ANY {
+            match policy.any_response {
+                AnyResponse::Minimal => {
+                    self.metrics
+                        .increment_zone_any_response(zone_name, "minimal");
+                    trace.stage("any", || "response=minimal");
+                    return self
+                        .reply_any_minimal(request, response_handle, header, zone_name, policy)
+                        .await;
+                }
+                AnyResponse::Full => {
+                    self.metrics.increment_zone_any_response(zone_name, "full");
+                    trace.stage("any", || "response=full");
+                }
+            }
+        }
+
+        let (soas, apex_ns, mut chain) = if query.name() == zone_name {
+            // At the zone apex the SOA and the requested records are stored under the same name,
+            // so fetch all of them in a single roundtrip instead of looking up the SOA and the
+            // requested type separately.
+            trace!("Fetching apex records for {}", zone_name);
+            let apex_records = match self
+                .before_deadline(
+                    clock,
+                    zone_name,
+                    self.storage.list_records(zone_name, zone_name),
+                )
+                .await
+            {
+                Err(e) => {
+                    error!("Failed to fetch apex records for {}: {}", zone_name, e);
+                    trace.stage("apex", || format!("error={}", e));
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::ServFail,
+                            Some(zone_name),
+                            Some(storage_error(&*e)),
+                        )
+                        .await;
+                }
+                Ok(records) => records,
+            };
+
+            let (soas, others): (Vec<_>, Vec<_>) = apex_records
+                .into_iter()
+                .partition(|sr| sr.as_record().record_type() == RecordType::SOA);
+            let apex_ns = others
+                .iter()
+                .filter(|sr| sr.as_record().record_type() == RecordType::NS)
+                .cloned()
+                .collect::<Vec<_>>();
+            let records = match query.query_type() {
+                RecordType::SOA => soas.clone(),
+                RecordType::ANY => soas.iter().cloned().chain(others).collect(),
+                rtype => others
+                    .into_iter()
+                    .filter(|sr| sr.as_record().record_type() == rtype)
+                    .collect(),
+            };
+
+            if soas.is_empty() {
                 return self
-                    .reply_error(request, response_handle, ResponseCode::ServFail)
+                    .reply_missing_soa(request, response_handle, zone_name)
                     .await;
             }
-            Ok(records) => records.expect("SOA record is always present if the zone exists"),
-        };
 
-        // Now get potential records
-        trace!(
-            "Fetching records for {} {}",
-            query.name(),
-            query.query_type()
-        );
+            trace.stage("apex", || {
+                format!(
+                    "soa={} ns={} matching={}",
+                    soas.len(),
+                    apex_ns.len(),
+                    records.len()
+                )
+            });
 
-        let mut records = match self
-            .storage
-            .lookup_records(query.name(), zone_name, query.query_type())
-            .await
-        {
-            Err(e) => {
-                error!(
-                    "Failed to fetch records for {} of type {}: {}",
-                    query.name(),
-                    query.query_type(),
-                    e
-                );
-                self.metrics
-                    .increment_zone_response_code(zone_name, ResponseCode::ServFail);
+            // The apex always exists if the zone exists. It can't be an alias either, since it
+            // always has an SOA record.
+            (soas, Some(apex_ns), CnameChain::direct(Some(records)))
+        } else {
+            // Names at or below a delegation are not ours to answer, refer to the child zone
+            // instead.
+            let delegation = match self
+                .before_deadline(
+                    clock,
+                    zone_name,
+                    self.find_delegation(
+                        zone_name,
+                        query.name(),
+                        query.query_type(),
+                        cached_zone.cuts.as_deref(),
+                    ),
+                )
+                .await
+            {
+                Err(e) => {
+                    error!("Failed to check delegations for {}: {}", query.name(), e);
+                    trace.stage("delegation", || format!("error={}", e));
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::ServFail,
+                            Some(zone_name),
+                            Some(storage_error(&*e)),
+                        )
+                        .await;
+                }
+                Ok(delegation) => delegation,
+            };
+            if let Some(ns_records) = delegation {
                 return self
-                    .reply_error(request, response_handle, ResponseCode::ServFail)
+                    .reply_referral(request, response_handle, zone_name, ns_records, &trace)
                     .await;
             }
-            Ok(records) => records,
+
+            // The SOA, a covering DNAME and the records of the name don't depend on each other,
+            // so fetch them concurrently to only wait for a single storage roundtrip. The SOA
+            // usually comes from the zone cache.
+            trace!(
+                "Fetching zone SOA for {} and records for {} {}",
+                zone_name,
+                query.name(),
+                query.query_type()
+            );
+            let (soas, dname, records) = match self
+                .before_deadline(clock, zone_name, async {
+                    tokio::try_join!(
+                        async {
+                            match cached_zone.soa {
+                                Some(soas) => Ok(Some(soas)),
+                                None => {
+                                    self.storage
+                                        .lookup_records(zone_name, zone_name, RecordType::SOA)
+                                        .await
+                                }
+                            }
+                        },
+                        self.find_dname(zone_name, query.name(), cached_zone.cuts.as_deref()),
+                        self.lookup_type(zone_name, query.name(), query.query_type()),
+                    )
+                })
+                .await
+            {
+                Err(e) => {
+                    error!(
+                        "Failed to fetch SOA and records for {} of type {}: {}",
+                        query.name(),
+                        query.query_type(),
+                        e
+                    );
+                    trace.stage("records", || format!("error={}", e));
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::ServFail,
+                            Some(zone_name),
+                            Some(storage_error(&*e)),
+                        )
+                        .await;
+                }
+                Ok(res) => res,
+            };
+
+            let soas = match soas {
+                Some(records) if !records.is_empty() => records,
+                _ => {
+                    return self
+                        .reply_missing_soa(request, response_handle, zone_name)
+                        .await;
+                }
+            };
+            trace.stage("soa", || format!("records={}", soas.len()));
+
+            // Names below a DNAME are redirected to the same names below its target, their own
+            // records are occluded.
+            let chain = if let Some(dname) = dname {
+                match self
+                    .before_deadline(
+                        clock,
+                        zone_name,
+                        self.resolve_dname(zone_name, query, dname, &trace),
+                    )
+                    .await
+                {
+                    Err(e) => {
+                        error!("Failed to resolve DNAME for {}: {}", query.name(), e);
+                        trace.stage("dname", || format!("error={}", e));
+                        return self
+                            .reply_error(
+                                request,
+                                response_handle,
+                                ResponseCode::ServFail,
+                                Some(zone_name),
+                                Some(storage_error(&*e)),
+                            )
+                            .await;
+                    }
+                    // The substituted name does not fit in a domain name (RFC 6672 section 2.2).
+                    Ok(None) => {
+                        return self
+                            .reply_error(
+                                request,
+                                response_handle,
+                                ResponseCode::YXDomain,
+                                Some(zone_name),
+                                None,
+                            )
+                            .await;
+                    }
+                    Ok(Some(chain)) => chain,
+                }
+            } else {
+                trace.stage("records", || match records {
+                    None => "domain=absent".to_string(),
+                    Some(ref records) => format!("domain=present matching={}", records.len()),
+                });
+
+                // If the name exists but has no records of the requested type, it might be an alias.
+                match records {
+                    Some(records)
+                        if records.is_empty() && query.query_type() != RecordType::CNAME =>
+                    {
+                        match self
+                            .before_deadline(
+                                clock,
+                                zone_name,
+                                self.follow_cname(
+                                    zone_name,
+                                    query.name(),
+                                    query.query_type(),
+                                    &trace,
+                                ),
+                            )
+                            .await
+                        {
+                            Err(e) => {
+                                error!("Failed to follow CNAME chain for {}: {}", query.name(), e);
+                                trace.stage("cname", || format!("error={}", e));
+                                return self
+                                    .reply_error(
+                                        request,
+                                        response_handle,
+                                        ResponseCode::ServFail,
+                                        Some(zone_name),
+                                        Some(storage_error(&*e)),
+                                    )
+                                    .await;
+                            }
+                            Ok(chain) => chain,
+                        }
+                    }
+                    // The name has no records, but it exists if names below it do. Otherwise a
+                    // wildcard might cover it.
+                    None => match self
+                        .before_deadline(
+                            clock,
+                            zone_name,
+                            self.resolve_missing(
+                                zone_name,
+                                query.name(),
+                                query.query_type(),
+                                &trace,
+                            ),
+                        )
+                        .await
+                    {
+                        Err(e) => {
+                            error!("Failed to resolve wildcard for {}: {}", query.name(), e);
+                            trace.stage("wildcard", || format!("error={}", e));
+                            return self
+                                .reply_error(
+                                    request,
+                                    response_handle,
+                                    ResponseCode::ServFail,
+                                    Some(zone_name),
+                                    Some(storage_error(&*e)),
+                                )
+                                .await;
+                        }
+                        Ok(chain) => chain,
+                    },
+                    records => CnameChain::direct(records),
+                }
+            };
+
+            (soas, None, chain)
+        };
+
+        // Leave out unhealthy records, then only serve the records meant for the location of the
+        // client, and pick from weighted sets. Full ANY answers are left as is, as they mix the
+        // sets of different types.
+        if let Some(ref mut records) = chain.records {
+            if query.query_type() != RecordType::ANY {
+                match self.config.health.filter(records) {
+                    Some(0) => {}
+                    Some(removed) => trace.stage("health", || format!("removed={}", removed)),
+                    None => trace.stage("health", || "all unhealthy, serving all"),
+                }
+            }
+        }
+        let steered = match chain.records {
+            Some(ref mut records) if query.query_type() != RecordType::ANY => {
+                geo::steer(records, country.as_deref(), continent.as_deref())
+            }
+            _ => false,
         };
+        if steered {
+            trace.stage("steer", || {
+                format!("answers={}", chain.records.as_ref().map_or(0, Vec::len))
+            });
+        }
+        // The picked record of a weighted set must stay first, other sets are ordered according
+        // to the policy.
+        if let Some(ref mut records) = chain.records {
+            if query.query_type() != RecordType::ANY {
+                if weight::select(records) {
+                    let picked = records[0]
+                        .as_record()
+                        .data()
+                        .map(ToString::to_string)
+                        .unwrap_or_default();
+                    trace.stage("weight", || format!("picked={}", picked));
+                    self.metrics
+                        .increment_zone_weighted_pick(zone_name, &picked);
+                } else {
+                    self.order_answers(records, policy.answer_order);
+                }
+            }
+        }
 
         // Set edns according to the request.
+        let response_edns = self.response_edns(request, steered);
         let mut response_builder = MessageResponseBuilder::from_message_request(request);
-        if let Some(edns) = request.edns() {
+        if let Some(ref edns) = response_edns {
             response_builder.edns(edns.clone());
         };
 
-        // Set NXDOMAIN if there domain is not found.
-        if records.is_none() {
+        // Set NXDOMAIN if there domain is not found. If we followed aliases, this applies to the
+        // final name in the chain (RFC 6604).
+        if chain.records.is_none() && !chain.dangling {
             header.set_response_code(ResponseCode::NXDomain);
         };
 
-        let required_soas = if match records {
-            None => true,
-            Some(ref records) => records.is_empty(),
-        } {
-            &soas[..]
+        let positive = chain.dangling
+            || match chain.records {
+                None => false,
+                Some(ref records) => !records.is_empty(),
+            };
+        let required_soas: Vec<_> = if !positive {
+            soas.into_iter().map(negative_soa).collect()
         } else {
-            &[][..]
+            Vec::new()
         };
 
-        let msg = response_builder.build(
-            header,
-            if let Some(ref mut records) = records {
-                &mut records[..]
-            } else {
-                &mut [][..]
-            }
-            .iter_mut()
-            .map(|sr| {
+        // Add the apex NS records to the authority section of positive answers if the policy
+        // asks for it. These are already known if the query is for the apex.
+        let mut authority_ns = if positive && policy.include_authority_ns {
+            match apex_ns {
+                Some(apex_ns) => apex_ns,
+                None => match self
+                    .before_deadline(
+                        clock,
+                        zone_name,
+                        self.storage
+                            .lookup_records(zone_name, zone_name, RecordType::NS),
+                    )
+                    .await
                 {
-                    // Preserve original casing in request.
-                    sr.as_mut_record().set_name(query.original().name().clone());
+                    Err(e) => {
+                        error!("Failed to fetch NS records for {}: {}", zone_name, e);
+                        trace.stage("authority", || format!("error={}", e));
+                        return self
+                            .reply_error(
+                                request,
+                                response_handle,
+                                ResponseCode::ServFail,
+                                Some(zone_name),
+                                Some(storage_error(&*e)),
+                            )
+                            .await;
+                    }
+                    Ok(records) => records.unwrap_or_default(),
+                },
+            }
+        } else {
+            Vec::new()
+        };
+        trace.stage("authority", || {
+            format!("ns={} soa={}", authority_ns.len(), required_soas.len())
+        });
+
+        trace.stage("response", || {
+            format!(
+                "code={} aliases={} answers={}",
+                header.response_code(),
+                chain.aliases.len(),
+                chain.records.as_ref().map_or(0, Vec::len)
+            )
+        });
+
+        let mut answers = chain.aliases;
+        if let Some(records) = chain.records {
+            answers.extend(records);
+        }
+        for sr in answers.iter_mut() {
+            // Preserve original casing in request. Records found by following an alias keep the
+            // name of the alias target.
+            if LowerName::from(sr.as_record().name()) == *query.name() {
+                sr.as_mut_record().set_name(query.original().name().clone());
+            }
+            if sr.as_record().ttl() < policy.min_ttl {
+                sr.as_mut_record().set_ttl(policy.min_ttl);
+            }
+        }
+
+        // Addresses of the targets of service bindings in the zone save the client a lookup.
+        // They are only a hint, so failing to fetch them does not fail the query.
+        let mut additionals = if matches!(query.query_type(), RecordType::SVCB | RecordType::HTTPS)
+        {
+            match self
+                .before_deadline(clock, zone_name, self.svcb_additionals(zone_name, &answers))
+                .await
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!(
+                        "Failed to fetch target addresses for {}: {}",
+                        query.name(),
+                        e
+                    );
+                    Vec::new()
                 }
-                sr.as_record()
-            }),
-            [],
+            }
+        } else {
+            Vec::new()
+        };
+        if !additionals.is_empty() {
+            trace.stage("additional", || format!("records={}", additionals.len()));
+        }
+
+        // The additional records and authority NS records are optional, and are dropped first if
+        // the response doesn't fit. An answer which does not fit in a UDP response is left out
+        // entirely, rather than sending a partial RRset. The client retries over TCP to get the
+        // full answer.
+        let fits = |authority: &[StorageRecord], additional: &[StorageRecord]| {
+            let response_records = answers
+                .iter()
+                .chain(authority)
+                .chain(&required_soas)
+                .chain(additional)
+                .map(StorageRecord::as_record)
+                .collect::<Vec<_>>();
+            fits_udp_payload(request, response_edns.as_ref(), &response_records)
+        };
+        if !fits(&authority_ns, &additionals) {
+            additionals.clear();
+        }
+        if !fits(&authority_ns, &[]) {
+            if fits(&[], &[]) {
+                authority_ns.clear();
+            } else {
+                debug!(
+                    "Truncating response for {} with {} answers",
+                    query.name(),
+                    answers.len()
+                );
+                trace.stage("truncate", || format!("answers={}", answers.len()));
+                self.metrics.increment_zone_truncated_response(zone_name);
+                header.set_truncated(true);
+                answers.clear();
+                authority_ns.clear();
+            }
+        }
+
+        let msg = response_builder.build(
+            header,
+            answers.iter().map(|sr| sr.as_record()),
+            authority_ns.iter().map(|sr| sr.as_record()),
             required_soas
                 .iter()
                 .map(|stored_soa| stored_soa.as_record()),
-            [],
+            additionals.iter().map(|sr| sr.as_record()),
         );
 
         self.metrics
@@ -285,6 +1095,403 @@ where
         }
     }
 
+    /// Wait for a storage operation of a query in a zone, giving up once the deadline of the query
+    /// has passed. The time spent waiting is added to the clock of the query.
+    async fn before_deadline<T>(
+        &self,
+        clock: &QueryClock,
+        zone: &LowerName,
+        operation: impl Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let start = Instant::now();
+        let res = tokio::time::timeout_at(clock.deadline, operation).await;
+        clock.add_storage_time(start.elapsed());
+        match res {
+            Ok(res) => res,
+            Err(_) => {
+                self.metrics.increment_zone_storage_timeout(zone);
+                Err(Box::new(StorageTimeout))
+            }
+        }
+    }
+
+    /// Reorder the records of an answer RRset.
+    fn order_answers(&self, records: &mut [StorageRecord], order: AnswerOrder) {
+        if records.len() < 2 {
+            return;
+        }
+        match order {
+            AnswerOrder::Fixed => {}
+            AnswerOrder::Shuffle => records.shuffle(&mut rand::thread_rng()),
+            AnswerOrder::RoundRobin => {
+                let rotation = self.answer_rotation.fetch_add(1, Ordering::Relaxed);
+                records.rotate_left(rotation % records.len());
+            }
+        }
+    }
+
+    /// Look up the records of a type for a name. ANY returns the records of all types.
+    async fn lookup_type(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        if rtype == RecordType::ANY {
+            self.storage.lookup_all_records(name, zone).await
+        } else {
+            self.storage.lookup_records(name, zone, rtype).await
+        }
+    }
+
+    /// Answer an ANY query with a single synthesized HINFO record, without looking at the
+    /// records of the name (RFC 8482).
+    async fn reply_any_minimal<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        mut response_handle: R,
+        header: Header,
+        zone: &LowerName,
+        policy: &ResponsePolicy,
+    ) -> ResponseInfo {
+        let hinfo = Record::from_rdata(
+            request.query().original().name().clone(),
+            policy.any_hinfo_ttl,
+            RData::HINFO(HINFO::new(ANY_HINFO_CPU.to_string(), String::new())),
+        );
+
+        let mut response_builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = self.response_edns(request, false) {
+            response_builder.edns(edns);
+        };
+        let msg = response_builder.build(header, [&hinfo], [], [], []);
+
+        self.metrics
+            .increment_zone_response_code(zone, msg.header().response_code());
+        match response_handle.send_response(msg).await {
+            Ok(info) => info,
+            Err(ioe) => {
+                warn!("Failed to send ANY response: {}", ioe);
+                ResponseInfo::from(*request.header())
+            }
+        }
+    }
+
+    /// Answer an ANY query with an empty truncated response, so the client retries over TCP.
+    async fn reply_any_truncated<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        mut response_handle: R,
+        header: Header,
+        zone: &LowerName,
+    ) -> ResponseInfo {
+        let mut response_builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = self.response_edns(request, false) {
+            response_builder.edns(edns);
+        };
+        let msg = response_builder.build_no_records(header);
+
+        self.metrics
+            .increment_zone_response_code(zone, msg.header().response_code());
+        match response_handle.send_response(msg).await {
+            Ok(info) => info,
+            Err(ioe) => {
+                warn!("Failed to send truncated ANY response: {}", ioe);
+                ResponseInfo::from(*request.header())
+            }
+        }
+    }
+
+    /// Find the delegation covering a name in the zone, returning the NS records at the cut.
+    /// Ancestors are checked from the apex down, so the topmost cut wins and everything below it
+    /// is occluded. DS records are served by the parent, so a DS query for the cut itself is not
+    /// considered delegated. If the cuts of the zone are known, only ancestors which are a
+    /// delegation are looked up.
+    async fn find_delegation(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        rtype: RecordType,
+        cuts: Option<&ZoneCuts>,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let mut candidates = Vec::new();
+        let mut candidate = if rtype == RecordType::DS {
+            name.base_name()
+        } else {
+            name.clone()
+        };
+        while candidate != *zone && zone.zone_of(&candidate) {
+            let parent = candidate.base_name();
+            candidates.push(candidate);
+            candidate = parent;
+        }
+
+        for candidate in candidates.iter().rev() {
+            if cuts.is_some_and(|cuts| !cuts.delegations.contains(candidate)) {
+                continue;
+            }
+            match self
+                .storage
+                .lookup_records(candidate, zone, RecordType::NS)
+                .await?
+            {
+                Some(ns_records) if !ns_records.is_empty() => {
+                    trace!("{} is delegated at {}", name, candidate);
+                    return Ok(Some(ns_records));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Send a referral to a delegated child zone. The answer is not authoritative, the NS records
+    /// of the delegation go in the authority section, and addresses of name servers inside the
+    /// zone are added as glue.
+    async fn reply_referral<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        mut response_handle: R,
+        zone: &LowerName,
+        ns_records: Vec<StorageRecord>,
+        trace: &QueryTrace,
+    ) -> ResponseInfo {
+        let mut targets = Vec::new();
+        for ns in &ns_records {
+            if let Some(RData::NS(target)) = ns.as_record().data() {
+                let target = LowerName::from(target);
+                if zone.zone_of(&target) && !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        let glue = match self.storage.list_records_bulk(zone, &targets).await {
+            Ok(records) => records
+                .into_iter()
+                .flatten()
+                .filter(|sr| {
+                    matches!(
+                        sr.as_record().record_type(),
+                        RecordType::A | RecordType::AAAA
+                    )
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                error!("Failed to fetch glue for a referral from {}: {}", zone, e);
+                trace.stage("referral", || format!("error={}", e));
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone),
+                        Some(storage_error(&*e)),
+                    )
+                    .await;
+            }
+        };
+        trace.stage("referral", || {
+            format!("ns={} glue={}", ns_records.len(), glue.len())
+        });
+
+        let mut header = *request.header();
+        header.set_authoritative(false);
+        header.set_message_type(MessageType::Response);
+
+        let mut response_builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = self.response_edns(request, false) {
+            response_builder.edns(edns);
+        };
+
+        let msg = response_builder.build(
+            header,
+            [],
+            ns_records.iter().map(|sr| sr.as_record()),
+            [],
+            glue.iter().map(|sr| sr.as_record()),
+        );
+
+        self.metrics
+            .increment_zone_response_code(zone, msg.header().response_code());
+        match response_handle.send_response(msg).await {
+            Ok(info) => info,
+            Err(ioe) => {
+                warn!("Failed to send referral: {}", ioe);
+                ResponseInfo::from(*request.header())
+            }
+        }
+    }
+
+    /// Follow the CNAME chain starting at the given name, which exists but has no records of the
+    /// requested type. Only names in the zone are followed.
+    async fn follow_cname(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        rtype: RecordType,
+        trace: &QueryTrace,
+    ) -> Result<CnameChain, Box<dyn Error + Send + Sync>> {
+        let mut aliases = Vec::new();
+        let mut visited = HashSet::new();
+        let mut name = name.clone();
+        // The name the alias at `name` is synthesized for, if `name` is a wildcard.
+        let mut owner: Option<LowerName> = None;
+        loop {
+            let mut cname = self
+                .storage
+                .lookup_records(&name, zone, RecordType::CNAME)
+                .await?
+                .and_then(|records| records.into_iter().next());
+            if let (Some(cname), Some(owner)) = (cname.as_mut(), owner.take()) {
+                cname.as_mut_record().set_name(Name::from(&owner));
+                name = owner;
+            }
+            let target = match cname.as_ref().and_then(|sr| sr.as_record().data()) {
+                Some(RData::CNAME(target)) => LowerName::from(target),
+                // Not an alias, so there is just no data of the requested type. If we followed
+                // aliases to get here, this name exists since we checked that before.
+                _ => {
+                    return Ok(CnameChain {
+                        aliases,
+                        records: Some(Vec::new()),
+                        dangling: false,
+                    })
+                }
+            };
+            if aliases.len() >= MAX_CNAME_CHAIN {
+                warn!("CNAME chain for {} in zone {} is too long", name, zone);
+                return Ok(CnameChain {
+                    aliases,
+                    records: None,
+                    dangling: true,
+                });
+            }
+            trace.stage("cname", || format!("{} -> {}", name, target));
+            // The match above guarantees there is a CNAME record.
+            aliases.extend(cname);
+            visited.insert(name);
+
+            if !zone.zone_of(&target) {
+                trace!("CNAME target {} is outside of zone {}", target, zone);
+                return Ok(CnameChain {
+                    aliases,
+                    records: None,
+                    dangling: true,
+                });
+            }
+            if visited.contains(&target) {
+                warn!("CNAME chain for {} in zone {} loops", target, zone);
+                return Ok(CnameChain {
+                    aliases,
+                    records: None,
+                    dangling: true,
+                });
+            }
+
+            match self.storage.lookup_records(&target, zone, rtype).await? {
+                Some(records) if records.is_empty() => name = target,
+                // A target which does not exist can still be covered by a wildcard.
+                None => {
+                    let records = match self.lookup_missing(zone, &target, rtype, trace).await? {
+                        Missing::EmptyNonTerminal => Some(Vec::new()),
+                        Missing::Wildcard(wildcard, records)
+                            if records.is_empty() && rtype != RecordType::CNAME =>
+                        {
+                            name = wildcard;
+                            owner = Some(target);
+                            continue;
+                        }
+                        Missing::Wildcard(_, mut records) => {
+                            for sr in &mut records {
+                                sr.as_mut_record().set_name(Name::from(&target));
+                            }
+                            Some(records)
+                        }
+                        Missing::Absent => None,
+                    };
+                    return Ok(CnameChain {
+                        aliases,
+                        records,
+                        dangling: false,
+                    });
+                }
+                records => {
+                    return Ok(CnameChain {
+                        aliases,
+                        records,
+                        dangling: false,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Resolve a name which has no records in the zone. If names below it have records, it is an
+    /// empty non-terminal which exists without data. Otherwise it does not exist, and is resolved
+    /// through a wildcard if possible. Records synthesized from the wildcard get the queried name
+    /// as owner.
+    async fn resolve_missing(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        rtype: RecordType,
+        trace: &QueryTrace,
+    ) -> Result<CnameChain, Box<dyn Error + Send + Sync>> {
+        let owner = Name::from(name.clone());
+        Ok(match self.lookup_missing(zone, name, rtype, trace).await? {
+            Missing::EmptyNonTerminal => CnameChain::direct(Some(Vec::new())),
+            Missing::Wildcard(wildcard, records)
+                if records.is_empty() && rtype != RecordType::CNAME =>
+            {
+                let mut chain = self.follow_cname(zone, &wildcard, rtype, trace).await?;
+                // An alias synthesized from the wildcard is owned by the queried name as well.
+                if let Some(alias) = chain.aliases.first_mut() {
+                    alias.as_mut_record().set_name(owner);
+                }
+                chain
+            }
+            Missing::Wildcard(_, mut records) => {
+                for sr in &mut records {
+                    sr.as_mut_record().set_name(owner.clone());
+                }
+                CnameChain::direct(Some(records))
+            }
+            Missing::Absent => CnameChain::direct(None),
+        })
+    }
+
+    /// Find what covers a name which has no records in the zone. The closest encloser is the
+    /// longest existing ancestor of the name (RFC 4592). If that is the name itself, it is an
+    /// empty non-terminal. Otherwise only the wildcard directly below the closest encloser can
+    /// match.
+    async fn lookup_missing(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        rtype: RecordType,
+        trace: &QueryTrace,
+    ) -> Result<Missing, Box<dyn Error + Send + Sync>> {
+        let closest_encloser = self.storage.closest_encloser(zone, name).await?;
+        if closest_encloser == *name {
+            trace.stage("records", || "empty_non_terminal=true");
+            return Ok(Missing::EmptyNonTerminal);
+        }
+        let wildcard = LowerName::from(
+            Name::from(name.clone())
+                .trim_to(closest_encloser.num_labels() as usize + 1)
+                .into_wildcard(),
+        );
+        trace.stage("wildcard", || {
+            format!("closest_encloser={} source={}", closest_encloser, wildcard)
+        });
+
+        Ok(match self.lookup_type(zone, &wildcard, rtype).await? {
+            Some(records) => Missing::Wildcard(wildcard, records),
+            None => Missing::Absent,
+        })
+    }
+
     async fn query_unknown_zone<R: trust_dns_server::server::ResponseHandler>(
         &self,
         request: &trust_dns_server::server::Request,
@@ -296,36 +1503,81 @@ where
             .increment_unknown_zone_connection_type(&request.src(), request.protocol());
         self.metrics
             .increment_unknown_zone_record_type(request.query().query_type());
-        let (country, _) = match self.geoip_db.lookup_ip(request.src().ip()) {
+        let (client_ip, source) = self.client_ip(request);
+        self.metrics.increment_unknown_zone_geo_lookup(source);
+        let (country, _) = match self.geoip_db.lookup_ip(client_ip) {
             Ok(info) => info,
             Err(e) => {
-                error!("Failed to fetch IP location {}: {}", &request.src().ip(), e);
-                self.metrics
-                    .increment_unknown_zone_response_code(ResponseCode::ServFail);
+                error!("Failed to fetch IP location {}: {}", client_ip, e);
                 return self
-                    .reply_error(request, response_handle, ResponseCode::ServFail)
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        None,
+                        Some(ExtendedError::GeoLookupFailed),
+                    )
                     .await;
             }
         };
         if let Some(ref country) = country {
             self.metrics.increment_unknown_zone_country_query(country);
         }
-        self.metrics
-            .increment_unknown_zone_response_code(ResponseCode::Refused);
         // We aren't an authority for this query, therefore it is refused.
-        self.reply_error(request, response_handle, ResponseCode::Refused)
-            .await
+        self.reply_error(
+            request,
+            response_handle,
+            ResponseCode::Refused,
+            None,
+            Some(ExtendedError::NotAuthoritative),
+        )
+        .await
+    }
+
+    /// Reply to a query in a zone which has no SOA record. Such a zone is broken, e.g. because
+    /// records have been removed from the storage by hand, so nothing in it can be answered
+    /// reliably. This is flagged, and a SERVFAIL is sent.
+    async fn reply_missing_soa<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        response_handle: R,
+        zone: &LowerName,
+    ) -> ResponseInfo {
+        error!("Zone {} has no SOA record, refusing to answer", zone);
+        self.metrics.increment_zone_missing_soa(zone);
+        self.reply_error(
+            request,
+            response_handle,
+            ResponseCode::ServFail,
+            Some(zone),
+            Some(ExtendedError::MissingSoa),
+        )
+        .await
     }
 
     /// Send a generic error response. If sending the response fails, a new [ResponseInfo] object is
-    /// created from a clone of the request header.
+    /// created from a clone of the request header. The response code is recorded in the metrics
+    /// of the given zone, or in the metrics of the unknown zone if no zone was identified. The
+    /// extended error is only sent if the request has an EDNS section.
     async fn reply_error<R: trust_dns_server::server::ResponseHandler>(
         &self,
         request: &trust_dns_server::server::Request,
         mut response_handle: R,
         code: ResponseCode,
+        zone: Option<&LowerName>,
+        extended_error: Option<ExtendedError>,
     ) -> ResponseInfo {
-        let response_builder = MessageResponseBuilder::from_message_request(request);
+        match zone {
+            Some(zone) => self.metrics.increment_zone_response_code(zone, code),
+            None => self.metrics.increment_unknown_zone_response_code(code),
+        }
+        let mut response_builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(mut edns) = self.response_edns(request, false) {
+            if let Some(extended_error) = extended_error {
+                edns.options_mut().insert(extended_error.to_option());
+            }
+            response_builder.edns(edns);
+        }
         let mut header = *request.header();
         header.set_message_type(MessageType::Response);
         let msg = response_builder.error_msg(&header, code);
@@ -341,95 +1593,335 @@ where
         };
     }
 
-    /// Gets the authority zone for the query if it is present.
-    ///
-    /// TODO: Currently this just returns the first match, but does not account for zone in zones.
-    fn find_authority(&self, query: &LowerQuery) -> Option<LowerName> {
+    /// Get the address used to locate the client of a request, together with the source of that
+    /// address for metrics. This is the EDNS Client Subnet sent by the resolver if there is one,
+    /// and the source address of the request otherwise.
+    fn client_ip(&self, request: &trust_dns_server::server::Request) -> (IpAddr, &'static str) {
+        if !self.config.ignore_client_subnet {
+            if let Some(subnet) = request.edns().and_then(ClientSubnet::from_edns) {
+                return (subnet.addr(), "ecs");
+            }
+        }
+        (request.src().ip(), "source")
+    }
+
+    /// Get the EDNS section of a response, which mirrors the request. A Client Subnet option is
+    /// echoed with a scope of 0, unless the answer was steered based on that subnet. In that case
+    /// the answer is scoped to the full subnet sent by the resolver.
+    fn response_edns(
+        &self,
+        request: &trust_dns_server::server::Request,
+        steered: bool,
+    ) -> Option<Edns> {
+        request.edns().map(|edns| {
+            let mut edns = edns.clone();
+            if let Some(subnet) = ClientSubnet::from_edns(&edns) {
+                let scope = if steered && !self.config.ignore_client_subnet {
+                    subnet.source_prefix()
+                } else {
+                    0
+                };
+                edns.options_mut().insert(subnet.to_option(scope));
+            }
+            edns
+        })
+    }
+
+    /// Gets the authority zone for the query if it is present, together with the cached data of
+    /// the zone. If multiple zones match (i.e. a zone is delegated from another zone we also
+    /// host), the most specific zone is returned.
+    fn find_authority(&self, query: &LowerQuery) -> Option<(LowerName, CachedZone)> {
         let name = query.name();
-        let zones = self.zone_list();
-        trace!("zone cache ref count {}", Arc::strong_count(&zones));
-        for zone in zones.iter() {
-            if zone.zone_of(name) {
-                debug!("query {} in known zone {}", name, zone);
-                return Some(zone.clone());
+        let zones = self.zone_cache.load();
+        // Walk up from the query name, so the first zone found is the longest match.
+        let mut candidate = name.clone();
+        loop {
+            if let Some(zone) = zones.get(&candidate) {
+                debug!("query {} in known zone {}", name, candidate);
+                return Some((candidate, zone.clone()));
+            }
+            if candidate.is_root() {
+                return None;
             }
+            candidate = candidate.base_name();
         }
-        None
     }
 
     /// Get the current zone list.
-    fn zone_list(&self) -> Arc<Vec<LowerName>> {
+    fn zone_list(&self) -> Arc<HashMap<LowerName, CachedZone>> {
         trace!("Loading zone cache");
-
-        let ptr = self.zone_cache.load(Ordering::Relaxed);
-        // SAFETY: These methods are safe if performed on *const T acquired by calling
-        // Arc::into_raw(), which is always the case here. Furthermore, we guarantee manually that
-        // the refcount is correct and accounts for the decrement once the reconstructed Arc gets
-        // dropped.
-        unsafe {
-            // Reconstructing the Arc from a pointer in step 2 does not increment the strong
-            // refcount, though it will be decremented once that goes out of scope. Hence, manually
-            // increment it first.
-            Arc::increment_strong_count(ptr);
-            Arc::from_raw(ptr)
-        }
+        self.zone_cache.load_full()
     }
 
-    /// Generates a future which continuously loads all know zones and caches them. This removes
-    /// previously stored zones.
+    /// Generates a future which continuously loads the zones into the zone cache. All zones are
+    /// reloaded periodically, zones named in reload requests right away.
     fn zone_loader(&self) -> impl Future<Output = ()> {
         trace!("Creating zone loader");
         let storage = self.storage.clone();
         let zone_cache = self.zone_cache.clone();
         let metrics = self.metrics.clone();
-        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let status = self.status.clone();
+        let reload = self.config.zone_reload.clone();
+        // If the storage reports changes itself, the periodic refresh only catches what the watch
+        // might have missed.
+        let refresh = match storage.watch_zones(reload.clone()) {
+            Some(watch) => {
+                tokio::spawn(watch);
+                Duration::from_secs(900)
+            }
+            None => Duration::from_secs(60),
+        };
+        let mut interval = tokio::time::interval(refresh);
 
         async move {
             loop {
                 trace!("Waiting for zone loader tick");
-                interval.tick().await;
-                trace!("Refreshing zone cache");
-                // Create the new zone mapping;
-                let zones = match storage.zones().await {
-                    Ok(zones) => zones,
-                    Err(e) => {
-                        error!("Failed to load zones: {}", e);
-                        continue;
+                let periodic = tokio::select! {
+                    _ = interval.tick() => true,
+                    _ = reload.requested() => false,
+                };
+                // A periodic refresh covers all pending requests.
+                let zones = match reload.take() {
+                    _ if periodic => Reload::All,
+                    Reload::All => {
+                        debug!("Zone cache reload requested");
+                        // Restart the periodic refresh from here.
+                        interval.reset();
+                        Reload::All
                     }
+                    zones => zones,
                 };
+                refresh_zone_cache(&storage, &zone_cache, &metrics, &status, zones).await;
+            }
+        }
+    }
+}
 
-                trace!("Loaded {} zones", zones.len());
-
-                // Load existing cache. We don't increment the refcount here so a cleanup is
-                // triggered once this one goes out of scope, and the last available Arc from this
-                // value goes out of scope if one exists.
-                let old_ptr = zone_cache.load(Ordering::Acquire);
-                // SAFETY: this is safe since regular loads of the pointer always increment refcount first,
-                // so the pointer is always valid.
-                let cache = unsafe { Arc::from_raw(old_ptr) };
-
-                // First add potentially new zones.
-                for zone in &zones {
-                    if !cache.contains(zone) {
-                        trace!("Zone {} is not in cache yet, register metrics now", zone);
-                        metrics.register_zone(zone.clone());
-                    }
+/// Load zones with their policy, SOA records and cuts, and swap them into the zone cache if
+/// anything changed. Metrics of added and removed zones are registered and unregistered.
+async fn refresh_zone_cache<S>(
+    storage: &S,
+    zone_cache: &ZoneCache,
+    metrics: &Metrics,
+    status: &Status,
+    reload: Reload,
+) where
+    S: Storage + Sync,
+{
+    // This task is the only writer, so the cache can't change until the new list is stored
+    // below.
+    let cache = zone_cache.load_full();
+
+    // The new data of every zone which is reloaded, or None if the zone does not exist (anymore).
+    let full = reload == Reload::All;
+    let updates: Vec<(LowerName, Option<CachedZone>)> = match reload {
+        Reload::All => {
+            trace!("Refreshing zone cache");
+            let zones = match storage.zones().await {
+                Ok(zones) => zones,
+                Err(e) => {
+                    error!("Failed to load zones: {}", e);
+                    return;
                 }
-                // Then unregister potentially removed zones.
-                for existing_zone in cache.iter() {
-                    if !zones.contains(existing_zone) {
-                        trace!("Zone {} was in cache but does not exist anymore, unregister metrics now", existing_zone);
-                        metrics.unregister_zone(existing_zone);
+            };
+            trace!("Loaded {} zones", zones.len());
+
+            let loaded = stream::iter(zones)
+                .map(|zone| async move {
+                    let cached_zone = load_zone(storage, &zone).await;
+                    (zone, Some(cached_zone))
+                })
+                .buffer_unordered(ZONE_LOAD_CONCURRENCY)
+                .collect::<Vec<_>>()
+                .await;
+
+            let names = loaded.iter().map(|(zone, _)| zone).collect::<HashSet<_>>();
+            let gone = cache
+                .keys()
+                .filter(|zone| !names.contains(zone))
+                .map(|zone| (zone.clone(), None))
+                .collect::<Vec<_>>();
+            loaded.into_iter().chain(gone).collect()
+        }
+        Reload::Zones(zones) => {
+            if zones.is_empty() {
+                return;
+            }
+            trace!("Reloading {} zones", zones.len());
+            stream::iter(zones)
+                .map(|zone| async move {
+                    match storage.zone_exists(&zone).await {
+                        Ok(true) => {
+                            let cached_zone = load_zone(storage, &zone).await;
+                            Some((zone, Some(cached_zone)))
+                        }
+                        Ok(false) => Some((zone, None)),
+                        Err(e) => {
+                            error!("Failed to check if zone {} exists: {}", zone, e);
+                            None
+                        }
                     }
-                }
+                })
+                .buffer_unordered(ZONE_LOAD_CONCURRENCY)
+                .filter_map(future::ready)
+                .collect()
+                .await
+        }
+    };
 
-                info!("Loaded {} zones in zone cache", zones.len());
-                let zones = Arc::new(zones);
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut zones_changed = 0;
+    for (zone, cached_zone) in &updates {
+        match (cache.get(zone), cached_zone) {
+            (None, Some(_)) => added.push(zone.clone()),
+            (Some(_), None) => removed.push(zone.clone()),
+            (Some(old_zone), Some(cached_zone)) if !old_zone.same_as(cached_zone) => {
+                zones_changed += 1
+            }
+            _ => {}
+        }
+    }
 
-                // Get the new pointer and store it.
-                let ptr = Arc::into_raw(zones) as *mut _;
-                zone_cache.store(ptr, Ordering::Release);
+    if added.is_empty() && removed.is_empty() && zones_changed == 0 {
+        trace!("Zone list unchanged, keeping existing zone cache");
+        if full {
+            status.set_zones_loaded();
+        }
+        return;
+    }
+
+    for (zone, cached_zone) in &updates {
+        let cached_zone = match cached_zone {
+            Some(cached_zone) => cached_zone,
+            None => continue,
+        };
+        if !cache.contains_key(zone) {
+            trace!("Zone {} is not in cache yet, register metrics now", zone);
+            metrics.register_zone(zone.clone());
+            // Flag new zones without SOA record, these can't be served.
+            if cached_zone.soa.is_none() {
+                error!("Zone {} has no SOA record, it will not be served", zone);
+                metrics.increment_zone_missing_soa(zone);
             }
         }
+        if let Some(soa) = cached_zone.soa.as_ref().and_then(|soas| soas.first()) {
+            metrics.set_zone_serial(zone, transfer::soa_serial(soa));
+        }
     }
+    for existing_zone in &removed {
+        trace!(
+            "Zone {} was in cache but does not exist anymore, unregister metrics now",
+            existing_zone
+        );
+        metrics.unregister_zone(existing_zone);
+    }
+
+    let zones = if full {
+        updates
+            .into_iter()
+            .filter_map(|(zone, cached_zone)| cached_zone.map(|cached_zone| (zone, cached_zone)))
+            .collect::<HashMap<_, _>>()
+    } else {
+        let mut zones = (*cache).clone();
+        for (zone, cached_zone) in updates {
+            match cached_zone {
+                Some(cached_zone) => zones.insert(zone, cached_zone),
+                None => zones.remove(&zone),
+            };
+        }
+        zones
+    };
+
+    info!(
+        "Loaded {} zones in zone cache ({} added, {} removed, {} changed)",
+        zones.len(),
+        added.len(),
+        removed.len(),
+        zones_changed
+    );
+    metrics.add_zone_cache_changes(added.len(), removed.len());
+
+    zone_cache.store(Arc::new(zones));
+    if full {
+        status.set_zones_loaded();
+    }
+}
+
+/// Load the policy overrides, SOA records and cuts of a zone. A zone for which the policy can't
+/// be loaded falls back to the global policy, rather than being dropped from the cache.
+async fn load_zone<S>(storage: &S, zone: &LowerName) -> CachedZone
+where
+    S: Storage + Sync,
+{
+    let (policy, soa, cuts) = futures_util::join!(
+        storage.zone_policy(zone),
+        storage.lookup_records(zone, zone, RecordType::SOA),
+        storage.zone_cuts(zone),
+    );
+    let policy = policy.unwrap_or_else(|e| {
+        error!("Failed to load policy for zone {}: {}", zone, e);
+        ZonePolicy::default()
+    });
+    let soa = match soa {
+        Ok(Some(soa)) if !soa.is_empty() => Some(soa),
+        Ok(_) => None,
+        Err(e) => {
+            error!("Failed to load SOA record of zone {}: {}", zone, e);
+            None
+        }
+    };
+    let cuts = match cuts {
+        Ok(cuts) => Some(Arc::new(cuts)),
+        Err(e) => {
+            error!("Failed to load cuts of zone {}: {}", zone, e);
+            None
+        }
+    };
+    CachedZone { policy, soa, cuts }
+}
+
+/// Prepare an SOA record for the authority section of a negative response. Resolvers cache
+/// negative answers for the lesser of the SOA TTL and its MINIMUM field (RFC 2308), so the TTL
+/// is capped to the MINIMUM to keep both consistent.
+fn negative_soa(mut soa: StorageRecord) -> StorageRecord {
+    if let Some(RData::SOA(rdata)) = soa.as_record().data() {
+        let ttl = soa.as_record().ttl().min(rdata.minimum());
+        soa.as_mut_record().set_ttl(ttl);
+    }
+    soa
+}
+
+/// Check if a response with the given records fits in the UDP payload size of the client.
+/// Responses over other transports always fit.
+fn fits_udp_payload(
+    request: &trust_dns_server::server::Request,
+    edns: Option<&Edns>,
+    records: &[&Record],
+) -> bool {
+    if request.protocol() != Protocol::Udp {
+        return true;
+    }
+    // RFC 6891 requires payload sizes below 512 to be treated as 512.
+    let max_size = edns.map_or(DEFAULT_UDP_PAYLOAD_SIZE, |edns| {
+        edns.max_payload().max(DEFAULT_UDP_PAYLOAD_SIZE)
+    });
+
+    // Encode the message parts to get the size after name compression.
+    let mut buffer = Vec::with_capacity(max_size as usize);
+    let mut encoder = BinEncoder::new(&mut buffer);
+    encoder.set_max_size(max_size);
+    let encoded = Header::new()
+        .emit(&mut encoder)
+        .and_then(|_| request.query().original().emit(&mut encoder))
+        .and_then(|_| {
+            records
+                .iter()
+                .try_for_each(|record| record.emit(&mut encoder))
+        })
+        .and_then(|_| match edns {
+            Some(edns) => Record::from(edns).emit(&mut encoder),
+            None => Ok(()),
+        });
+    encoded.is_ok()
 }
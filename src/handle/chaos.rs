@@ -0,0 +1,91 @@
+use log::{debug, warn};
+use trust_dns_proto::rr::{rdata::TXT, DNSClass, RData, Record, RecordType};
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    client::op::{MessageType, ResponseCode},
+    server::ResponseInfo,
+};
+
+use super::DnsHandler;
+use crate::{ede::ExtendedError, storage::Storage};
+
+/// TTL of CHAOS answers. These identify the instance which answered, so they should not be
+/// cached.
+const CHAOS_TTL: u32 = 0;
+
+/// Identification strings served for CHAOS class queries.
+pub struct ChaosIdentity {
+    /// Answer to `version.bind.` and `version.server.`.
+    pub version: String,
+    /// Answer to `id.server.` and `hostname.bind.`.
+    pub id: String,
+}
+
+impl<S> DnsHandler<S>
+where
+    S: Storage + Clone + Send + Sync + Unpin,
+{
+    /// Handle a CHAOS class query. Only TXT (or ANY) queries for the well known version and
+    /// identification names are answered, if enabled. Everything else is refused.
+    pub(super) async fn query_chaos<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let query = request.query();
+        let answer = match self.config.chaos {
+            Some(ref identity)
+                if matches!(query.query_type(), RecordType::TXT | RecordType::ANY) =>
+            {
+                match query.name().to_string().as_str() {
+                    "version.bind." | "version.server." => Some(("version", &identity.version)),
+                    "id.server." | "hostname.bind." => Some(("id", &identity.id)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let (label, answer) = match answer {
+            Some(answer) => answer,
+            None => {
+                self.metrics.increment_chaos_query("refused");
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::Refused,
+                        None,
+                        Some(ExtendedError::NotSupported),
+                    )
+                    .await;
+            }
+        };
+        debug!("Answering CHAOS query for {}", query.name());
+        self.metrics.increment_chaos_query(label);
+
+        let mut record = Record::from_rdata(
+            query.original().name().clone(),
+            CHAOS_TTL,
+            RData::TXT(TXT::new(vec![answer.clone()])),
+        );
+        record.set_dns_class(DNSClass::CH);
+
+        let mut header = *request.header();
+        header.set_authoritative(true);
+        header.set_message_type(MessageType::Response);
+        let mut response_builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = self.response_edns(request, false) {
+            response_builder.edns(edns);
+        };
+        let msg = response_builder.build(header, [&record], [], [], []);
+
+        match response_handle.send_response(msg).await {
+            Ok(info) => info,
+            Err(ioe) => {
+                warn!("Failed to send reply to CHAOS query: {}", ioe);
+                ResponseInfo::from(*request.header())
+            }
+        }
+    }
+}
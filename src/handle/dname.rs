@@ -0,0 +1,120 @@
+use std::error::Error;
+
+use trust_dns_proto::rr::{RData, Record, RecordType};
+use trust_dns_server::client::{op::LowerQuery, rr::LowerName};
+
+use super::{CnameChain, DnsHandler};
+use crate::{
+    dname::{self, DNAME},
+    storage::{Storage, StorageRecord, ZoneCuts},
+    trace::QueryTrace,
+};
+
+impl<S> DnsHandler<S>
+where
+    S: Storage + Clone + Send + Sync + Unpin,
+{
+    /// Find the DNAME record covering a name in the zone, i.e. a DNAME at one of its ancestors.
+    /// Ancestors are checked from the apex down, as names below a DNAME are occluded by it. If
+    /// the cuts of the zone are known, only ancestors with a DNAME are looked up.
+    pub(super) async fn find_dname(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+        cuts: Option<&ZoneCuts>,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let mut candidates = Vec::new();
+        let mut candidate = name.base_name();
+        while zone.zone_of(&candidate) {
+            let parent = candidate.base_name();
+            let apex = candidate == *zone;
+            candidates.push(candidate);
+            if apex {
+                break;
+            }
+            candidate = parent;
+        }
+
+        for candidate in candidates.iter().rev() {
+            if cuts.is_some_and(|cuts| !cuts.dnames.contains(candidate)) {
+                continue;
+            }
+            // All unknown record types share a type in storage, so the type must be checked.
+            let dname = self
+                .storage
+                .lookup_records(candidate, zone, DNAME)
+                .await?
+                .and_then(|records| {
+                    records
+                        .into_iter()
+                        .find(|sr| dname::target(sr.as_record()).is_some())
+                });
+            if dname.is_some() {
+                return Ok(dname);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Resolve a query for a name below a DNAME (RFC 6672). A CNAME from the queried name to the
+    /// substituted name is synthesized with the TTL of the DNAME, and followed if the target is
+    /// in the zone. Returns [None] if the substituted name is too long.
+    pub(super) async fn resolve_dname(
+        &self,
+        zone: &LowerName,
+        query: &LowerQuery,
+        dname: StorageRecord,
+        trace: &QueryTrace,
+    ) -> Result<Option<CnameChain>, Box<dyn Error + Send + Sync>> {
+        let owner = dname.as_record().name();
+        // The DNAME was found by checking its target.
+        let target = match dname::target(dname.as_record()) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let qname = query.original().name();
+        let alias_target = match dname::substitute(qname, owner, &target) {
+            Ok(alias_target) => alias_target,
+            Err(_) => return Ok(None),
+        };
+        trace.stage("dname", || {
+            format!("owner={} target={} alias={}", owner, target, alias_target)
+        });
+
+        let cname = StorageRecord::new(Record::from_rdata(
+            qname.clone(),
+            dname.as_record().ttl(),
+            RData::CNAME(alias_target.clone()),
+        ));
+        let mut aliases = vec![dname, cname];
+
+        let alias_target = LowerName::from(alias_target);
+        if !zone.zone_of(&alias_target) {
+            return Ok(Some(CnameChain {
+                aliases,
+                records: None,
+                dangling: true,
+            }));
+        }
+
+        let rtype = query.query_type();
+        let chain = match self.lookup_type(zone, &alias_target, rtype).await? {
+            Some(records) if records.is_empty() && rtype != RecordType::CNAME => {
+                self.follow_cname(zone, &alias_target, rtype, trace).await?
+            }
+            None => {
+                self.resolve_missing(zone, &alias_target, rtype, trace)
+                    .await?
+            }
+            records => CnameChain::direct(records),
+        };
+        aliases.extend(chain.aliases);
+
+        Ok(Some(CnameChain {
+            aliases,
+            records: chain.records,
+            dangling: chain.dangling,
+        }))
+    }
+}
@@ -0,0 +1,48 @@
+use std::error::Error;
+
+use trust_dns_proto::rr::{RData, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use super::DnsHandler;
+use crate::storage::{Storage, StorageRecord};
+
+impl<S> DnsHandler<S>
+where
+    S: Storage + Clone + Send + Sync + Unpin,
+{
+    /// Collect the A and AAAA records of the in-zone targets of SVCB and HTTPS answers, to be
+    /// sent in the additional section so clients don't need a separate lookup (RFC 9460 section
+    /// 4.2). A target of "." refers to the owner of the record itself.
+    pub(super) async fn svcb_additionals(
+        &self,
+        zone: &LowerName,
+        answers: &[StorageRecord],
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let mut targets = Vec::new();
+        for sr in answers {
+            let record = sr.as_record();
+            let svcb = match record.data() {
+                Some(RData::SVCB(svcb)) | Some(RData::HTTPS(svcb)) => svcb,
+                _ => continue,
+            };
+            let target = if svcb.target_name().is_root() {
+                LowerName::from(record.name())
+            } else {
+                LowerName::from(svcb.target_name())
+            };
+            if zone.zone_of(&target) && !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+
+        let mut additionals = Vec::new();
+        for target in &targets {
+            for rtype in [RecordType::A, RecordType::AAAA] {
+                if let Some(records) = self.storage.lookup_records(target, zone, rtype).await? {
+                    additionals.extend(records);
+                }
+            }
+        }
+        Ok(additionals)
+    }
+}
@@ -0,0 +1,1512 @@
+//! Loopback tests of the [DnsHandler]: a handler over [MemoryStorage] is served on ephemeral UDP
+//! and TCP ports, and queried with plain DNS messages like any client would.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::future::join_all;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{oneshot, RwLock},
+    task::JoinHandle,
+};
+use trust_dns_proto::{
+    op::{Edns, Message, OpCode, Query},
+    rr::{rdata::SOA, DNSClass, Name, RData, Record, RecordType},
+};
+use trust_dns_server::{
+    client::{op::ResponseCode, rr::LowerName},
+    ServerFuture,
+};
+
+use super::{negative_soa, CachedZone, DnsHandler, HandlerConfig, ZoneCache, MAX_CNAME_CHAIN};
+use crate::{
+    geo::GeoLocator,
+    health::HealthChecker,
+    memory::MemoryStorage,
+    metrics::Metrics,
+    notify::Notifier,
+    policy::{AnswerOrder, AnyResponse, ResponsePolicy, ZonePolicy},
+    reload::ZoneReload,
+    status::Status,
+    storage::Storage,
+    storage::StorageRecord,
+    storage::ZoneCuts,
+    trace::QueryTracer,
+};
+
+mod bench;
+mod counting;
+
+pub(crate) use counting::CountingStorage;
+
+/// How long to wait for the handler to answer, or to load the zones.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A [DnsHandler] listening on ephemeral loopback ports.
+pub(crate) struct TestServer {
+    pub udp: SocketAddr,
+    pub tcp: SocketAddr,
+    pub metrics: Metrics,
+    zone_cache: Arc<ZoneCache>,
+    zone_reload: Arc<ZoneReload>,
+    server: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Serve the zones in the storage with the default configuration, once they are loaded.
+    pub async fn start<S>(storage: S) -> TestServer
+    where
+        S: Storage + Clone + Send + Sync + Unpin + 'static,
+    {
+        TestServer::start_with(storage, |_| {}).await
+    }
+
+    /// Serve the zones in the storage, after adjusting the default configuration.
+    pub async fn start_with<S>(storage: S, configure: impl FnOnce(&mut HandlerConfig)) -> TestServer
+    where
+        S: Storage + Clone + Send + Sync + Unpin + 'static,
+    {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("Can bind UDP socket");
+        let tcp_listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Can bind TCP listener");
+        TestServer::start_on(storage, udp_socket, tcp_listener, configure).await
+    }
+
+    /// Serve the zones in the storage on already bound sockets, after adjusting the default
+    /// configuration. The sockets are served right away, before the zones are loaded.
+    pub async fn start_on<S>(
+        storage: S,
+        udp_socket: UdpSocket,
+        tcp_listener: TcpListener,
+        configure: impl FnOnce(&mut HandlerConfig),
+    ) -> TestServer
+    where
+        S: Storage + Clone + Send + Sync + Unpin + 'static,
+    {
+        let zones = storage.zones().await.expect("Can list zones");
+        let metrics = Metrics::new("test".to_string());
+        let mut config = HandlerConfig {
+            policy: ResponsePolicy::default(),
+            any_over_udp: Default::default(),
+            allow_transfer: Vec::new(),
+            allow_update: Vec::new(),
+            notifier: Notifier::new(Vec::new(), Default::default(), metrics.clone()),
+            ignore_client_subnet: false,
+            storage_timeout: Duration::from_secs(2),
+            query_deadline: Duration::from_secs(4),
+            slow_query_threshold: Duration::from_secs(1),
+            rate_limiter: None,
+            chaos: None,
+            health: Arc::new(HealthChecker::new()),
+            zone_reload: Arc::new(ZoneReload::new()),
+        };
+        configure(&mut config);
+        let zone_reload = config.zone_reload.clone();
+        let handler = DnsHandler::new(
+            metrics.clone(),
+            None,
+            GeoLocator::fixed(Some("BE"), Some("EU")),
+            storage.clone(),
+            Arc::new(Status::default()),
+            Arc::new(QueryTracer::new()),
+            config,
+        );
+        let zone_cache = handler.zone_cache.clone();
+
+        let udp = udp_socket.local_addr().expect("UDP socket has an address");
+        let tcp = tcp_listener
+            .local_addr()
+            .expect("TCP listener has an address");
+        let mut server = ServerFuture::new(handler);
+        server.register_socket(udp_socket);
+        server.register_listener(tcp_listener, TIMEOUT);
+        let server = tokio::spawn(async move {
+            let _ = server.block_until_done().await;
+        });
+
+        let server = TestServer {
+            udp,
+            tcp,
+            metrics,
+            zone_cache,
+            zone_reload,
+            server,
+        };
+        server.wait_for_zones(&zones).await;
+        server
+    }
+
+    async fn wait_for_zones(&self, zones: &[LowerName]) {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let cache = self.zone_cache.load();
+                if zones.iter().all(|zone| cache.contains_key(zone)) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Zones are loaded in time");
+    }
+
+    /// Reload the zone cache after a change to the zones, their SOA records or their cuts, and wait
+    /// until the changed cache is in use.
+    pub async fn reload_zones(&self) {
+        let cache = self.zone_cache.load_full();
+        self.zone_reload.all();
+        self.wait_for_swap(&cache).await;
+    }
+
+    /// Reload a single zone into the zone cache, and wait until the changed cache is in use.
+    pub async fn reload_zone(&self, zone: &str) {
+        let cache = self.zone_cache.load_full();
+        self.zone_reload.zone(&lower(zone));
+        self.wait_for_swap(&cache).await;
+    }
+
+    async fn wait_for_swap(&self, cache: &Arc<HashMap<LowerName, CachedZone>>) {
+        tokio::time::timeout(TIMEOUT, async {
+            while Arc::ptr_eq(cache, &self.zone_cache.load()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Zones are reloaded in time");
+    }
+
+    /// Ask a question over UDP.
+    pub async fn query_udp(&self, name: &str, rtype: RecordType) -> Message {
+        self.exchange_udp(query(name, rtype)).await
+    }
+
+    /// Ask a question over TCP.
+    pub async fn query_tcp(&self, name: &str, rtype: RecordType) -> Message {
+        self.exchange_tcp(query(name, rtype)).await
+    }
+
+    /// Send a message over UDP and wait for the response.
+    pub async fn exchange_udp(&self, message: Message) -> Message {
+        exchange_udp(self.udp, message).await
+    }
+
+    /// Send a message over TCP and wait for the response.
+    pub async fn exchange_tcp(&self, message: Message) -> Message {
+        exchange_tcp(self.tcp, message).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Send a message over UDP to a server, and wait for the response.
+pub(crate) async fn exchange_udp(server: SocketAddr, message: Message) -> Message {
+    let socket = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("Can bind UDP socket");
+    let request = message.to_vec().expect("Can encode message");
+    socket
+        .send_to(&request, server)
+        .await
+        .expect("Can send query");
+    let mut buffer = vec![0; 65535];
+    let len = tokio::time::timeout(TIMEOUT, socket.recv(&mut buffer))
+        .await
+        .expect("Response arrives in time")
+        .expect("Can receive response");
+    let response = Message::from_vec(&buffer[..len]).expect("Response can be decoded");
+    assert_eq!(response.id(), message.id());
+    response
+}
+
+/// Send a message over TCP to a server, and wait for the response.
+pub(crate) async fn exchange_tcp(server: SocketAddr, message: Message) -> Message {
+    let mut stream = TcpStream::connect(server)
+        .await
+        .expect("Can connect over TCP");
+    let request = message.to_vec().expect("Can encode message");
+    stream
+        .write_all(&(request.len() as u16).to_be_bytes())
+        .await
+        .expect("Can send query length");
+    stream.write_all(&request).await.expect("Can send query");
+    let response = tokio::time::timeout(TIMEOUT, async {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buffer).await?;
+        Ok::<_, std::io::Error>(buffer)
+    })
+    .await
+    .expect("Response arrives in time")
+    .expect("Can receive response");
+    let response = Message::from_vec(&response).expect("Response can be decoded");
+    assert_eq!(response.id(), message.id());
+    response
+}
+
+/// Build a query for a single question.
+pub(crate) fn query(name: &str, rtype: RecordType) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .add_query(Query::query(name_of(name), rtype));
+    message
+}
+
+pub(crate) fn name_of(name: &str) -> Name {
+    Name::from_ascii(name).expect("Valid name")
+}
+
+pub(crate) fn lower(name: &str) -> LowerName {
+    LowerName::from(name_of(name))
+}
+
+pub(crate) fn record(name: &str, ttl: u32, rdata: RData) -> StorageRecord {
+    StorageRecord::new(Record::from_rdata(name_of(name), ttl, rdata))
+}
+
+pub(crate) fn a(name: &str, ip: [u8; 4]) -> StorageRecord {
+    record(name, 300, RData::A(Ipv4Addr::from(ip)))
+}
+
+pub(crate) fn cname(name: &str, target: &str) -> StorageRecord {
+    record(name, 300, RData::CNAME(name_of(target)))
+}
+
+pub(crate) fn soa(zone: &str, serial: u32) -> StorageRecord {
+    record(
+        zone,
+        3600,
+        RData::SOA(SOA::new(
+            name_of(&format!("ns1.{}", zone)),
+            name_of(&format!("hostmaster.{}", zone)),
+            serial,
+            7200,
+            900,
+            1209600,
+            300,
+        )),
+    )
+}
+
+/// Create a zone with an SOA record, and the given records.
+pub(crate) async fn add_zone(storage: &impl Storage, zone: &str, records: Vec<StorageRecord>) {
+    let zone_name = lower(zone);
+    storage.add_zone(&zone_name).await.expect("Can add zone");
+    for record in std::iter::once(soa(zone, 1)).chain(records) {
+        let domain = LowerName::from(record.as_record().name());
+        storage
+            .add_record(&zone_name, &domain, record)
+            .await
+            .expect("Can add record");
+    }
+}
+
+/// A server for `example.com.` with an address at `www`.
+async fn example_server() -> TestServer {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![a("www.example.com.", [192, 0, 2, 1])],
+    )
+    .await;
+    TestServer::start(storage).await
+}
+
+/// Get the names and types of the records in a section.
+pub(crate) fn summary(records: &[Record]) -> Vec<(String, RecordType)> {
+    records
+        .iter()
+        .map(|record| (record.name().to_string(), record.record_type()))
+        .collect()
+}
+
+#[tokio::test]
+async fn positive_answer() {
+    let server = example_server().await;
+    for response in [
+        server.query_udp("www.example.com.", RecordType::A).await,
+        server.query_tcp("www.example.com.", RecordType::A).await,
+    ] {
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(
+            response
+                .answers()
+                .iter()
+                .map(|record| record.data().cloned())
+                .collect::<Vec<_>>(),
+            vec![Some(RData::A(Ipv4Addr::new(192, 0, 2, 1)))]
+        );
+        assert_eq!(
+            summary(response.answers()),
+            vec![("www.example.com.".to_string(), RecordType::A)]
+        );
+        assert!(response.name_servers().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn apex_is_a_single_lookup() {
+    let storage = CountingStorage::new();
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![record(
+            "example.com.",
+            3600,
+            RData::NS(name_of("ns1.example.com.")),
+        )],
+    )
+    .await;
+    let server = TestServer::start(storage.clone()).await;
+
+    // The SOA of the answer serves the authority section of negative answers as well, so it is
+    // only fetched once, together with the other records of the apex.
+    storage.take_lookups();
+    let response = server.query_udp("example.com.", RecordType::SOA).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        vec![("example.com.".to_string(), RecordType::SOA)]
+    );
+    assert_eq!(storage.take_lookups(), 1);
+
+    for rtype in [RecordType::NS, RecordType::A] {
+        server.query_udp("example.com.", rtype).await;
+        assert_eq!(storage.take_lookups(), 1, "{}", rtype);
+    }
+}
+
+#[tokio::test]
+async fn slow_storage_is_servfail() {
+    let storage = CountingStorage::new();
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![a("www.example.com.", [192, 0, 2, 1])],
+    )
+    .await;
+    let server = TestServer::start_with(storage.clone(), |config| {
+        config.storage_timeout = Duration::from_millis(100);
+    })
+    .await;
+    let zone = lower("example.com.");
+    let timeouts = || {
+        server
+            .metrics
+            .zone_counter(Some(&zone), "storage_timeout", None)
+    };
+
+    storage.set_lookup_delay(Duration::from_secs(2));
+    for name in ["www.example.com.", "example.com."] {
+        let start = std::time::Instant::now();
+        let response = server.query_udp(name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::ServFail, "{}", name);
+        // The answer doesn't wait for the storage.
+        assert!(start.elapsed() < Duration::from_secs(1), "{}", name);
+    }
+    assert_eq!(timeouts(), 2);
+
+    // Once the storage recovers, queries are answered again.
+    storage.set_lookup_delay(Duration::ZERO);
+    let response = server.query_udp("www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(timeouts(), 2);
+}
+
+#[tokio::test]
+async fn nodata_has_soa() {
+    let server = example_server().await;
+    let response = server.query_udp("www.example.com.", RecordType::AAAA).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    assert_eq!(
+        summary(response.name_servers()),
+        vec![("example.com.".to_string(), RecordType::SOA)]
+    );
+}
+
+#[tokio::test]
+async fn nxdomain_has_soa() {
+    let server = example_server().await;
+    let response = server
+        .query_udp("missing.example.com.", RecordType::A)
+        .await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(response.authoritative());
+    assert!(response.answers().is_empty());
+    assert_eq!(
+        summary(response.name_servers()),
+        vec![("example.com.".to_string(), RecordType::SOA)]
+    );
+}
+
+#[tokio::test]
+async fn negative_soa_ttl_is_capped_by_minimum() {
+    let server = example_server().await;
+    // The SOA has a TTL of 3600, and a MINIMUM of 300.
+    for (name, rtype) in [
+        ("missing.example.com.", RecordType::A),
+        ("www.example.com.", RecordType::AAAA),
+        ("example.com.", RecordType::A),
+    ] {
+        let response = server.query_udp(name, rtype).await;
+        assert!(response.answers().is_empty(), "{}", name);
+        assert_eq!(response.name_servers().len(), 1, "{}", name);
+        assert_eq!(response.name_servers()[0].ttl(), 300, "{}", name);
+    }
+
+    // The SOA itself is served with its own TTL.
+    let response = server.query_udp("example.com.", RecordType::SOA).await;
+    assert_eq!(response.answers()[0].ttl(), 3600);
+}
+
+#[test]
+fn negative_soa_keeps_lower_ttl() {
+    let mut record = soa("example.com.", 1);
+    record.as_mut_record().set_ttl(60);
+    assert_eq!(negative_soa(record).as_record().ttl(), 60);
+    assert_eq!(negative_soa(soa("example.com.", 1)).as_record().ttl(), 300);
+}
+
+#[tokio::test]
+async fn unknown_zone_is_refused() {
+    let server = example_server().await;
+    for response in [
+        server.query_udp("www.example.org.", RecordType::A).await,
+        server.query_tcp("www.example.org.", RecordType::A).await,
+    ] {
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(response.answers().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn zone_policy_overrides_global_policy() {
+    let storage = Arc::new(MemoryStorage::new());
+    for zone in ["example.com.", "example.org."] {
+        add_zone(
+            &storage,
+            zone,
+            vec![
+                record(zone, 3600, RData::NS(name_of(&format!("ns1.{}", zone)))),
+                a(&format!("www.{}", zone), [192, 0, 2, 1]),
+            ],
+        )
+        .await;
+    }
+    storage
+        .set_zone_policy(
+            &lower("example.com."),
+            &ZonePolicy {
+                include_authority_ns: Some(true),
+                min_ttl: Some(600),
+                any_response: Some(AnyResponse::Minimal),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Can set zone policy");
+    let server = TestServer::start_with(storage, |config| {
+        config.policy = ResponsePolicy {
+            include_authority_ns: false,
+            min_ttl: 0,
+            any_response: AnyResponse::Full,
+            ..Default::default()
+        };
+    })
+    .await;
+
+    // The zone with a policy uses its overrides.
+    let response = server.query_udp("www.example.com.", RecordType::A).await;
+    assert_eq!(response.answers()[0].ttl(), 600);
+    assert_eq!(
+        summary(response.name_servers()),
+        vec![("example.com.".to_string(), RecordType::NS)]
+    );
+    let response = server.query_tcp("www.example.com.", RecordType::ANY).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![("www.example.com.".to_string(), RecordType::HINFO)]
+    );
+
+    // The zone without one uses the global policy.
+    let response = server.query_udp("www.example.org.", RecordType::A).await;
+    assert_eq!(response.answers()[0].ttl(), 300);
+    assert!(response.name_servers().is_empty());
+    let response = server.query_tcp("www.example.org.", RecordType::ANY).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![("www.example.org.".to_string(), RecordType::A)]
+    );
+}
+
+#[tokio::test]
+async fn zone_without_soa_is_servfail() {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![a("www.example.com.", [192, 0, 2, 1])],
+    )
+    .await;
+    // A zone which lost its SOA, e.g. through a partial import.
+    let broken = lower("broken.test.");
+    storage.add_zone(&broken).await.expect("Can add zone");
+    storage
+        .add_record(
+            &broken,
+            &lower("www.broken.test."),
+            a("www.broken.test.", [192, 0, 2, 2]),
+        )
+        .await
+        .expect("Can add record");
+    let server = TestServer::start(storage).await;
+    let missing_soa = || {
+        server
+            .metrics
+            .zone_counter(Some(&broken), "zone_missing_soa", None)
+    };
+    // The zone loader flags the zone as soon as it is loaded.
+    assert_eq!(missing_soa(), 1);
+
+    for name in ["broken.test.", "www.broken.test.", "missing.broken.test."] {
+        let response = server.query_udp(name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::ServFail, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+    }
+    assert_eq!(missing_soa(), 4);
+
+    // Other zones are not affected.
+    let response = server.query_udp("www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        vec![("www.example.com.".to_string(), RecordType::A)]
+    );
+}
+
+#[tokio::test]
+async fn malformed_questions_are_formerr() {
+    let server = example_server().await;
+    let formerr = || response_codes(&server, None, ResponseCode::FormErr);
+
+    let mut messages = Vec::new();
+    // Two questions.
+    let mut message = query("www.example.com.", RecordType::A);
+    message.add_query(Query::query(name_of("www.example.com."), RecordType::AAAA));
+    messages.push(message);
+    // A question for a pseudo type.
+    messages.push(query("www.example.com.", RecordType::OPT));
+
+    for message in messages {
+        let before = formerr();
+        for response in [
+            server.exchange_udp(message.clone()).await,
+            server.exchange_tcp(message.clone()).await,
+        ] {
+            assert_eq!(response.response_code(), ResponseCode::FormErr);
+            assert!(response.answers().is_empty());
+        }
+        assert_eq!(formerr(), before + 2);
+    }
+}
+
+#[tokio::test]
+async fn odd_messages_are_answered() {
+    let server = example_server().await;
+    let names = [
+        "example.com.",
+        "www.example.com.",
+        "*.example.com.",
+        "_odd-.example.com.",
+        "a.b.c.d.e.f.g.h.i.j.example.com.",
+        "example.org.",
+        ".",
+    ];
+    let types = [
+        RecordType::A,
+        RecordType::ANY,
+        RecordType::AXFR,
+        RecordType::IXFR,
+        RecordType::NULL,
+        RecordType::OPT,
+        RecordType::SOA,
+        RecordType::Unknown(65280),
+    ];
+    let classes = [
+        DNSClass::IN,
+        DNSClass::CH,
+        DNSClass::HS,
+        DNSClass::NONE,
+        DNSClass::ANY,
+    ];
+    let op_codes = [
+        OpCode::Query,
+        OpCode::Status,
+        OpCode::Notify,
+        OpCode::Update,
+    ];
+    for _ in 0..200 {
+        let pick = || rand::random::<usize>();
+        let mut message = query(names[pick() % names.len()], types[pick() % types.len()]);
+        message.queries_mut()[0].set_query_class(classes[pick() % classes.len()]);
+        message
+            .set_op_code(op_codes[pick() % op_codes.len()])
+            .set_recursion_desired(rand::random())
+            .set_checking_disabled(rand::random())
+            .set_authentic_data(rand::random());
+        if rand::random() {
+            let mut edns = Edns::new();
+            edns.set_max_payload(rand::random());
+            edns.set_dnssec_ok(rand::random());
+            message.set_edns(edns);
+        }
+        // Every message gets a response, which echoes the question.
+        let response = server.exchange_udp(message.clone()).await;
+        assert_eq!(response.queries(), message.queries(), "{:?}", message);
+    }
+
+    // And the server is still answering normally.
+    let response = server.query_udp("www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+}
+
+/// Count the responses with a response code, of a zone or of the unknown zone.
+fn response_codes(server: &TestServer, zone: Option<&str>, code: ResponseCode) -> u64 {
+    server.metrics.zone_counter(
+        zone.map(lower).as_ref(),
+        "response_code",
+        Some(code.to_str()),
+    )
+}
+
+#[tokio::test]
+async fn rejections_are_counted() {
+    let server = example_server().await;
+    let zone = Some("example.com.");
+    let counts = |server: &TestServer| {
+        [
+            ResponseCode::NotImp,
+            ResponseCode::Refused,
+            ResponseCode::NXDomain,
+        ]
+        .map(|code| {
+            (
+                response_codes(server, None, code),
+                response_codes(server, zone, code),
+            )
+        })
+    };
+
+    // Unsupported opcodes and classes are rejected before the zone is known, even for names in a
+    // zone.
+    let before = counts(&server);
+    let mut message = query("www.example.com.", RecordType::A);
+    message.set_op_code(OpCode::Status);
+    let response = server.exchange_udp(message).await;
+    assert_eq!(response.response_code(), ResponseCode::NotImp);
+    let mut message = query("www.example.com.", RecordType::A);
+    message.queries_mut()[0].set_query_class(DNSClass::HS);
+    let response = server.exchange_udp(message).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    let response = server.query_udp("www.example.org.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert_eq!(
+        counts(&server),
+        [
+            (before[0].0 + 1, before[0].1),
+            (before[1].0 + 2, before[1].1),
+            (before[2].0, before[2].1),
+        ]
+    );
+
+    // Errors in a zone are counted for that zone, once.
+    let before = counts(&server);
+    let response = server
+        .query_udp("missing.example.com.", RecordType::A)
+        .await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert_eq!(
+        counts(&server),
+        [before[0], before[1], (before[2].0, before[2].1 + 1)]
+    );
+}
+
+#[tokio::test]
+async fn most_specific_zone_answers() {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![
+            a("www.example.com.", [192, 0, 2, 1]),
+            // Shadowed by the child zone, which is hosted here as well.
+            a("www.sub.example.com.", [192, 0, 2, 1]),
+            a("www.xsub.example.com.", [192, 0, 2, 1]),
+        ],
+    )
+    .await;
+    add_zone(
+        &storage,
+        "sub.example.com.",
+        vec![a("www.sub.example.com.", [192, 0, 2, 2])],
+    )
+    .await;
+    let server = TestServer::start(storage).await;
+    let assert_address = |response: Message, ip: [u8; 4]| {
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response
+                .answers()
+                .iter()
+                .map(|record| record.data().cloned())
+                .collect::<Vec<_>>(),
+            vec![Some(RData::A(Ipv4Addr::from(ip)))]
+        );
+    };
+    let assert_nxdomain = |response: Message, zone: &str| {
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(
+            summary(response.name_servers()),
+            vec![(zone.to_string(), RecordType::SOA)]
+        );
+    };
+
+    // Names in the child zone are answered from it.
+    assert_address(
+        server
+            .query_udp("www.sub.example.com.", RecordType::A)
+            .await,
+        [192, 0, 2, 2],
+    );
+    assert_nxdomain(
+        server
+            .query_udp("missing.sub.example.com.", RecordType::A)
+            .await,
+        "sub.example.com.",
+    );
+    let response = server.query_udp("sub.example.com.", RecordType::SOA).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![("sub.example.com.".to_string(), RecordType::SOA)]
+    );
+
+    // The parent still answers for the names outside of the child, including names which only
+    // share a suffix of the label of the child.
+    assert_address(
+        server.query_udp("www.example.com.", RecordType::A).await,
+        [192, 0, 2, 1],
+    );
+    assert_address(
+        server
+            .query_udp("www.xsub.example.com.", RecordType::A)
+            .await,
+        [192, 0, 2, 1],
+    );
+    assert_nxdomain(
+        server
+            .query_udp("missing.example.com.", RecordType::A)
+            .await,
+        "example.com.",
+    );
+}
+
+#[tokio::test]
+async fn large_answers_are_truncated_over_udp() {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        (1..=50)
+            .map(|i| a("many.example.com.", [192, 0, 2, i]))
+            .collect(),
+    )
+    .await;
+    let server = TestServer::start(storage).await;
+    let truncated = || {
+        server
+            .metrics
+            .zone_counter(Some(&lower("example.com.")), "truncated_responses", None)
+    };
+
+    // Without EDNS the response is limited to 512 bytes, so the answer is left out.
+    let response = server.query_udp("many.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.truncated());
+    assert!(response.answers().is_empty());
+    assert_eq!(truncated(), 1);
+
+    // Over TCP the full RRset is returned.
+    let response = server.query_tcp("many.example.com.", RecordType::A).await;
+    assert!(!response.truncated());
+    assert_eq!(response.answers().len(), 50);
+
+    // As it is over UDP if the client advertises a large enough payload size.
+    let mut message = query("many.example.com.", RecordType::A);
+    let mut edns = Edns::new();
+    edns.set_max_payload(4096);
+    message.set_edns(edns);
+    let response = server.exchange_udp(message).await;
+    assert!(!response.truncated());
+    assert_eq!(response.answers().len(), 50);
+    assert_eq!(truncated(), 1);
+}
+
+/// A server for `example.com.` with three addresses at `www`, and two name servers, answering with
+/// the given order.
+async fn ordered_server(answer_order: AnswerOrder) -> TestServer {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![
+            record("example.com.", 3600, RData::NS(name_of("ns1.example.com."))),
+            record("example.com.", 3600, RData::NS(name_of("ns2.example.com."))),
+            a("www.example.com.", [192, 0, 2, 1]),
+            a("www.example.com.", [192, 0, 2, 2]),
+            a("www.example.com.", [192, 0, 2, 3]),
+        ],
+    )
+    .await;
+    TestServer::start_with(storage, |config| {
+        config.policy.answer_order = answer_order;
+        config.policy.include_authority_ns = true;
+    })
+    .await
+}
+
+/// Get the address in the first answer, and the names of the name servers in the authority
+/// section.
+async fn first_address(server: &TestServer) -> (RData, Vec<RData>) {
+    let response = server.query_udp("www.example.com.", RecordType::A).await;
+    assert_eq!(response.answers().len(), 3);
+    (
+        response.answers()[0]
+            .data()
+            .cloned()
+            .expect("Answer has data"),
+        response
+            .name_servers()
+            .iter()
+            .filter_map(|record| record.data().cloned())
+            .collect(),
+    )
+}
+
+#[tokio::test]
+async fn shuffled_answers_are_uniform() {
+    let server = ordered_server(AnswerOrder::Shuffle).await;
+    let mut counts = [0; 3];
+    for _ in 0..300 {
+        let (first, authority) = first_address(&server).await;
+        match first {
+            RData::A(ip) => counts[ip.octets()[3] as usize - 1] += 1,
+            other => panic!("Unexpected answer {:?}", other),
+        }
+        // Only the answer section is shuffled.
+        assert_eq!(
+            authority,
+            vec![
+                RData::NS(name_of("ns1.example.com.")),
+                RData::NS(name_of("ns2.example.com.")),
+            ]
+        );
+    }
+    // Every address is expected first 100 times, the bounds are far enough off to never fail in
+    // practice.
+    for count in counts {
+        assert!((50..=150).contains(&count), "{:?}", counts);
+    }
+}
+
+#[tokio::test]
+async fn round_robin_answers_rotate() {
+    let server = ordered_server(AnswerOrder::RoundRobin).await;
+    let mut firsts = Vec::new();
+    for _ in 0..6 {
+        firsts.push(first_address(&server).await.0);
+    }
+    // Consecutive responses start at consecutive addresses.
+    assert_ne!(firsts[0], firsts[1]);
+    assert_ne!(firsts[1], firsts[2]);
+    assert_ne!(firsts[0], firsts[2]);
+    assert_eq!(firsts[..3], firsts[3..]);
+
+    let server = ordered_server(AnswerOrder::Fixed).await;
+    for _ in 0..3 {
+        assert_eq!(
+            first_address(&server).await.0,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn zone_cache_swaps_during_queries() {
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![a("www.example.com.", [192, 0, 2, 1])],
+    )
+    .await;
+    let server = TestServer::start(storage.clone()).await;
+
+    // Keep replacing the zone cache by adding and removing another zone, while the zone which is
+    // always there is queried concurrently.
+    let swaps = async {
+        for i in 0..20 {
+            if i % 2 == 0 {
+                add_zone(&storage, "churn.test.", Vec::new()).await;
+            } else {
+                storage
+                    .delete_zone(&lower("churn.test."), false)
+                    .await
+                    .expect("Can delete zone");
+            }
+            server.reload_zones().await;
+        }
+    };
+    let queries = async {
+        for _ in 0..50 {
+            let responses =
+                join_all((0..10).map(|_| server.query_udp("www.example.com.", RecordType::A)))
+                    .await;
+            for response in responses {
+                assert_eq!(response.response_code(), ResponseCode::NoError);
+                assert_eq!(response.answers().len(), 1);
+            }
+        }
+    };
+    tokio::join!(swaps, queries);
+
+    // The last swap removed the other zone again.
+    let response = server.query_udp("churn.test.", RecordType::SOA).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+}
+
+#[tokio::test]
+async fn reload_only_requested_zones() {
+    let storage = CountingStorage::new();
+    for zone in ["example.com.", "example.org.", "example.net."] {
+        add_zone(&storage, zone, Vec::new()).await;
+    }
+    let server = TestServer::start(storage.clone()).await;
+    storage.take_lookups();
+    storage.take_zone_lookups();
+
+    // The cached SOA of the zone serves the authority section of negative answers.
+    let zone = lower("example.com.");
+    storage
+        .set_records(&zone, &zone, RecordType::SOA, vec![soa("example.com.", 2)])
+        .await
+        .expect("Can replace SOA");
+    server.reload_zone("example.com.").await;
+    // Checking if the zone exists, and loading its policy, cuts and SOA.
+    assert_eq!(storage.take_zone_lookups(), 3);
+    assert_eq!(storage.take_lookups(), 1);
+    let response = server
+        .query_udp("missing.example.com.", RecordType::A)
+        .await;
+    match response.name_servers()[0].data() {
+        Some(RData::SOA(soa)) => assert_eq!(soa.serial(), 2),
+        data => panic!("Authority is not an SOA: {:?}", data),
+    }
+
+    // A zone which does not exist anymore is dropped, the others are kept.
+    storage
+        .delete_zone(&lower("example.org."), false)
+        .await
+        .expect("Can delete zone");
+    server.reload_zone("example.org.").await;
+    assert_eq!(storage.take_zone_lookups(), 1);
+    let response = server.query_udp("example.org.", RecordType::SOA).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    let response = server.query_udp("example.net.", RecordType::SOA).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+}
+
+#[tokio::test]
+async fn case_is_preserved() {
+    let server = example_server().await;
+    let response = server.query_udp("WwW.ExAmPlE.cOm.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.queries()[0].name().to_string(), "WwW.ExAmPlE.cOm.");
+    assert_eq!(
+        summary(response.answers()),
+        vec![("WwW.ExAmPlE.cOm.".to_string(), RecordType::A)]
+    );
+}
+
+/// Check the answers for a name three labels below the apex, and for the empty non-terminals on
+/// the way to it, as the records below them are added and removed.
+pub(crate) async fn assert_empty_non_terminals<S>(storage: S)
+where
+    S: Storage + Clone + Send + Sync + Unpin + 'static,
+{
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![a("a.b.c.example.com.", [192, 0, 2, 1])],
+    )
+    .await;
+    let server = TestServer::start(storage.clone()).await;
+
+    for name in ["c.example.com.", "b.c.example.com.", "a.b.c.example.com."] {
+        let response = server.query_udp(name, RecordType::AAAA).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+        assert_eq!(
+            summary(response.name_servers()),
+            vec![("example.com.".to_string(), RecordType::SOA)],
+            "{}",
+            name
+        );
+    }
+    for name in [
+        "x.example.com.",
+        "x.c.example.com.",
+        "x.b.c.example.com.",
+        "x.a.b.c.example.com.",
+        "bc.example.com.",
+    ] {
+        let response = server.query_udp(name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain, "{}", name);
+    }
+
+    // Without the record, the names on the way to it are gone as well.
+    let zone = lower("example.com.");
+    storage
+        .remove_rrset(&zone, &lower("a.b.c.example.com."), RecordType::A)
+        .await
+        .expect("Can remove RRset");
+    for name in ["c.example.com.", "b.c.example.com.", "a.b.c.example.com."] {
+        let response = server.query_udp(name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain, "{}", name);
+    }
+
+    // A record halfway makes the name above it an empty non-terminal again.
+    storage
+        .set_records(
+            &zone,
+            &lower("b.c.example.com."),
+            RecordType::A,
+            vec![a("b.c.example.com.", [192, 0, 2, 2])],
+        )
+        .await
+        .expect("Can set records");
+    let response = server.query_udp("c.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let response = server.query_udp("b.c.example.com.", RecordType::A).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![("b.c.example.com.".to_string(), RecordType::A)]
+    );
+    let response = server.query_udp("a.b.c.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+}
+
+#[tokio::test]
+async fn empty_non_terminals() {
+    assert_empty_non_terminals(Arc::new(MemoryStorage::new())).await;
+}
+
+/// Check the answers synthesized from wildcards, including for CNAME targets which do not exist.
+pub(crate) async fn assert_wildcards<S>(storage: S)
+where
+    S: Storage + Clone + Send + Sync + Unpin + 'static,
+{
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![
+            a("*.example.com.", [192, 0, 2, 10]),
+            a("www.example.com.", [192, 0, 2, 1]),
+            // Makes sub.example.com. an empty non-terminal, which stops the wildcard of the apex.
+            a("x.sub.example.com.", [192, 0, 2, 2]),
+            cname("cname.example.com.", "a.missing.example.com."),
+            cname("*.c.example.com.", "www.example.com."),
+            cname("*.loop.example.com.", "a.b.loop.example.com."),
+        ],
+    )
+    .await;
+    let server = TestServer::start(storage).await;
+
+    // A wildcard covers names any amount of labels below it.
+    for name in ["b.example.com.", "a.b.example.com.", "x.y.z.example.com."] {
+        let response = server.query_udp(name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError, "{}", name);
+        assert_eq!(
+            summary(response.answers()),
+            vec![(name.to_string(), RecordType::A)]
+        );
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(192, 0, 2, 10)))
+        );
+
+        let response = server.query_udp(name, RecordType::AAAA).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+    }
+
+    // The wildcard at the apex does not cover the apex, names which exist, or names below other
+    // names which exist.
+    let response = server.query_udp("example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let response = server.query_udp("www.example.com.", RecordType::AAAA).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let response = server.query_udp("sub.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let response = server.query_udp("a.sub.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+    // The wildcard itself is an ordinary name.
+    let response = server.query_udp("*.example.com.", RecordType::A).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![("*.example.com.".to_string(), RecordType::A)]
+    );
+
+    // A CNAME target which does not exist is covered by the wildcard as well.
+    let response = server.query_udp("cname.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        vec![
+            ("cname.example.com.".to_string(), RecordType::CNAME),
+            ("a.missing.example.com.".to_string(), RecordType::A),
+        ]
+    );
+
+    // An alias synthesized from a wildcard is followed.
+    let response = server.query_udp("x.c.example.com.", RecordType::A).await;
+    assert_eq!(
+        summary(response.answers()),
+        vec![
+            ("x.c.example.com.".to_string(), RecordType::CNAME),
+            ("www.example.com.".to_string(), RecordType::A),
+        ]
+    );
+
+    // Aliases synthesized from a wildcard which point back below it stop once they loop.
+    let response = server.query_udp("z.loop.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        vec![
+            ("z.loop.example.com.".to_string(), RecordType::CNAME),
+            ("a.b.loop.example.com.".to_string(), RecordType::CNAME),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn wildcards() {
+    assert_wildcards(Arc::new(MemoryStorage::new())).await;
+}
+
+#[tokio::test]
+async fn cname_chains() {
+    let mut records = vec![
+        a("www.example.com.", [192, 0, 2, 1]),
+        cname("alias.example.com.", "chain.example.com."),
+        cname("chain.example.com.", "www.example.com."),
+        cname("loop1.example.com.", "loop2.example.com."),
+        cname("loop2.example.com.", "loop1.example.com."),
+    ];
+    // hop1 is the start of a chain of the maximum length, hop0 of one alias more.
+    let hop = |i: usize| format!("hop{}.example.com.", i);
+    for i in 0..MAX_CNAME_CHAIN {
+        records.push(cname(&hop(i), &hop(i + 1)));
+    }
+    records.push(cname(&hop(MAX_CNAME_CHAIN), "www.example.com."));
+    let storage = Arc::new(MemoryStorage::new());
+    add_zone(&storage, "example.com.", records).await;
+    let server = TestServer::start(storage).await;
+    let aliases = |names: &[String]| {
+        names
+            .iter()
+            .map(|name| (name.clone(), RecordType::CNAME))
+            .collect::<Vec<_>>()
+    };
+
+    // Aliases in the zone are followed to the final answer.
+    let response = server.query_udp("alias.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        vec![
+            ("alias.example.com.".to_string(), RecordType::CNAME),
+            ("chain.example.com.".to_string(), RecordType::CNAME),
+            ("www.example.com.".to_string(), RecordType::A),
+        ]
+    );
+    assert_eq!(
+        response.answers()[2].data(),
+        Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+    );
+
+    // A loop is answered with the aliases up to the loop, rather than followed forever.
+    let response = server.query_udp("loop1.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        aliases(&[
+            "loop1.example.com.".to_string(),
+            "loop2.example.com.".to_string()
+        ])
+    );
+
+    // A chain of the maximum length is followed to its end.
+    let response = server.query_udp(&hop(1), RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    let mut expected = aliases(&(1..=MAX_CNAME_CHAIN).map(hop).collect::<Vec<_>>());
+    expected.push(("www.example.com.".to_string(), RecordType::A));
+    assert_eq!(summary(response.answers()), expected);
+
+    // A longer chain stops after the maximum amount of aliases, the client can follow the rest.
+    let response = server.query_udp(&hop(0), RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        summary(response.answers()),
+        aliases(&(0..MAX_CNAME_CHAIN).map(hop).collect::<Vec<_>>())
+    );
+}
+
+/// Check that a name below a delegation gets a referral, whether its records were added before or
+/// after the delegation, and that the zone cuts follow delegations which are added and removed.
+pub(crate) async fn assert_delegations<S>(storage: S)
+where
+    S: Storage + Clone + Send + Sync + Unpin + 'static,
+{
+    let zone = lower("example.com.");
+    add_zone(
+        &storage,
+        "example.com.",
+        vec![
+            record("example.com.", 3600, RData::NS(name_of("ns1.example.com."))),
+            a("www.child.example.com.", [192, 0, 2, 1]),
+            record(
+                "child.example.com.",
+                3600,
+                RData::NS(name_of("ns1.child.example.com.")),
+            ),
+            a("ns1.child.example.com.", [192, 0, 2, 53]),
+            a("www.other.example.com.", [192, 0, 2, 2]),
+        ],
+    )
+    .await;
+    let server = TestServer::start(storage.clone()).await;
+    let assert_referral = |response: Message, cut: &str| {
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(!response.authoritative());
+        assert!(response.answers().is_empty());
+        assert_eq!(
+            summary(response.name_servers()),
+            vec![(cut.to_string(), RecordType::NS)]
+        );
+    };
+    let assert_answer = |response: Message, name: &str| {
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(
+            summary(response.answers()),
+            vec![(name.to_string(), RecordType::A)]
+        );
+    };
+
+    // Records added before the delegation are occluded by it.
+    assert_referral(
+        server
+            .query_udp("www.child.example.com.", RecordType::A)
+            .await,
+        "child.example.com.",
+    );
+    // And so are records added after it.
+    storage
+        .add_record(
+            &zone,
+            &lower("new.child.example.com."),
+            a("new.child.example.com.", [192, 0, 2, 3]),
+        )
+        .await
+        .expect("Can add record");
+    assert_referral(
+        server
+            .query_udp("new.child.example.com.", RecordType::A)
+            .await,
+        "child.example.com.",
+    );
+
+    // A delegation added above existing records occludes them once the zone cache picks it up.
+    assert_answer(
+        server
+            .query_udp("www.other.example.com.", RecordType::A)
+            .await,
+        "www.other.example.com.",
+    );
+    storage
+        .add_record(
+            &zone,
+            &lower("other.example.com."),
+            record(
+                "other.example.com.",
+                3600,
+                RData::NS(name_of("ns1.example.net.")),
+            ),
+        )
+        .await
+        .expect("Can add record");
+    assert_eq!(
+        storage.zone_cuts(&zone).await.expect("Can load cuts"),
+        ZoneCuts {
+            delegations: [lower("child.example.com."), lower("other.example.com.")].into(),
+            dnames: HashSet::new(),
+        }
+    );
+    server.reload_zones().await;
+    assert_referral(
+        server
+            .query_udp("www.other.example.com.", RecordType::A)
+            .await,
+        "other.example.com.",
+    );
+
+    // Without the delegation, the records below it are served again.
+    storage
+        .remove_rrset(&zone, &lower("child.example.com."), RecordType::NS)
+        .await
+        .expect("Can remove RRset");
+    assert_eq!(
+        storage.zone_cuts(&zone).await.expect("Can load cuts"),
+        ZoneCuts {
+            delegations: [lower("other.example.com.")].into(),
+            dnames: HashSet::new(),
+        }
+    );
+    server.reload_zones().await;
+    for name in ["www.child.example.com.", "new.child.example.com."] {
+        assert_answer(server.query_udp(name, RecordType::A).await, name);
+    }
+}
+
+#[tokio::test]
+async fn delegations() {
+    assert_delegations(Arc::new(MemoryStorage::new())).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn sockets_are_handed_over() {
+    let old_storage = Arc::new(MemoryStorage::new());
+    let new_storage = CountingStorage::new();
+    for storage in [&old_storage, &new_storage.memory()] {
+        add_zone(
+            storage,
+            "example.com.",
+            vec![a("www.example.com.", [192, 0, 2, 1])],
+        )
+        .await;
+    }
+
+    // The old server runs on its own runtime, like a separate process. Dropping the runtime stops
+    // the server and closes its sockets, like the process exiting.
+    let (started_tx, started_rx) = oneshot::channel();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let old = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().expect("Can create runtime");
+        runtime.block_on(async move {
+            let local = "127.0.0.1:0".parse().unwrap();
+            let udp_socket = crate::bind_udp(local, true).expect("Can bind UDP socket");
+            let tcp_listener = crate::bind_tcp(local, true).expect("Can bind TCP listener");
+            let server = TestServer::start_on(old_storage, udp_socket, tcp_listener, |_| {}).await;
+            let _ = started_tx.send((server.udp, server.tcp));
+            let _ = stop_rx.await;
+        });
+    });
+    let (udp, tcp) = started_rx.await.expect("Old server starts");
+
+    // Clients keep asking, one query at a time each, over UDP and TCP. A query holds the gate
+    // while it is in flight, so the old server exits in between queries, like it does after
+    // draining.
+    let gate = Arc::new(RwLock::new(()));
+    let done = Arc::new(AtomicBool::new(false));
+    let clients = (0..4)
+        .map(|client| {
+            let (gate, done) = (gate.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut answered = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let _in_flight = gate.read().await;
+                    let message = query("www.example.com.", RecordType::A);
+                    let response = if client % 2 == 0 {
+                        exchange_udp(udp, message).await
+                    } else {
+                        exchange_tcp(tcp, message).await
+                    };
+                    assert_eq!(response.response_code(), ResponseCode::NoError);
+                    assert_eq!(response.answers().len(), 1);
+                    answered += 1;
+                }
+                answered
+            })
+        })
+        .collect::<Vec<_>>();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The new server binds the same ports, and gets its share of the queries while it is still
+    // loading its zones.
+    new_storage.set_lookup_delay(Duration::from_millis(300));
+    let udp_socket = crate::bind_udp(udp, true).expect("Can bind UDP socket to the same port");
+    let tcp_listener = crate::bind_tcp(tcp, true).expect("Can bind TCP listener to the same port");
+    let new = TestServer::start_on(new_storage.clone(), udp_socket, tcp_listener, |_| {}).await;
+    new_storage.set_lookup_delay(Duration::ZERO);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    {
+        let _drained = gate.write().await;
+        stop_tx.send(()).expect("Old server is running");
+        tokio::task::spawn_blocking(move || old.join())
+            .await
+            .expect("Can wait for old server")
+            .expect("Old server stops");
+    }
+    let answered_before = response_codes(&new, Some("example.com."), ResponseCode::NoError);
+    assert!(answered_before > 0, "New server shares the queries");
+
+    // Only the new server is left to answer.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    done.store(true, Ordering::Relaxed);
+    for client in clients {
+        assert!(client.await.expect("Client got all answers") > 0);
+    }
+    assert!(response_codes(&new, Some("example.com."), ResponseCode::NoError) > answered_before);
+    assert_eq!(
+        response_codes(&new, None, ResponseCode::Refused)
+            + response_codes(&new, None, ResponseCode::ServFail),
+        0
+    );
+}
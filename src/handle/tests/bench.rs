@@ -0,0 +1,111 @@
+//! Benchmarks of the zone loader. These are ignored by default, run them in release mode with
+//! `cargo test --release bench_ -- --ignored --nocapture`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use arc_swap::ArcSwap;
+use trust_dns_proto::rr::RecordType;
+
+use super::{add_zone, lower, soa, CountingStorage};
+use crate::{
+    handle::{refresh_zone_cache, ZoneCache},
+    metrics::Metrics,
+    reload::Reload,
+    status::Status,
+    storage::Storage,
+};
+
+/// Amount of zones in the zone cache.
+const ZONES: usize = 50_000;
+
+/// Refresh the zone cache, and get the amount of storage calls it took.
+async fn refresh(
+    storage: &CountingStorage,
+    zone_cache: &ZoneCache,
+    metrics: &Metrics,
+    reload: Reload,
+    name: &str,
+) -> usize {
+    storage.take_lookups();
+    storage.take_zone_lookups();
+    let start = Instant::now();
+    refresh_zone_cache(storage, zone_cache, metrics, &Status::default(), reload).await;
+    let calls = storage.take_lookups() + storage.take_zone_lookups();
+    println!(
+        "{} of {} zones: {} storage calls in {:?}",
+        name,
+        ZONES,
+        calls,
+        start.elapsed()
+    );
+    calls
+}
+
+#[tokio::test]
+#[ignore = "benchmark"]
+async fn bench_refresh_unchanged_zones() {
+    let storage = CountingStorage::new();
+    for idx in 0..ZONES {
+        add_zone(&storage, &format!("zone{}.example.", idx), Vec::new()).await;
+    }
+    let zone_cache: ZoneCache = ArcSwap::from_pointee(HashMap::new());
+    let metrics = Metrics::new("bench".to_string());
+
+    // The zones, and the policy, SOA and cuts of every zone.
+    let calls = refresh(&storage, &zone_cache, &metrics, Reload::All, "initial load").await;
+    assert_eq!(calls, 1 + 3 * ZONES);
+    let loaded = zone_cache.load_full();
+    assert_eq!(loaded.len(), ZONES);
+
+    // Without reload requests, nothing is loaded and the cache is kept.
+    let calls = refresh(
+        &storage,
+        &zone_cache,
+        &metrics,
+        Reload::Zones(HashSet::new()),
+        "unchanged refresh",
+    )
+    .await;
+    assert_eq!(calls, 0);
+    assert!(Arc::ptr_eq(&loaded, &zone_cache.load_full()));
+
+    // A changed zone is checked for existence, and loaded again.
+    let zone = lower("zone0.example.");
+    storage
+        .set_records(
+            &zone,
+            &zone,
+            RecordType::SOA,
+            vec![soa("zone0.example.", 2)],
+        )
+        .await
+        .expect("Can replace SOA");
+    let calls = refresh(
+        &storage,
+        &zone_cache,
+        &metrics,
+        Reload::Zones([zone].into()),
+        "refresh with a changed SOA",
+    )
+    .await;
+    assert_eq!(calls, 4);
+    assert!(!Arc::ptr_eq(&loaded, &zone_cache.load_full()));
+    assert_eq!(zone_cache.load().len(), ZONES);
+
+    // The periodic refresh still loads everything, but keeps the cache if nothing changed.
+    let loaded = zone_cache.load_full();
+    let calls = refresh(
+        &storage,
+        &zone_cache,
+        &metrics,
+        Reload::All,
+        "periodic refresh",
+    )
+    .await;
+    assert_eq!(calls, 1 + 3 * ZONES);
+    assert!(Arc::ptr_eq(&loaded, &zone_cache.load_full()));
+}
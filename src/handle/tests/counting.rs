@@ -0,0 +1,321 @@
+//! A [Storage] which counts the record lookups made through it, to check how many storage
+//! roundtrips answering a query or loading the zone cache takes. The lookups can be slowed down as well, to act like a
+//! degraded storage.
+
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::broadcast;
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::{
+    memory::MemoryStorage,
+    policy::ZonePolicy,
+    reload::ZoneReload,
+    storage::{
+        ApiToken, DnssecKey, Page, Storage, StorageChange, StorageOp, StorageRecord, ZoneChange,
+        ZoneCuts,
+    },
+};
+
+/// A [MemoryStorage] which counts the lookups of records and zones, and optionally delays the
+/// lookups of records.
+#[derive(Clone)]
+pub(crate) struct CountingStorage {
+    inner: Arc<MemoryStorage>,
+    lookups: Arc<AtomicUsize>,
+    /// Lookups of the zones, their existence, policy and cuts.
+    zone_lookups: Arc<AtomicUsize>,
+    /// Delay of every lookup, in milliseconds.
+    lookup_delay: Arc<AtomicU64>,
+}
+
+impl CountingStorage {
+    pub fn new() -> Self {
+        CountingStorage {
+            inner: Arc::new(MemoryStorage::new()),
+            lookups: Arc::new(AtomicUsize::new(0)),
+            zone_lookups: Arc::new(AtomicUsize::new(0)),
+            lookup_delay: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The storage the lookups are made in.
+    pub fn memory(&self) -> Arc<MemoryStorage> {
+        self.inner.clone()
+    }
+
+    /// The amount of record lookups since the last call, which resets the count.
+    pub fn take_lookups(&self) -> usize {
+        self.lookups.swap(0, Ordering::SeqCst)
+    }
+
+    /// The amount of zone lookups since the last call, which resets the count.
+    pub fn take_zone_lookups(&self) -> usize {
+        self.zone_lookups.swap(0, Ordering::SeqCst)
+    }
+
+    /// Delay every following lookup of records.
+    pub fn set_lookup_delay(&self, delay: Duration) {
+        self.lookup_delay
+            .store(delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    async fn lookup(&self) {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        let delay = self.lookup_delay.load(Ordering::SeqCst);
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for CountingStorage {
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.ping().await
+    }
+
+    async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.zone_lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.zones().await
+    }
+
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.zone_lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.zone_exists(zone).await
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.inner.zones_page(cursor, limit).await
+    }
+
+    async fn lookup_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.lookup().await;
+        self.inner.lookup_records(domain, zone, rtype).await
+    }
+
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.lookup().await;
+        self.inner.lookup_all_records(domain, zone).await
+    }
+
+    async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.add_zone(zone).await
+    }
+
+    async fn add_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.add_record(zone, domain, record).await
+    }
+
+    async fn list_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.lookup().await;
+        self.inner.list_records(zone, domain).await
+    }
+
+    async fn list_records_bulk(
+        &self,
+        zone: &LowerName,
+        domains: &[LowerName],
+    ) -> Result<Vec<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        // Backends pipeline the lookups of a batch, so it takes a single roundtrip.
+        if !domains.is_empty() {
+            self.lookup().await;
+        }
+        self.inner.list_records_bulk(zone, domains).await
+    }
+
+    async fn list_domains(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.inner.list_domains(zone).await
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.inner.list_domains_page(zone, cursor, limit).await
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        self.zone_lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.zone_policy(zone).await
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.set_zone_policy(zone, policy).await
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.append_change(zone, change).await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        self.inner.changes_since(zone, serial).await
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.apply(ops).await
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.inner.has_subdomains(zone, domain).await
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        self.inner.closest_encloser(zone, name).await
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        self.zone_lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.zone_cuts(zone).await
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.inner.remove_rrset(zone, domain, rtype).await
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.inner.remove_record(zone, domain, record).await
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        self.inner.delete_zone(zone, dry_run).await
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.inner.set_records(zone, domain, rtype, records).await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        self.inner.tokens().await
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.set_token(token).await
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.set_token_last_used(id, last_used).await
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        self.inner.dnssec_keys(zone).await
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.set_dnssec_key(zone, key).await
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.inner.remove_dnssec_key(zone, id).await
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        self.inner.bump_serial(zone, floor).await
+    }
+
+    fn watch_zones(&self, reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        self.inner.watch_zones(reload)
+    }
+
+    fn subscribe_changes(&self) -> Option<broadcast::Receiver<StorageChange>> {
+        self.inner.subscribe_changes()
+    }
+}
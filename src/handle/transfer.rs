@@ -0,0 +1,361 @@
+use std::{error::Error, iter};
+
+use log::{debug, error, info, warn};
+use trust_dns_proto::{
+    rr::{RData, Record, RecordType},
+    serialize::binary::BinEncodable,
+};
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    client::{
+        op::{MessageType, ResponseCode},
+        rr::LowerName,
+    },
+    server::{Protocol, ResponseInfo},
+};
+
+use super::{storage_error, DnsHandler};
+use crate::{
+    ede::ExtendedError,
+    storage::{Storage, StorageRecord, ZoneChange},
+};
+
+/// Maximum size of the records in a single transfer message. Messages over TCP are limited to
+/// 64KiB, this leaves room for the header, the query and name compression not kicking in.
+const MAX_TRANSFER_MESSAGE_SIZE: usize = 60_000;
+
+impl<S> DnsHandler<S>
+where
+    S: Storage + Clone + Send + Sync + Unpin,
+{
+    /// Handle an AXFR (RFC 5936) or IXFR (RFC 1995) request. Transfers are only allowed for the
+    /// apex of a known zone, and to clients in the configured allow list.
+    ///
+    /// A full transfer sends the whole zone over multiple messages, starting and ending with the
+    /// SOA record. An incremental transfer sends the changes since the serial of the client,
+    /// condensed into a single difference sequence. If the journal of the zone does not go back
+    /// far enough, a full transfer is sent instead.
+    pub(super) async fn zone_transfer<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let zone = request.query().name();
+        if !self.zone_list().contains_key(zone) {
+            self.metrics.increment_unknown_zone_transfer("refused");
+            return self
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::Refused,
+                    None,
+                    Some(ExtendedError::NotAuthoritative),
+                )
+                .await;
+        }
+
+        let incremental = request.query().query_type() == RecordType::IXFR;
+        let src = request.src().ip();
+        // IXFR can be attempted over UDP, in which case the client is told to retry over TCP.
+        if (request.protocol() != Protocol::Tcp && !incremental)
+            || !self
+                .config
+                .allow_transfer
+                .iter()
+                .any(|prefix| prefix.contains(src))
+        {
+            debug!(
+                "Refusing transfer of zone {} to {} over {}",
+                zone,
+                src,
+                request.protocol()
+            );
+            self.metrics.increment_zone_transfer(zone, "refused");
+            return self
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::Refused,
+                    Some(zone),
+                    Some(ExtendedError::Prohibited),
+                )
+                .await;
+        }
+
+        let soa = match self.zone_soa(zone).await {
+            Err(e) => {
+                error!("Failed to load SOA of zone {} for transfer: {}", zone, e);
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone),
+                        Some(storage_error(&*e)),
+                    )
+                    .await;
+            }
+            Ok(None) => {
+                return self.reply_missing_soa(request, response_handle, zone).await;
+            }
+            Ok(Some(soa)) => soa,
+        };
+
+        if incremental {
+            let client_serial = match request.name_servers().iter().find_map(|record| match record
+                .data()
+            {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            }) {
+                Some(serial) => serial,
+                None => {
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::FormErr,
+                            Some(zone),
+                            None,
+                        )
+                        .await;
+                }
+            };
+
+            // A client which is up to date, or which asked over UDP, only gets the SOA.
+            if client_serial == soa_serial(&soa) || request.protocol() != Protocol::Tcp {
+                return self
+                    .send_transfer(request, response_handle, zone, vec![soa.as_record()])
+                    .await;
+            }
+
+            match self.storage.changes_since(zone, client_serial).await {
+                Err(e) => {
+                    error!("Failed to load journal of zone {}: {}", zone, e);
+                    return self
+                        .reply_error(
+                            request,
+                            response_handle,
+                            ResponseCode::ServFail,
+                            Some(zone),
+                            Some(storage_error(&*e)),
+                        )
+                        .await;
+                }
+                Ok(Some(changes)) => {
+                    let (removed, added) = condense_changes(changes);
+                    let mut client_soa = soa.clone();
+                    set_soa_serial(&mut client_soa, client_serial);
+                    debug!(
+                        "Incremental transfer of zone {} from serial {} ({} removed, {} added)",
+                        zone,
+                        client_serial,
+                        removed.len(),
+                        added.len()
+                    );
+                    let records = iter::once(soa.as_record())
+                        .chain(iter::once(client_soa.as_record()))
+                        .chain(removed.iter().map(|sr| sr.as_record()))
+                        .chain(iter::once(soa.as_record()))
+                        .chain(added.iter().map(|sr| sr.as_record()))
+                        .chain(iter::once(soa.as_record()))
+                        .collect();
+                    let response_info = self
+                        .send_transfer(request, response_handle, zone, records)
+                        .await;
+                    self.metrics.increment_zone_transfer(zone, "incremental");
+                    return response_info;
+                }
+                Ok(None) => {
+                    debug!(
+                        "Journal of zone {} does not reach serial {}, sending full transfer",
+                        zone, client_serial
+                    );
+                }
+            }
+        }
+
+        let records = match self.zone_records(zone).await {
+            Err(e) => {
+                error!(
+                    "Failed to load records of zone {} for transfer: {}",
+                    zone, e
+                );
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone),
+                        Some(storage_error(&*e)),
+                    )
+                    .await;
+            }
+            Ok(records) => records,
+        };
+
+        let response_info = self
+            .send_transfer(
+                request,
+                response_handle,
+                zone,
+                iter::once(soa.as_record())
+                    .chain(records.iter().map(|sr| sr.as_record()))
+                    .chain(iter::once(soa.as_record()))
+                    .collect(),
+            )
+            .await;
+        self.metrics.increment_zone_transfer(zone, "served");
+
+        response_info
+    }
+
+    /// Send the records of a transfer, split over as many messages as needed.
+    async fn send_transfer<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        mut response_handle: R,
+        zone: &LowerName,
+        records: Vec<&Record>,
+    ) -> ResponseInfo {
+        let mut header = *request.header();
+        header.set_authoritative(true);
+        header.set_message_type(MessageType::Response);
+
+        let record_count = records.len();
+        let messages = split_messages(records);
+        let message_count = messages.len();
+
+        let mut response_info = ResponseInfo::from(*request.header());
+        for message in messages {
+            let response_builder = MessageResponseBuilder::from_message_request(request);
+            let msg = response_builder.build(header, message, [], [], []);
+            response_info = match response_handle.send_response(msg).await {
+                Ok(info) => info,
+                Err(ioe) => {
+                    warn!("Failed to send transfer of zone {}: {}", zone, ioe);
+                    return ResponseInfo::from(*request.header());
+                }
+            };
+        }
+
+        info!(
+            "Transferred zone {} to {} ({} records in {} messages)",
+            zone,
+            request.src().ip(),
+            record_count,
+            message_count
+        );
+        self.metrics
+            .increment_zone_response_code(zone, ResponseCode::NoError);
+
+        response_info
+    }
+
+    /// Load the SOA record of a zone.
+    async fn zone_soa(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .storage
+            .lookup_records(zone, zone, RecordType::SOA)
+            .await?
+            .and_then(|soas| soas.into_iter().next()))
+    }
+
+    /// Load all records of a zone, except for the SOA record.
+    async fn zone_records(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let domains = self.storage.list_domains(zone).await?;
+        Ok(self
+            .storage
+            .list_records_bulk(zone, &domains)
+            .await?
+            .into_iter()
+            .flatten()
+            .filter(|sr| sr.as_record().record_type() != RecordType::SOA)
+            .collect())
+    }
+}
+
+/// Condense a sequence of changes into a single set of removed and added records. A record which
+/// is added and later removed (or the other way around) cancels out. SOA records are left out, as
+/// these frame the difference sequence.
+fn condense_changes(changes: Vec<ZoneChange>) -> (Vec<StorageRecord>, Vec<StorageRecord>) {
+    let mut removed: Vec<StorageRecord> = Vec::new();
+    let mut added: Vec<StorageRecord> = Vec::new();
+    for change in changes {
+        for record in change.removed {
+            match added
+                .iter()
+                .position(|sr| sr.as_record() == record.as_record())
+            {
+                Some(idx) => {
+                    added.remove(idx);
+                }
+                None => removed.push(record),
+            }
+        }
+        for record in change.added {
+            match removed
+                .iter()
+                .position(|sr| sr.as_record() == record.as_record())
+            {
+                Some(idx) => {
+                    removed.remove(idx);
+                }
+                None => added.push(record),
+            }
+        }
+    }
+
+    let is_soa = |sr: &StorageRecord| sr.as_record().record_type() == RecordType::SOA;
+    removed.retain(|sr| !is_soa(sr));
+    added.retain(|sr| !is_soa(sr));
+    (removed, added)
+}
+
+/// Get the serial of an SOA record.
+pub(super) fn soa_serial(soa: &StorageRecord) -> u32 {
+    match soa.as_record().data() {
+        Some(RData::SOA(soa)) => soa.serial(),
+        _ => 0,
+    }
+}
+
+/// Overwrite the serial of an SOA record.
+pub(super) fn set_soa_serial(soa: &mut StorageRecord, serial: u32) {
+    if let Some(RData::SOA(soa)) = soa.as_mut_record().data_mut() {
+        *soa = trust_dns_proto::rr::rdata::SOA::new(
+            soa.mname().clone(),
+            soa.rname().clone(),
+            serial,
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum(),
+        );
+    }
+}
+
+/// Split records over messages which fit in a TCP message.
+fn split_messages(records: Vec<&Record>) -> Vec<Vec<&Record>> {
+    let mut messages = vec![Vec::new()];
+    let mut size = 0;
+    for record in records {
+        // The uncompressed size is an upper bound of the size in the message.
+        let record_size = record.to_bytes().map(|bytes| bytes.len()).unwrap_or(0);
+        let current = messages.last_mut().expect("There is always a message");
+        if size + record_size > MAX_TRANSFER_MESSAGE_SIZE && !current.is_empty() {
+            messages.push(vec![record]);
+            size = record_size;
+        } else {
+            current.push(record);
+            size += record_size;
+        }
+    }
+    messages
+}
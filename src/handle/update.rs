@@ -0,0 +1,606 @@
+use std::{collections::HashMap, error::Error};
+
+use log::{debug, error, info, trace};
+use trust_dns_proto::rr::{DNSClass, RData, Record, RecordType};
+use trust_dns_server::{
+    authority::UpdateRequest,
+    client::{op::ResponseCode, rr::LowerName},
+    server::ResponseInfo,
+};
+
+use super::{storage_error, transfer::soa_serial, DnsHandler};
+use crate::{
+    ede::ExtendedError,
+    storage::{RecordSet, SerialChanged, Storage, StorageOp, StorageRecord, ZoneChange},
+};
+
+/// Maximum amount of times an update is checked and applied again because the zone changed
+/// concurrently.
+const MAX_UPDATE_ATTEMPTS: usize = 8;
+
+/// Reasons an update is not applied.
+enum UpdateError {
+    /// The update is rejected with the given response code, nothing has been changed.
+    Rejected(ResponseCode),
+    /// The serial of the zone changed after the prerequisites were checked, nothing has been
+    /// changed.
+    Conflict,
+    /// The storage failed, nothing has been changed.
+    Storage(Box<dyn Error + Send + Sync>),
+}
+
+impl From<Box<dyn Error + Send + Sync>> for UpdateError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        if e.is::<SerialChanged>() {
+            UpdateError::Conflict
+        } else {
+            UpdateError::Storage(e)
+        }
+    }
+}
+
+impl<S> DnsHandler<S>
+where
+    S: Storage + Clone + Send + Sync + Unpin,
+{
+    /// Handle a dynamic update (RFC 2136). Updates are only accepted for the apex of a known
+    /// zone, from clients in the configured allow list.
+    ///
+    /// The prerequisites are checked and all updates are applied to a copy of the affected
+    /// domains first, so a message is either applied in full or not at all. If the update changes
+    /// the zone but not its SOA, the serial of the SOA is incremented. The change is only written
+    /// if the serial did not change since the prerequisites were checked, otherwise the update is
+    /// checked and applied again. Changes which don't touch the serial, like API changes in a zone
+    /// without automatic serials, are not noticed.
+    pub(super) async fn update<R: trust_dns_server::server::ResponseHandler>(
+        &self,
+        request: &trust_dns_server::server::Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let zone_section = request.zone();
+        if zone_section.query_type() != RecordType::SOA
+            || zone_section.query_class() != DNSClass::IN
+        {
+            return self
+                .reply_error(request, response_handle, ResponseCode::FormErr, None, None)
+                .await;
+        }
+
+        let zone = zone_section.name();
+        if !self.zone_list().contains_key(zone) {
+            return self
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::NotAuth,
+                    None,
+                    Some(ExtendedError::NotAuthoritative),
+                )
+                .await;
+        }
+
+        let src = request.src().ip();
+        if !self
+            .config
+            .allow_update
+            .iter()
+            .any(|prefix| prefix.contains(src))
+        {
+            debug!("Refusing update of zone {} from {}", zone, src);
+            self.metrics.increment_zone_update(zone, "refused");
+            return self
+                .reply_error(
+                    request,
+                    response_handle,
+                    ResponseCode::Refused,
+                    Some(zone),
+                    Some(ExtendedError::Prohibited),
+                )
+                .await;
+        }
+
+        let mut result = self.apply_update(request, zone).await;
+        for _ in 1..MAX_UPDATE_ATTEMPTS {
+            if !matches!(result, Err(UpdateError::Conflict)) {
+                break;
+            }
+            trace!("Zone {} changed during update, retrying", zone);
+            result = self.apply_update(request, zone).await;
+        }
+
+        match result {
+            Ok(None) => {
+                debug!("Update of zone {} did not change anything", zone);
+                self.metrics.increment_zone_update(zone, "applied");
+            }
+            Ok(Some(change)) => {
+                info!(
+                    "Updated zone {} to serial {} ({} removed, {} added)",
+                    zone,
+                    change.serial,
+                    change.removed.len(),
+                    change.added.len()
+                );
+                self.metrics.increment_zone_update(zone, "applied");
+                if change.changes_cuts(zone) {
+                    self.config.zone_reload.zone(zone);
+                }
+                // A change missing from the journal leaves a gap, which is never served: transfers
+                // from a serial before it fall back to a full transfer.
+                if let Err(e) = self.storage.append_change(zone, &change).await {
+                    error!("Failed to journal update of zone {}: {}", zone, e);
+                }
+                self.config.notifier.notify(zone);
+            }
+            Err(UpdateError::Rejected(code)) => {
+                debug!("Rejecting update of zone {}: {}", zone, code);
+                self.metrics.increment_zone_update(zone, "rejected");
+                return self
+                    .reply_error(request, response_handle, code, Some(zone), None)
+                    .await;
+            }
+            Err(UpdateError::Conflict) => {
+                error!("Zone {} keeps changing during update", zone);
+                self.metrics.increment_zone_update(zone, "failed");
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone),
+                        None,
+                    )
+                    .await;
+            }
+            Err(UpdateError::Storage(e)) => {
+                error!("Failed to update zone {}: {}", zone, e);
+                self.metrics.increment_zone_update(zone, "failed");
+                return self
+                    .reply_error(
+                        request,
+                        response_handle,
+                        ResponseCode::ServFail,
+                        Some(zone),
+                        Some(storage_error(&*e)),
+                    )
+                    .await;
+            }
+        }
+
+        // The response to an update carries no records, only the response code.
+        self.reply_error(
+            request,
+            response_handle,
+            ResponseCode::NoError,
+            Some(zone),
+            None,
+        )
+        .await
+    }
+
+    /// Check the prerequisites of an update, and apply the update if they hold. Returns the
+    /// change to the zone, or [None] if the update did not change anything.
+    async fn apply_update(
+        &self,
+        request: &trust_dns_server::server::Request,
+        zone: &LowerName,
+    ) -> Result<Option<ZoneChange>, UpdateError> {
+        let mut domains = ZoneDomains::new(&self.storage, zone);
+
+        self.check_prerequisites(&mut domains, request.prerequisites())
+            .await?;
+
+        for update in request.updates() {
+            prescan(zone, update)?;
+        }
+
+        let soa = domains
+            .records(zone)
+            .await?
+            .iter()
+            .find(|sr| sr.as_record().record_type() == RecordType::SOA)
+            .cloned();
+        let soa = match soa {
+            Some(soa) => soa,
+            None => {
+                error!("Zone {} has no SOA record, refusing update", zone);
+                self.metrics.increment_zone_missing_soa(zone);
+                return Err(UpdateError::Rejected(ResponseCode::ServFail));
+            }
+        };
+
+        for update in request.updates() {
+            let name = LowerName::from(update.name());
+            let records = domains.records(&name).await?;
+            apply(zone, &name, records, update);
+        }
+
+        let (rrsets, removed, added) = domains.changes();
+        if rrsets.is_empty() {
+            return Ok(None);
+        }
+
+        // The SOA is always part of a change, so secondaries notice it. It is swapped only if its
+        // serial is still the one the prerequisites were checked at.
+        let from_serial = soa_serial(&soa);
+        let mut new_soa = domains
+            .records(zone)
+            .await?
+            .iter()
+            .find(|sr| sr.as_record().record_type() == RecordType::SOA)
+            .cloned()
+            .unwrap_or_else(|| soa.clone());
+        if soa_serial(&new_soa) == from_serial {
+            new_soa.bump_serial(None);
+        }
+        let serial = soa_serial(&new_soa);
+
+        let mut ops = rrsets
+            .into_iter()
+            .filter(|rrset| !(rrset.domain == *zone && rrset.rtype == RecordType::SOA))
+            .map(|rrset| rrset.into_op(zone))
+            .collect::<Vec<_>>();
+        ops.push(StorageOp::SwapSoa {
+            zone: zone.clone(),
+            serial: from_serial,
+            soa: Box::new(new_soa),
+        });
+        self.storage.apply(ops).await?;
+
+        Ok(Some(ZoneChange {
+            from_serial,
+            serial,
+            removed,
+            added,
+        }))
+    }
+
+    /// Check the prerequisites of an update as described in RFC 2136 section 3.2.
+    async fn check_prerequisites(
+        &self,
+        domains: &mut ZoneDomains<'_, S>,
+        prerequisites: &[Record],
+    ) -> Result<(), UpdateError> {
+        // Value dependent prerequisites are grouped per RRset, which must match exactly.
+        let mut required_rrsets: HashMap<(LowerName, RecordType), Vec<&Record>> = HashMap::new();
+
+        for prerequisite in prerequisites {
+            if prerequisite.ttl() != 0 {
+                return Err(UpdateError::Rejected(ResponseCode::FormErr));
+            }
+            let name = LowerName::from(prerequisite.name());
+            if !domains.zone.zone_of(&name) {
+                return Err(UpdateError::Rejected(ResponseCode::NotZone));
+            }
+
+            let rtype = prerequisite.record_type();
+            match prerequisite.dns_class() {
+                DNSClass::ANY | DNSClass::NONE if prerequisite.data().is_some() => {
+                    return Err(UpdateError::Rejected(ResponseCode::FormErr));
+                }
+                DNSClass::ANY => {
+                    let records = domains.records(&name).await?;
+                    if rtype == RecordType::ANY {
+                        if records.is_empty() {
+                            return Err(UpdateError::Rejected(ResponseCode::NXDomain));
+                        }
+                    } else if !records
+                        .iter()
+                        .any(|sr| sr.as_record().record_type() == rtype)
+                    {
+                        return Err(UpdateError::Rejected(ResponseCode::NXRRSet));
+                    }
+                }
+                DNSClass::NONE => {
+                    let records = domains.records(&name).await?;
+                    if rtype == RecordType::ANY {
+                        if !records.is_empty() {
+                            return Err(UpdateError::Rejected(ResponseCode::YXDomain));
+                        }
+                    } else if records
+                        .iter()
+                        .any(|sr| sr.as_record().record_type() == rtype)
+                    {
+                        return Err(UpdateError::Rejected(ResponseCode::YXRRSet));
+                    }
+                }
+                DNSClass::IN => required_rrsets
+                    .entry((name, rtype))
+                    .or_default()
+                    .push(prerequisite),
+                _ => return Err(UpdateError::Rejected(ResponseCode::FormErr)),
+            }
+        }
+
+        for ((name, rtype), required) in required_rrsets {
+            let records = domains.records(&name).await?;
+            let existing = records
+                .iter()
+                .filter(|sr| sr.as_record().record_type() == rtype)
+                .collect::<Vec<_>>();
+            let matches = existing.len() == required.len()
+                && required.iter().all(|record| {
+                    existing
+                        .iter()
+                        .any(|sr| sr.as_record().data() == record.data())
+                });
+            if !matches {
+                return Err(UpdateError::Rejected(ResponseCode::NXRRSet));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The domains touched by an update, with their records as stored and as modified by the
+/// update.
+struct ZoneDomains<'a, S> {
+    storage: &'a S,
+    zone: &'a LowerName,
+    domains: HashMap<LowerName, (Vec<StorageRecord>, Vec<StorageRecord>)>,
+}
+
+impl<'a, S> ZoneDomains<'a, S>
+where
+    S: Storage,
+{
+    fn new(storage: &'a S, zone: &'a LowerName) -> Self {
+        ZoneDomains {
+            storage,
+            zone,
+            domains: HashMap::new(),
+        }
+    }
+
+    /// Get the (modified) records of a domain, loading them from storage on first access.
+    async fn records(
+        &mut self,
+        name: &LowerName,
+    ) -> Result<&mut Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        if !self.domains.contains_key(name) {
+            let records = self.storage.list_records(self.zone, name).await?;
+            self.domains
+                .insert(name.clone(), (records.clone(), records));
+        }
+        Ok(&mut self
+            .domains
+            .get_mut(name)
+            .expect("Domain was loaded above")
+            .1)
+    }
+
+    /// Get the RRsets which are modified, together with the records which are removed and added.
+    fn changes(&self) -> (Vec<RecordSet>, Vec<StorageRecord>, Vec<StorageRecord>) {
+        let mut rrsets = Vec::new();
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for (domain, (original, current)) in &self.domains {
+            let mut rtypes = original
+                .iter()
+                .chain(current)
+                .map(|sr| sr.as_record().record_type())
+                .collect::<Vec<_>>();
+            rtypes.sort();
+            rtypes.dedup();
+            for rtype in rtypes {
+                let old = rrset(original, rtype);
+                let new = rrset(current, rtype);
+                let changed = old.len() != new.len()
+                    || old.iter().zip(&new).any(|(old, new)| {
+                        old.as_record() != new.as_record()
+                            || old.as_record().ttl() != new.as_record().ttl()
+                    });
+                if !changed {
+                    continue;
+                }
+
+                removed.extend(
+                    old.iter()
+                        .filter(|old| !contains_record(&new, old))
+                        .map(|sr| (*sr).clone()),
+                );
+                added.extend(
+                    new.iter()
+                        .filter(|new| !contains_record(&old, new))
+                        .map(|sr| (*sr).clone()),
+                );
+                rrsets.push(RecordSet {
+                    domain: domain.clone(),
+                    rtype,
+                    records: new.into_iter().cloned().collect(),
+                });
+            }
+        }
+
+        let is_soa = |sr: &StorageRecord| sr.as_record().record_type() == RecordType::SOA;
+        removed.retain(|sr| !is_soa(sr));
+        added.retain(|sr| !is_soa(sr));
+        (rrsets, removed, added)
+    }
+}
+
+/// Get the records of a type from a list of records.
+fn rrset(records: &[StorageRecord], rtype: RecordType) -> Vec<&StorageRecord> {
+    records
+        .iter()
+        .filter(|sr| sr.as_record().record_type() == rtype)
+        .collect()
+}
+
+/// Check if a list of records contains a record, ignoring the TTL.
+fn contains_record(records: &[&StorageRecord], record: &StorageRecord) -> bool {
+    records
+        .iter()
+        .any(|sr| sr.as_record() == record.as_record())
+}
+
+/// Verify a single update is well formed, as described in RFC 2136 section 3.4.1.
+fn prescan(zone: &LowerName, update: &Record) -> Result<(), UpdateError> {
+    if !zone.zone_of(&LowerName::from(update.name())) {
+        return Err(UpdateError::Rejected(ResponseCode::NotZone));
+    }
+
+    let rtype = update.record_type();
+    let valid = match update.dns_class() {
+        DNSClass::IN => !is_meta_type(rtype) && rtype != RecordType::ANY && update.data().is_some(),
+        DNSClass::ANY => !is_meta_type(rtype) && update.ttl() == 0 && update.data().is_none(),
+        DNSClass::NONE => !is_meta_type(rtype) && rtype != RecordType::ANY && update.ttl() == 0,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(UpdateError::Rejected(ResponseCode::FormErr))
+    }
+}
+
+/// Check if a type is a query only type, which can't be part of an update.
+fn is_meta_type(rtype: RecordType) -> bool {
+    matches!(
+        rtype,
+        RecordType::AXFR | RecordType::IXFR | RecordType::OPT | RecordType::NULL
+    )
+}
+
+/// Apply a single, prescanned, update to the records of a domain, as described in RFC 2136
+/// section 3.4.2. Updates which can't be applied are silently ignored, as the RFC prescribes.
+fn apply(zone: &LowerName, name: &LowerName, records: &mut Vec<StorageRecord>, update: &Record) {
+    let rtype = update.record_type();
+    let apex = name == zone;
+    match update.dns_class() {
+        DNSClass::IN => {
+            if rtype == RecordType::SOA {
+                // The SOA can only be replaced, by one with a newer serial.
+                if !apex {
+                    return;
+                }
+                let current = records
+                    .iter_mut()
+                    .find(|sr| sr.as_record().record_type() == RecordType::SOA);
+                let new_serial = match update.data() {
+                    Some(RData::SOA(soa)) => soa.serial(),
+                    _ => return,
+                };
+                if let Some(current) = current {
+                    if (new_serial.wrapping_sub(soa_serial(current)) as i32) > 0 {
+                        *current = StorageRecord::new(update.clone());
+                    }
+                }
+                return;
+            }
+
+            // A CNAME can't coexist with other data.
+            let has_cname = records
+                .iter()
+                .any(|sr| sr.as_record().record_type() == RecordType::CNAME);
+            let has_other = records
+                .iter()
+                .any(|sr| sr.as_record().record_type() != RecordType::CNAME);
+            if (rtype == RecordType::CNAME && has_other)
+                || (rtype != RecordType::CNAME && has_cname)
+            {
+                return;
+            }
+
+            match records.iter_mut().find(|sr| sr.as_record() == update) {
+                // Only the TTL of an existing record can change.
+                Some(existing) => {
+                    existing.as_mut_record().set_ttl(update.ttl());
+                }
+                // A name has a single CNAME, so a new one replaces it (section 3.4.2.2).
+                None if rtype == RecordType::CNAME => {
+                    records.clear();
+                    records.push(StorageRecord::new(update.clone()));
+                }
+                None => records.push(StorageRecord::new(update.clone())),
+            }
+        }
+        DNSClass::ANY => {
+            records.retain(|sr| {
+                let record_type = sr.as_record().record_type();
+                let protected = apex && matches!(record_type, RecordType::SOA | RecordType::NS);
+                protected || (rtype != RecordType::ANY && record_type != rtype)
+            });
+        }
+        DNSClass::NONE => {
+            if rtype == RecordType::SOA {
+                return;
+            }
+            // The last NS record of the zone can't be removed.
+            if apex && rtype == RecordType::NS && rrset(records, RecordType::NS).len() <= 1 {
+                return;
+            }
+            records.retain(|sr| {
+                sr.as_record().record_type() != rtype || sr.as_record().data() != update.data()
+            });
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::rr::{RData, RecordType};
+
+    use super::apply;
+    use crate::handle::tests::{a, cname, lower, summary};
+
+    #[test]
+    fn cname_replaces_cname() {
+        let (zone, name) = (lower("example.com."), lower("www.example.com."));
+        let mut records = vec![cname("www.example.com.", "old.example.com.")];
+        let update = cname("www.example.com.", "new.example.com.");
+        apply(&zone, &name, &mut records, update.as_record());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_record(), update.as_record());
+    }
+
+    #[test]
+    fn cname_updates_ttl_of_same_cname() {
+        let (zone, name) = (lower("example.com."), lower("www.example.com."));
+        let mut records = vec![cname("www.example.com.", "web.example.com.")];
+        let mut update = cname("www.example.com.", "web.example.com.");
+        update.as_mut_record().set_ttl(60);
+        apply(&zone, &name, &mut records, update.as_record());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_record().ttl(), 60);
+    }
+
+    #[test]
+    fn cname_is_refused_alongside_other_data() {
+        let (zone, name) = (lower("example.com."), lower("www.example.com."));
+        let mut records = vec![a("www.example.com.", [192, 0, 2, 1])];
+        apply(
+            &zone,
+            &name,
+            &mut records,
+            cname("www.example.com.", "web.example.com.").as_record(),
+        );
+        let records = records
+            .into_iter()
+            .map(|sr| sr.as_record().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary(&records),
+            vec![("www.example.com.".to_string(), RecordType::A)]
+        );
+    }
+
+    #[test]
+    fn other_data_is_refused_alongside_cname() {
+        let (zone, name) = (lower("example.com."), lower("www.example.com."));
+        let mut records = vec![cname("www.example.com.", "web.example.com.")];
+        apply(
+            &zone,
+            &name,
+            &mut records,
+            a("www.example.com.", [192, 0, 2, 1]).as_record(),
+        );
+        assert_eq!(records.len(), 1);
+        assert!(matches!(
+            records[0].as_record().data(),
+            Some(RData::CNAME(_))
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info, warn};
+use prometheus::{opts, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use trust_dns_proto::rr::RData;
+use utoipa::ToSchema;
+
+use crate::storage::{Storage, StorageRecord};
+
+/// Interval at which the checked records are collected from storage.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// Maximum time a single probe can take. Probes with a shorter interval time out after the
+/// interval instead.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum length of the status line of an HTTP response.
+const MAX_STATUS_LINE_LENGTH: u64 = 256;
+
+/// Health check of the address in an A or AAAA record.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HealthCheck {
+    pub protocol: HealthProtocol,
+    pub port: u16,
+    /// Path requested by HTTP checks, defaults to `/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Seconds between probes.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u32,
+    /// Amount of consecutive failed probes after which the address is considered unhealthy.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+const fn default_interval_secs() -> u32 {
+    10
+}
+
+const fn default_failure_threshold() -> u32 {
+    3
+}
+
+/// The way an address is probed.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthProtocol {
+    /// The address is healthy if a TCP connection can be established.
+    Tcp,
+    /// The address is healthy if a GET request is answered with a 2xx or 3xx status.
+    Http,
+}
+
+/// Probes the addresses of records with a health check, and keeps their health in memory so
+/// unhealthy records can be left out of answers. Addresses which are not (yet) probed are
+/// considered healthy.
+pub struct HealthChecker {
+    targets: Mutex<HashMap<Target, TrackedTarget>>,
+    gauge: IntGaugeVec,
+}
+
+/// An address checked in a specific way. Records with the same address and check share a target.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Target {
+    addr: IpAddr,
+    check: HealthCheck,
+}
+
+struct TrackedTarget {
+    state: Arc<TargetState>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct TargetState {
+    unhealthy: AtomicBool,
+    failures: AtomicU32,
+    // unix timestamp in seconds of the last probe, 0 if the target was not probed yet.
+    last_checked: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Health of a single checked address, as exposed through the API.
+#[derive(Serialize, ToSchema)]
+pub struct TargetStatus {
+    #[schema(value_type = String, example = "192.0.2.1")]
+    pub address: IpAddr,
+    #[serde(flatten)]
+    pub check: HealthCheck,
+    pub healthy: bool,
+    pub failures: u32,
+    pub last_checked: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl HealthChecker {
+    /// Create a new health checker without any targets.
+    pub fn new() -> HealthChecker {
+        HealthChecker {
+            targets: Mutex::new(HashMap::new()),
+            gauge: IntGaugeVec::new(
+                opts!(
+                    "record_health",
+                    "health of addresses with a health check, 1 if healthy"
+                ),
+                &["address", "protocol", "port"],
+            )
+            .expect("Can create record health gauge"),
+        }
+    }
+
+    /// Gauge tracking the health of every checked address, to be registered in a metrics
+    /// registry.
+    pub fn gauge(&self) -> IntGaugeVec {
+        self.gauge.clone()
+    }
+
+    /// Check if a record is healthy. Records without a health check are always healthy.
+    fn is_healthy(&self, record: &StorageRecord) -> bool {
+        match Target::of(record) {
+            None => true,
+            Some(target) => self
+                .targets
+                .lock()
+                .unwrap()
+                .get(&target)
+                .is_none_or(|tracked| !tracked.state.unhealthy.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Remove unhealthy records from an RRset. If no record is healthy the set is left as is,
+    /// since answering with possibly unhealthy addresses beats not answering at all.
+    ///
+    /// Returns the amount of removed records, or [None] if all records are unhealthy.
+    pub fn filter(&self, records: &mut Vec<StorageRecord>) -> Option<usize> {
+        let healthy = records
+            .iter()
+            .map(|sr| self.is_healthy(sr))
+            .collect::<Vec<_>>();
+        if !records.is_empty() && !healthy.contains(&true) {
+            return None;
+        }
+
+        let before = records.len();
+        let mut healthy = healthy.into_iter();
+        records.retain(|_| healthy.next().unwrap_or(true));
+        Some(before - records.len())
+    }
+
+    /// Current health of all checked addresses.
+    pub fn statuses(&self) -> Vec<TargetStatus> {
+        self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(target, tracked)| TargetStatus {
+                address: target.addr,
+                check: target.check.clone(),
+                healthy: !tracked.state.unhealthy.load(Ordering::Relaxed),
+                failures: tracked.state.failures.load(Ordering::Relaxed),
+                last_checked: tracked.state.last_checked.load(Ordering::Relaxed),
+                last_error: tracked.state.last_error.lock().unwrap().clone(),
+            })
+            .collect()
+    }
+
+    /// Periodically collect the records with a health check from storage, start probing new
+    /// addresses, and stop probing addresses which are no longer used by any record.
+    pub async fn sync_loop<S>(self: Arc<Self>, storage: S)
+    where
+        S: Storage + Send + Sync,
+    {
+        let mut interval = tokio::time::interval(SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            match collect_targets(&storage).await {
+                Ok(targets) => self.sync(targets),
+                Err(e) => error!("Failed to collect health checked records: {}", e),
+            }
+        }
+    }
+
+    fn sync(&self, targets: HashSet<Target>) {
+        let mut tracked = self.targets.lock().unwrap();
+        let before = tracked.len();
+        tracked.retain(|target, tracked| {
+            if targets.contains(target) {
+                return true;
+            }
+            debug!("Stopping health check of {}", target.addr);
+            tracked.task.abort();
+            let (addr, protocol, port) = target.labels();
+            // The gauge is created when the target is added, so this can't fail.
+            let _ = self.gauge.remove_label_values(&[&addr, protocol, &port]);
+            false
+        });
+        let removed = before - tracked.len();
+
+        let mut added = 0;
+        for target in targets {
+            if tracked.contains_key(&target) {
+                continue;
+            }
+            debug!("Starting health check of {}", target.addr);
+            let state = Arc::new(TargetState::default());
+            let (addr, protocol, port) = target.labels();
+            let gauge = self.gauge.with_label_values(&[&addr, protocol, &port]);
+            // Targets are healthy until probes say otherwise.
+            gauge.set(1);
+            let task = tokio::spawn(probe_loop(target.clone(), state.clone(), gauge));
+            tracked.insert(target, TrackedTarget { state, task });
+            added += 1;
+        }
+
+        if added != 0 || removed != 0 {
+            info!(
+                "Health checks synced, {} started, {} stopped, {} active",
+                added,
+                removed,
+                tracked.len()
+            );
+        }
+    }
+}
+
+impl Default for HealthChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Target {
+    /// Get the target of a record, if it has a health check. Only A and AAAA records can be
+    /// checked.
+    fn of(record: &StorageRecord) -> Option<Target> {
+        let check = record.health_check.clone()?;
+        let addr = match record.as_record().data()? {
+            RData::A(addr) => IpAddr::V4(*addr),
+            RData::AAAA(addr) => IpAddr::V6(*addr),
+            _ => return None,
+        };
+        Some(Target { addr, check })
+    }
+
+    /// Values of the address, protocol and port labels of the health gauge.
+    fn labels(&self) -> (String, &'static str, String) {
+        let protocol = match self.check.protocol {
+            HealthProtocol::Tcp => "tcp",
+            HealthProtocol::Http => "http",
+        };
+        (self.addr.to_string(), protocol, self.check.port.to_string())
+    }
+
+    /// Probe the target once.
+    async fn probe(&self) -> io::Result<()> {
+        let addr = SocketAddr::new(self.addr, self.check.port);
+        let timeout = PROBE_TIMEOUT.min(Duration::from_secs(self.check.interval_secs as u64));
+        let probe = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            if self.check.protocol == HealthProtocol::Tcp {
+                return Ok(());
+            }
+
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cetus\r\nConnection: close\r\n\r\n",
+                self.check.path.as_deref().unwrap_or("/"),
+                addr
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut status_line = String::new();
+            BufReader::new(stream.take(MAX_STATUS_LINE_LENGTH))
+                .read_line(&mut status_line)
+                .await?;
+            // The status line looks like `HTTP/1.1 200 OK`.
+            match status_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|code| code.parse::<u16>().ok())
+            {
+                Some(code) if (200..400).contains(&code) => Ok(()),
+                Some(code) => Err(io::Error::other(format!("HTTP status {}", code))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid HTTP response",
+                )),
+            }
+        };
+
+        match tokio::time::timeout(timeout, probe).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out")),
+        }
+    }
+}
+
+/// Probe a target forever, updating its state after every probe.
+async fn probe_loop(target: Target, state: Arc<TargetState>, gauge: prometheus::IntGauge) {
+    let period = Duration::from_secs(target.check.interval_secs.max(1) as u64);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let result = target.probe().await;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        state.last_checked.store(now, Ordering::Relaxed);
+        let was_unhealthy = state.unhealthy.load(Ordering::Relaxed);
+        let unhealthy = match result {
+            Ok(()) => {
+                state.failures.store(0, Ordering::Relaxed);
+                *state.last_error.lock().unwrap() = None;
+                false
+            }
+            Err(e) => {
+                debug!("Health check of {} failed: {}", target.addr, e);
+                let failures = state.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                *state.last_error.lock().unwrap() = Some(e.to_string());
+                failures >= target.check.failure_threshold.max(1)
+            }
+        };
+        state.unhealthy.store(unhealthy, Ordering::Relaxed);
+        gauge.set(if unhealthy { 0 } else { 1 });
+
+        if unhealthy && !was_unhealthy {
+            warn!(
+                "{} port {} is unhealthy, removing it from answers",
+                target.addr, target.check.port
+            );
+        } else if !unhealthy && was_unhealthy {
+            info!(
+                "{} port {} is healthy again, adding it to answers",
+                target.addr, target.check.port
+            );
+        }
+    }
+}
+
+/// Collect the targets of all records with a health check in all zones.
+async fn collect_targets<S>(storage: &S) -> Result<HashSet<Target>, Box<dyn Error + Send + Sync>>
+where
+    S: Storage + Send + Sync,
+{
+    let mut targets = HashSet::new();
+    for zone in storage.zones().await? {
+        for domain in storage.list_domains(&zone).await? {
+            for record in storage.list_records(&zone, &domain).await? {
+                if let Some(target) = Target::of(&record) {
+                    targets.insert(target);
+                }
+            }
+        }
+    }
+    Ok(targets)
+}
@@ -0,0 +1,84 @@
+use std::{error::Error, ops::Deref, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// The state of an idempotency key, as kept in storage.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdempotencyRecord {
+    /// Hash of the request which first used the key, so the key can't be reused for a different
+    /// request.
+    pub fingerprint: String,
+    /// The response to the request, not set while the request is still being handled.
+    pub response: Option<StoredResponse>,
+}
+
+/// A response to replay for requests which reuse an idempotency key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// The body of the response, base64 encoded.
+    pub body: String,
+}
+
+/// Outcome of claiming an idempotency key.
+pub enum IdempotencyClaim {
+    /// The key was not in use, the caller now holds it and must handle the request.
+    Claimed,
+    /// The key is already in use, by a request which is either still in progress or completed.
+    Existing(IdempotencyRecord),
+}
+
+/// Storage of idempotency keys. Keys are opaque to the storage, callers scope them as needed.
+#[async_trait::async_trait]
+pub trait IdempotencyStore {
+    /// Atomically claim a key by storing the record under it, if no record is stored yet. The
+    /// record expires after `ttl`, so a key is released if its holder never completes it.
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>>;
+
+    /// Replace the record of a claimed key, which then expires after `ttl`.
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Release a claimed key, so it can be claimed again.
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+#[async_trait::async_trait]
+impl<S> IdempotencyStore for Arc<S>
+where
+    S: IdempotencyStore + Send + Sync,
+{
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>> {
+        self.deref().claim_idempotency_key(key, record, ttl).await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref()
+            .complete_idempotency_key(key, record, ttl)
+            .await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().release_idempotency_key(key).await
+    }
+}
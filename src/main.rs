@@ -1,17 +1,45 @@
-use log::error;
-use std::{path::PathBuf, sync::Arc, time::Duration};
-use tokio::net::{TcpListener, UdpSocket};
+use audit::AuditSink;
+use idempotency::IdempotencyStore;
+use log::{error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    error::Error, future::Future, io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
+use storage::Storage;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    signal::unix::{signal, SignalKind},
+};
+use trust_dns_proto::rustls::tls_server::{read_cert, read_key_from_pem};
 use trust_dns_server::ServerFuture;
 
 mod api;
+mod audit;
+mod cache;
 mod config;
+mod dname;
+mod ecs;
+mod ede;
+mod etcd;
 mod fs;
 mod geo;
 mod handle;
+mod health;
+mod idempotency;
 mod memory;
 mod metrics;
+mod notify;
+mod policy;
+mod prefix;
 mod redis;
+mod reload;
+mod rrl;
+mod sqlite;
+mod status;
 mod storage;
+mod trace;
+mod webhook;
+mod weight;
 
 fn main() {
     pretty_env_logger::init();
@@ -24,7 +52,16 @@ fn main() {
         toml::from_slice::<config::Config>(&std::fs::read(cfg_path).expect("Can read config file"))
             .expect("Can decode config file");
 
-    let rt = tokio::runtime::Builder::new_current_thread()
+    // All state shared between tasks is already Send + Sync, as tokio::spawn requires it even on
+    // the current thread runtime.
+    let mut builder = if cfg.worker_threads == 0 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(cfg.worker_threads);
+        builder
+    };
+    let rt = builder
         .enable_all()
         .thread_name("cetus-runtime")
         .build()
@@ -33,36 +70,314 @@ fn main() {
     rt.block_on(async {
         let mut base_path = PathBuf::new();
         base_path.push("dns_storage");
-        let storage = redis::RedisClusterClient::new(
-            cfg.redis_config.username,
-            cfg.redis_config.password,
-            &cfg.redis_config.node_addresses,
-        );
-        storage.test().await.unwrap();
-        let storage = Arc::new(storage);
-        if let Some(api_address) = cfg.api_listener {
-            api::listen(storage.clone(), api_address);
+        match cfg.storage {
+            config::StorageBackend::Redis => {
+                let storage = Arc::new(redis::RedisClusterClient::new(&cfg.redis_config));
+                // Without a timeout this only returns once the storage is reachable.
+                let timeout = match cfg.storage_startup_mode {
+                    config::StorageStartupMode::Wait => {
+                        Some(Duration::from_millis(cfg.storage_startup_timeout_millis))
+                    }
+                    config::StorageStartupMode::Degraded => None,
+                };
+                let reachable = {
+                    let storage = storage.clone();
+                    async move { storage.test_with_backoff(timeout).await }
+                };
+                serve(cfg, storage, reachable).await
+            }
+            config::StorageBackend::Memory => {
+                warn!("Using in memory storage, all zones and records are lost on exit");
+                serve(cfg, Arc::new(memory::MemoryStorage::new()), async {
+                    Ok(())
+                })
+                .await
+            }
+            config::StorageBackend::Sqlite => {
+                let storage = match sqlite::SqliteStorage::open(&cfg.sqlite_config.path) {
+                    Ok(storage) => Arc::new(storage),
+                    Err(e) => {
+                        error!(
+                            "Failed to open database {:?}: {}",
+                            cfg.sqlite_config.path, e
+                        );
+                        std::process::exit(1);
+                    }
+                };
+                serve(cfg, storage, async { Ok(()) }).await
+            }
+            config::StorageBackend::Etcd => {
+                let timeout = Duration::from_millis(cfg.storage_startup_timeout_millis);
+                let storage = match tokio::time::timeout(
+                    timeout,
+                    etcd::EtcdStorage::connect(&cfg.etcd_config),
+                )
+                .await
+                {
+                    Ok(Ok(storage)) => Arc::new(storage),
+                    Ok(Err(e)) => {
+                        error!("Failed to connect to etcd: {}", e);
+                        std::process::exit(1);
+                    }
+                    Err(_) => {
+                        error!("Timed out connecting to etcd");
+                        std::process::exit(1);
+                    }
+                };
+                serve(cfg, storage, async { Ok(()) }).await
+            }
         }
-        let geoip_db = geo::GeoLocator::new(cfg.geoip_db_location).unwrap();
-        let handler =
-            handle::DnsHandler::new(cfg.instance_name, cfg.metric_listener, geoip_db, storage);
-        let mut fut = ServerFuture::new(handler);
-        log::trace!("Setup server future");
-        for sock_addr in cfg.udp_sockets {
-            match UdpSocket::bind(sock_addr).await {
-                Ok(socket) => fut.register_socket(socket),
-                Err(e) => error!("Could not bind udp socket {}: {}", sock_addr, e),
-            };
+    })
+}
+
+/// Run the DNS server, and the api if configured, on top of the storage until the process is asked
+/// to shut down. `reachable` completes once the storage is reachable, or fails if the storage is
+/// given up on.
+async fn serve<S, F>(mut cfg: config::Config, storage: Arc<S>, reachable: F)
+where
+    S: Storage + AuditSink + IdempotencyStore + Send + Sync + 'static,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    let metrics = metrics::Metrics::new(cfg.instance_name.clone());
+    match cfg.cache.take() {
+        Some(cache_cfg) => {
+            info!(
+                "Caching up to {} RRsets for {} seconds",
+                cache_cfg.capacity, cache_cfg.ttl_secs
+            );
+            let storage = Arc::new(cache::CachedStorage::new(
+                storage,
+                &cache_cfg,
+                metrics.clone(),
+            ));
+            // Without change events, entries changed through other instances linger until they
+            // expire.
+            if let Some(changes) = storage.subscribe_changes() {
+                tokio::spawn(storage.clone().follow_changes(changes));
+            }
+            run(cfg, storage, metrics, reachable).await
         }
-        for tcp_cfg in cfg.tcp_listeners {
-            match TcpListener::bind(tcp_cfg.address).await {
-                Ok(listener) => {
-                    fut.register_listener(listener, Duration::from_millis(tcp_cfg.timeout_millis))
+        None => run(cfg, storage, metrics, reachable).await,
+    }
+}
+
+async fn run<S, F>(cfg: config::Config, storage: Arc<S>, metrics: metrics::Metrics, reachable: F)
+where
+    S: Storage + AuditSink + IdempotencyStore + Send + Sync + 'static,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    let status = Arc::new(status::Status::default());
+    let tracer = Arc::new(trace::QueryTracer::new());
+    tokio::spawn(tracer.clone().expiry_loop());
+    let rate_limiter = cfg
+        .rate_limit
+        .map(|config| Arc::new(rrl::RateLimiter::new(config)));
+    if let Some(ref rate_limiter) = rate_limiter {
+        tokio::spawn(rate_limiter.clone().prune_loop());
+    }
+    let zone_reload = Arc::new(reload::ZoneReload::new());
+    let health = Arc::new(health::HealthChecker::new());
+    tokio::spawn(health.clone().sync_loop(storage.clone()));
+    let geoip_db = geo::GeoLocator::new(cfg.geoip_db_location).unwrap();
+    status.set_geoip_loaded();
+    let chaos = if cfg.chaos.enabled {
+        Some(handle::ChaosIdentity {
+            version: cfg
+                .chaos
+                .version
+                .unwrap_or_else(|| format!("cetus {}", env!("CARGO_PKG_VERSION"))),
+            id: cfg.chaos.id.unwrap_or_else(|| cfg.instance_name.clone()),
+        })
+    } else {
+        None
+    };
+    let notifier = notify::Notifier::new(
+        cfg.notify.also_notify,
+        cfg.notify
+            .zones
+            .into_iter()
+            .map(|(zone, targets)| (zone.into(), targets))
+            .collect(),
+        metrics.clone(),
+    );
+    let handler = handle::DnsHandler::new(
+        metrics.clone(),
+        cfg.metric_listener,
+        geoip_db,
+        storage.clone(),
+        status.clone(),
+        tracer.clone(),
+        handle::HandlerConfig {
+            policy: cfg.response_policy,
+            any_over_udp: cfg.any_over_udp,
+            allow_transfer: cfg.allow_transfer,
+            allow_update: cfg.allow_update,
+            ignore_client_subnet: cfg.ignore_client_subnet,
+            storage_timeout: Duration::from_millis(cfg.storage_query_timeout_millis),
+            query_deadline: Duration::from_millis(cfg.query_deadline_millis),
+            slow_query_threshold: Duration::from_millis(cfg.slow_query_threshold_millis),
+            rate_limiter,
+            chaos,
+            health: health.clone(),
+            zone_reload: zone_reload.clone(),
+            notifier: notifier.clone(),
+        },
+    );
+    let mut fut = ServerFuture::new(handler);
+    log::trace!("Setup server future");
+    // Bind the listeners before the storage is confirmed to be reachable, so health checkers
+    // can distinguish a process waiting for its storage from one which is down. During a
+    // rolling restart the new process binds the same ports as the old one, so the kernel
+    // hands over traffic once the old process closes its sockets. Queries which reach the new
+    // process before its zones are loaded wait for the load.
+    let reuse_port = cfg.rolling_restart.is_some();
+    for sock_addr in cfg.udp_sockets {
+        match bind_udp(sock_addr, reuse_port) {
+            Ok(socket) => fut.register_socket(socket),
+            Err(e) => error!("Could not bind udp socket {}: {}", sock_addr, e),
+        };
+    }
+    for tcp_cfg in cfg.tcp_listeners {
+        match bind_tcp(tcp_cfg.address, reuse_port) {
+            Ok(listener) => {
+                fut.register_listener(listener, Duration::from_millis(tcp_cfg.timeout_millis))
+            }
+            Err(e) => error!("Could not bind tcp listener {}: {}", tcp_cfg.address, e),
+        }
+    }
+    for https_cfg in cfg.https_listeners {
+        let certificate_and_key = match read_cert(&https_cfg.cert_path)
+            .and_then(|cert| Ok((cert, read_key_from_pem(&https_cfg.key_path)?)))
+        {
+            Ok(certificate_and_key) => certificate_and_key,
+            Err(e) => {
+                error!(
+                    "Could not load certificate for https listener {}: {}",
+                    https_cfg.address, e
+                );
+                continue;
+            }
+        };
+        let res = bind_tcp(https_cfg.address, reuse_port).and_then(|listener| {
+            fut.register_https_listener(
+                listener,
+                Duration::from_millis(https_cfg.timeout_millis),
+                certificate_and_key,
+                https_cfg.dns_hostname,
+            )
+        });
+        if let Err(e) = res {
+            error!("Could not bind https listener {}: {}", https_cfg.address, e);
+        }
+    }
+
+    match cfg.storage_startup_mode {
+        config::StorageStartupMode::Wait => {
+            if let Err(e) = reachable.await {
+                error!("Storage is not reachable, giving up: {}", e);
+                std::process::exit(1);
+            }
+            status.set_storage_reachable();
+        }
+        config::StorageStartupMode::Degraded => {
+            let status = status.clone();
+            tokio::spawn(async move {
+                if reachable.await.is_ok() {
+                    info!("Storage is reachable, leaving degraded mode");
+                    status.set_storage_reachable();
                 }
-                Err(e) => error!("Could not bind tcp listener {}: {}", tcp_cfg.address, e),
+            });
+        }
+    }
+
+    let api = cfg.api_listener.map(|api_address| {
+        let api = api::listen(
+            storage.clone(),
+            api_address,
+            api::ApiConfig {
+                bootstrap_tokens: cfg.api_tokens,
+                mx_cname_exchange: cfg.mx_cname_exchange,
+                txt_limits: cfg.txt_limits,
+                auto_serial: cfg.auto_serial,
+                reject_private_addresses: cfg.reject_private_addresses,
+                tracer,
+                notifier,
+                health,
+                zone_reload,
+                metrics,
+                status: status.clone(),
+                rate_limit: cfg.api_rate_limit,
+                cors: cfg.api_cors,
+                webhooks: cfg.webhooks,
+                audit: storage.clone(),
+                audit_mandatory: cfg.api_audit_mandatory,
+                idempotency: storage.clone(),
+                idempotency_ttl_secs: cfg.api_idempotency_ttl_secs,
+                dnssec: cfg.dnssec,
+                tls: cfg.api_tls,
+            },
+        );
+        match api {
+            Ok(api) => {
+                info!("Api listening on {}", api.local_addr());
+                api
+            }
+            Err(e) => {
+                error!("Could not start api: {}", e);
+                std::process::exit(1);
             }
         }
+    });
 
-        fut.block_until_done().await.unwrap();
-    })
+    tokio::select! {
+        res = fut.block_until_done() => res.unwrap(),
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            status.set_draining();
+            if let Some(rolling_restart) = cfg.rolling_restart {
+                // Keep answering queries while load balancers notice the failing readiness.
+                info!("Draining for {} ms", rolling_restart.drain_millis);
+                tokio::time::sleep(Duration::from_millis(rolling_restart.drain_millis)).await;
+            }
+        }
+    }
+
+    if let Some(api) = api {
+        // Let in flight api requests finish, rather than cutting them off halfway through a
+        // change.
+        api.shutdown();
+        if let Err(e) = api.join().await {
+            error!("Api server task failed: {}", e);
+        }
+    }
+}
+
+/// Wait until the process is asked to shut down, either through SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Can install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Can install SIGINT handler");
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+}
+
+/// Bind a UDP socket on the given address, optionally with `SO_REUSEPORT` set.
+fn bind_udp(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind a TCP listener on the given address, optionally with `SO_REUSEPORT` set.
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
 }
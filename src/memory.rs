@@ -1,66 +1,775 @@
-use crate::storage::{Storage, StorageRecord};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    error::Error,
+    ops::Bound,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
 
-pub struct MemoryStorage {}
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::{
+    audit::{AuditEntry, AuditSink},
+    idempotency::{IdempotencyClaim, IdempotencyRecord, IdempotencyStore},
+    policy::ZonePolicy,
+    storage::{
+        closest_encloser_from_keys, name_index_key, ApiToken, CutKind, DnssecKey, InvalidCursor,
+        Page, SerialChanged, Storage, StorageOp, StorageRecord, ZoneChange, ZoneCuts,
+    },
+};
+
+/// Maximum amount of changes kept in the journal of a zone.
+const MAX_JOURNAL_LENGTH: usize = 1_000;
+/// Maximum amount of entries kept in the audit log. Older entries are dropped.
+const MAX_AUDIT_LENGTH: usize = 10_000;
+
+/// The records of a zone, per domain and type. Domains are kept in canonical order, where the names
+/// below a domain directly follow it, so the map doubles as the index of names in the zone.
+type ZoneRecords = BTreeMap<LowerName, HashMap<RecordType, Vec<StorageRecord>>>;
+
+/// An implementation of storage which keeps everything in memory, for tests and local
+/// development. Nothing is persisted, so all data is lost once the process exits.
+pub struct MemoryStorage {
+    data: RwLock<Data>,
+}
+
+#[derive(Default)]
+struct Data {
+    /// Markers of the zones, with their policy.
+    zones: HashMap<LowerName, ZonePolicy>,
+    /// Records are kept apart from the zone markers, like in the other backends, so records of a
+    /// zone which does not exist don't make it exist.
+    records: HashMap<LowerName, ZoneRecords>,
+    journals: HashMap<LowerName, VecDeque<ZoneChange>>,
+    dnssec_keys: HashMap<LowerName, HashMap<String, DnssecKey>>,
+    tokens: HashMap<String, ApiToken>,
+    token_usage: HashMap<String, u64>,
+    audit: VecDeque<AuditEntry>,
+    /// Amount of audit log entries dropped so far, so cursors stay valid when entries are dropped.
+    audit_dropped: usize,
+    /// Idempotency keys with the time they expire.
+    idempotency: HashMap<String, (IdempotencyRecord, Instant)>,
+}
 
 impl MemoryStorage {
-    #[allow(dead_code)]
     pub fn new() -> Self {
-        MemoryStorage {}
+        MemoryStorage {
+            data: RwLock::new(Data::default()),
+        }
+    }
+}
+
+impl Data {
+    /// The serial of the SOA of a zone.
+    fn serial(&self, zone: &LowerName) -> Option<u32> {
+        self.records
+            .get(zone)
+            .and_then(|domains| domains.get(zone))
+            .and_then(|rrsets| rrsets.get(&RecordType::SOA))
+            .and_then(|soas| soas.first())
+            .and_then(StorageRecord::serial)
+    }
+
+    /// Replace an RRset, removing it if there are no records. Returns whether the RRset existed.
+    fn set_rrset(
+        &mut self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> bool {
+        if records.is_empty() {
+            let removed = self
+                .records
+                .get_mut(zone)
+                .and_then(|domains| domains.get_mut(domain))
+                .and_then(|rrsets| rrsets.remove(&rtype))
+                .is_some();
+            self.prune(zone, domain);
+            return removed;
+        }
+
+        self.records
+            .entry(zone.clone())
+            .or_default()
+            .entry(domain.clone())
+            .or_default()
+            .insert(rtype, records)
+            .is_some()
+    }
+
+    /// Remove a domain which has no RRsets left, and a zone which has no domains left.
+    fn prune(&mut self, zone: &LowerName, domain: &LowerName) {
+        if let Some(domains) = self.records.get_mut(zone) {
+            if domains.get(domain).is_some_and(HashMap::is_empty) {
+                domains.remove(domain);
+            }
+            if domains.is_empty() {
+                self.records.remove(zone);
+            }
+        }
     }
 }
 
+/// Get a page of about `limit` names after the cursor, which is the last name of the previous
+/// page.
+fn name_page(
+    mut names: Vec<LowerName>,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+    let after = cursor
+        .map(LowerName::from_str)
+        .transpose()
+        .map_err(|_| InvalidCursor)?;
+    names.sort();
+    let mut items = names
+        .into_iter()
+        .filter(|name| after.as_ref().is_none_or(|after| name > after))
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|name| name.to_string())
+    } else {
+        None
+    };
+
+    Ok(Page { items, next_cursor })
+}
+
 #[async_trait::async_trait]
 impl Storage for MemoryStorage {
-    async fn zones(
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        Ok(self.data.read().unwrap().zones.keys().cloned().collect())
+    }
+
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self.data.read().unwrap().zones.contains_key(zone))
+    }
+
+    async fn zones_page(
         &self,
-    ) -> Result<
-        Vec<trust_dns_server::client::rr::LowerName>,
-        Box<dyn std::error::Error + Send + Sync>,
-    > {
-        unimplemented!();
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        name_page(self.zones().await?, cursor, limit)
     }
 
     async fn lookup_records(
         &self,
-        _domain: &trust_dns_server::client::rr::LowerName,
-        _zone: &trust_dns_server::client::rr::LowerName,
-        _rtype: trust_dns_server::proto::rr::RecordType,
-    ) -> Result<Option<Vec<crate::storage::StorageRecord>>, Box<dyn std::error::Error + Send + Sync>>
-    {
-        unimplemented!();
+        domain: &LowerName,
+        zone: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .records
+            .get(zone)
+            .and_then(|domains| domains.get(domain))
+            .map(|rrsets| rrsets.get(&rtype).cloned().unwrap_or_default()))
     }
 
-    async fn add_zone(
+    async fn lookup_all_records(
         &self,
-        _zone: &trust_dns_server::client::rr::LowerName,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        unimplemented!();
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .records
+            .get(zone)
+            .and_then(|domains| domains.get(domain))
+            .map(|rrsets| rrsets.values().flatten().cloned().collect()))
+    }
+
+    #[cfg(test)]
+    async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Like the other backends, this resets the marker, and so the policy, of an existing zone.
+        self.data
+            .write()
+            .unwrap()
+            .zones
+            .insert(zone.clone(), ZonePolicy::default());
+        Ok(())
     }
 
+    #[cfg(test)]
     async fn add_record(
         &self,
-        _zone: &trust_dns_server::client::rr::LowerName,
-        _domain: &trust_dns_server::client::rr::LowerName,
-        _record: StorageRecord,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        unimplemented!();
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data
+            .write()
+            .unwrap()
+            .records
+            .entry(zone.clone())
+            .or_default()
+            .entry(domain.clone())
+            .or_default()
+            .entry(record.record.record_type())
+            .or_default()
+            .push(record);
+        Ok(())
     }
 
     async fn list_records(
         &self,
-        _zone: &trust_dns_server::client::rr::LowerName,
-        _domain: &trust_dns_server::client::rr::LowerName,
-    ) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
-        unimplemented!();
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .lookup_all_records(domain, zone)
+            .await?
+            .unwrap_or_default())
     }
 
     async fn list_domains(
         &self,
-        _zone: &trust_dns_server::client::rr::LowerName,
-    ) -> Result<
-        Vec<trust_dns_server::client::rr::LowerName>,
-        Box<dyn std::error::Error + Send + Sync>,
-    > {
-        unimplemented!();
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .records
+            .get(zone)
+            .map(|domains| domains.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        name_page(self.list_domains(zone).await?, cursor, limit)
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .zones
+            .get(zone)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // This must never create a zone.
+        if let Some(existing) = self.data.write().unwrap().zones.get_mut(zone) {
+            *existing = policy.clone();
+        }
+        Ok(())
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        let journal = data.journals.entry(zone.clone()).or_default();
+        journal.push_back(change.clone());
+        if journal.len() > MAX_JOURNAL_LENGTH {
+            journal.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .journals
+            .get(zone)
+            .and_then(|journal| ZoneChange::since(journal.iter().cloned().collect(), serial)))
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The serials are checked first, after that none of the changes can fail, and they are
+        // all applied under a single lock.
+        let mut data = self.data.write().unwrap();
+        for op in &ops {
+            if let StorageOp::SwapSoa { zone, serial, .. } = op {
+                if data.serial(zone) != Some(*serial) {
+                    return Err(SerialChanged.into());
+                }
+            }
+        }
+        for op in ops {
+            match op {
+                StorageOp::AddZone { zone } => {
+                    data.zones.entry(zone).or_default();
+                }
+                StorageOp::SetRecords {
+                    zone,
+                    domain,
+                    rtype,
+                    records,
+                } => {
+                    data.set_rrset(&zone, &domain, rtype, records);
+                }
+                StorageOp::RemoveRrset {
+                    zone,
+                    domain,
+                    rtype,
+                } => {
+                    data.set_rrset(&zone, &domain, rtype, Vec::new());
+                }
+                StorageOp::DeleteZone { zone } => {
+                    data.records.remove(&zone);
+                    data.journals.remove(&zone);
+                    data.dnssec_keys.remove(&zone);
+                    data.zones.remove(&zone);
+                }
+                StorageOp::SwapSoa { zone, soa, .. } => {
+                    data.set_rrset(&zone, &zone, RecordType::SOA, vec![*soa]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data.records.get(zone).is_some_and(|domains| {
+            domains
+                .range::<LowerName, _>((Bound::Excluded(domain), Bound::Unbounded))
+                .next()
+                .is_some_and(|(name, _)| domain.zone_of(name))
+        }))
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        let domains = match data.records.get(zone) {
+            Some(domains) => domains,
+            None => return Ok(zone.clone()),
+        };
+        let neighbours = domains
+            .range::<LowerName, _>(..=name)
+            .next_back()
+            .into_iter()
+            .chain(
+                domains
+                    .range::<LowerName, _>((Bound::Excluded(name), Bound::Unbounded))
+                    .next(),
+            )
+            .map(|(domain, _)| name_index_key(domain))
+            .collect::<Vec<_>>();
+        Ok(closest_encloser_from_keys(zone, name, &neighbours))
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        let mut cuts = ZoneCuts::default();
+        for (domain, rrsets) in data.records.get(zone).into_iter().flatten() {
+            for (rtype, records) in rrsets {
+                match CutKind::of(zone, domain, *rtype) {
+                    Some(kind) if !records.is_empty() => cuts.insert(kind, domain.clone()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(cuts)
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        let removed = data
+            .records
+            .get_mut(zone)
+            .and_then(|domains| domains.get_mut(domain))
+            .and_then(|rrsets| rrsets.remove(&rtype))
+            .unwrap_or_default();
+        data.prune(zone, domain);
+        Ok(removed)
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        let rtype = record.record_type();
+        let rrsets = match data
+            .records
+            .get_mut(zone)
+            .and_then(|domains| domains.get_mut(domain))
+        {
+            Some(rrsets) => rrsets,
+            None => return Ok(None),
+        };
+        let removed = rrsets.get_mut(&rtype).and_then(|records| {
+            records
+                .iter()
+                .position(|sr| sr.as_record().data() == record.data())
+                .map(|idx| records.remove(idx))
+        });
+        if rrsets.get(&rtype).is_some_and(Vec::is_empty) {
+            rrsets.remove(&rtype);
+        }
+        data.prune(zone, domain);
+        Ok(removed)
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        // Count entries the way the Redis backend counts keys: one per domain, the journal, the
+        // DNSSEC keys and the marker.
+        let entries = data.records.get(zone).map_or(0, BTreeMap::len)
+            + usize::from(data.journals.contains_key(zone))
+            + usize::from(data.dnssec_keys.contains_key(zone))
+            + usize::from(data.zones.contains_key(zone));
+        if !dry_run {
+            data.records.remove(zone);
+            data.journals.remove(zone);
+            data.dnssec_keys.remove(zone);
+            data.zones.remove(zone);
+        }
+        Ok(entries)
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .data
+            .write()
+            .unwrap()
+            .set_rrset(zone, domain, rtype, records))
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        Ok(data
+            .records
+            .get_mut(zone)
+            .and_then(|domains| domains.get_mut(zone))
+            .and_then(|rrsets| rrsets.get_mut(&RecordType::SOA))
+            .and_then(|records| records.first_mut())
+            .and_then(|soa| soa.bump_serial(floor)))
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .tokens
+            .values()
+            .map(|token| ApiToken {
+                last_used: data.token_usage.get(&token.id).copied(),
+                ..token.clone()
+            })
+            .collect())
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data
+            .write()
+            .unwrap()
+            .tokens
+            .insert(token.id.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data
+            .write()
+            .unwrap()
+            .token_usage
+            .insert(id.to_string(), last_used);
+        Ok(())
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .dnssec_keys
+            .get(zone)
+            .map(|keys| keys.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data
+            .write()
+            .unwrap()
+            .dnssec_keys
+            .entry(zone.clone())
+            .or_default()
+            .insert(key.id.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        let keys = match data.dnssec_keys.get_mut(zone) {
+            Some(keys) => keys,
+            None => return Ok(false),
+        };
+        let removed = keys.remove(id).is_some();
+        if keys.is_empty() {
+            data.dnssec_keys.remove(zone);
+        }
+        Ok(removed)
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for MemoryStorage {
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        data.audit.push_back(entry.clone());
+        if data.audit.len() > MAX_AUDIT_LENGTH {
+            data.audit.pop_front();
+            data.audit_dropped += 1;
+        }
+        Ok(())
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>> {
+        // The cursor is the position of the next entry, counting dropped entries as well.
+        let start = cursor
+            .map(|cursor| cursor.parse::<usize>())
+            .transpose()
+            .map_err(|_| InvalidCursor)?
+            .unwrap_or(0);
+        let data = self.data.read().unwrap();
+
+        let mut items = Vec::new();
+        let first = start.saturating_sub(data.audit_dropped);
+        for (offset, entry) in data.audit.iter().enumerate().skip(first) {
+            if entry.timestamp >= since
+                && zone.is_none_or(|zone| entry.zone.as_deref() == Some(zone))
+            {
+                items.push(entry.clone());
+            }
+            if items.len() == limit {
+                return Ok(Page {
+                    items,
+                    next_cursor: Some((data.audit_dropped + offset + 1).to_string()),
+                });
+            }
+        }
+
+        Ok(Page {
+            items,
+            next_cursor: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for MemoryStorage {
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.write().unwrap();
+        let now = Instant::now();
+        data.idempotency.retain(|_, (_, expires)| *expires > now);
+        if let Some((existing, _)) = data.idempotency.get(key) {
+            return Ok(IdempotencyClaim::Existing(existing.clone()));
+        }
+        data.idempotency
+            .insert(key.to_string(), (record.clone(), now + ttl));
+        Ok(IdempotencyClaim::Claimed)
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data
+            .write()
+            .unwrap()
+            .idempotency
+            .insert(key.to_string(), (record.clone(), Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.data.write().unwrap().idempotency.remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::rr::RecordType;
+
+    use super::{MemoryStorage, MAX_JOURNAL_LENGTH};
+    use crate::{
+        handle::tests::{a, lower},
+        storage::{tests::conformance, Storage, ZoneChange},
+    };
+
+    #[tokio::test]
+    async fn storage_conformance() {
+        conformance(&MemoryStorage::new()).await;
+    }
+
+    #[tokio::test]
+    async fn missing_domain_and_missing_type() {
+        let storage = MemoryStorage::new();
+        let zone = lower("example.com.");
+        let www = lower("www.example.com.");
+        storage.add_zone(&zone).await.unwrap();
+
+        assert_eq!(
+            storage
+                .lookup_records(&www, &zone, RecordType::A)
+                .await
+                .unwrap()
+                .map(|records| records.len()),
+            None
+        );
+        storage
+            .add_record(&zone, &www, a("www.example.com.", [192, 0, 2, 1]))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .lookup_records(&www, &zone, RecordType::AAAA)
+                .await
+                .unwrap()
+                .map(|records| records.len()),
+            Some(0)
+        );
+        assert_eq!(
+            storage
+                .lookup_records(&www, &zone, RecordType::A)
+                .await
+                .unwrap()
+                .map(|records| records.len()),
+            Some(1)
+        );
+
+        // Removing the last RRset removes the domain.
+        storage
+            .remove_rrset(&zone, &www, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .lookup_records(&www, &zone, RecordType::AAAA)
+                .await
+                .unwrap()
+                .map(|records| records.len()),
+            None
+        );
+        assert!(storage
+            .lookup_all_records(&www, &zone)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn records_do_not_create_zones() {
+        let storage = MemoryStorage::new();
+        let zone = lower("example.com.");
+        let www = lower("www.example.com.");
+        storage
+            .add_record(&zone, &www, a("www.example.com.", [192, 0, 2, 1]))
+            .await
+            .unwrap();
+        assert!(!storage.zone_exists(&zone).await.unwrap());
+        assert!(storage.zones().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn journal_is_bounded() {
+        let storage = MemoryStorage::new();
+        let zone = lower("example.com.");
+        for serial in 0..MAX_JOURNAL_LENGTH as u32 + 10 {
+            let change = ZoneChange {
+                from_serial: serial,
+                serial: serial + 1,
+                removed: Vec::new(),
+                added: Vec::new(),
+            };
+            storage.append_change(&zone, &change).await.unwrap();
+        }
+        assert!(storage.changes_since(&zone, 9).await.unwrap().is_none());
+        let changes = storage
+            .changes_since(&zone, 10)
+            .await
+            .unwrap()
+            .expect("Journal goes back far enough");
+        assert_eq!(changes.len(), MAX_JOURNAL_LENGTH);
+        assert_eq!(changes[0].from_serial, 10);
     }
 }
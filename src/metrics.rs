@@ -5,14 +5,17 @@ use std::{
     net::SocketAddr,
     ops::Deref,
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{routing::get, Router};
 use chashmap::CHashMap;
 use log::debug;
 use prometheus::{
-    labels, opts, register_int_counter_vec_with_registry, Encoder, IntCounterVec, Registry,
-    TextEncoder,
+    histogram_opts, labels, opts, register_histogram_vec_with_registry,
+    register_int_counter_vec_with_registry, register_int_counter_with_registry,
+    register_int_gauge_with_registry, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Registry, TextEncoder,
 };
 use trust_dns_proto::{
     op::ResponseCode,
@@ -20,6 +23,11 @@ use trust_dns_proto::{
 };
 use trust_dns_server::{client::rr::LowerName, server::Protocol};
 
+use crate::{
+    status::{self, Status},
+    storage::Storage,
+};
+
 /// &str representation of ipv4
 const IPV4: &str = "IPv4";
 /// &str representation of ipv6
@@ -46,6 +54,22 @@ pub struct MetricsInner {
     zone_metrics: CHashMap<LowerName, ZoneMetrics>,
     /// metrics used if a query is not in the zone
     unknown_zone_metrics: ZoneMetrics,
+    /// zones added to or removed from the zone cache by the zone loader
+    zone_cache_changes: IntCounterVec,
+    /// CHAOS class queries, by the kind of answer
+    chaos_queries: IntCounterVec,
+    /// requests to the management API, by route template, method and status class
+    api_requests: IntCounterVec,
+    /// duration of requests to the management API, with the same labels as the request counter
+    api_request_duration: HistogramVec,
+    /// requests to the management API rejected by a rate limit
+    api_throttled: IntCounterVec,
+    /// audit log entries of API requests which could not be written
+    audit_write_failures: IntCounter,
+    /// storage lookups answered from the storage cache, or not
+    storage_cache_lookups: IntCounterVec,
+    /// entries removed from the storage cache, by reason
+    storage_cache_evictions: IntCounterVec,
 }
 
 /// Metrics for a specific zone
@@ -56,6 +80,19 @@ pub struct ZoneMetrics {
     connection_types: IntCounterVec,
     response_codes: IntCounterVec,
     country_queries: IntCounterVec,
+    missing_soa: IntCounter,
+    any_responses: IntCounterVec,
+    transfers: IntCounterVec,
+    notifies: IntCounterVec,
+    updates: IntCounterVec,
+    geo_lookups: IntCounterVec,
+    rate_limited: IntCounterVec,
+    truncated_responses: IntCounter,
+    weighted_picks: IntCounterVec,
+    storage_timeouts: IntCounter,
+    query_timeouts: IntCounter,
+    slow_queries: IntCounter,
+    serial: IntGauge,
 }
 
 impl ZoneMetrics {
@@ -162,17 +199,17 @@ impl ZoneMetrics {
         .expect("Can register connection type counter vec");
 
         // pre fill connection types.
-        // NOTE: currently only UDP and TCP are able to be used.
+        // NOTE: currently only UDP, TCP and HTTPS are able to be used.
         connection_types.with_label_values(&[IPV4, &Protocol::Udp.to_string()]);
         connection_types.with_label_values(&[IPV4, &Protocol::Tcp.to_string()]);
         // connection_types.with_label_values(&[IPV4, &Protocol::Dtls.to_string()]);
         // connection_types.with_label_values(&[IPV4, &Protocol::Tls.to_string()]);
-        // connection_types.with_label_values(&[IPV4, &Protocol::Https.to_string()]);
+        connection_types.with_label_values(&[IPV4, &Protocol::Https.to_string()]);
         connection_types.with_label_values(&[IPV6, &Protocol::Udp.to_string()]);
         connection_types.with_label_values(&[IPV6, &Protocol::Tcp.to_string()]);
         // connection_types.with_label_values(&[IPV6, &Protocol::Dtls.to_string()]);
         // connection_types.with_label_values(&[IPV6, &Protocol::Tls.to_string()]);
-        // connection_types.with_label_values(&[IPV6, &Protocol::Https.to_string()]);
+        connection_types.with_label_values(&[IPV6, &Protocol::Https.to_string()]);
 
         // We don't prefill this vec
         let country_queries = register_int_counter_vec_with_registry!(
@@ -186,6 +223,159 @@ impl ZoneMetrics {
         )
         .expect("Can register query class counter vec");
 
+        let missing_soa = register_int_counter_with_registry!(
+            opts!(
+                "zone_missing_soa",
+                "Times the zone was found to have no SOA record",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register missing SOA counter");
+
+        let any_responses = register_int_counter_vec_with_registry!(
+            opts!(
+                "any_responses",
+                "The type of response sent to queries of type ANY",
+                labels! {"zone" => &zone_name}
+            ),
+            &["response"],
+            registry
+        )
+        .expect("Can register ANY response counter vec");
+        any_responses.with_label_values(&["minimal"]);
+        any_responses.with_label_values(&["full"]);
+
+        let transfers = register_int_counter_vec_with_registry!(
+            opts!(
+                "zone_transfers",
+                "Zone transfers requested from the zone",
+                labels! {"zone" => &zone_name}
+            ),
+            &["result"],
+            registry
+        )
+        .expect("Can register zone transfer counter vec");
+        transfers.with_label_values(&["served"]);
+        transfers.with_label_values(&["incremental"]);
+        transfers.with_label_values(&["refused"]);
+
+        let notifies = register_int_counter_vec_with_registry!(
+            opts!(
+                "notifies",
+                "NOTIFY messages sent to secondaries of the zone",
+                labels! {"zone" => &zone_name}
+            ),
+            &["result"],
+            registry
+        )
+        .expect("Can register notify counter vec");
+        notifies.with_label_values(&["sent"]);
+        notifies.with_label_values(&["failed"]);
+
+        let updates = register_int_counter_vec_with_registry!(
+            opts!(
+                "zone_updates",
+                "Dynamic updates received for the zone",
+                labels! {"zone" => &zone_name}
+            ),
+            &["result"],
+            registry
+        )
+        .expect("Can register zone update counter vec");
+        updates.with_label_values(&["applied"]);
+        updates.with_label_values(&["rejected"]);
+        updates.with_label_values(&["refused"]);
+        updates.with_label_values(&["failed"]);
+
+        let geo_lookups = register_int_counter_vec_with_registry!(
+            opts!(
+                "geo_lookups",
+                "Client locations looked up for queries in the zone, by source of the address",
+                labels! {"zone" => &zone_name}
+            ),
+            &["source"],
+            registry
+        )
+        .expect("Can register geo lookup counter vec");
+        geo_lookups.with_label_values(&["ecs"]);
+        geo_lookups.with_label_values(&["source"]);
+
+        let rate_limited = register_int_counter_vec_with_registry!(
+            opts!(
+                "rate_limited_responses",
+                "Responses for the zone which exceeded the response rate limit",
+                labels! {"zone" => &zone_name}
+            ),
+            &["action"],
+            registry
+        )
+        .expect("Can register rate limited response counter vec");
+        rate_limited.with_label_values(&["dropped"]);
+        rate_limited.with_label_values(&["slipped"]);
+
+        let truncated_responses = register_int_counter_with_registry!(
+            opts!(
+                "truncated_responses",
+                "UDP responses for the zone truncated because the answer did not fit",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register truncated response counter");
+
+        // We don't prefill this vec
+        let weighted_picks = register_int_counter_vec_with_registry!(
+            opts!(
+                "weighted_picks",
+                "Records picked from weighted RRsets in the zone",
+                labels! {"zone" => &zone_name}
+            ),
+            &["record"],
+            registry
+        )
+        .expect("Can register weighted pick counter vec");
+
+        let storage_timeouts = register_int_counter_with_registry!(
+            opts!(
+                "storage_timeout",
+                "Queries for the zone failed because storage did not answer in time",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register storage timeout counter");
+
+        let query_timeouts = register_int_counter_with_registry!(
+            opts!(
+                "query_timeout",
+                "Queries for the zone answered with SERVFAIL because they were not answered before the query deadline",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register query timeout counter");
+
+        let slow_queries = register_int_counter_with_registry!(
+            opts!(
+                "slow_queries",
+                "Queries for the zone which took longer than the slow query threshold",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register slow query counter");
+
+        let serial = register_int_gauge_with_registry!(
+            opts!(
+                "zone_serial",
+                "Serial of the SOA of the zone",
+                labels! {"zone" => &zone_name}
+            ),
+            registry
+        )
+        .expect("Can register zone serial gauge");
+
         ZoneMetrics {
             registry,
             query_class,
@@ -193,6 +383,19 @@ impl ZoneMetrics {
             connection_types,
             response_codes,
             country_queries,
+            missing_soa,
+            any_responses,
+            transfers,
+            notifies,
+            updates,
+            geo_lookups,
+            rate_limited,
+            truncated_responses,
+            weighted_picks,
+            storage_timeouts,
+            query_timeouts,
+            slow_queries,
+            serial,
         }
     }
 
@@ -218,6 +421,50 @@ impl ZoneMetrics {
         self.registry
             .unregister(Box::new(self.country_queries))
             .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.missing_soa))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.any_responses))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry.unregister(Box::new(self.transfers)).unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry.unregister(Box::new(self.notifies)).unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry.unregister(Box::new(self.updates)).unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.geo_lookups))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.rate_limited))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.truncated_responses))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.weighted_picks))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.storage_timeouts))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.query_timeouts))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry
+            .unregister(Box::new(self.slow_queries))
+            .unwrap();
+        // This unwrap is safe as self.registry is the registry used to add the metrics
+        self.registry.unregister(Box::new(self.serial)).unwrap();
     }
 }
 
@@ -231,11 +478,104 @@ impl Metrics {
             .expect("can create a new registry");
         let zone_metrics = CHashMap::new();
         let unknown_zone_metrics = ZoneMetrics::register(None, registry.clone());
+        let zone_cache_changes = register_int_counter_vec_with_registry!(
+            opts!(
+                "zone_cache_changes",
+                "zones added to or removed from the zone cache on refresh"
+            ),
+            &["change"],
+            registry
+        )
+        .expect("Can register zone cache change counter vec");
+        zone_cache_changes.with_label_values(&["added"]);
+        zone_cache_changes.with_label_values(&["removed"]);
+        let chaos_queries = register_int_counter_vec_with_registry!(
+            opts!(
+                "chaos_queries",
+                "CHAOS class queries for the version or identity of the server"
+            ),
+            &["answer"],
+            registry
+        )
+        .expect("Can register CHAOS query counter vec");
+        chaos_queries.with_label_values(&["version"]);
+        chaos_queries.with_label_values(&["id"]);
+        chaos_queries.with_label_values(&["refused"]);
+        let api_requests = register_int_counter_vec_with_registry!(
+            opts!(
+                "api_requests",
+                "requests to the management API, by route template, method and status class"
+            ),
+            &["route", "method", "status"],
+            registry
+        )
+        .expect("Can register API request counter vec");
+        let api_request_duration = register_histogram_vec_with_registry!(
+            histogram_opts!(
+                "api_request_duration_seconds",
+                "time taken to handle requests to the management API"
+            ),
+            &["route", "method", "status"],
+            registry
+        )
+        .expect("Can register API request duration histogram vec");
+        let api_throttled = register_int_counter_vec_with_registry!(
+            opts!(
+                "api_throttled_requests",
+                "requests to the management API rejected by a rate limit, by kind of request and limit"
+            ),
+            &["kind", "limit"],
+            registry
+        )
+        .expect("Can register API throttled request counter vec");
+        for kind in ["read", "write"] {
+            for limit in ["global", "client"] {
+                api_throttled.with_label_values(&[kind, limit]);
+            }
+        }
+        let audit_write_failures = register_int_counter_with_registry!(
+            opts!(
+                "audit_write_failures",
+                "audit log entries of management API requests which could not be written"
+            ),
+            registry
+        )
+        .expect("Can register audit write failure counter");
+        let storage_cache_lookups = register_int_counter_vec_with_registry!(
+            opts!(
+                "storage_cache_lookups",
+                "record lookups through the storage cache, by whether they were cached"
+            ),
+            &["result"],
+            registry
+        )
+        .expect("Can register storage cache lookup counter vec");
+        storage_cache_lookups.with_label_values(&["hit"]);
+        storage_cache_lookups.with_label_values(&["miss"]);
+        let storage_cache_evictions = register_int_counter_vec_with_registry!(
+            opts!(
+                "storage_cache_evictions",
+                "entries removed from the storage cache because they expired or the cache was full"
+            ),
+            &["reason"],
+            registry
+        )
+        .expect("Can register storage cache eviction counter vec");
+        storage_cache_evictions.with_label_values(&["expired"]);
+        storage_cache_evictions.with_label_values(&["capacity"]);
         Metrics {
             inner: Arc::new(MetricsInner {
                 registry,
                 zone_metrics,
                 unknown_zone_metrics,
+                zone_cache_changes,
+                chaos_queries,
+                api_requests,
+                api_request_duration,
+                api_throttled,
+                audit_write_failures,
+                storage_cache_lookups,
+                storage_cache_evictions,
             }),
         }
     }
@@ -258,6 +598,72 @@ impl Metrics {
         }
     }
 
+    /// Register the gauge tracking the amount of active query trace filters.
+    pub fn register_trace_filter_gauge(&self, gauge: IntGauge) {
+        self.registry
+            .register(Box::new(gauge))
+            .expect("Can register trace filter gauge");
+    }
+
+    /// Register the gauge tracking the health of addresses with a health check.
+    pub fn register_health_gauge(&self, gauge: IntGaugeVec) {
+        self.registry
+            .register(Box::new(gauge))
+            .expect("Can register record health gauge");
+    }
+
+    /// Record the amount of zones added to and removed from the zone cache in a refresh.
+    pub fn add_zone_cache_changes(&self, added: usize, removed: usize) {
+        self.zone_cache_changes
+            .with_label_values(&["added"])
+            .inc_by(added as u64);
+        self.zone_cache_changes
+            .with_label_values(&["removed"])
+            .inc_by(removed as u64);
+    }
+
+    /// Increment the count of CHAOS class queries with the given kind of answer.
+    pub fn increment_chaos_query(&self, answer: &str) {
+        self.chaos_queries.with_label_values(&[answer]).inc();
+    }
+
+    /// Record a handled request to the management API. The route must be the route template
+    /// rather than the actual path, and the status a class like `2xx`, to keep the amount of
+    /// label values bounded.
+    pub fn observe_api_request(&self, route: &str, method: &str, status: &str, duration: Duration) {
+        let labels = [route, method, status];
+        self.api_requests.with_label_values(&labels).inc();
+        self.api_request_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Increment the count of API requests of a kind (read or write) throttled by a limit (global
+    /// or client).
+    pub fn increment_api_throttled(&self, kind: &str, limit: &str) {
+        self.api_throttled.with_label_values(&[kind, limit]).inc();
+    }
+
+    /// Increment the count of audit log entries which could not be written.
+    pub fn increment_audit_write_failure(&self) {
+        self.audit_write_failures.inc();
+    }
+
+    /// Increment the count of storage cache lookups with the given result (hit or miss).
+    pub fn increment_storage_cache_lookup(&self, result: &str) {
+        self.storage_cache_lookups
+            .with_label_values(&[result])
+            .inc();
+    }
+
+    /// Record the amount of entries removed from the storage cache for a reason (expired or
+    /// capacity).
+    pub fn add_storage_cache_evictions(&self, reason: &str, evictions: usize) {
+        self.storage_cache_evictions
+            .with_label_values(&[reason])
+            .inc_by(evictions as u64);
+    }
+
     /// Increment the query record type for a zone.
     pub fn increment_zone_record_type(&self, zone: &LowerName, record_type: RecordType) {
         if let Some(metrics) = self.zone_metrics.get(zone) {
@@ -286,6 +692,124 @@ impl Metrics {
         }
     }
 
+    /// Increment the count of truncated UDP responses of a zone.
+    pub fn increment_zone_truncated_response(&self, zone: &LowerName) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.truncated_responses.inc();
+        }
+    }
+
+    /// Increment the count of times a record was picked from a weighted RRset in a zone.
+    pub fn increment_zone_weighted_pick(&self, zone: &LowerName, record: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.weighted_picks.with_label_values(&[record]).inc();
+        }
+    }
+
+    /// Increment the count of queries in a zone which hit the storage deadline.
+    pub fn increment_zone_storage_timeout(&self, zone: &LowerName) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.storage_timeouts.inc();
+        }
+    }
+
+    /// Increment the count of queries in a zone which hit the query deadline.
+    pub fn increment_zone_query_timeout(&self, zone: &LowerName) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.query_timeouts.inc();
+        }
+    }
+
+    /// Increment the count of slow queries in a zone.
+    pub fn increment_zone_slow_query(&self, zone: &LowerName) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.slow_queries.inc();
+        }
+    }
+
+    /// Set the current serial of the SOA of a zone.
+    pub fn set_zone_serial(&self, zone: &LowerName, serial: u32) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.serial.set(i64::from(serial));
+        }
+    }
+
+    /// Increment the count of times a zone was found to have no SOA record.
+    pub fn increment_zone_missing_soa(&self, zone: &LowerName) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.missing_soa.inc();
+        }
+    }
+
+    /// Increment the count of the given type of response to an ANY query for a zone.
+    pub fn increment_zone_any_response(&self, zone: &LowerName, response: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.any_responses.with_label_values(&[response]).inc();
+        }
+    }
+
+    /// Increment the count of zone transfers of a zone with the given result.
+    pub fn increment_zone_transfer(&self, zone: &LowerName, result: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.transfers.with_label_values(&[result]).inc();
+        }
+    }
+
+    /// Increment the count of NOTIFY messages of a zone with the given result.
+    pub fn increment_zone_notify(&self, zone: &LowerName, result: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.notifies.with_label_values(&[result]).inc();
+        }
+    }
+
+    /// Increment the count of dynamic updates of a zone with the given result.
+    pub fn increment_zone_update(&self, zone: &LowerName, result: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.updates.with_label_values(&[result]).inc();
+        }
+    }
+
+    /// Increment the count of client location lookups of a zone with the given address source.
+    pub fn increment_zone_geo_lookup(&self, zone: &LowerName, source: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.geo_lookups.with_label_values(&[source]).inc();
+        }
+    }
+
+    /// Increment the count of client location lookups for the unknown zone with the given address
+    /// source.
+    pub fn increment_unknown_zone_geo_lookup(&self, source: &str) {
+        self.unknown_zone_metrics
+            .geo_lookups
+            .with_label_values(&[source])
+            .inc();
+    }
+
+    /// Increment the count of responses of a zone which exceeded the rate limit, with the action
+    /// taken.
+    pub fn increment_zone_rate_limited(&self, zone: &LowerName, action: &str) {
+        if let Some(metrics) = self.zone_metrics.get(zone) {
+            metrics.rate_limited.with_label_values(&[action]).inc();
+        }
+    }
+
+    /// Increment the count of responses for the unknown zone which exceeded the rate limit, with
+    /// the action taken.
+    pub fn increment_unknown_zone_rate_limited(&self, action: &str) {
+        self.unknown_zone_metrics
+            .rate_limited
+            .with_label_values(&[action])
+            .inc();
+    }
+
+    /// Increment the count of zone transfers for the unknown zone with the given result.
+    pub fn increment_unknown_zone_transfer(&self, result: &str) {
+        self.unknown_zone_metrics
+            .transfers
+            .with_label_values(&[result])
+            .inc();
+    }
+
     /// Increment the response code count for the unknown zone.
     pub fn increment_unknown_zone_response_code(&self, response_code: ResponseCode) {
         self.unknown_zone_metrics
@@ -359,27 +883,40 @@ impl Metrics {
     }
 
     /// Set up the metric server and bind it to the given socket address. The server won't start
-    /// until the future returned by this function is awaited.
-    pub fn server_future(
+    /// until the future returned by this function is awaited. Next to the metrics, the server
+    /// also exposes the liveness of the process, and the readiness of the server based on the
+    /// given [`Status`] and [`Storage`].
+    pub fn server_future<S>(
         &self,
         addr: SocketAddr,
-    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> {
+        status: Arc<Status>,
+        storage: S,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>>
+    where
+        S: Storage + Clone + Send + Sync + 'static,
+    {
         let registry = self.registry.clone();
 
         async move {
-            let app = Router::new().route(
-                "/metrics",
-                get(move || {
-                    ready({
-                        let encoder = TextEncoder::new();
-                        let metric_families = registry.gather();
-                        let mut buffer = vec![];
-                        encoder.encode(&metric_families, &mut buffer).unwrap();
-
-                        buffer
-                    })
-                }),
-            );
+            let app = Router::new()
+                .route(
+                    "/metrics",
+                    get(move || {
+                        ready({
+                            let encoder = TextEncoder::new();
+                            let metric_families = registry.gather();
+                            let mut buffer = vec![];
+                            encoder.encode(&metric_families, &mut buffer).unwrap();
+
+                            buffer
+                        })
+                    }),
+                )
+                .route("/health", get(status::liveness))
+                .route(
+                    "/ready",
+                    get(move || async move { status.readiness(&storage).await }),
+                );
 
             Ok(axum::Server::bind(&addr)
                 .serve(app.into_make_service())
@@ -388,3 +925,38 @@ impl Metrics {
         }
     }
 }
+
+#[cfg(test)]
+impl Metrics {
+    /// Current value of a counter of a zone, or of the unknown zone. For counter vecs, only the
+    /// counter with the given label value is counted.
+    pub(crate) fn zone_counter(
+        &self,
+        zone: Option<&LowerName>,
+        name: &str,
+        label: Option<&str>,
+    ) -> u64 {
+        let zone_name = zone.map_or_else(|| "UNKNOWN".to_string(), |zone| zone.to_string());
+        let name = format!("cetus_{}", name);
+        self.registry
+            .gather()
+            .iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| {
+                let labels = metric.get_label();
+                labels
+                    .iter()
+                    .any(|pair| pair.get_name() == "zone" && pair.get_value() == zone_name)
+                    && match label {
+                        Some(label) => labels.iter().any(|pair| {
+                            !matches!(pair.get_name(), "zone" | "instance_name")
+                                && pair.get_value() == label
+                        }),
+                        None => true,
+                    }
+            })
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum()
+    }
+}
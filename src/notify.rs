@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, error, trace, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{net::UdpSocket, sync::mpsc};
+use trust_dns_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::metrics::Metrics;
+
+/// Amount of times a NOTIFY is sent to a secondary before giving up.
+const NOTIFY_ATTEMPTS: usize = 5;
+/// Time to wait for a response to a NOTIFY before retrying. This doubles after every attempt.
+const INITIAL_NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum size of a response to a NOTIFY.
+const MAX_NOTIFY_RESPONSE_SIZE: usize = 512;
+
+/// Handle to the background task sending DNS NOTIFY messages (RFC 1996) to secondaries when a
+/// zone changes. This can be cheaply cloned.
+#[derive(Clone)]
+pub struct Notifier {
+    changes: mpsc::UnboundedSender<LowerName>,
+}
+
+impl Notifier {
+    /// Create a new notifier. Every zone is notified to the global targets, and the targets
+    /// configured specifically for the zone.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if called outside the context of a `[tokio]` runtime.
+    pub fn new(
+        targets: Vec<SocketAddr>,
+        zone_targets: HashMap<LowerName, Vec<SocketAddr>>,
+        metrics: Metrics,
+    ) -> Self {
+        let (changes, rx) = mpsc::unbounded_channel();
+        tokio::spawn(notify_loop(rx, targets, zone_targets, metrics));
+        Notifier { changes }
+    }
+
+    /// Notify secondaries that the zone changed.
+    pub fn notify(&self, zone: &LowerName) {
+        if self.changes.send(zone.clone()).is_err() {
+            error!("Notifier is not running, can't notify change of {}", zone);
+        }
+    }
+}
+
+async fn notify_loop(
+    mut changes: mpsc::UnboundedReceiver<LowerName>,
+    targets: Vec<SocketAddr>,
+    zone_targets: HashMap<LowerName, Vec<SocketAddr>>,
+    metrics: Metrics,
+) {
+    while let Some(zone) = changes.recv().await {
+        let zone_specific = zone_targets.get(&zone).map(Vec::as_slice).unwrap_or(&[]);
+        for target in targets.iter().chain(zone_specific) {
+            // Every target gets its own task, so slow or unreachable secondaries don't hold up
+            // the others.
+            let zone = zone.clone();
+            let target = *target;
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                match send_notify(&zone, target).await {
+                    Ok(()) => {
+                        debug!("Notified {} of change in zone {}", target, zone);
+                        metrics.increment_zone_notify(&zone, "sent");
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to notify {} of change in zone {}: {}",
+                            target, zone, e
+                        );
+                        metrics.increment_zone_notify(&zone, "failed");
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Send a NOTIFY for the zone to the target, retrying with backoff until it is acknowledged.
+async fn send_notify(
+    zone: &LowerName,
+    target: SocketAddr,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+
+    let mut timeout = INITIAL_NOTIFY_TIMEOUT;
+    let mut last_err: Box<dyn Error + Send + Sync> = "No NOTIFY attempts made".into();
+    for attempt in 1..=NOTIFY_ATTEMPTS {
+        trace!(
+            "Sending NOTIFY for {} to {}, attempt {}",
+            zone,
+            target,
+            attempt
+        );
+        let id = random_id()?;
+        socket.send(&notify_message(zone, id).to_vec()?).await?;
+
+        match tokio::time::timeout(timeout, await_response(&socket, id)).await {
+            Ok(Ok(ResponseCode::NoError)) => return Ok(()),
+            Ok(Ok(code)) => last_err = format!("Secondary responded with {}", code).into(),
+            Ok(Err(e)) => last_err = e,
+            Err(_) => last_err = "Timeout waiting for NOTIFY response".into(),
+        }
+        timeout *= 2;
+    }
+
+    Err(last_err)
+}
+
+/// Wait for the response to the NOTIFY with the given id, returning its response code.
+async fn await_response(
+    socket: &UdpSocket,
+    id: u16,
+) -> Result<ResponseCode, Box<dyn Error + Send + Sync>> {
+    let mut buf = [0; MAX_NOTIFY_RESPONSE_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        let response = match Message::from_vec(&buf[..n]) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Ignoring invalid NOTIFY response: {}", e);
+                continue;
+            }
+        };
+        // Responses to earlier attempts can still arrive, ignore those.
+        if response.id() == id && response.message_type() == MessageType::Response {
+            return Ok(response.response_code());
+        }
+    }
+}
+
+/// Create a NOTIFY message for the zone.
+fn notify_message(zone: &LowerName, id: u16) -> Message {
+    let mut msg = Message::new();
+    msg.set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Notify)
+        .set_authoritative(true)
+        .add_query(Query::query(Name::from(zone.clone()), RecordType::SOA));
+    msg
+}
+
+/// Generate a random message id.
+fn random_id() -> Result<u16, Box<dyn Error + Send + Sync>> {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "Failed to generate message id")?;
+    Ok(u16::from_be_bytes(id))
+}
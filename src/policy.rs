@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The global response policy, used for every zone which does not override it.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponsePolicy {
+    /// Include the apex NS records in the authority section of positive answers.
+    pub include_authority_ns: bool,
+    /// Minimum TTL of records in the answer section. Records with a lower TTL are served with
+    /// this TTL instead.
+    pub min_ttl: u32,
+    /// How queries of type ANY are answered.
+    pub any_response: AnyResponse,
+    /// TTL of the synthesized HINFO record in minimal ANY responses.
+    pub any_hinfo_ttl: u32,
+    /// Order of the records of the answer RRset.
+    pub answer_order: AnswerOrder,
+}
+
+impl Default for ResponsePolicy {
+    fn default() -> Self {
+        ResponsePolicy {
+            include_authority_ns: false,
+            min_ttl: 0,
+            any_response: AnyResponse::default(),
+            any_hinfo_ttl: 3600,
+            answer_order: AnswerOrder::default(),
+        }
+    }
+}
+
+/// Response to queries of type ANY.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyResponse {
+    /// Answer with a single synthesized HINFO record, as described in RFC 8482.
+    #[default]
+    Minimal,
+    /// Answer with all records of the name. This can result in large responses, so it is mostly
+    /// useful for internal deployments.
+    Full,
+}
+
+/// Handling of ANY queries over UDP, which are almost exclusively used for amplification attacks.
+/// ANY queries over TCP are always answered according to the [`AnyResponse`] of the zone.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyOverUdp {
+    /// Answer according to the [`AnyResponse`] of the zone, as over TCP.
+    #[default]
+    Answer,
+    /// Send an empty truncated response, so legitimate clients retry over TCP.
+    Truncate,
+    /// Answer with NOTIMP.
+    Refuse,
+}
+
+/// Order of the records of the answer RRset. Many stub resolvers only use the first address of an
+/// answer, so changing the order spreads clients over all addresses.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerOrder {
+    /// Keep the order in which the records are stored.
+    #[default]
+    Fixed,
+    /// Shuffle the records for every response.
+    Shuffle,
+    /// Rotate the records by one position for every response.
+    RoundRobin,
+}
+
+/// How the SOA serial of a zone is changed when its records are changed through the API.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoSerial {
+    /// Leave the serial as is, it is only changed through the SOA endpoint.
+    #[default]
+    Off,
+    /// Increment the serial by 1.
+    Increment,
+    /// Use a serial of the form YYYYMMDDnn, with the current date in UTC and a counter for the
+    /// changes of that day. Once the counter runs out the serial is incremented into the next
+    /// day, and the date catches up later.
+    Date,
+}
+
+/// Per zone overrides of the [`ResponsePolicy`], stored as zone metadata. Fields which are not
+/// set inherit the global policy, so an empty policy changes nothing.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ZonePolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_authority_ns: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub any_response: Option<AnyResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub any_hinfo_ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_order: Option<AnswerOrder>,
+    /// Add PTR records in the reverse zone for addresses added to the zone through the API. This
+    /// is not part of the response policy, and is disabled unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_ptr: Option<bool>,
+    /// Change the SOA serial for every change of the records of the zone made through the API.
+    /// This is not part of the response policy, and overrides the global setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_serial: Option<AutoSerial>,
+    /// Refuse A and AAAA records with addresses which are not reachable from the internet when
+    /// they are added through the API. This is not part of the response policy, and overrides the
+    /// global setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reject_private_addresses: Option<bool>,
+}
+
+impl ZonePolicy {
+    /// Apply the overrides in this policy to the global policy, returning the effective policy
+    /// for the zone.
+    pub fn resolve(&self, global: &ResponsePolicy) -> ResponsePolicy {
+        ResponsePolicy {
+            include_authority_ns: self
+                .include_authority_ns
+                .unwrap_or(global.include_authority_ns),
+            min_ttl: self.min_ttl.unwrap_or(global.min_ttl),
+            any_response: self.any_response.unwrap_or(global.any_response),
+            any_hinfo_ttl: self.any_hinfo_ttl.unwrap_or(global.any_hinfo_ttl),
+            answer_order: self.answer_order.unwrap_or(global.answer_order),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnswerOrder, AnyResponse, ResponsePolicy, ZonePolicy};
+
+    #[test]
+    fn absent_policy_inherits_everything() {
+        let policy: ZonePolicy = serde_json::from_str("{}").expect("Empty policy is valid");
+        assert_eq!(policy, ZonePolicy::default());
+        let global = ResponsePolicy {
+            include_authority_ns: true,
+            min_ttl: 60,
+            any_response: AnyResponse::Full,
+            any_hinfo_ttl: 120,
+            answer_order: AnswerOrder::Shuffle,
+        };
+        let resolved = policy.resolve(&global);
+        assert!(resolved.include_authority_ns);
+        assert_eq!(resolved.min_ttl, 60);
+        assert_eq!(resolved.any_response, AnyResponse::Full);
+        assert_eq!(resolved.any_hinfo_ttl, 120);
+        assert_eq!(resolved.answer_order, AnswerOrder::Shuffle);
+    }
+
+    #[test]
+    fn zone_overrides_global() {
+        let policy = ZonePolicy {
+            include_authority_ns: Some(true),
+            min_ttl: Some(600),
+            any_response: Some(AnyResponse::Full),
+            answer_order: Some(AnswerOrder::RoundRobin),
+            ..Default::default()
+        };
+        let resolved = policy.resolve(&ResponsePolicy::default());
+        assert!(resolved.include_authority_ns);
+        assert_eq!(resolved.min_ttl, 600);
+        assert_eq!(resolved.any_response, AnyResponse::Full);
+        assert_eq!(resolved.answer_order, AnswerOrder::RoundRobin);
+        // Knobs which are not overridden still come from the global policy.
+        assert_eq!(
+            resolved.any_hinfo_ttl,
+            ResponsePolicy::default().any_hinfo_ttl
+        );
+    }
+
+    #[test]
+    fn unset_overrides_are_not_serialized() {
+        let policy = ZonePolicy {
+            min_ttl: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&policy).expect("Policy can be serialized"),
+            r#"{"min_ttl":600}"#
+        );
+    }
+}
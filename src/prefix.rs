@@ -0,0 +1,245 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+/// An IP network in CIDR notation. A plain address is a network of just that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    /// Get the network of the given length containing the address. The length is capped to the
+    /// size of the address.
+    pub fn network(addr: IpAddr, len: u8) -> IpPrefix {
+        match addr {
+            IpAddr::V4(addr) => {
+                let len = len.min(32);
+                let mut octets = addr.octets();
+                mask_octets(&mut octets, len);
+                IpPrefix {
+                    addr: IpAddr::from(octets),
+                    len,
+                }
+            }
+            IpAddr::V6(addr) => {
+                let len = len.min(128);
+                let mut octets = addr.octets();
+                mask_octets(&mut octets, len);
+                IpPrefix {
+                    addr: IpAddr::from(octets),
+                    len,
+                }
+            }
+        }
+    }
+
+    /// Check if the address is part of this network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Special purpose ranges of addresses which are not reachable from the internet, or not meant to
+/// be published at all, with the kind of addresses they hold.
+const SPECIAL_PURPOSE_RANGES: [(&str, &str); 18] = [
+    ("0.0.0.0/8", "this network (RFC 791)"),
+    ("10.0.0.0/8", "private (RFC 1918)"),
+    ("100.64.0.0/10", "shared address space, CGNAT (RFC 6598)"),
+    ("127.0.0.0/8", "loopback (RFC 1122)"),
+    ("169.254.0.0/16", "link-local (RFC 3927)"),
+    ("172.16.0.0/12", "private (RFC 1918)"),
+    ("192.0.2.0/24", "documentation (RFC 5737)"),
+    ("192.168.0.0/16", "private (RFC 1918)"),
+    ("198.18.0.0/15", "benchmarking (RFC 2544)"),
+    ("198.51.100.0/24", "documentation (RFC 5737)"),
+    ("203.0.113.0/24", "documentation (RFC 5737)"),
+    ("224.0.0.0/3", "multicast or reserved (RFC 5771, RFC 1112)"),
+    ("::/127", "unspecified or loopback (RFC 4291)"),
+    ("2001:db8::/32", "documentation (RFC 3849)"),
+    ("3fff::/20", "documentation (RFC 9637)"),
+    ("fc00::/7", "unique local (RFC 4193)"),
+    ("fe80::/10", "link-local (RFC 4291)"),
+    ("ff00::/8", "multicast (RFC 4291)"),
+];
+
+/// Find the special purpose range an address is in, with a description of the range. IPv4 mapped
+/// IPv6 addresses are checked as the IPv4 address they map.
+pub fn special_purpose_range(addr: IpAddr) -> Option<(IpPrefix, &'static str)> {
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    };
+    SPECIAL_PURPOSE_RANGES
+        .iter()
+        .map(|(prefix, kind)| {
+            let prefix = prefix
+                .parse::<IpPrefix>()
+                .expect("Special purpose ranges are valid prefixes");
+            (prefix, *kind)
+        })
+        .find(|(prefix, _)| prefix.contains(addr))
+}
+
+/// Clear all bits of an address after the first `len` bits.
+fn mask_octets(octets: &mut [u8], len: u8) {
+    for (idx, octet) in octets.iter_mut().enumerate() {
+        let start = idx * 8;
+        if start + 8 <= len as usize {
+            continue;
+        }
+        let keep = (len as usize).saturating_sub(start);
+        *octet &= !(0xffu8 >> keep);
+    }
+}
+
+/// Check if the first `len` bits of both addresses are equal.
+fn prefix_matches(net: &[u8], addr: &[u8], len: u8) -> bool {
+    let full_bytes = len as usize / 8;
+    let remaining_bits = len % 8;
+    if net[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    net[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+impl FromStr for IpPrefix {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| "Invalid address in IP prefix")?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse::<u8>().map_err(|_| "Invalid IP prefix length")?,
+            None => max_len,
+        };
+        if len > max_len {
+            return Err("IP prefix length is too long for the address");
+        }
+        Ok(IpPrefix { addr, len })
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpPrefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{special_purpose_range, IpPrefix};
+
+    fn range(addr: &str) -> Option<String> {
+        let addr = addr.parse::<IpAddr>().expect("Valid address");
+        special_purpose_range(addr).map(|(prefix, _)| prefix.to_string())
+    }
+
+    #[test]
+    fn special_purpose_ranges() {
+        for (addr, expected) in [
+            ("0.0.0.0", Some("0.0.0.0/8")),
+            ("9.255.255.255", None),
+            ("10.0.0.0", Some("10.0.0.0/8")),
+            ("10.255.255.255", Some("10.0.0.0/8")),
+            ("11.0.0.0", None),
+            ("100.63.255.255", None),
+            ("100.64.0.0", Some("100.64.0.0/10")),
+            ("100.127.255.255", Some("100.64.0.0/10")),
+            ("100.128.0.0", None),
+            ("127.0.0.1", Some("127.0.0.0/8")),
+            ("169.254.1.1", Some("169.254.0.0/16")),
+            ("172.15.255.255", None),
+            ("172.16.0.0", Some("172.16.0.0/12")),
+            ("172.31.255.255", Some("172.16.0.0/12")),
+            ("172.32.0.0", None),
+            ("192.0.2.1", Some("192.0.2.0/24")),
+            ("192.0.3.1", None),
+            ("192.167.255.255", None),
+            ("192.168.0.1", Some("192.168.0.0/16")),
+            ("192.169.0.0", None),
+            ("198.18.0.1", Some("198.18.0.0/15")),
+            ("198.51.100.7", Some("198.51.100.0/24")),
+            ("203.0.113.7", Some("203.0.113.0/24")),
+            ("223.255.255.255", None),
+            ("224.0.0.1", Some("224.0.0.0/3")),
+            ("255.255.255.255", Some("224.0.0.0/3")),
+            ("1.1.1.1", None),
+            ("::", Some("::/127")),
+            ("::1", Some("::/127")),
+            ("::2", None),
+            ("2001:db8::1", Some("2001:db8::/32")),
+            ("2001:db9::1", None),
+            ("3fff::1", Some("3fff::/20")),
+            ("3fff:1000::1", None),
+            ("fbff:ffff::1", None),
+            ("fc00::1", Some("fc00::/7")),
+            ("fdff:ffff::1", Some("fc00::/7")),
+            ("fe00::1", None),
+            ("fe80::1", Some("fe80::/10")),
+            ("febf:ffff::1", Some("fe80::/10")),
+            ("fec0::1", None),
+            ("ff02::1", Some("ff00::/8")),
+            ("2606:4700::1111", None),
+            ("::ffff:10.1.2.3", Some("10.0.0.0/8")),
+            ("::ffff:1.1.1.1", None),
+        ] {
+            assert_eq!(range(addr).as_deref(), expected, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        let prefix = "192.168.1.77/20".parse::<IpPrefix>().expect("Valid prefix");
+        assert_eq!(prefix.to_string(), "192.168.1.77/20");
+        assert!(prefix.contains("192.168.15.255".parse().unwrap()));
+        assert!(!prefix.contains("192.168.16.0".parse().unwrap()));
+        assert!(!prefix.contains("::ffff:192.168.1.77".parse().unwrap()));
+        assert_eq!(
+            IpPrefix::network("192.168.1.77".parse().unwrap(), 20).to_string(),
+            "192.168.0.0/20"
+        );
+        assert_eq!(
+            IpPrefix::network("2001:db8::1".parse().unwrap(), 200).to_string(),
+            "2001:db8::1/128"
+        );
+        assert_eq!(
+            "10.0.0.1"
+                .parse::<IpPrefix>()
+                .expect("Valid prefix")
+                .to_string(),
+            "10.0.0.1/32"
+        );
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "10.0.0/8", "10.0.0.0/x"] {
+            assert!(invalid.parse::<IpPrefix>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -1,29 +1,194 @@
 use fred::{
     pool::RedisPool,
     prelude::*,
-    types::{BackpressureConfig, PerformanceConfig, RespVersion, ScanType},
+    types::{
+        BackpressureConfig, CustomCommand, Expiration, PerformanceConfig, RespVersion, ScanResult,
+        ScanType, SetOptions,
+    },
+    util::redis_keyslot,
 };
-use futures_util::StreamExt;
-use log::error;
+use futures_util::{
+    future::{join_all, BoxFuture},
+    stream::BoxStream,
+    StreamExt,
+};
+use log::{debug, error, trace, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use trust_dns_proto::rr::{Record, RecordType};
 use trust_dns_server::client::rr::LowerName;
 
-use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    audit::{AuditEntry, AuditSink},
+    config::{RedisConnectionConfig, RedisMode},
+    idempotency::{IdempotencyClaim, IdempotencyRecord, IdempotencyStore},
+    policy::ZonePolicy,
+    reload::ZoneReload,
+    storage::{
+        closest_encloser_from_keys, name_index_end, name_index_key, ApiToken, CutKind, DnssecKey,
+        InvalidCursor, Page, SerialChanged, Storage, StorageChange, StorageOp, StorageRecord,
+        ZoneChange, ZoneCuts,
+    },
+};
+
+/// Hash holding all API tokens, keyed by token id.
+const API_TOKENS_KEY: &str = "api_tokens";
+/// Hash holding the last used time of API tokens, keyed by token id.
+const API_TOKEN_USAGE_KEY: &str = "api_token_usage";
+
+/// Time audit log entries are kept. Entries are stored in a list per day, which expires once
+/// all of its entries are older than this.
+const AUDIT_RETENTION: Duration = Duration::from_secs(90 * SECONDS_PER_DAY);
+/// Amount of audit log entries loaded at once when reading the audit log.
+const AUDIT_BATCH_SIZE: i64 = 500;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Maximum amount of changes kept in the journal of a zone. Older changes are dropped, and
+/// secondaries which are further behind get a full transfer instead.
+const MAX_JOURNAL_LENGTH: i64 = 1_000;
+
+/// Lua script setting multiple fields of a single hash atomically. Arguments are pairs of a field
+/// and its new value, an empty value removes the field. Redis removes the hash itself once its
+/// last field is gone.
+const REPLACE_FIELDS_SCRIPT: &str = r#"
+for i = 1, #ARGV, 2 do
+    if ARGV[i + 1] == "" then
+        redis.call("HDEL", KEYS[1], ARGV[i])
+    else
+        redis.call("HSET", KEYS[1], ARGV[i], ARGV[i + 1])
+    end
+end
+"#;
+
+/// Lua script removing a field of a hash, returning its value, or nil if it did not exist. Redis
+/// removes the hash itself once its last field is gone.
+const TAKE_FIELD_SCRIPT: &str = r#"
+local value = redis.call("HGET", KEYS[1], ARGV[1])
+if value then
+    redis.call("HDEL", KEYS[1], ARGV[1])
+end
+return value
+"#;
+
+/// Lua script replacing a field of a hash only if it still has the value it was read with. The
+/// arguments are the field, its expected value and its new value, where an empty value stands
+/// for a missing field. Returns 1 if the field was replaced, 0 if it changed since it was read.
+const SWAP_FIELD_SCRIPT: &str = r#"
+local current = redis.call("HGET", KEYS[1], ARGV[1]) or ""
+if current ~= ARGV[2] then
+    return 0
+end
+if ARGV[3] == "" then
+    redis.call("HDEL", KEYS[1], ARGV[1])
+else
+    redis.call("HSET", KEYS[1], ARGV[1], ARGV[3])
+end
+return 1
+"#;
+
+/// Maximum amount of times a read-modify-write of a field is retried if the field is changed
+/// concurrently.
+const MAX_SWAP_ATTEMPTS: usize = 16;
+
+/// Lua script updating the index of names of a zone, a sorted set where all names have the same
+/// score, so they are sorted by their index key. Arguments are pairs of the index key of a name and
+/// "1" if the name has records, or "0" if it has none.
+const INDEX_NAMES_SCRIPT: &str = r#"
+for i = 1, #ARGV, 2 do
+    if ARGV[i + 1] == "1" then
+        redis.call("ZADD", KEYS[1], 0, ARGV[i])
+    else
+        redis.call("ZREM", KEYS[1], ARGV[i])
+    end
+end
+"#;
+
+/// Lua script updating the index of zone cuts of a zone, a set of `{domain}/{kind}` entries, see
+/// [`CutKind`]. Arguments are pairs of an entry and "1" if the RRset of the cut exists, or "0" if
+/// it does not.
+const INDEX_CUTS_SCRIPT: &str = r#"
+for i = 1, #ARGV, 2 do
+    if ARGV[i + 1] == "1" then
+        redis.call("SADD", KEYS[1], ARGV[i])
+    else
+        redis.call("SREM", KEYS[1], ARGV[i])
+    end
+end
+"#;
+
+/// Lua script checking if the index of names of a zone has a name below a name. The only argument
+/// is the index key of the name. Returns 1 if there is such a name, 0 otherwise.
+const HAS_NAMES_BELOW_SCRIPT: &str = r#"
+local below = redis.call("ZRANGEBYLEX", KEYS[1], "(" .. ARGV[1], "(" .. ARGV[2], "LIMIT", 0, 1)
+return #below
+"#;
+
+/// Lua script getting the names next to a name in the index of names of a zone: the last name at
+/// or before it, and the first name after it. The only argument is the index key of the name.
+const NAMES_AROUND_SCRIPT: &str = r#"
+local names = redis.call("ZREVRANGEBYLEX", KEYS[1], "[" .. ARGV[1], "-", "LIMIT", 0, 1)
+for _, name in ipairs(redis.call("ZRANGEBYLEX", KEYS[1], "(" .. ARGV[1], "+", "LIMIT", 0, 1)) do
+    table.insert(names, name)
+end
+return names
+"#;
+
+/// Fields of a hash with their new values, where an empty value removes the field.
+type Fields = Vec<(String, Vec<u8>)>;
 
-use crate::storage::{Storage, StorageRecord};
+/// Maximum amount of keys removed with a single command.
+const DELETE_BATCH_SIZE: usize = 100;
+/// Maximum amount of commands sent to a node at once when pipelining. This stays well below the
+/// maximum amount of in flight commands of the client, so bulk reads don't trigger backpressure.
+const PIPELINE_BATCH_SIZE: usize = 500;
+/// Amount of keys requested per SCAN when listing all keys matching a pattern.
+const SCAN_BATCH_SIZE: usize = 1_000;
+
+/// Time allowed for a single connectivity test.
+const CONNECTION_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Initial delay between connectivity tests.
+const MIN_CONNECTION_TEST_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximum delay between connectivity tests.
+const MAX_CONNECTION_TEST_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Pub/sub channel on which every instance announces the changes it made.
+const CHANGES_CHANNEL: &str = "cetus:changes";
+/// Amount of received changes buffered for slow subscribers. Subscribers which fall further
+/// behind are told they missed changes.
+const CHANGE_BUFFER_SIZE: usize = 1_024;
+/// Delay before retrying to subscribe to the changes channel.
+const SUBSCRIBE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct RedisClusterClient {
     client: RedisPool,
+    mode: RedisMode,
+    /// Changes published by all instances, if change events are enabled.
+    changes: Option<broadcast::Sender<StorageChange>>,
+    /// Zones for which the indexes of names and zone cuts are known to be complete.
+    indexed_zones: RwLock<HashSet<LowerName>>,
 }
 
 impl RedisClusterClient {
     /// Create a new [`RedisClusterClient`] by connecting to a node in the cluster at the given ip
-    /// and port.
+    /// and port. In standalone mode, the first address is the server to connect to. If change
+    /// events are enabled, a task following the changes of all instances is started as well.
     ///
     /// # Panics
     ///
     /// This function will panic if an invalid configuration is passed
-    pub fn new(username: Option<String>, password: Option<String>, addrs: &[SocketAddr]) -> Self {
+    pub fn new(cfg: &RedisConnectionConfig) -> Self {
         let performance = PerformanceConfig {
+            // Bulk reads rely on commands sent concurrently on a client being pipelined.
+            pipeline: true,
             cluster_cache_update_delay_ms: 10,
             max_command_attempts: 20,
             backpressure: BackpressureConfig {
@@ -35,28 +200,180 @@ impl RedisClusterClient {
             ..Default::default()
         };
         let conf = RedisConfig {
-            username,
-            password,
+            username: cfg.username.clone(),
+            password: cfg.password.clone(),
             performance,
             version: RespVersion::RESP2,
-            server: ServerConfig::Clustered {
-                hosts: addrs
-                    .iter()
-                    .map(|sa| (sa.ip().to_string(), sa.port()))
-                    .collect(),
-            },
+            server: server_config(cfg.mode, &cfg.node_addresses),
             ..Default::default()
         };
+        let changes = cfg.change_events.then(|| {
+            let (changes, _) = broadcast::channel(CHANGE_BUFFER_SIZE);
+            tokio::spawn(follow_changes(conf.clone(), changes.clone()));
+            changes
+        });
         let client = RedisPool::new(conf, 10).expect("Valid pool config");
         let reconnect = ReconnectPolicy::new_constant(1_000, 10);
         let _conn_task = client.connect(Some(reconnect));
         //tokio::spawn(conn_task);
-        RedisClusterClient { client }
+        RedisClusterClient {
+            client,
+            mode: cfg.mode,
+            changes,
+            indexed_zones: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Bring the indexes of names and zone cuts up to date with changes which are written, and
+    /// announce them to all instances. Writes are not atomic with their index update, as the
+    /// indexes are in another hash slot, so concurrent writes to the same domain can leave them
+    /// briefly out of date, until the next write to the domain.
+    async fn commit_changes(
+        &self,
+        changes: Vec<StorageChange>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut domains: HashMap<&LowerName, HashSet<&LowerName>> = HashMap::new();
+        let mut cuts: HashMap<&LowerName, Vec<(LowerName, RecordType)>> = HashMap::new();
+        for change in &changes {
+            if let StorageChange::Rrset {
+                zone,
+                domain,
+                rtype,
+            } = change
+            {
+                domains.entry(zone).or_default().insert(domain);
+                if CutKind::of(zone, domain, *rtype).is_some() {
+                    cuts.entry(zone).or_default().push((domain.clone(), *rtype));
+                }
+            }
+        }
+        for (zone, domains) in domains {
+            self.index_names(zone, domains.into_iter().cloned().collect())
+                .await?;
+        }
+        for (zone, rrsets) in cuts {
+            self.index_cuts(zone, rrsets).await?;
+        }
+
+        self.publish_changes(changes).await;
+        Ok(())
+    }
+
+    /// Add the domains which have records to the index of names of a zone, and remove the others.
+    async fn index_names(
+        &self,
+        zone: &LowerName,
+        domains: Vec<LowerName>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for batch in domains.chunks(PIPELINE_BATCH_SIZE) {
+            let exists = join_all(batch.iter().map(|domain| {
+                self.client
+                    .exists::<u64, _>(format!("resource:{}:{}", zone, domain))
+            }))
+            .await;
+            let mut args = Vec::with_capacity(batch.len() * 2);
+            for (domain, exists) in batch.iter().zip(exists) {
+                args.push(name_index_key(domain));
+                args.push(if exists? > 0 { "1" } else { "0" }.to_string());
+            }
+            self.client
+                .eval::<(), _, _, _>(INDEX_NAMES_SCRIPT, names_key(zone), args)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Add the RRsets defining zone cuts which exist to the index of zone cuts of a zone, and
+    /// remove the others.
+    async fn index_cuts(
+        &self,
+        zone: &LowerName,
+        rrsets: Vec<(LowerName, RecordType)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for batch in rrsets.chunks(PIPELINE_BATCH_SIZE) {
+            let exists = join_all(batch.iter().map(|(domain, rtype)| {
+                self.client.hexists::<bool, _, _>(
+                    format!("resource:{}:{}", zone, domain),
+                    rtype.to_string(),
+                )
+            }))
+            .await;
+            let mut args = Vec::with_capacity(batch.len() * 2);
+            for ((domain, rtype), exists) in batch.iter().zip(exists) {
+                if let Some(kind) = CutKind::of(zone, domain, *rtype) {
+                    args.push(format!("{}/{}", domain, kind.as_str()));
+                    args.push(if exists? { "1" } else { "0" }.to_string());
+                }
+            }
+            self.client
+                .eval::<(), _, _, _>(INDEX_CUTS_SCRIPT, cuts_key(zone), args)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Build the indexes of names and zone cuts of a zone from its records, unless they are
+    /// complete already. Only zones created before the indexes existed need this, and only once,
+    /// which is tracked with a marker next to the indexes. Zones created since have their marker
+    /// set on creation.
+    async fn ensure_indexes(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.indexed_zones.read().unwrap().contains(zone) {
+            return Ok(());
+        }
+
+        let marker = index_marker_key(zone);
+        if self.client.exists::<u64, _>(marker.as_str()).await? == 0 {
+            debug!("Building indexes of zone {}", zone);
+            let domains = self.list_domains(zone).await?;
+            let records = self.list_records_bulk(zone, &domains).await?;
+            let mut cuts = Vec::new();
+            for (domain, records) in domains.iter().zip(records) {
+                for rtype in records.iter().map(|sr| sr.as_record().record_type()) {
+                    if CutKind::of(zone, domain, rtype).is_some() {
+                        cuts.push((domain.clone(), rtype));
+                    }
+                }
+            }
+            cuts.dedup();
+            self.index_names(zone, domains).await?;
+            self.index_cuts(zone, cuts).await?;
+            self.client
+                .set::<(), _, _>(marker, "", None, None, false)
+                .await?;
+        }
+        self.indexed_zones.write().unwrap().insert(zone.clone());
+        Ok(())
+    }
+
+    /// Announce changes to all instances. This is best effort: the changes are already made, so
+    /// if they can't be published, other instances only see them once their caches expire.
+    async fn publish_changes(&self, changes: Vec<StorageChange>) {
+        if self.changes.is_none() || changes.is_empty() {
+            return;
+        }
+        let messages = changes
+            .iter()
+            .filter_map(ChangeMessage::from_change)
+            .collect::<Vec<_>>();
+        let encoded_messages = match serde_json::to_string(&messages) {
+            Ok(encoded_messages) => encoded_messages,
+            Err(e) => {
+                error!("Failed to encode change events: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .client
+            .publish::<(), _, _>(CHANGES_CHANNEL, encoded_messages)
+            .await
+        {
+            warn!("Failed to publish {} change events: {}", messages.len(), e);
+        }
     }
 
     /// Test the client, to see if it can actually connect to the given node. If this fails, the
     /// client should be discarded as future operations will likely also fails.
-    pub async fn test(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn test(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::trace!("Testing cluster connection");
         self.client.wait_for_connect().await?;
         log::trace!("Client connected - try to ping");
@@ -64,10 +381,475 @@ impl RedisClusterClient {
         log::trace!("Cluster connection OK");
         Ok(())
     }
+
+    /// Repeatedly [`test`](Self::test) the client with exponential backoff until it succeeds. If
+    /// a timeout is given and the client still can't connect once it expires, an error is
+    /// returned. Without a timeout, this keeps retrying forever.
+    pub async fn test_with_backoff(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let start = Instant::now();
+        let mut backoff = MIN_CONNECTION_TEST_BACKOFF;
+        loop {
+            match tokio::time::timeout(CONNECTION_TEST_TIMEOUT, self.test()).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => warn!("Cluster connection test failed: {}", e),
+                Err(_) => warn!("Cluster connection test timed out"),
+            }
+
+            if let Some(timeout) = timeout {
+                if start.elapsed() + backoff > timeout {
+                    return Err(format!(
+                        "cluster not reachable after {} ms",
+                        start.elapsed().as_millis()
+                    )
+                    .into());
+                }
+            }
+
+            log::debug!("Retrying cluster connection test in {:?}", backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_CONNECTION_TEST_BACKOFF);
+        }
+    }
+
+    /// Atomically set the given fields of a hash, removing fields with an empty value.
+    async fn replace_fields(&self, key: &str, fields: Fields) -> Result<(), RedisError> {
+        let args = fields
+            .into_iter()
+            .flat_map(|(field, value)| [field.into_bytes(), value])
+            .collect::<Vec<_>>();
+        self.client.eval(REPLACE_FIELDS_SCRIPT, key, args).await
+    }
+
+    /// Replace fields of multiple hashes. Every hash is updated atomically, but different hashes
+    /// are in different hash slots, so a transaction can't span them. Instead, the previous values
+    /// are kept so hashes which are already updated can be restored if a later one fails. These
+    /// previous values are returned, to undo the update if something after it fails.
+    async fn write_fields(
+        &self,
+        keys: HashMap<String, Fields>,
+    ) -> Result<Vec<(String, Fields)>, RedisError> {
+        let mut applied = Vec::with_capacity(keys.len());
+        for (key, fields) in keys {
+            let result = match self
+                .client
+                .hgetall::<HashMap<String, Vec<u8>>, _>(&key)
+                .await
+            {
+                Ok(mut previous) => {
+                    let previous_fields = fields
+                        .iter()
+                        .map(|(field, _)| {
+                            (field.clone(), previous.remove(field).unwrap_or_default())
+                        })
+                        .collect::<Vec<_>>();
+                    self.replace_fields(&key, fields)
+                        .await
+                        .map(|_| previous_fields)
+                }
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(previous_fields) => applied.push((key, previous_fields)),
+                Err(e) => {
+                    self.restore_fields(applied).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Restore the previous values of fields replaced by [`RedisClusterClient::write_fields`].
+    async fn restore_fields(&self, previous: Vec<(String, Fields)>) {
+        // Fields are restored in reverse, so a field replaced twice ends up with its first value.
+        for (key, previous_fields) in previous.into_iter().rev() {
+            if let Err(e) = self.replace_fields(&key, previous_fields).await {
+                error!("Failed to restore {} after failed update: {}", key, e);
+            }
+        }
+    }
+
+    /// Write the records of a batch, and then the markers of the added zones, so a zone never
+    /// exists without its records. Everything is undone if a marker can't be written.
+    async fn write_batch(
+        &self,
+        keys: HashMap<String, Fields>,
+        zones: Vec<LowerName>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let previous = self.write_fields(keys).await?;
+
+        let mut created = Vec::with_capacity(zones.len());
+        for zone in zones {
+            let marker = format!("zone:{}", zone);
+            // Only markers which did not exist yet are removed again if the batch fails.
+            match self
+                .client
+                .set::<Option<String>, _, _>(&marker, "", None, Some(SetOptions::NX), false)
+                .await
+            {
+                Ok(Some(_)) => created.push(zone),
+                Ok(None) => {}
+                Err(e) => {
+                    for zone in created {
+                        let marker = format!("zone:{}", zone);
+                        if let Err(e) = self.client.del::<u64, _>(&marker).await {
+                            error!("Failed to remove {} after failed update: {}", marker, e);
+                        }
+                    }
+                    self.restore_fields(previous).await;
+                    return Err(e.into());
+                }
+            }
+        }
+
+        for zone in created {
+            self.mark_indexed(&zone).await;
+        }
+        Ok(())
+    }
+
+    /// Replace the SOA of a zone if it still has the given serial, see [`StorageOp::SwapSoa`].
+    /// Returns the replaced field, to restore it if the rest of the batch fails.
+    async fn swap_soa(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+        soa: StorageRecord,
+    ) -> Result<(String, Fields), Box<dyn Error + Send + Sync>> {
+        let key = format!("resource:{}:{}", zone, zone);
+        let field = RecordType::SOA.to_string();
+        let current = self
+            .client
+            .hget::<Option<Vec<u8>>, _, _>(&key, field.as_str())
+            .await?
+            .unwrap_or_default();
+        let soas: Vec<StorageRecord> = if current.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_slice(&current)?
+        };
+        if soas.first().and_then(StorageRecord::serial) != Some(serial) {
+            return Err(SerialChanged.into());
+        }
+
+        let swapped = self
+            .client
+            .eval::<u64, _, _, _>(
+                SWAP_FIELD_SCRIPT,
+                key.as_str(),
+                vec![
+                    field.clone().into_bytes(),
+                    current.clone(),
+                    encode_rrset(&[soa])?,
+                ],
+            )
+            .await?;
+        if swapped == 0 {
+            return Err(SerialChanged.into());
+        }
+        Ok((key, vec![(field, current)]))
+    }
+
+    /// Mark the indexes of a new zone as complete, as all of its writes maintain them. If this
+    /// fails, the indexes are built again when they are first used.
+    async fn mark_indexed(&self, zone: &LowerName) {
+        if let Err(e) = self
+            .client
+            .set::<(), _, _>(index_marker_key(zone), "", None, None, false)
+            .await
+        {
+            warn!("Failed to mark indexes of zone {} as complete: {}", zone, e);
+        }
+    }
+
+    /// Change the records of a type of a domain, without losing concurrent changes. The change is
+    /// retried on the latest records if they were changed after being read. An empty set of
+    /// records removes the field, and with it the domain if this was its last RRset.
+    async fn update_rrset<T>(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        mut change: impl FnMut(&mut Vec<StorageRecord>) -> Option<T>,
+    ) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+        let key = format!("resource:{}:{}", zone, domain);
+        let field = rtype.to_string();
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let current = self
+                .client
+                .hget::<Option<Vec<u8>>, _, _>(&key, field.as_str())
+                .await?
+                .unwrap_or_default();
+            let mut record_set = if current.is_empty() {
+                Vec::new()
+            } else {
+                serde_json::from_slice(&current)?
+            };
+
+            let outcome = match change(&mut record_set) {
+                Some(outcome) => outcome,
+                None => return Ok(None),
+            };
+            let new = encode_rrset(&record_set)?;
+
+            let swapped = self
+                .client
+                .eval::<u64, _, _, _>(
+                    SWAP_FIELD_SCRIPT,
+                    key.as_str(),
+                    vec![field.clone().into_bytes(), current, new],
+                )
+                .await?;
+            if swapped == 1 {
+                return Ok(Some(outcome));
+            }
+            trace!(
+                "{} records of {} changed concurrently, retrying",
+                field,
+                domain
+            );
+        }
+
+        Err(format!("{} records of {} keep changing concurrently", field, domain).into())
+    }
+
+    /// Get a page of keys matching a pattern. The primaries of the cluster are scanned one after
+    /// the other, so the cursor is the index of the node being scanned, and the SCAN cursor on
+    /// that node. Like SCAN itself, a page can hold a few more keys than the limit, and a key can
+    /// be returned more than once if the node rehashes during the listing.
+    async fn scan_page(
+        &self,
+        pattern: &str,
+        scan_type: &'static str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>), Box<dyn Error + Send + Sync>> {
+        let nodes = self.scan_nodes()?;
+        let (mut node, mut node_cursor) = match cursor {
+            Some(cursor) => {
+                let (node, node_cursor) = cursor.split_once(':').ok_or(InvalidCursor)?;
+                let node = node.parse::<usize>().map_err(|_| InvalidCursor)?;
+                if node >= nodes.len() || node_cursor.parse::<u64>().is_err() {
+                    return Err(InvalidCursor.into());
+                }
+                (node, node_cursor.to_string())
+            }
+            None => (0, "0".to_string()),
+        };
+
+        let mut keys = Vec::new();
+        while node < nodes.len() && keys.len() < limit {
+            let next_cursor = self
+                .scan_node(
+                    nodes[node],
+                    &node_cursor,
+                    pattern,
+                    scan_type,
+                    limit - keys.len(),
+                    &mut keys,
+                )
+                .await?;
+            // A node is done once SCAN returns to cursor 0, which is also where the next starts.
+            if next_cursor == "0" {
+                node += 1;
+            }
+            node_cursor = next_cursor;
+        }
+
+        let next_cursor = if node < nodes.len() {
+            Some(format!("{}:{}", node, node_cursor))
+        } else {
+            None
+        };
+        Ok((keys, next_cursor))
+    }
+
+    /// Get all keys matching a pattern. Unlike [`scan_page`](Self::scan_page), the primaries of the
+    /// cluster are scanned concurrently, in large pages.
+    async fn scan_all(
+        &self,
+        pattern: &str,
+        scan_type: &'static str,
+    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let nodes = self.scan_nodes()?;
+        let scans = nodes.into_iter().map(|node| async move {
+            let mut keys = Vec::new();
+            let mut cursor = "0".to_string();
+            loop {
+                cursor = self
+                    .scan_node(
+                        node,
+                        &cursor,
+                        pattern,
+                        scan_type,
+                        SCAN_BATCH_SIZE,
+                        &mut keys,
+                    )
+                    .await?;
+                if cursor == "0" {
+                    return Ok::<_, Box<dyn Error + Send + Sync>>(keys);
+                }
+            }
+        });
+
+        let mut keys = Vec::new();
+        for node_keys in join_all(scans).await {
+            keys.extend(node_keys?);
+        }
+        Ok(keys)
+    }
+
+    /// Run a single SCAN on the node serving a hash slot, adding the keys to `keys`. Returns the
+    /// cursor to continue the scan with, which is "0" once the node is fully scanned.
+    async fn scan_node(
+        &self,
+        node: Option<u16>,
+        cursor: &str,
+        pattern: &str,
+        scan_type: &'static str,
+        count: usize,
+        keys: &mut Vec<String>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let reply = self
+            .client
+            .custom::<RedisValue, String>(
+                CustomCommand::new("SCAN", node, false),
+                vec![
+                    cursor.to_string(),
+                    "MATCH".to_string(),
+                    pattern.to_string(),
+                    "COUNT".to_string(),
+                    count.to_string(),
+                    "TYPE".to_string(),
+                    scan_type.to_string(),
+                ],
+            )
+            .await?;
+        let (next_cursor, page) = match reply {
+            RedisValue::Array(mut reply) if reply.len() == 2 => {
+                let page = reply.pop();
+                let next_cursor = reply.pop().and_then(|cursor| cursor.into_string());
+                match (next_cursor, page) {
+                    (Some(next_cursor), Some(RedisValue::Array(page))) => (next_cursor, page),
+                    _ => return Err("unexpected SCAN reply".into()),
+                }
+            }
+            _ => return Err("unexpected SCAN reply".into()),
+        };
+        keys.extend(page.into_iter().filter_map(|key| key.into_string()));
+        Ok(next_cursor)
+    }
+
+    /// Get all fields of multiple hashes, in the order of the keys. Keys are grouped by the node
+    /// serving them, and the commands of a group are sent together on a single client, so they
+    /// are pipelined on its connection to the node instead of each waiting for the previous
+    /// reply. The groups of different nodes are sent concurrently.
+    async fn hgetall_many(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<HashMap<String, Vec<u8>>>, RedisError> {
+        let groups = self.group_by_node(keys);
+        let replies = join_all(groups.into_iter().map(|group| async move {
+            let client = self.client.next();
+            let mut replies = Vec::with_capacity(group.len());
+            for batch in group.chunks(PIPELINE_BATCH_SIZE) {
+                let batch_replies =
+                    join_all(batch.iter().map(|&idx| {
+                        client.hgetall::<HashMap<String, Vec<u8>>, _>(keys[idx].as_str())
+                    }))
+                    .await;
+                for (&idx, reply) in batch.iter().zip(batch_replies) {
+                    replies.push((idx, reply?));
+                }
+            }
+            Ok::<_, RedisError>(replies)
+        }))
+        .await;
+
+        let mut hashes = vec![HashMap::new(); keys.len()];
+        for group in replies {
+            for (idx, hash) in group? {
+                hashes[idx] = hash;
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Group the indices of keys by the node serving their hash slot. If the cluster state is not
+    /// known yet, all keys are put in a single group.
+    fn group_by_node(&self, keys: &[String]) -> Vec<Vec<usize>> {
+        let slots = self
+            .client
+            .cached_cluster_state()
+            .map(|state| state.slots())
+            .unwrap_or_default();
+        let mut groups: HashMap<Option<String>, Vec<usize>> = HashMap::new();
+        for (idx, key) in keys.iter().enumerate() {
+            let slot = redis_keyslot(key);
+            let node = slots
+                .iter()
+                .find(|range| range.start <= slot && slot <= range.end)
+                .map(|range| range.server.to_string());
+            groups.entry(node).or_default().push(idx);
+        }
+        groups.into_values().collect()
+    }
+
+    /// Scan the keys matching a pattern on all primaries of the cluster, or on the server in
+    /// standalone mode.
+    fn scan_keys(
+        &self,
+        pattern: String,
+        count: u32,
+        scan_type: ScanType,
+    ) -> BoxStream<'static, Result<ScanResult, RedisError>> {
+        match self.mode {
+            RedisMode::Cluster => self
+                .client
+                .scan_cluster(pattern, Some(count), Some(scan_type))
+                .boxed(),
+            RedisMode::Standalone => self
+                .client
+                .scan(pattern, Some(count), Some(scan_type))
+                .boxed(),
+        }
+    }
+
+    /// A hash slot of every primary in the cluster, used to send commands to a specific node. The
+    /// nodes are sorted, so cursors of [`scan_page`](Self::scan_page) remain valid as long as the
+    /// cluster topology does not change. A standalone server is a single node, which is not
+    /// addressed by a hash slot.
+    fn scan_nodes(&self) -> Result<Vec<Option<u16>>, Box<dyn Error + Send + Sync>> {
+        if self.mode == RedisMode::Standalone {
+            return Ok(vec![None]);
+        }
+        let state = self
+            .client
+            .cached_cluster_state()
+            .ok_or("cluster state is not known yet")?;
+        let mut nodes = state
+            .slots()
+            .iter()
+            .map(|range| (range.server.clone(), range.start))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.dedup_by(|a, b| a.0 == b.0);
+        Ok(nodes.into_iter().map(|(_, slot)| Some(slot)).collect())
+    }
 }
 
 #[async_trait::async_trait]
 impl Storage for RedisClusterClient {
+    async fn ping(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client.ping().await?;
+        Ok(())
+    }
+
     async fn zones(
         &self,
     ) -> Result<
@@ -75,50 +857,49 @@ impl Storage for RedisClusterClient {
         Box<dyn std::error::Error + Send + Sync>,
     > {
         log::trace!("Getting zones from redis cluster");
-        let scan_stream = self
-            .client
-            .scan_cluster("zone:*", Some(10), Some(ScanType::String));
-        // TODO: simplify this
-        Ok(scan_stream
-            .filter_map(|result| async move {
-                let mut page = match result {
-                    Ok(page) => page,
+        let keys = self.scan_all("zone:*", "string").await?;
+        Ok(keys
+            .iter()
+            .filter_map(
+                |key| match LowerName::from_str(key.trim_start_matches("zone:")) {
+                    Ok(ln) => Some(ln),
                     Err(e) => {
-                        log::error!("Could not get zone scan entry: {}", e);
-                        return None;
+                        log::error!("Ignoring invalid zone {:?}: {}", key, e);
+                        None
                     }
-                };
-                if let Some(keys) = page.take_results() {
-                    return Some(
-                        keys.into_iter()
-                            .filter_map(|key| {
-                                let key = match key.into_string() {
-                                    Some(key) => key,
-                                    None => {
-                                        log::error!("Could not convert key to string");
-                                        return None;
-                                    }
-                                };
-                                match LowerName::from_str(key.trim_start_matches("zone:")) {
-                                    Ok(ln) => Some(ln),
-                                    Err(e) => {
-                                        log::error!("Ignoring invalid zone {:?}: {}", key, e);
-                                        None
-                                    }
-                                }
-                            })
-                            .collect(),
-                    );
-                };
-                None
-            })
-            .collect::<Vec<Vec<LowerName>>>()
-            .await
-            .into_iter()
-            .flatten()
+                },
+            )
             .collect())
     }
 
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
+        let (keys, next_cursor) = self.scan_page("zone:*", "string", cursor, limit).await?;
+        let items = keys
+            .iter()
+            .filter_map(|key| {
+                LowerName::from_str(key.trim_start_matches("zone:"))
+                    .map_err(|e| error!("Ignoring invalid zone {:?}: {}", key, e))
+                    .ok()
+            })
+            .collect();
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn zone_exists(
+        &self,
+        zone: &LowerName,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .client
+            .exists::<u64, _>(format!("zone:{}", zone))
+            .await?
+            > 0)
+    }
+
     async fn lookup_records(
         &self,
         domain: &LowerName,
@@ -151,16 +932,38 @@ impl Storage for RedisClusterClient {
         }
     }
 
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn std::error::Error + Send + Sync>> {
+        let records = self.list_records(zone, domain).await?;
+        // A domain only exists in storage if it has at least 1 record.
+        if records.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(records))
+        }
+    }
+
+    #[cfg(test)]
     async fn add_zone(
         &self,
         zone: &LowerName,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(self
+        let previous = self
             .client
-            .set(format!("zone:{}", zone), "", None, None, false)
-            .await?)
+            .set::<Option<String>, _, _>(format!("zone:{}", zone), "", None, None, true)
+            .await?;
+        if previous.is_none() {
+            self.mark_indexed(zone).await;
+        }
+        self.publish_changes(vec![StorageChange::Zone { zone: zone.clone() }])
+            .await;
+        Ok(())
     }
 
+    #[cfg(test)]
     async fn add_record(
         &self,
         zone: &LowerName,
@@ -169,79 +972,990 @@ impl Storage for RedisClusterClient {
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let record_type = record.record.record_type();
 
-        let mut record_set = self
-            .lookup_records(domain, zone, record_type)
-            .await?
-            .unwrap_or_default();
+        // Add new record to the set, without overwriting records removed or added meanwhile.
+        self.update_rrset(zone, domain, record_type, |record_set| {
+            record_set.push(record.clone());
+            Some(())
+        })
+        .await?;
+        self.commit_changes(vec![rrset_change(zone, domain, record_type)])
+            .await
+    }
 
-        // Add new record to the set
-        record_set.push(record);
-        let new_record_set = serde_json::to_vec(&record_set)?;
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        if records.is_empty() {
+            let removed = self
+                .client
+                .hdel::<u64, _, _>(format!("resource:{}:{}", zone, domain), rtype.to_string())
+                .await?;
+            if removed > 0 {
+                self.commit_changes(vec![rrset_change(zone, domain, rtype)])
+                    .await?;
+            }
+            return Ok(removed > 0);
+        }
+        let encoded_records = serde_json::to_vec(&records)?;
 
-        Ok(self
+        // HSET returns the amount of fields which did not exist yet.
+        let created = self
             .client
-            .hset::<_, _, (&str, &[u8])>(
+            .hset::<u64, _, (&str, &[u8])>(
                 format!("resource:{}:{}", zone, domain),
-                (record_type.into(), &new_record_set),
+                (rtype.to_string().as_str(), &encoded_records),
             )
-            .await?)
+            .await?;
+        self.commit_changes(vec![rrset_change(zone, domain, rtype)])
+            .await?;
+
+        Ok(created == 0)
     }
 
-    async fn list_records(
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn std::error::Error + Send + Sync>> {
+        let serials = self
+            .update_rrset(zone, zone, RecordType::SOA, |records| {
+                records.first_mut().and_then(|soa| soa.bump_serial(floor))
+            })
+            .await?;
+        if serials.is_some() {
+            self.publish_changes(vec![rrset_change(zone, zone, RecordType::SOA)])
+                .await;
+        }
+        Ok(serials)
+    }
+
+    async fn remove_rrset(
         &self,
         zone: &LowerName,
         domain: &LowerName,
+        rtype: RecordType,
     ) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
-        let encoded_records = self
+        // Reading and removing the field at once returns exactly the records which were removed.
+        let encoded_records = match self
             .client
-            .hgetall::<HashMap<String, Vec<u8>>, _>(format!("resource:{}:{}", zone, domain))
+            .eval::<Option<Vec<u8>>, _, _, _>(
+                TAKE_FIELD_SCRIPT,
+                format!("resource:{}:{}", zone, domain),
+                rtype.to_string(),
+            )
+            .await?
+        {
+            Some(encoded_records) => encoded_records,
+            None => return Ok(Vec::new()),
+        };
+        self.commit_changes(vec![rrset_change(zone, domain, rtype)])
             .await?;
 
-        Ok(encoded_records
-            .into_values()
-            .filter_map::<Vec<_>, _>(|jv| serde_json::from_slice(&jv).ok())
-            .flatten()
-            .collect())
+        Ok(serde_json::from_slice(&encoded_records)?)
     }
 
-    async fn list_domains(
+    async fn remove_record(
         &self,
         zone: &LowerName,
-    ) -> Result<Vec<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
-        Ok(self
-            .client
-            .scan_cluster(
-                format!("resource:{}:*", zone),
-                Some(10),
-                Some(ScanType::Hash),
-            )
-            .filter_map(|scan_entry| async {
-                if let Ok(mut entry) = scan_entry {
-                    if let Some(results) = entry.take_results() {
-                        return Some(
-                            results
-                                .into_iter()
-                                .filter_map(|re| {
-                                    if let Some(raw_key) = re.as_str() {
-                                        if let Some(domain) = raw_key.split(':').nth(2) {
-                                            LowerName::from_str(domain).ok()
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect(),
-                        );
-                    }
-                }
-                None
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+        let removed = self
+            .update_rrset(zone, domain, record.record_type(), |record_set| {
+                record_set
+                    .iter()
+                    .position(|sr| sr.as_record().data() == record.data())
+                    .map(|idx| record_set.remove(idx))
             })
-            .collect::<Vec<Vec<_>>>()
-            .await
-            .into_iter()
+            .await?;
+        if removed.is_some() {
+            self.commit_changes(vec![rrset_change(zone, domain, record.record_type())])
+                .await?;
+        }
+        Ok(removed)
+    }
+
+    async fn list_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_records = self
+            .client
+            .hgetall::<HashMap<String, Vec<u8>>, _>(format!("resource:{}:{}", zone, domain))
+            .await?;
+
+        Ok(encoded_records
+            .into_values()
+            .filter_map::<Vec<_>, _>(|jv| serde_json::from_slice(&jv).ok())
             .flatten()
             .collect())
     }
+
+    async fn list_records_bulk(
+        &self,
+        zone: &LowerName,
+        domains: &[LowerName],
+    ) -> Result<Vec<Vec<StorageRecord>>, Box<dyn std::error::Error + Send + Sync>> {
+        let keys = domains
+            .iter()
+            .map(|domain| format!("resource:{}:{}", zone, domain))
+            .collect::<Vec<_>>();
+
+        Ok(self
+            .hgetall_many(&keys)
+            .await?
+            .into_iter()
+            .map(|encoded_records| {
+                encoded_records
+                    .into_values()
+                    .filter_map::<Vec<StorageRecord>, _>(|jv| serde_json::from_slice(&jv).ok())
+                    .flatten()
+                    .collect()
+            })
+            .collect())
+    }
+
+    async fn list_domains(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
+        let pattern = format!("resource:{}:*", escape_pattern(&zone.to_string()));
+        let keys = self.scan_all(&pattern, "hash").await?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| key.split(':').nth(2))
+            .filter_map(|domain| LowerName::from_str(domain).ok())
+            .collect())
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn std::error::Error + Send + Sync>> {
+        let pattern = format!("resource:{}:*", escape_pattern(&zone.to_string()));
+        let (keys, next_cursor) = self.scan_page(&pattern, "hash", cursor, limit).await?;
+        let items = keys
+            .iter()
+            .filter_map(|key| key.split(':').nth(2))
+            .filter_map(|domain| LowerName::from_str(domain).ok())
+            .collect();
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut keys = Vec::new();
+        let mut scan = self.scan_keys(
+            format!("resource:{}:*", escape_pattern(&zone.to_string())),
+            100,
+            ScanType::Hash,
+        );
+        while let Some(entry) = scan.next().await {
+            let mut entry = entry?;
+            if let Some(results) = entry.take_results() {
+                keys.extend(results.into_iter().filter_map(|key| key.into_string()));
+            }
+            entry.next()?;
+        }
+        for key in [format!("journal:{}", zone), dnssec_keys_key(zone)] {
+            if self.client.exists::<u64, _>(key.as_str()).await? > 0 {
+                keys.push(key);
+            }
+        }
+        // The marker goes last, so the zone stays around to retry if removing any of the other
+        // keys fails.
+        keys.push(format!("zone:{}", zone));
+
+        if dry_run {
+            return Ok(keys.len());
+        }
+
+        // Keys of a zone are spread over the cluster, and a single command can only touch keys in
+        // the same hash slot.
+        let marker = keys.pop();
+        let mut slots: HashMap<u16, Vec<String>> = HashMap::new();
+        for key in keys {
+            slots.entry(redis_keyslot(&key)).or_default().push(key);
+        }
+        let total = slots.values().map(Vec::len).sum::<usize>() + 1;
+        let mut removed = 0;
+        for slot_keys in slots.into_values() {
+            for batch in slot_keys.chunks(DELETE_BATCH_SIZE) {
+                removed += self.client.unlink::<usize, _>(batch.to_vec()).await?;
+            }
+            trace!("Removed {} of {} keys of zone {}", removed, total, zone);
+        }
+        // The indexes are not counted, like in the other backends, which have none or keep them
+        // along with the records.
+        for key in [names_key(zone), cuts_key(zone), index_marker_key(zone)] {
+            self.client.unlink::<usize, _>(key).await?;
+        }
+        self.indexed_zones.write().unwrap().remove(zone);
+        if let Some(marker) = marker {
+            removed += self.client.unlink::<usize, _>(marker).await?;
+        }
+        self.publish_changes(vec![StorageChange::Zone { zone: zone.clone() }])
+            .await;
+
+        Ok(removed)
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn std::error::Error + Send + Sync>> {
+        // The policy is stored as the value of the zone marker. Zones created before policies
+        // existed have an empty marker, which means the default policy.
+        let encoded_policy = self
+            .client
+            .get::<Option<String>, _>(format!("zone:{}", zone))
+            .await?;
+
+        match encoded_policy {
+            Some(encoded_policy) if !encoded_policy.is_empty() => {
+                Ok(serde_json::from_str(&encoded_policy)?)
+            }
+            _ => Ok(ZonePolicy::default()),
+        }
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_policy = serde_json::to_string(policy)?;
+        // Only overwrite an existing marker, this must never create a zone.
+        self.client
+            .set::<(), _, _>(
+                format!("zone:{}", zone),
+                encoded_policy,
+                None,
+                Some(SetOptions::XX),
+                false,
+            )
+            .await?;
+        self.publish_changes(vec![StorageChange::Zone { zone: zone.clone() }])
+            .await;
+        Ok(())
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let key = format!("journal:{}", zone);
+        let encoded_change = serde_json::to_string(change)?;
+        self.client.rpush::<(), _, _>(&key, encoded_change).await?;
+        // Only keep the most recent changes.
+        Ok(self.client.ltrim(&key, -MAX_JOURNAL_LENGTH, -1).await?)
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_changes = self
+            .client
+            .lrange::<Vec<String>, _>(format!("journal:{}", zone), 0, -1)
+            .await?;
+        let changes = encoded_changes
+            .iter()
+            .map(|encoded_change| serde_json::from_str::<ZoneChange>(encoded_change))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ZoneChange::since(changes, serial))
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        self.ensure_indexes(zone).await?;
+        let key = name_index_key(domain);
+        let end = name_index_end(&key);
+        Ok(self
+            .client
+            .eval::<u64, _, _, _>(HAS_NAMES_BELOW_SCRIPT, names_key(zone), vec![key, end])
+            .await?
+            > 0)
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn std::error::Error + Send + Sync>> {
+        self.ensure_indexes(zone).await?;
+        let neighbours = self
+            .client
+            .eval::<Vec<String>, _, _, _>(
+                NAMES_AROUND_SCRIPT,
+                names_key(zone),
+                vec![name_index_key(name)],
+            )
+            .await?;
+        Ok(closest_encloser_from_keys(zone, name, &neighbours))
+    }
+
+    async fn zone_cuts(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZoneCuts, Box<dyn std::error::Error + Send + Sync>> {
+        self.ensure_indexes(zone).await?;
+        let mut cuts = ZoneCuts::default();
+        for entry in self
+            .client
+            .smembers::<Vec<String>, _>(cuts_key(zone))
+            .await?
+        {
+            let kind = entry
+                .rsplit_once('/')
+                .and_then(|(domain, kind)| Some((domain, CutKind::from_name(kind)?)));
+            match kind {
+                Some((domain, kind)) => cuts.insert(kind, LowerName::from_str(domain)?),
+                None => return Err(format!("{} is not a zone cut", entry).into()),
+            }
+        }
+        Ok(cuts)
+    }
+
+    async fn apply(
+        &self,
+        ops: Vec<StorageOp>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut keys: HashMap<String, Fields> = HashMap::new();
+        let mut zones = Vec::new();
+        // Removed zones are announced by delete_zone itself.
+        let mut changes = Vec::new();
+        // SOAs are swapped right away, before any other change of the batch is written, and
+        // restored if a later change fails.
+        let mut swapped = Vec::new();
+        let result = async {
+            for op in ops {
+                match op {
+                    StorageOp::AddZone { zone } => {
+                        zones.push(zone.clone());
+                        changes.push(StorageChange::Zone { zone });
+                    }
+                    StorageOp::SetRecords {
+                        zone,
+                        domain,
+                        rtype,
+                        records,
+                    } => {
+                        keys.entry(format!("resource:{}:{}", zone, domain))
+                            .or_default()
+                            .push((rtype.to_string(), encode_rrset(&records)?));
+                        changes.push(rrset_change(&zone, &domain, rtype));
+                    }
+                    StorageOp::RemoveRrset {
+                        zone,
+                        domain,
+                        rtype,
+                    } => {
+                        keys.entry(format!("resource:{}:{}", zone, domain))
+                            .or_default()
+                            .push((rtype.to_string(), Vec::new()));
+                        changes.push(rrset_change(&zone, &domain, rtype));
+                    }
+                    StorageOp::DeleteZone { zone } => {
+                        // Changes before the removal must not be applied after it.
+                        self.write_batch(std::mem::take(&mut keys), std::mem::take(&mut zones))
+                            .await?;
+                        self.delete_zone(&zone, false).await?;
+                    }
+                    StorageOp::SwapSoa { zone, serial, soa } => {
+                        swapped.push(self.swap_soa(&zone, serial, *soa).await?);
+                        changes.push(rrset_change(&zone, &zone, RecordType::SOA));
+                    }
+                }
+            }
+            self.write_batch(keys, zones).await
+        }
+        .await;
+        if let Err(e) = result {
+            self.restore_fields(swapped).await;
+            return Err(e);
+        }
+        self.commit_changes(changes).await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_tokens = self
+            .client
+            .hgetall::<HashMap<String, Vec<u8>>, _>(API_TOKENS_KEY)
+            .await?;
+        let usage = self
+            .client
+            .hgetall::<HashMap<String, u64>, _>(API_TOKEN_USAGE_KEY)
+            .await?;
+
+        let mut tokens = Vec::with_capacity(encoded_tokens.len());
+        for (id, encoded_token) in encoded_tokens {
+            let mut token: ApiToken = match serde_json::from_slice(&encoded_token) {
+                Ok(token) => token,
+                Err(e) => {
+                    error!("Ignoring invalid API token {}: {}", id, e);
+                    continue;
+                }
+            };
+            token.last_used = usage.get(&id).copied();
+            tokens.push(token);
+        }
+
+        Ok(tokens)
+    }
+
+    async fn set_token(
+        &self,
+        token: &ApiToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_token = serde_json::to_vec(token)?;
+        Ok(self
+            .client
+            .hset::<_, _, (&str, &[u8])>(API_TOKENS_KEY, (token.id.as_str(), &encoded_token))
+            .await?)
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .client
+            .hset::<_, _, (&str, u64)>(API_TOKEN_USAGE_KEY, (id, last_used))
+            .await?)
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_keys = self
+            .client
+            .hgetall::<HashMap<String, Vec<u8>>, _>(dnssec_keys_key(zone))
+            .await?;
+
+        let mut keys = Vec::with_capacity(encoded_keys.len());
+        for (id, encoded_key) in encoded_keys {
+            match serde_json::from_slice(&encoded_key) {
+                Ok(key) => keys.push(key),
+                Err(e) => error!("Ignoring invalid DNSSEC key {} of zone {}: {}", id, zone, e),
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_key = serde_json::to_vec(key)?;
+        Ok(self
+            .client
+            .hset::<_, _, (&str, &[u8])>(dnssec_keys_key(zone), (key.id.as_str(), &encoded_key))
+            .await?)
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .client
+            .hdel::<u64, _, _>(dnssec_keys_key(zone), id)
+            .await?
+            > 0)
+    }
+
+    fn watch_zones(&self, reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        let changes = self.subscribe_changes()?;
+        Some(Box::pin(watch_zones(changes, reload)))
+    }
+
+    fn subscribe_changes(&self) -> Option<broadcast::Receiver<StorageChange>> {
+        self.changes.as_ref().map(broadcast::Sender::subscribe)
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for RedisClusterClient {
+    async fn append_audit(
+        &self,
+        entry: &AuditEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let day = entry.timestamp / SECONDS_PER_DAY;
+        let key = audit_key(day);
+        self.client
+            .rpush::<(), _, _>(&key, serde_json::to_string(entry)?)
+            .await?;
+        let expire_at = (day + 1) * SECONDS_PER_DAY + AUDIT_RETENTION.as_secs();
+        Ok(self
+            .client
+            .expire::<(), _>(&key, expire_at.saturating_sub(entry.timestamp) as i64)
+            .await?)
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        // The cursor is the day and the index in the list of that day to continue from.
+        let (mut day, mut index) = match cursor {
+            Some(cursor) => {
+                let (day, index) = cursor.split_once(':').ok_or(InvalidCursor)?;
+                (
+                    day.parse::<u64>().map_err(|_| InvalidCursor)?,
+                    index.parse::<i64>().map_err(|_| InvalidCursor)?,
+                )
+            }
+            None => (since / SECONDS_PER_DAY, 0),
+        };
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time is after the unix epoch")
+            .as_secs()
+            / SECONDS_PER_DAY;
+        // Days before the retention are expired, no need to look for them.
+        let oldest = today.saturating_sub(AUDIT_RETENTION.as_secs() / SECONDS_PER_DAY + 1);
+        if day < oldest {
+            (day, index) = (oldest, 0);
+        }
+
+        let mut items = Vec::new();
+        while day <= today {
+            let batch = self
+                .client
+                .lrange::<Vec<String>, _>(audit_key(day), index, index + AUDIT_BATCH_SIZE - 1)
+                .await?;
+            for (offset, encoded_entry) in batch.iter().enumerate() {
+                let entry = match serde_json::from_str::<AuditEntry>(encoded_entry) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        error!("Ignoring invalid audit log entry: {}", e);
+                        continue;
+                    }
+                };
+                if entry.timestamp >= since
+                    && zone.is_none_or(|zone| entry.zone.as_deref() == Some(zone))
+                {
+                    items.push(entry);
+                }
+                if items.len() == limit {
+                    return Ok(Page {
+                        items,
+                        next_cursor: Some(format!("{}:{}", day, index + offset as i64 + 1)),
+                    });
+                }
+            }
+            if (batch.len() as i64) < AUDIT_BATCH_SIZE {
+                day += 1;
+                index = 0;
+            } else {
+                index += AUDIT_BATCH_SIZE;
+            }
+        }
+
+        Ok(Page {
+            items,
+            next_cursor: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for RedisClusterClient {
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded_record = serde_json::to_string(record)?;
+        loop {
+            let claimed = self
+                .client
+                .set::<Option<String>, _, _>(
+                    idempotency_key(key),
+                    encoded_record.as_str(),
+                    Some(Expiration::EX(ttl.as_secs() as i64)),
+                    Some(SetOptions::NX),
+                    false,
+                )
+                .await?;
+            if claimed.is_some() {
+                return Ok(IdempotencyClaim::Claimed);
+            }
+            // The key can expire between the 2 commands, in which case it can be claimed again.
+            if let Some(existing) = self
+                .client
+                .get::<Option<String>, _>(idempotency_key(key))
+                .await?
+            {
+                return Ok(IdempotencyClaim::Existing(serde_json::from_str(&existing)?));
+            }
+        }
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .client
+            .set(
+                idempotency_key(key),
+                serde_json::to_string(record)?,
+                Some(Expiration::EX(ttl.as_secs() as i64)),
+                None,
+                false,
+            )
+            .await?)
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        key: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.client.del(idempotency_key(key)).await?)
+    }
+}
+
+/// List holding the audit log entries of a day, counted in days since the unix epoch.
+/// Encode the records of an RRset as the value of its field, where an empty value removes the
+/// field.
+/// A change as announced on the changes channel.
+#[derive(Serialize, Deserialize)]
+struct ChangeMessage {
+    zone: String,
+    /// Domain and type of the changed RRset, absent if the zone itself changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rtype: Option<RecordType>,
+}
+
+impl ChangeMessage {
+    /// The message announcing a change. Missed changes are local to a subscriber and never
+    /// announced.
+    fn from_change(change: &StorageChange) -> Option<Self> {
+        match change {
+            StorageChange::Zone { zone } => Some(ChangeMessage {
+                zone: zone.to_string(),
+                domain: None,
+                rtype: None,
+            }),
+            StorageChange::Rrset {
+                zone,
+                domain,
+                rtype,
+            } => Some(ChangeMessage {
+                zone: zone.to_string(),
+                domain: Some(domain.to_string()),
+                rtype: Some(*rtype),
+            }),
+            StorageChange::Missed => None,
+        }
+    }
+
+    fn into_change(self) -> Result<StorageChange, Box<dyn Error + Send + Sync>> {
+        let zone = LowerName::from_str(&self.zone)?;
+        Ok(match (self.domain, self.rtype) {
+            (Some(domain), Some(rtype)) => StorageChange::Rrset {
+                zone,
+                domain: LowerName::from_str(&domain)?,
+                rtype,
+            },
+            _ => StorageChange::Zone { zone },
+        })
+    }
+}
+
+fn rrset_change(zone: &LowerName, domain: &LowerName, rtype: RecordType) -> StorageChange {
+    StorageChange::Rrset {
+        zone: zone.clone(),
+        domain: domain.clone(),
+        rtype,
+    }
+}
+
+/// Follow the changes announced by all instances on a dedicated connection, and pass them on to
+/// `changes`. Subscriptions don't survive a reconnect, so the channel is subscribed to again
+/// after every reconnect, and subscribers are told they missed the changes made in between.
+async fn follow_changes(config: RedisConfig, changes: broadcast::Sender<StorageChange>) {
+    let client = RedisClient::new(config);
+    let mut messages = client.on_message();
+    let mut reconnects = client.on_reconnect();
+    // Keep retrying forever, a lost subscription would silently leave caches stale.
+    let _conn_task = client.connect(Some(ReconnectPolicy::new_constant(0, 1_000)));
+    if let Err(e) = client.wait_for_connect().await {
+        warn!(
+            "Failed to connect to Redis to follow storage changes: {}",
+            e
+        );
+    }
+    subscribe_changes(&client).await;
+
+    loop {
+        tokio::select! {
+            reconnect = reconnects.next() => {
+                if reconnect.is_none() {
+                    warn!("Stopped following storage changes, caches are only refreshed on expiry");
+                    return;
+                }
+                subscribe_changes(&client).await;
+                // An error means there are no subscribers right now, which is fine.
+                let _ = changes.send(StorageChange::Missed);
+            }
+            message = messages.next() => {
+                let (channel, message) = match message {
+                    Some(message) => message,
+                    None => {
+                        warn!("Stopped following storage changes, caches are only refreshed on expiry");
+                        return;
+                    }
+                };
+                if channel != CHANGES_CHANNEL {
+                    continue;
+                }
+                let received = match message
+                    .as_str()
+                    .map(|message| serde_json::from_str::<Vec<ChangeMessage>>(&message))
+                {
+                    Some(Ok(received)) => received,
+                    _ => {
+                        warn!("Ignoring malformed message on {}", CHANGES_CHANNEL);
+                        continue;
+                    }
+                };
+                for message in received {
+                    match message.into_change() {
+                        Ok(change) => {
+                            let _ = changes.send(change);
+                        }
+                        Err(e) => warn!("Ignoring malformed change on {}: {}", CHANGES_CHANNEL, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Subscribe to the changes channel, retrying until it succeeds.
+async fn subscribe_changes(client: &RedisClient) {
+    loop {
+        match client.subscribe(CHANGES_CHANNEL).await {
+            Ok(_) => {
+                debug!("Subscribed to {}", CHANGES_CHANNEL);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to subscribe to {}: {}", CHANGES_CHANNEL, e);
+                tokio::time::sleep(SUBSCRIBE_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Request a reload of the zone of every change which affects the zone cache. Missed changes could
+/// have affected any zone.
+async fn watch_zones(mut changes: broadcast::Receiver<StorageChange>, reload: Arc<ZoneReload>) {
+    loop {
+        match changes.recv().await {
+            Ok(change) if change.affects_zone_cache() => match change {
+                StorageChange::Zone { zone } | StorageChange::Rrset { zone, .. } => {
+                    reload.zone(&zone)
+                }
+                StorageChange::Missed => reload.all(),
+            },
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(_)) => reload.all(),
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// The server config for a deployment mode. A cluster is discovered from all given nodes, a
+/// standalone server is the first address.
+fn server_config(mode: RedisMode, addrs: &[SocketAddr]) -> ServerConfig {
+    match mode {
+        RedisMode::Cluster => ServerConfig::Clustered {
+            hosts: addrs
+                .iter()
+                .map(|sa| (sa.ip().to_string(), sa.port()))
+                .collect(),
+        },
+        RedisMode::Standalone => {
+            let addr = addrs
+                .first()
+                .expect("A standalone Redis server needs an address");
+            if addrs.len() > 1 {
+                warn!(
+                    "Using only {} of {} Redis addresses in standalone mode",
+                    addr,
+                    addrs.len()
+                );
+            }
+            ServerConfig::Centralized {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+            }
+        }
+    }
+}
+
+fn encode_rrset(records: &[StorageRecord]) -> Result<Vec<u8>, serde_json::Error> {
+    if records.is_empty() {
+        Ok(Vec::new())
+    } else {
+        serde_json::to_vec(records)
+    }
+}
+
+fn audit_key(day: u64) -> String {
+    format!("audit:{}", day)
+}
+
+/// String holding the state of an idempotency key.
+fn idempotency_key(key: &str) -> String {
+    format!("idempotency:{}", key)
+}
+
+/// Hash holding the DNSSEC keys of a zone, keyed by key id.
+fn dnssec_keys_key(zone: &LowerName) -> String {
+    format!("dnssec_keys:{}", zone)
+}
+
+/// Sorted set holding the index of names of a zone, see [`name_index_key`].
+fn names_key(zone: &LowerName) -> String {
+    format!("names:{}", zone)
+}
+
+/// Set holding the index of zone cuts of a zone, as `{domain}/{kind}` entries.
+fn cuts_key(zone: &LowerName) -> String {
+    format!("cuts:{}", zone)
+}
+
+/// String which exists once the indexes of names and zone cuts of a zone are complete.
+fn index_marker_key(zone: &LowerName) -> String {
+    format!("indexed:{}", zone)
+}
+
+/// Escape the characters with a special meaning in a redis key pattern.
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fred::types::ServerConfig;
+
+    use super::{escape_pattern, server_config, RedisClusterClient};
+    use crate::{
+        config::{RedisConnectionConfig, RedisMode},
+        storage::tests::conformance,
+    };
+
+    #[test]
+    fn cluster_server_config() {
+        let addrs = [
+            "10.0.0.1:7000".parse().unwrap(),
+            "[fd00::2]:7001".parse().unwrap(),
+        ];
+        match server_config(RedisMode::Cluster, &addrs) {
+            ServerConfig::Clustered { hosts } => assert_eq!(
+                hosts,
+                vec![
+                    ("10.0.0.1".to_string(), 7000),
+                    ("fd00::2".to_string(), 7001)
+                ]
+            ),
+            _ => panic!("A cluster is clustered"),
+        }
+    }
+
+    #[test]
+    fn standalone_server_config() {
+        let addrs = [
+            "10.0.0.1:6379".parse().unwrap(),
+            "10.0.0.2:6380".parse().unwrap(),
+        ];
+        for addrs in [&addrs[..1], &addrs[..]] {
+            match server_config(RedisMode::Standalone, addrs) {
+                ServerConfig::Centralized { host, port } => {
+                    assert_eq!((host.as_str(), port), ("10.0.0.1", 6379))
+                }
+                _ => panic!("A standalone server is centralized"),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "A standalone Redis server needs an address")]
+    fn standalone_server_needs_an_address() {
+        server_config(RedisMode::Standalone, &[]);
+    }
+
+    #[test]
+    fn escaped_patterns() {
+        assert_eq!(escape_pattern("example.com."), "example.com.");
+        assert_eq!(escape_pattern("*.example.com."), "\\*.example.com.");
+        assert_eq!(escape_pattern("a?[b]\\c"), "a\\?\\[b\\]\\\\c");
+    }
+
+    /// Runs against the standalone Redis server at the address in `CETUS_TEST_REDIS`, e.g.
+    /// `127.0.0.1:6379`, and is skipped if it is not set.
+    #[tokio::test]
+    async fn storage_conformance() {
+        let address = match std::env::var("CETUS_TEST_REDIS") {
+            Ok(address) => address,
+            Err(_) => {
+                eprintln!("CETUS_TEST_REDIS is not set, skipping");
+                return;
+            }
+        };
+        let storage = RedisClusterClient::new(&RedisConnectionConfig {
+            mode: RedisMode::Standalone,
+            node_addresses: vec![address.parse().expect("Valid Redis address")],
+            change_events: false,
+            ..Default::default()
+        });
+        storage
+            .test_with_backoff(Some(Duration::from_secs(10)))
+            .await
+            .expect("Redis is reachable");
+        conformance(&storage).await;
+    }
 }
@@ -0,0 +1,58 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use tokio::sync::Notify;
+use trust_dns_server::client::rr::LowerName;
+
+/// Zones to reload into the zone cache of the DNS server.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reload {
+    /// Reload every zone, and pick up zones which were added or removed.
+    All,
+    /// Reload only these zones, keeping the cached data of the others. Zones which don't exist
+    /// anymore are removed from the cache.
+    Zones(HashSet<LowerName>),
+}
+
+/// Requests to reload zones into the zone cache, collected until the zone loader gets to them.
+/// Requests for single zones are merged, a request for all zones overrides them.
+pub struct ZoneReload {
+    pending: Mutex<Reload>,
+    requested: Notify,
+}
+
+impl ZoneReload {
+    pub fn new() -> Self {
+        ZoneReload {
+            pending: Mutex::new(Reload::Zones(HashSet::new())),
+            requested: Notify::new(),
+        }
+    }
+
+    /// Request a reload of a single zone, because it was added or removed, or its policy, SOA or
+    /// zone cuts changed.
+    pub fn zone(&self, zone: &LowerName) {
+        if let Reload::Zones(zones) = &mut *self.pending.lock().unwrap() {
+            zones.insert(zone.clone());
+        }
+        self.requested.notify_one();
+    }
+
+    /// Request a reload of all zones, e.g. because changes could have been missed.
+    pub fn all(&self) {
+        *self.pending.lock().unwrap() = Reload::All;
+        self.requested.notify_one();
+    }
+
+    /// Wait until a reload is requested. A request made while nobody waits is not lost.
+    pub async fn requested(&self) {
+        self.requested.notified().await
+    }
+
+    /// Take the pending requests, leaving none.
+    pub fn take(&self) -> Reload {
+        std::mem::replace(
+            &mut *self.pending.lock().unwrap(),
+            Reload::Zones(HashSet::new()),
+        )
+    }
+}
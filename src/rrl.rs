@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use trust_dns_proto::rr::Record;
+use trust_dns_server::{
+    authority::{MessageResponse, MessageResponseBuilder},
+    client::{
+        op::{LowerQuery, ResponseCode},
+        rr::LowerName,
+    },
+    server::{ResponseHandler, ResponseInfo},
+};
+
+use crate::{config::RateLimitConfig, metrics::Metrics, prefix::IpPrefix};
+
+/// Interval at which accounts which are no longer active are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Response Rate Limiting. Responses are accounted per client network, per name and per kind of
+/// response. Once the rate of an account goes over the limit, further responses are dropped,
+/// except every slip-th response which is sent truncated, so legitimate clients can retry over
+/// TCP.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    window: Duration,
+    start: Instant,
+    accounts: Mutex<HashMap<AccountKey, Account>>,
+}
+
+/// What happens with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Send,
+    Drop,
+    Slip,
+}
+
+/// Responses are accounted together if they go to the same client network, have the same kind
+/// and the same name.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct AccountKey {
+    client: IpPrefix,
+    kind: ResponseKind,
+    name: Option<LowerName>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ResponseKind {
+    Answer,
+    NxDomain,
+    Error,
+}
+
+/// Response counts of the current and previous window of an account. The rate over a sliding
+/// window is estimated from these.
+struct Account {
+    window: u64,
+    current: u64,
+    previous: u64,
+    limited: u64,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter without any accounts.
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            window: Duration::from_secs(config.window_secs.max(1) as u64),
+            config,
+            start: Instant::now(),
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Account a response to a client, and decide what to do with it. NXDOMAIN responses are
+    /// accounted per zone rather than per name, as random names are the common way to get them.
+    fn check(
+        &self,
+        client: IpAddr,
+        code: ResponseCode,
+        qname: &LowerName,
+        zone: Option<&LowerName>,
+    ) -> Action {
+        let prefix_len = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix_len,
+            IpAddr::V6(_) => self.config.ipv6_prefix_len,
+        };
+        let (kind, name) = match code {
+            ResponseCode::NoError => (ResponseKind::Answer, Some(qname.clone())),
+            ResponseCode::NXDomain => (ResponseKind::NxDomain, zone.cloned()),
+            _ => (ResponseKind::Error, None),
+        };
+        let key = AccountKey {
+            client: IpPrefix::network(client, prefix_len),
+            kind,
+            name,
+        };
+
+        let elapsed = self.start.elapsed();
+        let window = elapsed.as_secs() / self.window.as_secs();
+        // Fraction of the current window which has passed.
+        let progress =
+            (elapsed.as_secs_f64() % self.window.as_secs_f64()) / self.window.as_secs_f64();
+        let limit = self.config.responses_per_second as f64 * self.window.as_secs_f64();
+
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(key).or_insert(Account {
+            window,
+            current: 0,
+            previous: 0,
+            limited: 0,
+        });
+        if account.window != window {
+            account.previous = if account.window + 1 == window {
+                account.current
+            } else {
+                0
+            };
+            account.current = 0;
+            account.window = window;
+        }
+
+        let rate = account.previous as f64 * (1.0 - progress) + account.current as f64;
+        account.current += 1;
+        if rate < limit {
+            return Action::Send;
+        }
+
+        account.limited += 1;
+        if self.config.slip != 0 && account.limited.is_multiple_of(self.config.slip as u64) {
+            Action::Slip
+        } else {
+            Action::Drop
+        }
+    }
+
+    /// Periodically remove accounts which did not see any response in the last window.
+    pub async fn prune_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let window = self.start.elapsed().as_secs() / self.window.as_secs();
+            let mut accounts = self.accounts.lock().unwrap();
+            let before = accounts.len();
+            accounts.retain(|_, account| account.window + 1 >= window);
+            debug!(
+                "Pruned {} rate limit accounts, {} left",
+                before - accounts.len(),
+                accounts.len()
+            );
+        }
+    }
+}
+
+/// A [`ResponseHandler`] which applies a [`RateLimiter`] to the responses of a query. Without a
+/// limiter, responses are passed through as is.
+#[derive(Clone)]
+pub struct RateLimitedResponseHandle<R> {
+    inner: R,
+    limiter: Option<Arc<RateLimiter>>,
+    metrics: Metrics,
+    client: IpAddr,
+    query: LowerQuery,
+    zone: Option<LowerName>,
+}
+
+impl<R> RateLimitedResponseHandle<R> {
+    pub fn new(
+        inner: R,
+        limiter: Option<Arc<RateLimiter>>,
+        metrics: Metrics,
+        client: IpAddr,
+        query: LowerQuery,
+        zone: Option<LowerName>,
+    ) -> Self {
+        RateLimitedResponseHandle {
+            inner,
+            limiter,
+            metrics,
+            client,
+            query,
+            zone,
+        }
+    }
+
+    fn increment_metric(&self, action: &str) {
+        match self.zone {
+            Some(ref zone) => self.metrics.increment_zone_rate_limited(zone, action),
+            None => self.metrics.increment_unknown_zone_rate_limited(action),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> ResponseHandler for RateLimitedResponseHandle<R>
+where
+    R: ResponseHandler,
+{
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let limiter = match self.limiter {
+            Some(ref limiter) => limiter,
+            None => return self.inner.send_response(response).await,
+        };
+
+        let header = *response.header();
+        match limiter.check(
+            self.client,
+            header.response_code(),
+            self.query.name(),
+            self.zone.as_ref(),
+        ) {
+            Action::Send => self.inner.send_response(response).await,
+            Action::Drop => {
+                self.increment_metric("dropped");
+                Ok(ResponseInfo::from(header))
+            }
+            Action::Slip => {
+                self.increment_metric("slipped");
+                let mut header = header;
+                header.set_truncated(true);
+                let query = self.query.clone();
+                let msg = MessageResponseBuilder::new(Some(&query)).build_no_records(header);
+                self.inner.send_response(msg).await
+            }
+        }
+    }
+}
@@ -0,0 +1,1134 @@
+use std::{
+    error::Error,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use rusqlite::{params, Connection, OptionalExtension};
+use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use crate::{
+    audit::{AuditEntry, AuditSink},
+    dname::DNAME,
+    idempotency::{IdempotencyClaim, IdempotencyRecord, IdempotencyStore},
+    policy::ZonePolicy,
+    storage::{
+        closest_encloser_from_keys, name_index_end, name_index_key, ApiToken, CutKind, DnssecKey,
+        InvalidCursor, Page, SerialChanged, Storage, StorageOp, StorageRecord, ZoneChange,
+        ZoneCuts,
+    },
+};
+
+/// Maximum amount of changes kept in the journal of a zone.
+const MAX_JOURNAL_LENGTH: usize = 1_000;
+/// Maximum amount of entries kept in the audit log. Older entries are removed.
+const MAX_AUDIT_LENGTH: i64 = 100_000;
+/// Time a write waits for another connection to release its lock on the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A step filling new columns from the existing data, in the transaction of its migration.
+type Backfill = fn(&Connection) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+/// A change to the database schema, with an optional step to fill new columns from existing data.
+struct Migration {
+    schema: &'static str,
+    backfill: Option<Backfill>,
+}
+
+/// Migrations of the database schema, applied in order. The amount of migrations which are
+/// applied is kept as the `user_version` of the database, so only new ones are applied on startup.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        schema: r#"
+CREATE TABLE zones (
+    name TEXT PRIMARY KEY NOT NULL,
+    -- JSON encoded policy, NULL for the default policy.
+    policy TEXT
+);
+
+-- All records of a type of a domain, as a JSON array, like a field of a domain hash in Redis.
+CREATE TABLE rrsets (
+    zone TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    rtype TEXT NOT NULL,
+    records TEXT NOT NULL,
+    PRIMARY KEY (zone, domain, rtype)
+);
+
+CREATE TABLE journal (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    zone TEXT NOT NULL,
+    change TEXT NOT NULL
+);
+CREATE INDEX journal_zone ON journal (zone, id);
+
+CREATE TABLE dnssec_keys (
+    zone TEXT NOT NULL,
+    id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    PRIMARY KEY (zone, id)
+);
+
+CREATE TABLE tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    token TEXT NOT NULL,
+    last_used INTEGER
+);
+
+CREATE TABLE audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    zone TEXT,
+    entry TEXT NOT NULL
+);
+
+CREATE TABLE idempotency (
+    key TEXT PRIMARY KEY NOT NULL,
+    record TEXT NOT NULL,
+    -- Time the key expires, in milliseconds since the unix epoch.
+    expires INTEGER NOT NULL
+);
+"#,
+        backfill: None,
+    },
+    // The index of names in a zone, to find the names below a domain without a table scan.
+    Migration {
+        schema: r#"
+ALTER TABLE rrsets ADD COLUMN name_key TEXT;
+CREATE INDEX rrsets_name_key ON rrsets (zone, name_key);
+"#,
+        backfill: Some(backfill_name_keys),
+    },
+    // The RRsets of a type in a zone, to find the zone cuts without a table scan.
+    Migration {
+        schema: "CREATE INDEX rrsets_rtype ON rrsets (zone, rtype);",
+        backfill: None,
+    },
+];
+
+/// An implementation of storage in a single SQLite database, for deployments with a single
+/// instance. Queries are blocking, so they run on the blocking thread pool of the runtime.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open the database at the given path, creating it if it does not exist yet, and bring its
+    /// schema up to date.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn)?;
+
+        Ok(SqliteStorage {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run a function with the connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+    }
+}
+
+/// Apply the migrations which are not applied yet, each in its own transaction.
+fn migrate(conn: &mut Connection) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let applied = conn.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0))?;
+    if applied > MIGRATIONS.len() {
+        return Err(format!(
+            "Database schema version {} is newer than the supported version {}",
+            applied,
+            MIGRATIONS.len()
+        )
+        .into());
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        debug!("Applying database migration {}", version + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.schema)?;
+        if let Some(backfill) = migration.backfill {
+            backfill(&tx)?;
+        }
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Set the index key of the domain of every RRset.
+fn backfill_name_keys(conn: &Connection) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let domains = conn
+        .prepare("SELECT DISTINCT domain FROM rrsets")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = conn.prepare("UPDATE rrsets SET name_key = ?1 WHERE domain = ?2")?;
+    for domain in domains {
+        update.execute(params![name_key(&domain)?, domain])?;
+    }
+    Ok(())
+}
+
+/// Get the key of a domain in the index of names, see [`name_index_key`].
+fn name_key(domain: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(name_index_key(&LowerName::from_str(domain)?))
+}
+
+/// Read the records of an RRset, or [`Option::None`] if it does not exist.
+fn read_rrset(
+    conn: &Connection,
+    zone: &str,
+    domain: &str,
+    rtype: &str,
+) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+    let encoded_records = conn
+        .query_row(
+            "SELECT records FROM rrsets WHERE zone = ?1 AND domain = ?2 AND rtype = ?3",
+            params![zone, domain, rtype],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+
+    Ok(encoded_records
+        .map(|encoded_records| serde_json::from_str(&encoded_records))
+        .transpose()?)
+}
+
+/// Replace the records of an RRset, removing it if there are no records. Returns whether the RRset
+/// existed.
+fn write_rrset(
+    conn: &Connection,
+    zone: &str,
+    domain: &str,
+    rtype: &str,
+    records: &[StorageRecord],
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if records.is_empty() {
+        let removed = conn.execute(
+            "DELETE FROM rrsets WHERE zone = ?1 AND domain = ?2 AND rtype = ?3",
+            params![zone, domain, rtype],
+        )?;
+        return Ok(removed > 0);
+    }
+
+    let existed = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM rrsets WHERE zone = ?1 AND domain = ?2 AND rtype = ?3)",
+        params![zone, domain, rtype],
+        |row| row.get::<_, bool>(0),
+    )?;
+    conn.execute(
+        "INSERT OR REPLACE INTO rrsets (zone, domain, rtype, records, name_key)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            zone,
+            domain,
+            rtype,
+            serde_json::to_string(records)?,
+            name_key(domain)?
+        ],
+    )?;
+
+    Ok(existed)
+}
+
+/// Remove all data of a zone. Returns the amount of entries removed, counted the way the Redis
+/// backend counts keys: one per domain, the journal, the DNSSEC keys and the marker.
+fn remove_zone(
+    conn: &Connection,
+    zone: &str,
+    dry_run: bool,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let entries = conn.query_row(
+        "SELECT
+            (SELECT COUNT(DISTINCT domain) FROM rrsets WHERE zone = ?1)
+            + EXISTS(SELECT 1 FROM journal WHERE zone = ?1)
+            + EXISTS(SELECT 1 FROM dnssec_keys WHERE zone = ?1)
+            + EXISTS(SELECT 1 FROM zones WHERE name = ?1)",
+        params![zone],
+        |row| row.get::<_, usize>(0),
+    )?;
+    if !dry_run {
+        for table in ["rrsets", "journal", "dnssec_keys"] {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE zone = ?1", table),
+                params![zone],
+            )?;
+        }
+        conn.execute("DELETE FROM zones WHERE name = ?1", params![zone])?;
+    }
+
+    Ok(entries)
+}
+
+/// Get a page of about `limit` names of a query, after the cursor. The query must take the cursor
+/// and the maximum amount of rows as its last two parameters, and return the names in order.
+fn name_page(
+    conn: &Connection,
+    query: &str,
+    zone: Option<&str>,
+    cursor: Option<String>,
+    limit: usize,
+) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+    if let Some(cursor) = &cursor {
+        LowerName::from_str(cursor).map_err(|_| InvalidCursor)?;
+    }
+    let after = cursor.unwrap_or_default();
+
+    let mut stmt = conn.prepare(query)?;
+    let mut names = match zone {
+        Some(zone) => stmt
+            .query_map(params![zone, after, limit + 1], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?,
+        None => stmt
+            .query_map(params![after, limit + 1], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?,
+    };
+
+    let next_cursor = if names.len() > limit {
+        names.truncate(limit);
+        names.last().cloned()
+    } else {
+        None
+    };
+
+    Ok(Page {
+        items: names
+            .iter()
+            .map(|name| LowerName::from_str(name))
+            .collect::<Result<_, _>>()?,
+        next_cursor,
+    })
+}
+
+/// Current time in milliseconds since the unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.with_conn(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?))
+            .await
+    }
+
+    async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT name FROM zones")?;
+            let names = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(names
+                .iter()
+                .map(|name| LowerName::from_str(name))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM zones WHERE name = ?1)",
+                params![zone],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        let cursor = cursor.map(str::to_string);
+        self.with_conn(move |conn| {
+            name_page(
+                conn,
+                "SELECT name FROM zones WHERE name > ?1 ORDER BY name LIMIT ?2",
+                None,
+                cursor,
+                limit,
+            )
+        })
+        .await
+    }
+
+    async fn lookup_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        self.with_conn(move |conn| {
+            if let Some(records) = read_rrset(conn, &zone, &domain, &rtype.to_string())? {
+                return Ok(Some(records));
+            }
+            // A domain which exists but has no records of the type has an empty RRset.
+            let exists = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM rrsets WHERE zone = ?1 AND domain = ?2)",
+                params![zone, domain],
+                |row| row.get::<_, bool>(0),
+            )?;
+            Ok(exists.then(Vec::new))
+        })
+        .await
+    }
+
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT records FROM rrsets WHERE zone = ?1 AND domain = ?2")?;
+            let encoded_rrsets = stmt
+                .query_map(params![zone, domain], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if encoded_rrsets.is_empty() {
+                return Ok(None);
+            }
+
+            let mut records = Vec::new();
+            for encoded_records in encoded_rrsets {
+                records.extend(serde_json::from_str::<Vec<StorageRecord>>(
+                    &encoded_records,
+                )?);
+            }
+            Ok(Some(records))
+        })
+        .await
+    }
+
+    #[cfg(test)]
+    async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        // Like the other backends, this resets the marker, and so the policy, of an existing zone.
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO zones (name, policy) VALUES (?1, NULL)",
+                params![zone],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[cfg(test)]
+    async fn add_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: StorageRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        self.with_conn(move |conn| {
+            let rtype = record.record.record_type().to_string();
+            let tx = conn.transaction()?;
+            let mut records = read_rrset(&tx, &zone, &domain, &rtype)?.unwrap_or_default();
+            records.push(record);
+            write_rrset(&tx, &zone, &domain, &rtype, &records)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .lookup_all_records(domain, zone)
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn list_domains(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT DISTINCT domain FROM rrsets WHERE zone = ?1")?;
+            let names = stmt
+                .query_map(params![zone], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(names
+                .iter()
+                .map(|name| LowerName::from_str(name))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let cursor = cursor.map(str::to_string);
+        self.with_conn(move |conn| {
+            name_page(
+                conn,
+                "SELECT DISTINCT domain FROM rrsets WHERE zone = ?1 AND domain > ?2
+                ORDER BY domain LIMIT ?3",
+                Some(&zone),
+                cursor,
+                limit,
+            )
+        })
+        .await
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let encoded_policy = conn
+                .query_row(
+                    "SELECT policy FROM zones WHERE name = ?1",
+                    params![zone],
+                    |row| row.get::<_, Option<String>>(0),
+                )
+                .optional()?
+                .flatten();
+            match encoded_policy {
+                Some(encoded_policy) => Ok(serde_json::from_str(&encoded_policy)?),
+                None => Ok(ZonePolicy::default()),
+            }
+        })
+        .await
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let encoded_policy = serde_json::to_string(policy)?;
+        // This must never create a zone.
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE zones SET policy = ?2 WHERE name = ?1",
+                params![zone, encoded_policy],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let encoded_change = serde_json::to_string(change)?;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO journal (zone, change) VALUES (?1, ?2)",
+                params![zone, encoded_change],
+            )?;
+            tx.execute(
+                "DELETE FROM journal WHERE zone = ?1 AND id <= (
+                    SELECT id FROM journal WHERE zone = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+                )",
+                params![zone, MAX_JOURNAL_LENGTH],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT change FROM journal WHERE zone = ?1 ORDER BY id")?;
+            let encoded_changes = stmt
+                .query_map(params![zone], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let journal = encoded_changes
+                .iter()
+                .map(|encoded_change| serde_json::from_str::<ZoneChange>(encoded_change))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(ZoneChange::since(journal, serial))
+        })
+        .await
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for op in &ops {
+                if let StorageOp::SwapSoa { zone, serial, .. } = op {
+                    let zone = zone.to_string();
+                    let current = read_rrset(&tx, &zone, &zone, &RecordType::SOA.to_string())?
+                        .and_then(|soas| soas.first().and_then(StorageRecord::serial));
+                    if current != Some(*serial) {
+                        return Err(SerialChanged.into());
+                    }
+                }
+            }
+            for op in ops {
+                match op {
+                    StorageOp::AddZone { zone } => {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO zones (name, policy) VALUES (?1, NULL)",
+                            params![zone.to_string()],
+                        )?;
+                    }
+                    StorageOp::SetRecords {
+                        zone,
+                        domain,
+                        rtype,
+                        records,
+                    } => {
+                        write_rrset(
+                            &tx,
+                            &zone.to_string(),
+                            &domain.to_string(),
+                            &rtype.to_string(),
+                            &records,
+                        )?;
+                    }
+                    StorageOp::RemoveRrset {
+                        zone,
+                        domain,
+                        rtype,
+                    } => {
+                        write_rrset(
+                            &tx,
+                            &zone.to_string(),
+                            &domain.to_string(),
+                            &rtype.to_string(),
+                            &[],
+                        )?;
+                    }
+                    StorageOp::DeleteZone { zone } => {
+                        remove_zone(&tx, &zone.to_string(), false)?;
+                    }
+                    StorageOp::SwapSoa { zone, soa, .. } => {
+                        let zone = zone.to_string();
+                        write_rrset(&tx, &zone, &zone, &RecordType::SOA.to_string(), &[*soa])?;
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let key = name_index_key(domain);
+        let end = name_index_end(&key);
+        self.with_conn(move |conn| {
+            Ok(conn.query_row(
+                "SELECT EXISTS(
+                    SELECT 1 FROM rrsets WHERE zone = ?1 AND name_key > ?2 AND name_key < ?3
+                )",
+                params![zone, key, end],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        let (zone, name) = (zone.clone(), name.clone());
+        let key = name_index_key(&name);
+        self.with_conn(move |conn| {
+            let zone_name = zone.to_string();
+            let mut neighbours = Vec::with_capacity(2);
+            for query in [
+                "SELECT name_key FROM rrsets WHERE zone = ?1 AND name_key <= ?2
+                ORDER BY name_key DESC LIMIT 1",
+                "SELECT name_key FROM rrsets WHERE zone = ?1 AND name_key > ?2
+                ORDER BY name_key LIMIT 1",
+            ] {
+                neighbours.extend(
+                    conn.query_row(query, params![zone_name, key], |row| row.get(0))
+                        .optional()?,
+                );
+            }
+            Ok(closest_encloser_from_keys(&zone, &name, &neighbours))
+        })
+        .await
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        let zone = zone.clone();
+        self.with_conn(move |conn| {
+            let (ns, dname) = (RecordType::NS.to_string(), DNAME.to_string());
+            let mut stmt = conn.prepare(
+                "SELECT domain, rtype FROM rrsets WHERE zone = ?1 AND rtype IN (?2, ?3)",
+            )?;
+            let rows = stmt.query_map(params![zone.to_string(), ns, dname], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            let mut cuts = ZoneCuts::default();
+            for row in rows {
+                let (domain, rtype) = row?;
+                let domain = LowerName::from_str(&domain)?;
+                let rtype = if rtype == ns { RecordType::NS } else { DNAME };
+                if let Some(kind) = CutKind::of(&zone, &domain, rtype) {
+                    cuts.insert(kind, domain);
+                }
+            }
+            Ok(cuts)
+        })
+        .await
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        self.with_conn(move |conn| {
+            let rtype = rtype.to_string();
+            let tx = conn.transaction()?;
+            let removed = read_rrset(&tx, &zone, &domain, &rtype)?.unwrap_or_default();
+            write_rrset(&tx, &zone, &domain, &rtype, &[])?;
+            tx.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        let record = record.clone();
+        self.with_conn(move |conn| {
+            let rtype = record.record_type().to_string();
+            let tx = conn.transaction()?;
+            let mut records = read_rrset(&tx, &zone, &domain, &rtype)?.unwrap_or_default();
+            let removed = match records
+                .iter()
+                .position(|sr| sr.as_record().data() == record.data())
+            {
+                Some(idx) => records.remove(idx),
+                None => return Ok(None),
+            };
+            write_rrset(&tx, &zone, &domain, &rtype, &records)?;
+            tx.commit()?;
+            Ok(Some(removed))
+        })
+        .await
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let removed = remove_zone(&tx, &zone, dry_run)?;
+            tx.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let (zone, domain) = (zone.to_string(), domain.to_string());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let existed = write_rrset(&tx, &zone, &domain, &rtype.to_string(), &records)?;
+            tx.commit()?;
+            Ok(existed)
+        })
+        .await
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let rtype = RecordType::SOA.to_string();
+            let tx = conn.transaction()?;
+            let mut records = read_rrset(&tx, &zone, &zone, &rtype)?.unwrap_or_default();
+            let serials = match records.first_mut().and_then(|soa| soa.bump_serial(floor)) {
+                Some(serials) => serials,
+                None => return Ok(None),
+            };
+            write_rrset(&tx, &zone, &zone, &rtype, &records)?;
+            tx.commit()?;
+            Ok(Some(serials))
+        })
+        .await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT token, last_used FROM tokens")?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Option<u64>>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut tokens = Vec::with_capacity(rows.len());
+            for (encoded_token, last_used) in rows {
+                let mut token: ApiToken = serde_json::from_str(&encoded_token)?;
+                token.last_used = last_used;
+                tokens.push(token);
+            }
+            Ok(tokens)
+        })
+        .await
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let id = token.id.clone();
+        let encoded_token = serde_json::to_string(token)?;
+        // The last use is tracked apart from the token, so it is kept when the token changes.
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO tokens (id, token) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET token = excluded.token",
+                params![id, encoded_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE tokens SET last_used = ?2 WHERE id = ?1",
+                params![id, last_used],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT key FROM dnssec_keys WHERE zone = ?1")?;
+            let encoded_keys = stmt
+                .query_map(params![zone], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(encoded_keys
+                .iter()
+                .map(|encoded_key| serde_json::from_str(encoded_key))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let id = key.id.clone();
+        let encoded_key = serde_json::to_string(key)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO dnssec_keys (zone, id, key) VALUES (?1, ?2, ?3)",
+                params![zone, id, encoded_key],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let zone = zone.to_string();
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM dnssec_keys WHERE zone = ?1 AND id = ?2",
+                params![zone, id],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for SqliteStorage {
+    async fn append_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let timestamp = entry.timestamp;
+        let zone = entry.zone.clone();
+        let encoded_entry = serde_json::to_string(entry)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO audit (timestamp, zone, entry) VALUES (?1, ?2, ?3)",
+                params![timestamp, zone, encoded_entry],
+            )?;
+            conn.execute(
+                "DELETE FROM audit WHERE id <= ?1",
+                params![conn.last_insert_rowid() - MAX_AUDIT_LENGTH],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn audit_page(
+        &self,
+        since: u64,
+        zone: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<AuditEntry>, Box<dyn Error + Send + Sync>> {
+        // The cursor is the id of the last entry of the previous page.
+        let after = cursor
+            .map(|cursor| cursor.parse::<i64>())
+            .transpose()
+            .map_err(|_| InvalidCursor)?
+            .unwrap_or(0);
+        let zone = zone.map(str::to_string);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, entry FROM audit
+                WHERE id > ?1 AND timestamp >= ?2 AND (?3 IS NULL OR zone = ?3)
+                ORDER BY id LIMIT ?4",
+            )?;
+            let rows = stmt
+                .query_map(params![after, since, zone, limit], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let next_cursor = if rows.len() == limit {
+                rows.last().map(|(id, _)| id.to_string())
+            } else {
+                None
+            };
+            Ok(Page {
+                items: rows
+                    .iter()
+                    .map(|(_, encoded_entry)| serde_json::from_str(encoded_entry))
+                    .collect::<Result<_, _>>()?,
+                next_cursor,
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for SqliteStorage {
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<IdempotencyClaim, Box<dyn Error + Send + Sync>> {
+        let key = key.to_string();
+        let encoded_record = serde_json::to_string(record)?;
+        self.with_conn(move |conn| {
+            let now = now_millis();
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM idempotency WHERE expires <= ?1", params![now])?;
+            let existing = tx
+                .query_row(
+                    "SELECT record FROM idempotency WHERE key = ?1",
+                    params![key],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            if let Some(existing) = existing {
+                return Ok(IdempotencyClaim::Existing(serde_json::from_str(&existing)?));
+            }
+            tx.execute(
+                "INSERT INTO idempotency (key, record, expires) VALUES (?1, ?2, ?3)",
+                params![key, encoded_record, now + ttl.as_millis() as u64],
+            )?;
+            tx.commit()?;
+            Ok(IdempotencyClaim::Claimed)
+        })
+        .await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = key.to_string();
+        let encoded_record = serde_json::to_string(record)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO idempotency (key, record, expires) VALUES (?1, ?2, ?3)",
+                params![key, encoded_record, now_millis() + ttl.as_millis() as u64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM idempotency WHERE key = ?1", params![key])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{params, Connection};
+
+    use super::{SqliteStorage, MIGRATIONS};
+    use crate::{
+        handle::tests::{
+            a, assert_delegations, assert_empty_non_terminals, assert_wildcards, lower,
+        },
+        storage::{tests::conformance, Storage},
+    };
+
+    #[tokio::test]
+    async fn empty_non_terminals() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = SqliteStorage::open(&dir.path().join("cetus.db")).expect("Can open database");
+        assert_empty_non_terminals(std::sync::Arc::new(storage)).await;
+    }
+
+    #[tokio::test]
+    async fn wildcards() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = SqliteStorage::open(&dir.path().join("cetus.db")).expect("Can open database");
+        assert_wildcards(std::sync::Arc::new(storage)).await;
+    }
+
+    #[tokio::test]
+    async fn storage_conformance() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = SqliteStorage::open(&dir.path().join("cetus.db")).expect("Can open database");
+        conformance(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn delegations() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let storage = SqliteStorage::open(&dir.path().join("cetus.db")).expect("Can open database");
+        assert_delegations(std::sync::Arc::new(storage)).await;
+    }
+
+    #[tokio::test]
+    async fn indexes_names_of_existing_databases() {
+        let dir = tempfile::tempdir().expect("Can create temporary directory");
+        let path = dir.path().join("cetus.db");
+        {
+            let conn = Connection::open(&path).expect("Can create database");
+            conn.execute_batch(MIGRATIONS[0].schema)
+                .expect("Can create first schema");
+            conn.pragma_update(None, "user_version", 1)
+                .expect("Can set schema version");
+            conn.execute(
+                "INSERT INTO rrsets (zone, domain, rtype, records) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    "example.com.",
+                    "a.b.example.com.",
+                    "A",
+                    serde_json::to_string(&[a("a.b.example.com.", [192, 0, 2, 1])]).unwrap()
+                ],
+            )
+            .expect("Can insert RRset");
+        }
+
+        let storage = SqliteStorage::open(&path).expect("Can migrate database");
+        let zone = lower("example.com.");
+        for (domain, below) in [
+            ("example.com.", true),
+            ("b.example.com.", true),
+            ("a.b.example.com.", false),
+            ("c.example.com.", false),
+        ] {
+            assert_eq!(
+                storage
+                    .has_subdomains(&zone, &lower(domain))
+                    .await
+                    .expect("Can check subdomains"),
+                below,
+                "{}",
+                domain
+            );
+        }
+    }
+}
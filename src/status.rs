@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+
+use crate::storage::Storage;
+
+/// Maximum time the storage gets to answer the ping of a readiness check.
+const READINESS_PING_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum time a spawned task may wait for the runtime before the process is considered stuck.
+const MAX_SCHEDULING_DELAY: Duration = Duration::from_secs(1);
+
+/// Shared runtime status of the server, used to decide if the server is ready to receive traffic.
+#[derive(Default)]
+pub struct Status {
+    /// The storage has been confirmed to be reachable.
+    storage_reachable: AtomicBool,
+    /// The zone cache completed at least 1 successful load.
+    zones_loaded: AtomicBool,
+    /// Wakes the tasks waiting for the first load of the zone cache.
+    zones_notify: Notify,
+    /// The GeoIP database is opened.
+    geoip_loaded: AtomicBool,
+    /// The server is shutting down, and only answers queries still in flight.
+    draining: AtomicBool,
+}
+
+/// Outcome of a single check of a probe.
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    ok: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn passed() -> Check {
+        Check {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl Into<String>) -> Check {
+        Check {
+            ok: false,
+            error: Some(error.into()),
+        }
+    }
+
+    /// A check which passed if the flag is set, and failed with the given error otherwise.
+    fn from_flag(ok: bool, error: &str) -> Check {
+        if ok {
+            Check::passed()
+        } else {
+            Check::failed(error)
+        }
+    }
+}
+
+/// Result of a liveness or readiness probe, with the outcome of every check. Rendered with status
+/// 200 if all checks passed, and 503 otherwise.
+#[derive(Serialize, ToSchema)]
+pub struct ProbeResult {
+    ok: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl ProbeResult {
+    fn new(checks: BTreeMap<&'static str, Check>) -> ProbeResult {
+        ProbeResult {
+            ok: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+impl IntoResponse for ProbeResult {
+    fn into_response(self) -> Response {
+        let status = if self.ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+impl Status {
+    /// Mark the storage as reachable.
+    pub fn set_storage_reachable(&self) {
+        self.storage_reachable.store(true, Ordering::Release);
+    }
+
+    /// Mark the zone cache as loaded.
+    pub fn set_zones_loaded(&self) {
+        self.zones_loaded.store(true, Ordering::Release);
+        self.zones_notify.notify_waiters();
+    }
+
+    /// Wait until the zone cache completed its first load.
+    pub async fn wait_for_zones(&self) {
+        loop {
+            // Created before the check, so a load in between is not missed.
+            let notified = self.zones_notify.notified();
+            if self.zones_loaded.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Mark the GeoIP database as opened.
+    pub fn set_geoip_loaded(&self) {
+        self.geoip_loaded.store(true, Ordering::Release);
+    }
+
+    /// Mark the server as draining. Once set, the server is never ready again.
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    /// Check if the server is ready to receive traffic, including if the storage answers right
+    /// now rather than only at startup.
+    pub async fn readiness<S>(&self, storage: &S) -> ProbeResult
+    where
+        S: Storage + Sync + ?Sized,
+    {
+        let storage_check = if !self.storage_reachable.load(Ordering::Acquire) {
+            Check::failed("storage has not been reachable since startup")
+        } else {
+            match tokio::time::timeout(READINESS_PING_TIMEOUT, storage.ping()).await {
+                Ok(Ok(())) => Check::passed(),
+                Ok(Err(e)) => Check::failed(format!("storage ping failed: {}", e)),
+                Err(_) => Check::failed("storage ping timed out"),
+            }
+        };
+
+        let mut checks = BTreeMap::new();
+        checks.insert("storage", storage_check);
+        checks.insert(
+            "zones",
+            Check::from_flag(
+                self.zones_loaded.load(Ordering::Acquire),
+                "zone cache has not been loaded yet",
+            ),
+        );
+        checks.insert(
+            "geoip",
+            Check::from_flag(
+                self.geoip_loaded.load(Ordering::Acquire),
+                "GeoIP database is not opened",
+            ),
+        );
+        checks.insert(
+            "draining",
+            Check::from_flag(
+                !self.draining.load(Ordering::Acquire),
+                "server is shutting down",
+            ),
+        );
+        ProbeResult::new(checks)
+    }
+}
+
+/// Check if the process is alive, meaning the runtime still picks up new tasks in time.
+pub async fn liveness() -> ProbeResult {
+    let start = Instant::now();
+    let scheduled = tokio::spawn(async move { start.elapsed() }).await;
+    let check = match scheduled {
+        Ok(delay) if delay <= MAX_SCHEDULING_DELAY => Check::passed(),
+        Ok(delay) => Check::failed(format!(
+            "runtime took {} ms to schedule a task",
+            delay.as_millis()
+        )),
+        Err(e) => Check::failed(format!("task failed: {}", e)),
+    };
+
+    let mut checks = BTreeMap::new();
+    checks.insert("runtime", check);
+    ProbeResult::new(checks)
+}
@@ -1,16 +1,65 @@
+use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
-use std::{error::Error, sync::Arc};
-use trust_dns_proto::rr::RecordType;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    sync::Arc,
+};
+use tokio::sync::broadcast;
+use trust_dns_proto::rr::{domain::Label, rdata::SOA, Name, RData, RecordType};
 use trust_dns_server::{client::rr::LowerName, proto::rr::Record};
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+use crate::{
+    dname::DNAME, geo::GeoPolicy, health::HealthCheck, policy::ZonePolicy, reload::ZoneReload,
+    weight::WeightedAnswer,
+};
+
+#[cfg(test)]
+pub(crate) mod tests;
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
 pub struct StorageRecord {
+    #[schema(value_type = Object)]
     pub record: Record,
-    // TODO
+    /// An optional free form comment for operators. This is never served over DNS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Optional free form labels for operators. These are never served over DNS.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    /// Optional locations the record is served to. Records without a policy are served to
+    /// clients for which no record of the set has a matching policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo: Option<GeoPolicy>,
+    /// Optional weight of the record, to answer with a weighted random pick from its RRset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// How the RRset is answered if the record has a weight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weighted_answer: Option<WeightedAnswer>,
+    /// Optional health check of the address in the record. Unhealthy records are left out of
+    /// answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
 }
 
 impl StorageRecord {
+    /// Create a new [`StorageRecord`] for a record, without any metadata attached.
+    pub fn new(record: Record) -> Self {
+        StorageRecord {
+            record,
+            comment: None,
+            labels: HashMap::new(),
+            geo: None,
+            weight: None,
+            weighted_answer: None,
+            health_check: None,
+        }
+    }
+
     /// Get access to the actual record.
     pub fn as_record(&self) -> &Record {
         &self.record
@@ -20,14 +69,464 @@ impl StorageRecord {
     pub fn as_mut_record(&mut self) -> &mut Record {
         &mut self.record
     }
+
+    /// Increment the serial of an SOA record, or set it to the floor if that is ahead of the
+    /// incremented serial. Returns the old and the new serial, or [`Option::None`] if this is not
+    /// an SOA record.
+    pub fn bump_serial(&mut self, floor: Option<u32>) -> Option<(u32, u32)> {
+        let soa = match self.record.data() {
+            Some(RData::SOA(soa)) => soa.clone(),
+            _ => return None,
+        };
+
+        let old = soa.serial();
+        let mut serial = old.wrapping_add(1);
+        if let Some(floor) = floor {
+            // The floor is ahead if it is within half the serial space (RFC 1982).
+            let ahead = floor.wrapping_sub(serial);
+            if ahead > 0 && ahead < 1 << 31 {
+                serial = floor;
+            }
+        }
+        self.record.set_data(Some(RData::SOA(SOA::new(
+            soa.mname().clone(),
+            soa.rname().clone(),
+            serial,
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum(),
+        ))));
+
+        Some((old, serial))
+    }
+
+    /// The serial of an SOA record, or [`Option::None`] if this is not an SOA record.
+    pub fn serial(&self) -> Option<u32> {
+        match self.record.data() {
+            Some(RData::SOA(soa)) => Some(soa.serial()),
+            _ => None,
+        }
+    }
+}
+
+/// A change to the records of a zone, as kept in the zone journal to answer incremental zone
+/// transfers.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ZoneChange {
+    /// Serial of the zone SOA before the change.
+    pub from_serial: u32,
+    /// Serial of the zone SOA after the change. This is the same as `from_serial` if the change
+    /// did not update the SOA.
+    pub serial: u32,
+    /// Records removed from the zone.
+    #[serde(default)]
+    pub removed: Vec<StorageRecord>,
+    /// Records added to the zone.
+    #[serde(default)]
+    pub added: Vec<StorageRecord>,
+}
+
+impl ZoneChange {
+    /// Take the changes of a journal starting with the change made at the given serial. Returns
+    /// [`Option::None`] if there is no such change, or if a change is missing after it, e.g.
+    /// because appending it to the journal failed. Clients then fall back to a full transfer.
+    pub fn since(mut journal: Vec<ZoneChange>, serial: u32) -> Option<Vec<ZoneChange>> {
+        let start = journal
+            .iter()
+            .position(|change| change.from_serial == serial)?;
+        let changes = journal.split_off(start);
+        changes
+            .windows(2)
+            .all(|pair| pair[1].from_serial == pair[0].serial)
+            .then_some(changes)
+    }
+}
+
+impl ZoneChange {
+    /// Check if the change adds or removes a zone cut, i.e. a delegation or a DNAME record, below
+    /// which records of the zone are occluded.
+    pub fn changes_cuts(&self, zone: &LowerName) -> bool {
+        self.removed.iter().chain(&self.added).any(|sr| {
+            let record = sr.as_record();
+            CutKind::of(zone, &LowerName::from(record.name()), record.record_type()).is_some()
+        })
+    }
+}
+
+/// Names in a zone which occlude the records below them: delegations to child zones, and DNAME
+/// records. Keeping these in memory means ancestors of a query name only need to be looked up in
+/// storage if they actually are a cut.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct ZoneCuts {
+    /// Names below the apex with NS records.
+    pub delegations: HashSet<LowerName>,
+    /// Names with a DNAME record.
+    pub dnames: HashSet<LowerName>,
+}
+
+impl ZoneCuts {
+    /// Add a cut of the given kind at a name.
+    pub fn insert(&mut self, kind: CutKind, name: LowerName) {
+        match kind {
+            CutKind::Delegation => self.delegations.insert(name),
+            CutKind::Dname => self.dnames.insert(name),
+        };
+    }
+}
+
+/// The kind of a zone cut, see [`ZoneCuts`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CutKind {
+    /// NS records below the apex.
+    Delegation,
+    /// A DNAME record.
+    Dname,
+}
+
+impl CutKind {
+    /// The kind of cut defined by an RRset of the given type at a domain in a zone, if any.
+    pub fn of(zone: &LowerName, domain: &LowerName, rtype: RecordType) -> Option<CutKind> {
+        match rtype {
+            RecordType::NS if domain != zone => Some(CutKind::Delegation),
+            rtype if rtype == DNAME => Some(CutKind::Dname),
+            _ => None,
+        }
+    }
+
+    /// Name of the kind of cut, as used by the backends in their index of cuts.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CutKind::Delegation => "NS",
+            CutKind::Dname => "DNAME",
+        }
+    }
+
+    /// Parse the name of a kind of cut, see [`CutKind::as_str`].
+    pub fn from_name(name: &str) -> Option<CutKind> {
+        match name {
+            "NS" => Some(CutKind::Delegation),
+            "DNAME" => Some(CutKind::Dname),
+            _ => None,
+        }
+    }
+}
+
+/// The full contents of an RRset, i.e. all records of a single type for a domain.
+#[derive(Clone, Debug)]
+pub struct RecordSet {
+    pub domain: LowerName,
+    pub rtype: RecordType,
+    /// The records in the set. An empty list means the RRset does not exist.
+    pub records: Vec<StorageRecord>,
+}
+
+impl RecordSet {
+    /// The change which replaces the RRset in a zone as part of a batch, or removes it if it has
+    /// no records.
+    pub fn into_op(self, zone: &LowerName) -> StorageOp {
+        if self.records.is_empty() {
+            StorageOp::RemoveRrset {
+                zone: zone.clone(),
+                domain: self.domain,
+                rtype: self.rtype,
+            }
+        } else {
+            StorageOp::SetRecords {
+                zone: zone.clone(),
+                domain: self.domain,
+                rtype: self.rtype,
+                records: self.records,
+            }
+        }
+    }
+}
+
+/// A single change in a batch applied with [`Storage::apply`].
+#[derive(Clone, Debug)]
+pub enum StorageOp {
+    /// Add the marker of a zone, which makes the zone exist. A zone which exists is left as is,
+    /// including its policy.
+    AddZone { zone: LowerName },
+    /// Replace an RRset, or remove it if there are no records, like [`Storage::set_records`].
+    SetRecords {
+        zone: LowerName,
+        domain: LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    },
+    /// Remove an RRset, like [`Storage::remove_rrset`].
+    RemoveRrset {
+        zone: LowerName,
+        domain: LowerName,
+        rtype: RecordType,
+    },
+    /// Remove a zone with all of its data, like [`Storage::delete_zone`]. For a zone which does
+    /// not exist, this removes records left behind by a batch which was interrupted.
+    DeleteZone { zone: LowerName },
+    /// Replace the SOA of a zone, but only if the serial of its SOA before the batch is `serial`,
+    /// so two concurrent changes never end up with the same serial. Otherwise the batch fails
+    /// with a [`SerialChanged`] error, and nothing is changed.
+    SwapSoa {
+        zone: LowerName,
+        serial: u32,
+        soa: Box<StorageRecord>,
+    },
+}
+
+/// A change to the data of a zone, as announced to the other instances sharing the storage.
+#[derive(Clone, Debug)]
+pub enum StorageChange {
+    /// The zone was added or removed, or its policy changed.
+    Zone { zone: LowerName },
+    /// An RRset of a domain in the zone changed.
+    Rrset {
+        zone: LowerName,
+        domain: LowerName,
+        rtype: RecordType,
+    },
+    /// Changes could have been missed, e.g. while the connection to the storage was down, so
+    /// everything derived from the storage should be considered stale.
+    Missed,
+}
+
+impl StorageChange {
+    /// Check if the change affects the zone cache, i.e. the zones, their SOA records or their zone
+    /// cuts.
+    pub fn affects_zone_cache(&self) -> bool {
+        match self {
+            StorageChange::Zone { .. } | StorageChange::Missed => true,
+            StorageChange::Rrset { rtype, .. } => {
+                matches!(rtype, RecordType::SOA | RecordType::NS) || *rtype == DNAME
+            }
+        }
+    }
+}
+
+/// A page of a listing, with the cursor to continue the listing if there are more items. The
+/// format of the cursor is up to the storage backend.
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// Error returned by paged listings for a cursor which was not issued by the backend, or which
+/// can't be continued anymore.
+#[derive(Debug)]
+pub struct InvalidCursor;
+
+impl fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid listing cursor")
+    }
+}
+
+impl Error for InvalidCursor {}
+
+/// Error returned by [`Storage::apply`] if the serial of a zone changed since it was read, see
+/// [`StorageOp::SwapSoa`].
+#[derive(Debug)]
+pub struct SerialChanged;
+
+impl fmt::Display for SerialChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("serial of the zone changed concurrently")
+    }
+}
+
+impl Error for SerialChanged {}
+
+/// An API token as persisted in storage. The token secret itself is never stored, only a hash of
+/// it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApiToken {
+    /// Unique, non secret, identifier of the token.
+    pub id: String,
+    /// Hex encoded SHA-256 hash of the token secret.
+    pub secret_hash: String,
+    /// The scopes granted to the token.
+    pub scopes: Vec<TokenScope>,
+    /// Creation time of the token, in seconds since the unix epoch.
+    pub created_at: u64,
+    /// Optional expiry time of the token, in seconds since the unix epoch.
+    pub expires_at: Option<u64>,
+    /// Revoked tokens are disabled rather than removed, so they can still be audited.
+    pub disabled: bool,
+    /// Approximate time the token was last used, in seconds since the unix epoch.
+    pub last_used: Option<u64>,
+}
+
+/// Access scopes which can be granted to an [`ApiToken`].
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Read only access to zones and records.
+    Read,
+    /// Read and write access to zones and records.
+    Write,
+    /// Full access, including token management.
+    Admin,
+}
+
+/// A DNSSEC signing key of a zone as persisted in storage. The state of the key follows from its
+/// timestamps, so a rollover is scheduled by storing the key once, rather than by a process which
+/// has to run at the right time.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DnssecKey {
+    /// Unique identifier of the key within its zone.
+    pub id: String,
+    pub role: KeyRole,
+    pub algorithm: KeyAlgorithm,
+    /// PKCS#8 document of the private key, encrypted with the configured key encryption key. This
+    /// is the base64 encoded nonce, followed by the ChaCha20-Poly1305 ciphertext and tag. The zone
+    /// and id of the key are authenticated with it, so it can't be moved to another key.
+    pub private_key: String,
+    /// Public key in the wire format of the DNSKEY record of the algorithm.
+    pub public_key: Vec<u8>,
+    /// Creation time of the key, in seconds since the unix epoch. The key is published from then.
+    pub created_at: u64,
+    /// Time from which the key is used to sign, in seconds since the unix epoch.
+    pub activate_at: u64,
+    /// Optional time from which the key is no longer used to sign, in seconds since the unix
+    /// epoch.
+    pub retire_at: Option<u64>,
+}
+
+impl DnssecKey {
+    /// The state of the key at the given time, in seconds since the unix epoch.
+    pub fn state(&self, now: u64) -> KeyState {
+        if self.retire_at.is_some_and(|retire_at| retire_at <= now) {
+            KeyState::Retired
+        } else if self.activate_at <= now {
+            KeyState::Active
+        } else {
+            KeyState::Published
+        }
+    }
+}
+
+/// Role of a [`DnssecKey`].
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRole {
+    /// Key signing key, which only signs the DNSKEY RRset and is referenced by the DS record in
+    /// the parent zone.
+    Ksk,
+    /// Zone signing key, which signs all other RRsets.
+    Zsk,
+}
+
+/// Signing algorithms supported for a [`DnssecKey`].
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// ECDSA with curve P-256 and SHA-256, algorithm 13.
+    #[serde(rename = "ECDSAP256SHA256")]
+    EcdsaP256Sha256,
+    /// Ed25519, algorithm 15.
+    #[serde(rename = "ED25519")]
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    /// The algorithm number as used in DNSKEY and DS records.
+    pub fn number(self) -> u8 {
+        match self {
+            KeyAlgorithm::EcdsaP256Sha256 => 13,
+            KeyAlgorithm::Ed25519 => 15,
+        }
+    }
+}
+
+/// State of a [`DnssecKey`] in its lifecycle.
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    /// The key is published in the DNSKEY RRset, but not used to sign yet.
+    Published,
+    /// The key is published and used to sign.
+    Active,
+    /// The key is no longer used to sign, and can be removed.
+    Retired,
+}
+
+impl TokenScope {
+    /// Check if this scope grants the access required by the given scope.
+    pub fn grants(self, required: TokenScope) -> bool {
+        match self {
+            TokenScope::Admin => true,
+            TokenScope::Write => required != TokenScope::Admin,
+            TokenScope::Read => required == TokenScope::Read,
+        }
+    }
+}
+
+/// Key of a name in the per-zone index of names kept by the backends. Labels are written from the
+/// root down, each followed by a `.`, so the key of a name is a prefix of the keys of all names
+/// below it. Escaped labels only contain printable ASCII, so those keys all sort after the key of
+/// the name, and before [`name_index_end`].
+pub fn name_index_key(name: &LowerName) -> String {
+    let name = Name::from(name);
+    let mut key = String::with_capacity(name.len() + 1);
+    for label in name.iter().rev() {
+        // Labels of a valid name always fit in a label.
+        if let Ok(label) = Label::from_raw_bytes(label) {
+            key.push_str(&label.to_ascii());
+        }
+        key.push('.');
+    }
+    key
+}
+
+/// The end of the range of index keys of the names below a name, see [`name_index_key`].
+pub fn name_index_end(key: &str) -> String {
+    format!("{}\x7f", key)
+}
+
+/// Find the closest encloser of a name in a zone, see [`Storage::closest_encloser`], from the
+/// index keys right before and after the key of the name. The keys of the names below the closest
+/// encloser are a single range around the key of the name, so one of them is in that range if
+/// any is. Keys may have a suffix after the index key of their name.
+pub fn closest_encloser_from_keys(
+    zone: &LowerName,
+    name: &LowerName,
+    neighbours: &[String],
+) -> LowerName {
+    let mut encloser = name.clone();
+    while encloser != *zone && zone.zone_of(&encloser) {
+        let key = name_index_key(&encloser);
+        if neighbours
+            .iter()
+            .any(|neighbour| neighbour.starts_with(&key))
+        {
+            return encloser;
+        }
+        encloser = encloser.base_name();
+    }
+    zone.clone()
 }
 
 #[async_trait::async_trait]
 pub trait Storage {
+    /// Check if the storage is reachable and answering requests.
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
+
     /// Get a list of all zones served by the server. These are only the names - not the actual SOA
     /// records.
     async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>>;
 
+    /// Check if a zone is served by the server, without listing all zones.
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    /// Get a page of about `limit` zones, starting at the cursor of the previous page, or at the
+    /// start if no cursor is given. Zones which exist during the whole listing are returned at
+    /// least once. An unusable cursor gives an [`InvalidCursor`] error.
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>>;
+
     /// Look up the records for a fqdn in the data store. It is possible that no records exist for
     /// the given name of the given type. It is also possible that more than 1 record exists for
     /// the given name and type.
@@ -44,13 +543,28 @@ pub trait Storage {
         rtype: RecordType,
     ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>>;
 
+    /// Look up all records of any type for a fqdn in the data store.
+    ///
+    /// # Returns
+    ///
+    /// This method should return [`Option::None`] if the domain does not exist at all.
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>>;
+
     /// Add a new zone to the server. This only sets a marker in storage to indicate that the
     /// server is indeed authoritative for the zone, but importantly the SOA and NS records will
-    /// need to be added manually after this.
+    /// need to be added manually after this. Outside of tests zones are added in a batch with a
+    /// [`StorageOp::AddZone`], together with their records.
+    #[cfg(test)]
     async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>>;
 
     /// Store a record in a domain in a zone. Callers should always verify that the zone exists before
-    /// submitting a record.
+    /// submitting a record. Outside of tests records are stored with [`Storage::set_records`] or
+    /// in a batch.
+    #[cfg(test)]
     async fn add_record(
         &self,
         zone: &LowerName,
@@ -65,11 +579,260 @@ pub trait Storage {
         domain: &LowerName,
     ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>>;
 
+    /// List all records of multiple domains in a zone, in the order of the domains. Reading a large
+    /// part of a zone should use this rather than [`list_records`](Storage::list_records) per
+    /// domain, so backends can batch the lookups.
+    async fn list_records_bulk(
+        &self,
+        zone: &LowerName,
+        domains: &[LowerName],
+    ) -> Result<Vec<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        let mut records = Vec::with_capacity(domains.len());
+        for domain in domains {
+            records.push(self.list_records(zone, domain).await?);
+        }
+        Ok(records)
+    }
+
     /// List all available domains for a given zone.
     async fn list_domains(
         &self,
         zone: &LowerName,
     ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>>;
+
+    /// Get a page of about `limit` domains of a zone. Cursors work like those of
+    /// [`zones_page`](Storage::zones_page).
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>>;
+
+    /// Get the response policy overrides of a zone. Zones without explicit overrides, and zones
+    /// which don't exist, return the default (empty) policy.
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>>;
+
+    /// Set the response policy overrides of an existing zone.
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Append a change to the journal of a zone. Implementations should bound the size of the
+    /// journal, dropping the oldest changes.
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Get all changes in the journal of a zone, starting with the change made to the zone at the
+    /// given serial.
+    ///
+    /// # Returns
+    ///
+    /// This method should return [`Option::None`] if the journal has no change starting at the given
+    /// serial, i.e. the journal does not go back far enough, or if it has a gap after that change,
+    /// see [`ZoneChange::since`].
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>>;
+
+    /// Apply a batch of changes, in order. Either all changes are applied, or none of them are.
+    ///
+    /// Backends which can't update everything in a single transaction write zone markers after the
+    /// records, and restore what they already changed if a later change fails. A process which
+    /// dies halfway through can then leave records without a marker behind. These are not served,
+    /// and are replaced when the zone is created again, or removed with a [`StorageOp::DeleteZone`].
+    /// Removing a zone is never undone, so a failed batch with a [`StorageOp::DeleteZone`] can
+    /// leave the zone partially removed, but it can always be applied again.
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Check if any domain with records exists below the given domain in a zone. A domain without
+    /// records of its own, but with subdomains, is an empty non-terminal: it exists, but has no data.
+    ///
+    /// Backends with an index of the names in the zone answer this without listing its domains.
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    /// Get the closest encloser of a name in a zone (RFC 4592): the longest ancestor of the name,
+    /// or the name itself, which exists because it or a name below it has records. This is the
+    /// zone if no other ancestor exists.
+    ///
+    /// This is on the query path of every name which does not exist. The default walks up from
+    /// the name, backends with an index of names find it with a single lookup instead.
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        let mut encloser = name.clone();
+        while encloser != *zone
+            && zone.zone_of(&encloser)
+            && self.list_records(zone, &encloser).await?.is_empty()
+            && !self.has_subdomains(zone, &encloser).await?
+        {
+            encloser = encloser.base_name();
+        }
+        Ok(if zone.zone_of(&encloser) {
+            encloser
+        } else {
+            zone.clone()
+        })
+    }
+
+    /// Get the zone cuts of a zone, i.e. its delegations and DNAME records.
+    ///
+    /// The zone cache loads these for every zone on every refresh. The default scans all records
+    /// of the zone, backends with an index of cuts, kept up to date as RRsets are written, read
+    /// that instead.
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        let mut cuts = ZoneCuts::default();
+        let domains = self.list_domains(zone).await?;
+        let records = self.list_records_bulk(zone, &domains).await?;
+        for (domain, records) in domains.into_iter().zip(records) {
+            for sr in records {
+                let record = sr.as_record();
+                if let Some(kind) = CutKind::of(zone, &domain, record.record_type()) {
+                    cuts.insert(kind, domain.clone());
+                }
+            }
+        }
+        Ok(cuts)
+    }
+
+    /// Remove an RRset from a domain in a zone. A domain without any RRsets left is removed as well.
+    ///
+    /// # Returns
+    ///
+    /// The removed records, which is empty if the RRset did not exist.
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>>;
+
+    /// Remove a single record from a domain in a zone. The record to remove is the one in the RRset
+    /// of the same type with the same data, the TTL and metadata are not compared. An RRset without
+    /// records left is removed, as is a domain without any RRsets left.
+    ///
+    /// # Returns
+    ///
+    /// The removed record, or [`Option::None`] if no record with the same data exists.
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>>;
+
+    /// Remove a zone, with all of its records, its policy and its journal. If `dry_run` is set
+    /// nothing is removed.
+    ///
+    /// # Returns
+    ///
+    /// The amount of storage entries which are (or would be) removed.
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>>;
+
+    /// Replace the RRset of a type in a domain in a zone with the given records, in a single atomic
+    /// update. Callers should always verify that the zone exists before submitting records. An
+    /// empty list of records removes the RRset, and with it the domain if this was its last RRset.
+    ///
+    /// # Returns
+    ///
+    /// `true` if an existing RRset was replaced or removed, `false` if the RRset was created, or
+    /// if there was nothing to remove.
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    /// Increment the serial of the SOA of a zone in a single atomic update, so concurrent changes
+    /// never get the same serial. If a floor is given and it is ahead of the incremented serial in
+    /// serial number arithmetic (RFC 1982), the serial is set to the floor instead.
+    ///
+    /// # Returns
+    ///
+    /// The serial before and after the update, or [`Option::None`] if the zone has no SOA.
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>>;
+
+    /// List all API tokens, including disabled and expired ones.
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>>;
+
+    /// Store an API token, overwriting an existing token with the same id. The last used time of
+    /// the token is not persisted by this method, see [`Storage::set_token_last_used`].
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Record the time an API token was last used. This is kept separate from the token itself so
+    /// tracking usage can never overwrite concurrent changes to the token.
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// List the DNSSEC keys of a zone, in all states.
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>>;
+
+    /// Store a DNSSEC key of a zone, overwriting an existing key with the same id.
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Remove a DNSSEC key of a zone.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the key existed.
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    /// Watch for changes which affect the zone cache, i.e. zones being added or removed and
+    /// changes to their SOA records and zone cuts, made through any instance. A reload of the
+    /// changed zone is requested for every such change, and a reload of all zones if changes
+    /// could have been missed, for as long as the returned future runs.
+    ///
+    /// Backends which can't watch for changes return [`Option::None`], in which case the zone
+    /// cache is refreshed periodically.
+    fn watch_zones(&self, _reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        None
+    }
+
+    /// Subscribe to the changes made through any instance sharing the storage. Backends which
+    /// don't announce changes return [`Option::None`].
+    fn subscribe_changes(&self) -> Option<broadcast::Receiver<StorageChange>> {
+        None
+    }
 }
 
 #[async_trait::async_trait]
@@ -77,10 +840,26 @@ impl<S> Storage for Arc<S>
 where
     S: Storage + Send + Sync,
 {
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().ping().await
+    }
+
     async fn zones(&self) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
         self.deref().zones().await
     }
 
+    async fn zone_exists(&self, zone: &LowerName) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.deref().zone_exists(zone).await
+    }
+
+    async fn zones_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.deref().zones_page(cursor, limit).await
+    }
+
     async fn lookup_records(
         &self,
         domain: &LowerName,
@@ -90,10 +869,20 @@ where
         self.deref().lookup_records(domain, zone, rtype).await
     }
 
+    async fn lookup_all_records(
+        &self,
+        domain: &LowerName,
+        zone: &LowerName,
+    ) -> Result<Option<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.deref().lookup_all_records(domain, zone).await
+    }
+
+    #[cfg(test)]
     async fn add_zone(&self, zone: &LowerName) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.deref().add_zone(zone).await
     }
 
+    #[cfg(test)]
     async fn add_record(
         &self,
         zone: &LowerName,
@@ -111,10 +900,173 @@ where
         self.deref().list_records(zone, domain).await
     }
 
+    async fn list_records_bulk(
+        &self,
+        zone: &LowerName,
+        domains: &[LowerName],
+    ) -> Result<Vec<Vec<StorageRecord>>, Box<dyn Error + Send + Sync>> {
+        self.deref().list_records_bulk(zone, domains).await
+    }
+
     async fn list_domains(
         &self,
         zone: &LowerName,
     ) -> Result<Vec<LowerName>, Box<dyn Error + Send + Sync>> {
         self.deref().list_domains(zone).await
     }
+
+    async fn list_domains_page(
+        &self,
+        zone: &LowerName,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<LowerName>, Box<dyn Error + Send + Sync>> {
+        self.deref().list_domains_page(zone, cursor, limit).await
+    }
+
+    async fn zone_policy(
+        &self,
+        zone: &LowerName,
+    ) -> Result<ZonePolicy, Box<dyn Error + Send + Sync>> {
+        self.deref().zone_policy(zone).await
+    }
+
+    async fn set_zone_policy(
+        &self,
+        zone: &LowerName,
+        policy: &ZonePolicy,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().set_zone_policy(zone, policy).await
+    }
+
+    async fn append_change(
+        &self,
+        zone: &LowerName,
+        change: &ZoneChange,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().append_change(zone, change).await
+    }
+
+    async fn changes_since(
+        &self,
+        zone: &LowerName,
+        serial: u32,
+    ) -> Result<Option<Vec<ZoneChange>>, Box<dyn Error + Send + Sync>> {
+        self.deref().changes_since(zone, serial).await
+    }
+
+    async fn apply(&self, ops: Vec<StorageOp>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().apply(ops).await
+    }
+
+    async fn has_subdomains(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.deref().has_subdomains(zone, domain).await
+    }
+
+    async fn closest_encloser(
+        &self,
+        zone: &LowerName,
+        name: &LowerName,
+    ) -> Result<LowerName, Box<dyn Error + Send + Sync>> {
+        self.deref().closest_encloser(zone, name).await
+    }
+
+    async fn zone_cuts(&self, zone: &LowerName) -> Result<ZoneCuts, Box<dyn Error + Send + Sync>> {
+        self.deref().zone_cuts(zone).await
+    }
+
+    async fn remove_rrset(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Vec<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.deref().remove_rrset(zone, domain, rtype).await
+    }
+
+    async fn remove_record(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        record: &Record,
+    ) -> Result<Option<StorageRecord>, Box<dyn Error + Send + Sync>> {
+        self.deref().remove_record(zone, domain, record).await
+    }
+
+    async fn delete_zone(
+        &self,
+        zone: &LowerName,
+        dry_run: bool,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        self.deref().delete_zone(zone, dry_run).await
+    }
+
+    async fn set_records(
+        &self,
+        zone: &LowerName,
+        domain: &LowerName,
+        rtype: RecordType,
+        records: Vec<StorageRecord>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.deref().set_records(zone, domain, rtype, records).await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiToken>, Box<dyn Error + Send + Sync>> {
+        self.deref().tokens().await
+    }
+
+    async fn set_token(&self, token: &ApiToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().set_token(token).await
+    }
+
+    async fn set_token_last_used(
+        &self,
+        id: &str,
+        last_used: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().set_token_last_used(id, last_used).await
+    }
+
+    async fn dnssec_keys(
+        &self,
+        zone: &LowerName,
+    ) -> Result<Vec<DnssecKey>, Box<dyn Error + Send + Sync>> {
+        self.deref().dnssec_keys(zone).await
+    }
+
+    async fn set_dnssec_key(
+        &self,
+        zone: &LowerName,
+        key: &DnssecKey,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.deref().set_dnssec_key(zone, key).await
+    }
+
+    async fn remove_dnssec_key(
+        &self,
+        zone: &LowerName,
+        id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.deref().remove_dnssec_key(zone, id).await
+    }
+
+    async fn bump_serial(
+        &self,
+        zone: &LowerName,
+        floor: Option<u32>,
+    ) -> Result<Option<(u32, u32)>, Box<dyn Error + Send + Sync>> {
+        self.deref().bump_serial(zone, floor).await
+    }
+
+    fn watch_zones(&self, reload: Arc<ZoneReload>) -> Option<BoxFuture<'static, ()>> {
+        self.deref().watch_zones(reload)
+    }
+
+    fn subscribe_changes(&self) -> Option<broadcast::Receiver<StorageChange>> {
+        self.deref().subscribe_changes()
+    }
 }
@@ -0,0 +1,937 @@
+//! Conformance suite of the [Storage] trait, which every backend runs to check it behaves like the
+//! others. Every check works in zones with a random name, which are removed again at the end, so
+//! the suite can run against a shared Redis or etcd as well.
+
+use std::collections::HashSet;
+
+use trust_dns_proto::rr::{rdata::TXT, RData, RecordType};
+use trust_dns_server::client::rr::LowerName;
+
+use super::{
+    ApiToken, CutKind, DnssecKey, InvalidCursor, KeyAlgorithm, KeyRole, Page, RecordSet,
+    SerialChanged, Storage, StorageOp, StorageRecord, TokenScope, ZoneChange, ZoneCuts,
+};
+use crate::{
+    dname,
+    handle::tests::{a, cname, lower, name_of, record, soa},
+    policy::ZonePolicy,
+};
+
+/// Run all checks of the suite against a backend.
+pub(crate) async fn conformance<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    storage.ping().await.expect("Storage is reachable");
+    zones(storage).await;
+    records(storage).await;
+    set_records(storage).await;
+    remove_records(storage).await;
+    batches(storage).await;
+    names(storage).await;
+    zone_cuts(storage).await;
+    policy(storage).await;
+    journal(storage).await;
+    serials(storage).await;
+    tokens(storage).await;
+    dnssec_keys(storage).await;
+    delete_zone(storage).await;
+}
+
+/// A zone name which is not used by any other run of the suite.
+fn random_zone() -> String {
+    format!("z{:08x}.conformance.test.", rand::random::<u32>())
+}
+
+/// Create a zone with an SOA record with serial 1.
+async fn create_zone<S>(storage: &S) -> (String, LowerName)
+where
+    S: Storage + Sync,
+{
+    let name = random_zone();
+    let zone = lower(&name);
+    storage.add_zone(&zone).await.expect("Can add zone");
+    storage
+        .add_record(&zone, &zone, soa(&name, 1))
+        .await
+        .expect("Can add SOA record");
+    (name, zone)
+}
+
+/// Remove a zone created by a check.
+async fn remove_zone<S>(storage: &S, zone: &LowerName)
+where
+    S: Storage + Sync,
+{
+    storage
+        .delete_zone(zone, false)
+        .await
+        .expect("Can delete zone");
+}
+
+/// Collect all items of a paged listing.
+async fn all_pages<F, Fut>(mut page: F) -> Vec<LowerName>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: std::future::Future<
+        Output = Result<Page<LowerName>, Box<dyn std::error::Error + Send + Sync>>,
+    >,
+{
+    let mut items = Vec::new();
+    let mut cursor = None;
+    loop {
+        let Page {
+            items: page_items,
+            next_cursor,
+        } = page(cursor).await.expect("Can list page");
+        items.extend(page_items);
+        match next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return items,
+        }
+    }
+}
+
+/// The data of records in presentation format, sorted, as backends don't have to keep the order
+/// of the records in an RRset.
+fn data_of(records: &[StorageRecord]) -> Vec<String> {
+    let mut data = records
+        .iter()
+        .filter_map(|sr| sr.as_record().data().map(ToString::to_string))
+        .collect::<Vec<_>>();
+    data.sort();
+    data
+}
+
+async fn zones<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let mut created = Vec::new();
+    for _ in 0..3 {
+        created.push(create_zone(storage).await.1);
+    }
+    let missing = lower(&random_zone());
+
+    let zones = storage.zones().await.expect("Can list zones");
+    for zone in &created {
+        assert!(zones.contains(zone), "{} is listed", zone);
+        assert!(storage.zone_exists(zone).await.expect("Can check zone"));
+    }
+    assert!(!zones.contains(&missing));
+    assert!(!storage.zone_exists(&missing).await.expect("Can check zone"));
+
+    // Adding an existing zone keeps it as it is.
+    storage.add_zone(&created[0]).await.expect("Can add zone");
+    assert!(storage
+        .lookup_records(&created[0], &created[0], RecordType::SOA)
+        .await
+        .expect("Can look up records")
+        .is_some_and(|soa| soa.len() == 1));
+
+    let paged =
+        all_pages(|cursor| async move { storage.zones_page(cursor.as_deref(), 2).await }).await;
+    for zone in &created {
+        assert!(paged.contains(zone), "{} is listed in pages", zone);
+    }
+    let err = storage
+        .zones_page(Some(".."), 2)
+        .await
+        .expect_err("Cursor is refused");
+    assert!(err.is::<InvalidCursor>(), "{}", err);
+
+    for zone in &created {
+        remove_zone(storage, zone).await;
+    }
+}
+
+async fn records<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = lower(&format!("www.{}", name));
+    let mail = lower(&format!("mail.{}", name));
+    for record in [
+        a(&www.to_string(), [192, 0, 2, 1]),
+        a(&www.to_string(), [192, 0, 2, 2]),
+        cname(&mail.to_string(), &www.to_string()),
+    ] {
+        let domain = LowerName::from(record.as_record().name());
+        storage
+            .add_record(&zone, &domain, record)
+            .await
+            .expect("Can add record");
+    }
+
+    let addresses = storage
+        .lookup_records(&www, &zone, RecordType::A)
+        .await
+        .expect("Can look up records")
+        .expect("Domain exists");
+    assert_eq!(data_of(&addresses), vec!["192.0.2.1", "192.0.2.2"]);
+    // A domain without records of the type exists, a domain without records does not.
+    assert!(storage
+        .lookup_records(&www, &zone, RecordType::AAAA)
+        .await
+        .expect("Can look up records")
+        .is_some_and(|records| records.is_empty()));
+    let missing = lower(&format!("missing.{}", name));
+    assert!(storage
+        .lookup_records(&missing, &zone, RecordType::A)
+        .await
+        .expect("Can look up records")
+        .is_none());
+    assert!(storage
+        .lookup_all_records(&missing, &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+
+    let all = storage
+        .lookup_all_records(&mail, &zone)
+        .await
+        .expect("Can look up records")
+        .expect("Domain exists");
+    assert_eq!(data_of(&all), vec![www.to_string()]);
+    assert_eq!(
+        data_of(&storage.list_records(&zone, &www).await.expect("Can list")),
+        data_of(&addresses)
+    );
+    assert!(storage
+        .list_records(&zone, &missing)
+        .await
+        .expect("Can list records")
+        .is_empty());
+
+    let bulk = storage
+        .list_records_bulk(&zone, &[mail.clone(), missing.clone(), www.clone()])
+        .await
+        .expect("Can list records");
+    assert_eq!(bulk.len(), 3);
+    assert_eq!(data_of(&bulk[0]), data_of(&all));
+    assert!(bulk[1].is_empty());
+    assert_eq!(data_of(&bulk[2]), data_of(&addresses));
+
+    let domains = storage
+        .list_domains(&zone)
+        .await
+        .expect("Can list domains")
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let expected = [zone.clone(), www.clone(), mail.clone()]
+        .into_iter()
+        .collect::<HashSet<_>>();
+    assert_eq!(domains, expected);
+    let paged = all_pages(|cursor| {
+        let zone = &zone;
+        async move { storage.list_domains_page(zone, cursor.as_deref(), 1).await }
+    })
+    .await
+    .into_iter()
+    .collect::<HashSet<_>>();
+    assert_eq!(paged, expected);
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn set_records<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = format!("www.{}", name);
+    let domain = lower(&www);
+
+    let replaced = storage
+        .set_records(&zone, &domain, RecordType::A, vec![a(&www, [192, 0, 2, 1])])
+        .await
+        .expect("Can set records");
+    assert!(!replaced, "RRset is created");
+    let replaced = storage
+        .set_records(
+            &zone,
+            &domain,
+            RecordType::A,
+            vec![a(&www, [192, 0, 2, 2]), a(&www, [192, 0, 2, 3])],
+        )
+        .await
+        .expect("Can set records");
+    assert!(replaced, "RRset is replaced");
+    assert_eq!(
+        data_of(
+            &storage
+                .list_records(&zone, &domain)
+                .await
+                .expect("Can list records")
+        ),
+        vec!["192.0.2.2", "192.0.2.3"]
+    );
+
+    // An empty RRset removes it, and the domain with it.
+    let replaced = storage
+        .set_records(&zone, &domain, RecordType::A, Vec::new())
+        .await
+        .expect("Can set records");
+    assert!(replaced, "RRset is removed");
+    assert!(storage
+        .lookup_records(&domain, &zone, RecordType::A)
+        .await
+        .expect("Can look up records")
+        .is_none());
+    let replaced = storage
+        .set_records(&zone, &domain, RecordType::A, Vec::new())
+        .await
+        .expect("Can set records");
+    assert!(!replaced, "Nothing is removed");
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn remove_records<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = format!("www.{}", name);
+    let domain = lower(&www);
+    storage
+        .set_records(
+            &zone,
+            &domain,
+            RecordType::A,
+            vec![a(&www, [192, 0, 2, 1]), a(&www, [192, 0, 2, 2])],
+        )
+        .await
+        .expect("Can set records");
+    storage
+        .add_record(
+            &zone,
+            &domain,
+            record(&www, 300, RData::TXT(TXT::new(vec!["x".to_string()]))),
+        )
+        .await
+        .expect("Can add record");
+
+    // The TTL is not compared.
+    let mut other_ttl = a(&www, [192, 0, 2, 1]);
+    other_ttl.as_mut_record().set_ttl(60);
+    let removed = storage
+        .remove_record(&zone, &domain, other_ttl.as_record())
+        .await
+        .expect("Can remove record")
+        .expect("Record exists");
+    assert_eq!(removed.as_record().ttl(), 300);
+    assert!(storage
+        .remove_record(&zone, &domain, other_ttl.as_record())
+        .await
+        .expect("Can remove record")
+        .is_none());
+    storage
+        .remove_record(&zone, &domain, a(&www, [192, 0, 2, 2]).as_record())
+        .await
+        .expect("Can remove record")
+        .expect("Record exists");
+    assert!(storage
+        .lookup_records(&domain, &zone, RecordType::A)
+        .await
+        .expect("Can look up records")
+        .is_some_and(|records| records.is_empty()));
+
+    let removed = storage
+        .remove_rrset(&zone, &domain, RecordType::TXT)
+        .await
+        .expect("Can remove RRset");
+    assert_eq!(removed.len(), 1);
+    assert!(storage
+        .remove_rrset(&zone, &domain, RecordType::TXT)
+        .await
+        .expect("Can remove RRset")
+        .is_empty());
+    assert!(storage
+        .lookup_all_records(&domain, &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+    assert!(!storage
+        .list_domains(&zone)
+        .await
+        .expect("Can list domains")
+        .contains(&domain));
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn batches<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let (www, mail) = (format!("www.{}", name), format!("mail.{}", name));
+    storage
+        .apply(
+            [
+                RecordSet {
+                    domain: lower(&www),
+                    rtype: RecordType::A,
+                    records: vec![a(&www, [192, 0, 2, 1])],
+                },
+                RecordSet {
+                    domain: lower(&mail),
+                    rtype: RecordType::A,
+                    records: vec![a(&mail, [192, 0, 2, 2])],
+                },
+            ]
+            .into_iter()
+            .map(|rrset| rrset.into_op(&zone))
+            .collect(),
+        )
+        .await
+        .expect("Can apply batch");
+    storage
+        .apply(
+            [
+                RecordSet {
+                    domain: lower(&www),
+                    rtype: RecordType::A,
+                    records: vec![a(&www, [192, 0, 2, 3])],
+                },
+                RecordSet {
+                    domain: lower(&mail),
+                    rtype: RecordType::A,
+                    records: Vec::new(),
+                },
+            ]
+            .into_iter()
+            .map(|rrset| rrset.into_op(&zone))
+            .collect(),
+        )
+        .await
+        .expect("Can apply batch");
+    assert_eq!(
+        data_of(
+            &storage
+                .list_records(&zone, &lower(&www))
+                .await
+                .expect("Can list records")
+        ),
+        vec!["192.0.2.3"]
+    );
+    assert!(storage
+        .lookup_all_records(&lower(&mail), &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+
+    // A batch creating a zone with its records.
+    let other_name = random_zone();
+    let other = lower(&other_name);
+    let other_www = format!("www.{}", other_name);
+    storage
+        .apply(vec![
+            StorageOp::SetRecords {
+                zone: other.clone(),
+                domain: other.clone(),
+                rtype: RecordType::SOA,
+                records: vec![soa(&other_name, 1)],
+            },
+            StorageOp::SetRecords {
+                zone: other.clone(),
+                domain: lower(&other_www),
+                rtype: RecordType::A,
+                records: vec![a(&other_www, [192, 0, 2, 4])],
+            },
+            StorageOp::AddZone {
+                zone: other.clone(),
+            },
+            StorageOp::RemoveRrset {
+                zone: zone.clone(),
+                domain: lower(&www),
+                rtype: RecordType::A,
+            },
+        ])
+        .await
+        .expect("Can apply batch");
+    assert!(storage.zone_exists(&other).await.expect("Can check zone"));
+    assert_eq!(
+        storage
+            .list_domains(&other)
+            .await
+            .expect("Can list domains")
+            .len(),
+        2
+    );
+    assert!(storage
+        .lookup_all_records(&lower(&www), &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+
+    storage
+        .apply(vec![StorageOp::DeleteZone {
+            zone: other.clone(),
+        }])
+        .await
+        .expect("Can apply batch");
+    assert!(!storage.zone_exists(&other).await.expect("Can check zone"));
+    assert!(storage
+        .lookup_all_records(&lower(&other_www), &other)
+        .await
+        .expect("Can look up records")
+        .is_none());
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn names<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let deep = format!("a.b.c.{}", name);
+    storage
+        .add_record(&zone, &lower(&deep), a(&deep, [192, 0, 2, 1]))
+        .await
+        .expect("Can add record");
+
+    for (domain, below) in [
+        (name.clone(), true),
+        (format!("c.{}", name), true),
+        (format!("b.c.{}", name), true),
+        (deep.clone(), false),
+        (format!("x.{}", name), false),
+        (format!("bc.{}", name), false),
+    ] {
+        assert_eq!(
+            storage
+                .has_subdomains(&zone, &lower(&domain))
+                .await
+                .expect("Can check subdomains"),
+            below,
+            "{}",
+            domain
+        );
+    }
+    for (query, encloser) in [
+        (format!("x.{}", name), name.clone()),
+        (format!("x.c.{}", name), format!("c.{}", name)),
+        (format!("x.y.b.c.{}", name), format!("b.c.{}", name)),
+        (format!("x.{}", deep), deep.clone()),
+        (deep.clone(), deep.clone()),
+        (format!("c.{}", name), format!("c.{}", name)),
+    ] {
+        assert_eq!(
+            storage
+                .closest_encloser(&zone, &lower(&query))
+                .await
+                .expect("Can find closest encloser"),
+            lower(&encloser),
+            "{}",
+            query
+        );
+    }
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn zone_cuts<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let (child, redirect) = (format!("child.{}", name), format!("old.{}", name));
+    for (domain, record) in [
+        (
+            name.clone(),
+            record(&name, 3600, RData::NS(name_of(&format!("ns1.{}", name)))),
+        ),
+        (
+            child.clone(),
+            record(&child, 3600, RData::NS(name_of("ns1.example.net."))),
+        ),
+        (
+            redirect.clone(),
+            StorageRecord::new(
+                dname::record(name_of(&redirect), 300, &name_of("example.net."))
+                    .expect("Valid DNAME record"),
+            ),
+        ),
+    ] {
+        storage
+            .add_record(&zone, &lower(&domain), record)
+            .await
+            .expect("Can add record");
+    }
+
+    let mut expected = ZoneCuts::default();
+    expected.insert(CutKind::Delegation, lower(&child));
+    expected.insert(CutKind::Dname, lower(&redirect));
+    assert_eq!(
+        storage.zone_cuts(&zone).await.expect("Can load cuts"),
+        expected
+    );
+
+    storage
+        .remove_rrset(&zone, &lower(&child), RecordType::NS)
+        .await
+        .expect("Can remove RRset");
+    storage
+        .set_records(&zone, &lower(&redirect), dname::DNAME, Vec::new())
+        .await
+        .expect("Can set records");
+    assert_eq!(
+        storage.zone_cuts(&zone).await.expect("Can load cuts"),
+        ZoneCuts::default()
+    );
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn policy<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (_, zone) = create_zone(storage).await;
+    assert_eq!(
+        storage.zone_policy(&zone).await.expect("Can load policy"),
+        ZonePolicy::default()
+    );
+    let policy = ZonePolicy {
+        min_ttl: Some(60),
+        auto_ptr: Some(true),
+        ..Default::default()
+    };
+    storage
+        .set_zone_policy(&zone, &policy)
+        .await
+        .expect("Can set policy");
+    assert_eq!(
+        storage.zone_policy(&zone).await.expect("Can load policy"),
+        policy
+    );
+    // A batch adding the zone keeps its policy, adding the zone by itself resets it.
+    storage
+        .apply(vec![StorageOp::AddZone { zone: zone.clone() }])
+        .await
+        .expect("Can apply batch");
+    assert_eq!(
+        storage.zone_policy(&zone).await.expect("Can load policy"),
+        policy
+    );
+    storage.add_zone(&zone).await.expect("Can add zone");
+    assert_eq!(
+        storage.zone_policy(&zone).await.expect("Can load policy"),
+        ZonePolicy::default()
+    );
+
+    remove_zone(storage, &zone).await;
+    assert_eq!(
+        storage.zone_policy(&zone).await.expect("Can load policy"),
+        ZonePolicy::default()
+    );
+}
+
+async fn journal<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = format!("www.{}", name);
+    for serial in 1..4 {
+        storage
+            .append_change(
+                &zone,
+                &ZoneChange {
+                    from_serial: serial,
+                    serial: serial + 1,
+                    removed: vec![a(&www, [192, 0, 2, serial as u8])],
+                    added: vec![a(&www, [192, 0, 2, serial as u8 + 1])],
+                },
+            )
+            .await
+            .expect("Can append change");
+    }
+
+    let changes = storage
+        .changes_since(&zone, 2)
+        .await
+        .expect("Can read journal")
+        .expect("Journal goes back far enough");
+    assert_eq!(
+        changes
+            .iter()
+            .map(|change| (change.from_serial, change.serial))
+            .collect::<Vec<_>>(),
+        vec![(2, 3), (3, 4)]
+    );
+    assert_eq!(data_of(&changes[0].added), vec!["192.0.2.3"]);
+    assert!(storage
+        .changes_since(&zone, 100)
+        .await
+        .expect("Can read journal")
+        .is_none());
+
+    // A change missing from the journal is never skipped.
+    storage
+        .append_change(
+            &zone,
+            &ZoneChange {
+                from_serial: 5,
+                serial: 6,
+                removed: Vec::new(),
+                added: Vec::new(),
+            },
+        )
+        .await
+        .expect("Can append change");
+    assert!(storage
+        .changes_since(&zone, 3)
+        .await
+        .expect("Can read journal")
+        .is_none());
+    assert_eq!(
+        storage
+            .changes_since(&zone, 5)
+            .await
+            .expect("Can read journal")
+            .expect("Journal goes back far enough")
+            .len(),
+        1
+    );
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn serials<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = format!("www.{}", name);
+
+    // A swap of an SOA with a serial which changed fails the whole batch.
+    let batch = |serial, new_serial| {
+        vec![
+            StorageOp::SetRecords {
+                zone: zone.clone(),
+                domain: lower(&www),
+                rtype: RecordType::A,
+                records: vec![a(&www, [192, 0, 2, 1])],
+            },
+            StorageOp::SwapSoa {
+                zone: zone.clone(),
+                serial,
+                soa: Box::new(soa(&name, new_serial)),
+            },
+        ]
+    };
+    let err = storage
+        .apply(batch(7, 8))
+        .await
+        .expect_err("Serial changed");
+    assert!(err.is::<SerialChanged>(), "{}", err);
+    assert!(storage
+        .lookup_all_records(&lower(&www), &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+    storage.apply(batch(1, 7)).await.expect("Can apply batch");
+    assert_eq!(
+        storage
+            .bump_serial(&zone, None)
+            .await
+            .expect("Can bump serial"),
+        Some((7, 8))
+    );
+    storage
+        .remove_rrset(&zone, &lower(&www), RecordType::A)
+        .await
+        .expect("Can remove RRset");
+    storage
+        .set_records(&zone, &zone, RecordType::SOA, vec![soa(&name, 1)])
+        .await
+        .expect("Can reset SOA");
+
+    assert_eq!(
+        storage
+            .bump_serial(&zone, None)
+            .await
+            .expect("Can bump serial"),
+        Some((1, 2))
+    );
+    assert_eq!(
+        storage
+            .bump_serial(&zone, Some(2024010100))
+            .await
+            .expect("Can bump serial"),
+        Some((2, 2024010100))
+    );
+    // A floor behind the incremented serial is ignored.
+    assert_eq!(
+        storage
+            .bump_serial(&zone, Some(5))
+            .await
+            .expect("Can bump serial"),
+        Some((2024010100, 2024010101))
+    );
+    let soa = storage
+        .lookup_records(&zone, &zone, RecordType::SOA)
+        .await
+        .expect("Can look up records")
+        .expect("Zone has an SOA");
+    assert!(matches!(
+        soa[0].as_record().data(),
+        Some(RData::SOA(soa)) if soa.serial() == 2024010101
+    ));
+
+    storage
+        .remove_rrset(&zone, &zone, RecordType::SOA)
+        .await
+        .expect("Can remove RRset");
+    assert_eq!(
+        storage
+            .bump_serial(&zone, None)
+            .await
+            .expect("Can bump serial"),
+        None
+    );
+
+    remove_zone(storage, &zone).await;
+}
+
+async fn tokens<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let id = format!("conformance-{:08x}", rand::random::<u32>());
+    let mut token = ApiToken {
+        id: id.clone(),
+        secret_hash: "00".repeat(32),
+        scopes: vec![TokenScope::Read],
+        created_at: 1_700_000_000,
+        expires_at: None,
+        disabled: false,
+        last_used: None,
+    };
+    storage.set_token(&token).await.expect("Can set token");
+    storage
+        .set_token_last_used(&id, 1_700_000_100)
+        .await
+        .expect("Can set last used time");
+    // Changing the token keeps the time it was last used.
+    token.disabled = true;
+    storage.set_token(&token).await.expect("Can set token");
+
+    let stored = storage
+        .tokens()
+        .await
+        .expect("Can list tokens")
+        .into_iter()
+        .find(|token| token.id == id)
+        .expect("Token is listed");
+    assert!(stored.disabled);
+    assert_eq!(stored.scopes, vec![TokenScope::Read]);
+    assert_eq!(stored.last_used, Some(1_700_000_100));
+}
+
+async fn dnssec_keys<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (_, zone) = create_zone(storage).await;
+    let key = |id: &str| DnssecKey {
+        id: id.to_string(),
+        role: KeyRole::Zsk,
+        algorithm: KeyAlgorithm::Ed25519,
+        private_key: String::new(),
+        public_key: vec![1, 2, 3],
+        created_at: 1_700_000_000,
+        activate_at: 1_700_000_000,
+        retire_at: None,
+    };
+    storage
+        .set_dnssec_key(&zone, &key("one"))
+        .await
+        .expect("Can set key");
+    storage
+        .set_dnssec_key(&zone, &key("two"))
+        .await
+        .expect("Can set key");
+    let mut retired = key("two");
+    retired.retire_at = Some(1_700_000_100);
+    storage
+        .set_dnssec_key(&zone, &retired)
+        .await
+        .expect("Can set key");
+
+    let mut keys = storage.dnssec_keys(&zone).await.expect("Can list keys");
+    keys.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(
+        keys.iter()
+            .map(|key| (key.id.as_str(), key.retire_at))
+            .collect::<Vec<_>>(),
+        vec![("one", None), ("two", Some(1_700_000_100))]
+    );
+
+    assert!(storage
+        .remove_dnssec_key(&zone, "one")
+        .await
+        .expect("Can remove key"));
+    assert!(!storage
+        .remove_dnssec_key(&zone, "one")
+        .await
+        .expect("Can remove key"));
+    assert_eq!(
+        storage
+            .dnssec_keys(&zone)
+            .await
+            .expect("Can list keys")
+            .len(),
+        1
+    );
+
+    remove_zone(storage, &zone).await;
+    assert!(storage
+        .dnssec_keys(&zone)
+        .await
+        .expect("Can list keys")
+        .is_empty());
+}
+
+async fn delete_zone<S>(storage: &S)
+where
+    S: Storage + Sync,
+{
+    let (name, zone) = create_zone(storage).await;
+    let www = format!("www.{}", name);
+    storage
+        .add_record(&zone, &lower(&www), a(&www, [192, 0, 2, 1]))
+        .await
+        .expect("Can add record");
+
+    let entries = storage
+        .delete_zone(&zone, true)
+        .await
+        .expect("Can count entries");
+    assert!(entries > 0);
+    assert!(storage.zone_exists(&zone).await.expect("Can check zone"));
+
+    assert_eq!(
+        storage
+            .delete_zone(&zone, false)
+            .await
+            .expect("Can delete zone"),
+        entries
+    );
+    assert!(!storage.zone_exists(&zone).await.expect("Can check zone"));
+    assert!(storage
+        .list_domains(&zone)
+        .await
+        .expect("Can list domains")
+        .is_empty());
+    assert!(storage
+        .lookup_all_records(&lower(&www), &zone)
+        .await
+        .expect("Can look up records")
+        .is_none());
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use prometheus::IntGauge;
+use trust_dns_server::client::rr::LowerName;
+
+use crate::prefix::IpPrefix;
+
+/// Log target of query traces, so they can be routed separately from the regular logs.
+const TRACE_LOG_TARGET: &str = "cetus::query_trace";
+/// Interval at which expired filters are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runtime settable filters selecting queries for which a detailed resolution trace is logged.
+/// Filters only live in memory of this instance, and always expire.
+pub struct QueryTracer {
+    filters: RwLock<Vec<TraceFilter>>,
+    // amount of active filters, so the common case of no filters doesn't need the lock.
+    active: AtomicUsize,
+    next_filter_id: AtomicU64,
+    next_trace_id: AtomicU64,
+    active_gauge: IntGauge,
+}
+
+/// A single trace filter. A query matches if it matches all of the set criteria.
+#[derive(Clone)]
+pub struct TraceFilter {
+    pub id: u64,
+    pub qname_suffix: Option<LowerName>,
+    pub client_prefix: Option<IpPrefix>,
+    // unix timestamp in seconds after which the filter is no longer active.
+    pub expires_at: u64,
+}
+
+impl QueryTracer {
+    /// Create a new tracer without any filters.
+    pub fn new() -> QueryTracer {
+        QueryTracer {
+            filters: RwLock::new(Vec::new()),
+            active: AtomicUsize::new(0),
+            next_filter_id: AtomicU64::new(1),
+            next_trace_id: AtomicU64::new(1),
+            active_gauge: IntGauge::new("active_trace_filters", "amount of active trace filters")
+                .expect("Can create active trace filter gauge"),
+        }
+    }
+
+    /// Gauge tracking the amount of active filters, to be registered in a metrics registry.
+    pub fn active_gauge(&self) -> IntGauge {
+        self.active_gauge.clone()
+    }
+
+    /// Add a new filter which is active for the given duration, returning the filter.
+    pub fn add_filter(
+        &self,
+        qname_suffix: Option<LowerName>,
+        client_prefix: Option<IpPrefix>,
+        duration: Duration,
+    ) -> TraceFilter {
+        let filter = TraceFilter {
+            id: self.next_filter_id.fetch_add(1, Ordering::Relaxed),
+            qname_suffix,
+            client_prefix,
+            expires_at: unix_now() + duration.as_secs(),
+        };
+        let mut filters = self.filters.write().unwrap();
+        filters.push(filter.clone());
+        self.update_active(filters.len());
+        filter
+    }
+
+    /// List all filters which are still active.
+    pub fn filters(&self) -> Vec<TraceFilter> {
+        let now = unix_now();
+        self.filters
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|filter| filter.expires_at > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the filter with the given id. Returns false if no such filter exists.
+    pub fn remove_filter(&self, id: u64) -> bool {
+        let mut filters = self.filters.write().unwrap();
+        let len = filters.len();
+        filters.retain(|filter| filter.id != id);
+        self.update_active(filters.len());
+        len != filters.len()
+    }
+
+    /// Remove all filters.
+    pub fn clear(&self) {
+        let mut filters = self.filters.write().unwrap();
+        filters.clear();
+        self.update_active(0);
+    }
+
+    /// Start a trace for a query. The returned trace only logs anything if one of the active
+    /// filters matches the query.
+    pub fn start(&self, name: &LowerName, client: IpAddr) -> QueryTrace {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return QueryTrace(None);
+        }
+
+        let now = unix_now();
+        let matched = self
+            .filters
+            .read()
+            .unwrap()
+            .iter()
+            .any(|filter| filter.expires_at > now && filter.matches(name, client));
+        if !matched {
+            return QueryTrace(None);
+        }
+
+        let trace = QueryTrace(Some(self.next_trace_id.fetch_add(1, Ordering::Relaxed)));
+        trace.stage("query", || format!("{} from {}", name, client));
+        trace
+    }
+
+    /// Periodically remove expired filters, so they stop being checked and the gauge is
+    /// accurate.
+    pub async fn expiry_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.active.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let now = unix_now();
+            let mut filters = self.filters.write().unwrap();
+            filters.retain(|filter| filter.expires_at > now);
+            self.update_active(filters.len());
+        }
+    }
+
+    fn update_active(&self, active: usize) {
+        self.active.store(active, Ordering::Relaxed);
+        self.active_gauge.set(active as i64);
+    }
+}
+
+impl Default for QueryTracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceFilter {
+    fn matches(&self, name: &LowerName, client: IpAddr) -> bool {
+        if let Some(ref suffix) = self.qname_suffix {
+            if !suffix.zone_of(name) {
+                return false;
+            }
+        }
+        if let Some(ref prefix) = self.client_prefix {
+            if !prefix.contains(client) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A (possibly disabled) trace of a single query. All stages logged on a trace are tagged with the
+/// same trace id.
+pub struct QueryTrace(Option<u64>);
+
+impl QueryTrace {
+    /// Log a resolution stage. The detail is only generated if the trace is enabled.
+    pub fn stage<F, D>(&self, stage: &str, detail: F)
+    where
+        F: FnOnce() -> D,
+        D: fmt::Display,
+    {
+        if let Some(id) = self.0 {
+            info!(target: TRACE_LOG_TARGET, "trace_id={} stage={} {}", id, stage, detail());
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time is after the unix epoch")
+        .as_secs()
+}
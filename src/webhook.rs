@@ -0,0 +1,352 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    client::conn,
+    header::{CONTENT_TYPE, HOST, USER_AGENT},
+    Body, Request, Uri,
+};
+use log::{debug, error, warn};
+use ring::hmac;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use utoipa::ToSchema;
+
+use crate::config::WebhookConfig;
+
+/// Amount of times an event is sent to an endpoint before giving up.
+const DELIVERY_ATTEMPTS: u32 = 5;
+/// Time to wait before retrying a failed delivery. This doubles after every attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum time a single delivery attempt may take, including connecting.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Amount of events which can wait for delivery. Events which don't fit are dropped, so a slow
+/// endpoint can't make the API use unbounded memory.
+const EVENT_QUEUE_SIZE: usize = 1_000;
+/// Amount of delivery attempts kept for inspection.
+const DELIVERY_LOG_SIZE: usize = 200;
+/// Header holding the hex encoded HMAC-SHA256 of the body, if the endpoint has a secret.
+const SIGNATURE_HEADER: &str = "x-cetus-signature";
+/// Header holding the action of the event, so endpoints can route events without parsing them.
+const EVENT_HEADER: &str = "x-cetus-event";
+
+/// A change made through the API, as sent to webhook endpoints.
+#[derive(Serialize, Clone, Debug)]
+pub struct WebhookEvent {
+    /// What happened, like `zone.add` or `record.delete`.
+    pub action: &'static str,
+    pub zone: String,
+    /// The domain of the changed records, not set for changes of a zone as a whole.
+    pub name: Option<String>,
+    /// The type of the changed records, not set for changes of a zone as a whole.
+    #[serde(rename = "type")]
+    pub record_type: Option<String>,
+    /// Id of the token which made the change, or `bootstrap` for a token from the config file.
+    /// Not set if authentication is disabled.
+    pub actor: Option<String>,
+    /// Time of the change, in seconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+/// Outcome of a single attempt to deliver an event.
+#[derive(Serialize, ToSchema, Clone)]
+pub struct Delivery {
+    url: String,
+    action: &'static str,
+    zone: String,
+    attempt: u32,
+    /// Time of the attempt, in seconds since the unix epoch.
+    timestamp: u64,
+    status: DeliveryStatus,
+    /// Status code of the response of the endpoint, if it responded.
+    response_status: Option<u16>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// The endpoint accepted the event.
+    Delivered,
+    /// The attempt failed, and will be retried.
+    Retrying,
+    /// The last attempt failed, the event is dropped for this endpoint.
+    Failed,
+}
+
+struct Endpoint {
+    url: String,
+    uri: Uri,
+    key: Option<hmac::Key>,
+}
+
+/// Handle to the background task delivering events to webhook endpoints. This can be cheaply
+/// cloned.
+#[derive(Clone)]
+pub struct Webhooks {
+    events: Option<mpsc::Sender<WebhookEvent>>,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+}
+
+impl Webhooks {
+    /// Create a new handle delivering to the given endpoints. If there are no endpoints, events
+    /// are discarded right away.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if called outside the context of a `[tokio]` runtime.
+    pub fn new(configs: Vec<WebhookConfig>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let deliveries = Arc::new(Mutex::new(VecDeque::with_capacity(DELIVERY_LOG_SIZE)));
+        if configs.is_empty() {
+            return Ok(Webhooks {
+                events: None,
+                deliveries,
+            });
+        }
+
+        let mut endpoints = Vec::with_capacity(configs.len());
+        for config in configs {
+            let uri = config
+                .url
+                .parse::<Uri>()
+                .map_err(|e| format!("Invalid webhook url {}: {}", config.url, e))?;
+            if !matches!(uri.scheme_str(), Some("http") | Some("https")) || uri.host().is_none() {
+                return Err(format!("Webhook url {} is not an http(s) url", config.url).into());
+            }
+            endpoints.push(Arc::new(Endpoint {
+                url: config.url,
+                uri,
+                key: config
+                    .secret
+                    .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+            }));
+        }
+
+        let (events, rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        tokio::spawn(delivery_loop(rx, endpoints, deliveries.clone()));
+        Ok(Webhooks {
+            events: Some(events),
+            deliveries,
+        })
+    }
+
+    /// Queue an event for delivery to all endpoints.
+    pub fn send(&self, event: WebhookEvent) {
+        if let Some(events) = &self.events {
+            if let Err(e) = events.try_send(event) {
+                warn!("Dropping webhook event: {}", e);
+            }
+        }
+    }
+
+    /// The most recent delivery attempts, newest first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+}
+
+async fn delivery_loop(
+    mut events: mpsc::Receiver<WebhookEvent>,
+    endpoints: Vec<Arc<Endpoint>>,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+) {
+    let tls = TlsConnector::from(Arc::new(tls_client_config()));
+    while let Some(event) = events.recv().await {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to encode webhook event: {}", e);
+                continue;
+            }
+        };
+        for endpoint in &endpoints {
+            // Every endpoint gets its own task, so an endpoint which is down doesn't hold up the
+            // others while its deliveries are retried.
+            tokio::spawn(deliver(
+                endpoint.clone(),
+                tls.clone(),
+                event.clone(),
+                body.clone(),
+                deliveries.clone(),
+            ));
+        }
+    }
+}
+
+/// Deliver an event to an endpoint, retrying with exponential backoff.
+async fn deliver(
+    endpoint: Arc<Endpoint>,
+    tls: TlsConnector,
+    event: WebhookEvent,
+    body: Vec<u8>,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+) {
+    let signature = endpoint.key.as_ref().map(|key| {
+        format!(
+            "sha256={}",
+            faster_hex::hex_string(hmac::sign(key, &body).as_ref())
+        )
+    });
+    let mut delay = INITIAL_RETRY_DELAY;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let result = tokio::time::timeout(
+            DELIVERY_TIMEOUT,
+            post(&endpoint, &tls, &event, &body, signature.as_deref()),
+        )
+        .await
+        .unwrap_or_else(|_| Err("timed out".into()));
+        let (response_status, error) = match result {
+            Ok(status) if (200..300).contains(&status) => (Some(status), None),
+            Ok(status) => (
+                Some(status),
+                Some(format!("endpoint responded with {}", status)),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let status = match (&error, attempt) {
+            (None, _) => DeliveryStatus::Delivered,
+            (Some(_), DELIVERY_ATTEMPTS) => DeliveryStatus::Failed,
+            (Some(_), _) => DeliveryStatus::Retrying,
+        };
+        match &error {
+            None => debug!("Delivered {} event to {}", event.action, endpoint.url),
+            Some(e) => warn!(
+                "Failed to deliver {} event to {} (attempt {}): {}",
+                event.action, endpoint.url, attempt, e
+            ),
+        }
+
+        log_delivery(
+            &deliveries,
+            Delivery {
+                url: endpoint.url.clone(),
+                action: event.action,
+                zone: event.zone.clone(),
+                attempt,
+                timestamp: unix_now(),
+                status,
+                response_status,
+                error,
+            },
+        );
+
+        if !matches!(status, DeliveryStatus::Retrying) {
+            return;
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// Add a delivery attempt to the log, dropping the oldest attempt if the log is full.
+fn log_delivery(deliveries: &Mutex<VecDeque<Delivery>>, delivery: Delivery) {
+    let mut log = deliveries.lock().unwrap();
+    if log.len() == DELIVERY_LOG_SIZE {
+        log.pop_front();
+    }
+    log.push_back(delivery);
+}
+
+/// Post the event to the endpoint, returning the status code of the response.
+async fn post(
+    endpoint: &Endpoint,
+    tls: &TlsConnector,
+    event: &WebhookEvent,
+    body: &[u8],
+    signature: Option<&str>,
+) -> Result<u16, Box<dyn Error + Send + Sync>> {
+    // Presence of the host is checked when the endpoint is configured.
+    let host = endpoint.uri.host().unwrap_or_default();
+    let https = endpoint.uri.scheme_str() == Some("https");
+    let port = endpoint
+        .uri
+        .port_u16()
+        .unwrap_or(if https { 443 } else { 80 });
+    // IPv6 literals are bracketed in urls, but not in socket addresses.
+    let address = host.trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((address, port)).await?;
+
+    let mut request = Request::post(
+        endpoint
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str()),
+    )
+    .header(
+        HOST,
+        endpoint
+            .uri
+            .authority()
+            .map_or(host, |authority| authority.as_str()),
+    )
+    .header(CONTENT_TYPE, "application/json")
+    .header(USER_AGENT, concat!("cetus/", env!("CARGO_PKG_VERSION")))
+    .header(EVENT_HEADER, event.action);
+    if let Some(signature) = signature {
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+    let request = request.body(Body::from(body.to_vec()))?;
+
+    if https {
+        let server_name = ServerName::try_from(address)?;
+        let stream = tls.connect(server_name, stream).await?;
+        send(stream, request).await
+    } else {
+        send(stream, request).await
+    }
+}
+
+async fn send<T>(io: T, request: Request<Body>) -> Result<u16, Box<dyn Error + Send + Sync>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Webhook connection failed: {}", e);
+        }
+    });
+    let response = sender.send_request(request).await?;
+    Ok(response.status().as_u16())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time is after the unix epoch")
+        .as_secs()
+}
+
+/// TLS configuration trusting the Mozilla root certificates.
+fn tls_client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
@@ -0,0 +1,58 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::storage::StorageRecord;
+
+/// How a weighted RRset is answered.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightedAnswer {
+    /// Answer with only the picked record.
+    #[default]
+    Pick,
+    /// Answer with the full set, with the picked record first.
+    All,
+}
+
+/// Pick a record of an RRset at random, proportional to the weights of the records. Records
+/// without a weight are not part of the pick, and are always included in the answer. The set is
+/// answered with all records if any weighted record asks for it, otherwise only the picked record
+/// is kept.
+///
+/// Returns true if a record was picked, in which case it is the first record of the set. Sets
+/// without weighted records are left untouched.
+pub fn select(records: &mut Vec<StorageRecord>) -> bool {
+    let total = records
+        .iter()
+        .filter_map(|sr| sr.weight)
+        .map(u64::from)
+        .sum::<u64>();
+    if total == 0 {
+        return false;
+    }
+
+    let mut point = rand::thread_rng().gen_range(0..total);
+    let picked = records
+        .iter()
+        .position(|sr| match sr.weight {
+            Some(weight) if point < weight as u64 => true,
+            Some(weight) => {
+                point -= weight as u64;
+                false
+            }
+            None => false,
+        })
+        // The points cover exactly the total weight, so a record is always found.
+        .expect("Weighted pick is within the total weight");
+
+    let answer_all = records
+        .iter()
+        .any(|sr| sr.weight.is_some() && sr.weighted_answer == Some(WeightedAnswer::All));
+    let picked = records.remove(picked);
+    if !answer_all {
+        records.retain(|sr| sr.weight.is_none());
+    }
+    records.insert(0, picked);
+    true
+}